@@ -0,0 +1,159 @@
+use eframe::egui::Color32;
+use std::iter::Peekable;
+use std::str::Chars;
+
+const ESC: char = '\x1b';
+const BEL: char = '\x07';
+
+/// Levels of the 6x6x6 colour cube of the 256 colour palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// Whether the text has escape sequences, e.g. pasted terminal output
+pub fn has_escapes(text: &str) -> bool {
+    text.contains(ESC)
+}
+
+/// The text without its escape sequences
+pub fn strip(text: &str) -> String {
+    colored_spans(text, Color32::PLACEHOLDER, false)
+        .into_iter()
+        .map(|(text, _)| text)
+        .collect()
+}
+
+/// Text split where its colour changes, with the escape sequences taken
+/// out. SGR foreground colours are applied, other sequences are dropped
+pub fn colored_spans(text: &str, default: Color32, is_dark_mode: bool) -> Vec<(String, Color32)> {
+    let mut spans = Vec::new();
+    let mut current = String::new();
+    let mut color = default;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != ESC {
+            current.push(c);
+            continue;
+        }
+        let Some(sgr) = skip_sequence(&mut chars) else {
+            continue;
+        };
+        let next = sgr_color(&sgr, color, default, is_dark_mode);
+        if next != color && !current.is_empty() {
+            spans.push((std::mem::take(&mut current), color));
+        }
+        color = next;
+    }
+    if !current.is_empty() {
+        spans.push((current, color));
+    }
+    spans
+}
+
+/// Consume the sequence after an ESC, returns the parameters when it's SGR
+fn skip_sequence(chars: &mut Peekable<Chars>) -> Option<String> {
+    match chars.peek() {
+        // CSI: parameters, then a final byte from @ to ~
+        Some('[') => {
+            chars.next();
+            let mut params = String::new();
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    return (c == 'm').then_some(params);
+                }
+                params.push(c);
+            }
+            None
+        }
+        // OSC, e.g. a window title, ends with BEL or ESC \
+        Some(']') => {
+            chars.next();
+            while let Some(c) = chars.next() {
+                if c == BEL || (c == ESC && chars.next_if_eq(&'\\').is_some()) {
+                    break;
+                }
+            }
+            None
+        }
+        // intermediate bytes then a final one, e.g. ESC ( B to pick a
+        // character set, or just the final one
+        Some(_) => {
+            while chars.next_if(|c| (' '..='/').contains(c)).is_some() {}
+            chars.next();
+            None
+        }
+        None => None,
+    }
+}
+
+/// Foreground colour once the SGR parameters apply, background colours
+/// and text attributes are ignored
+fn sgr_color(params: &str, mut color: Color32, default: Color32, is_dark_mode: bool) -> Color32 {
+    let mut codes = params.split(';').map(|code| code.parse::<u8>().unwrap_or(0));
+    while let Some(code) = codes.next() {
+        match code {
+            0 | 39 => color = default,
+            30..=37 => color = basic_color(code - 30, false, is_dark_mode),
+            90..=97 => color = basic_color(code - 90, true, is_dark_mode),
+            38 | 48 => {
+                let extended = match codes.next() {
+                    Some(5) => codes.next().map(|index| palette_color(index, is_dark_mode)),
+                    Some(2) => match (codes.next(), codes.next(), codes.next()) {
+                        (Some(r), Some(g), Some(b)) => Some(Color32::from_rgb(r, g, b)),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if let (38, Some(extended)) = (code, extended) {
+                    color = extended;
+                }
+            }
+            _ => {}
+        }
+    }
+    color
+}
+
+/// One of the 8 basic colours, readable on the code block background
+fn basic_color(index: u8, bright: bool, is_dark_mode: bool) -> Color32 {
+    let (r, g, b) = match (index, is_dark_mode) {
+        (0, true) => (110, 110, 110),
+        (0, false) => (40, 40, 40),
+        (1, true) => (240, 110, 110),
+        (1, false) => (180, 30, 30),
+        (2, true) => (130, 210, 110),
+        (2, false) => (30, 130, 30),
+        (3, true) => (230, 200, 100),
+        (3, false) => (150, 110, 0),
+        (4, true) => (110, 160, 240),
+        (4, false) => (30, 80, 190),
+        (5, true) => (210, 130, 220),
+        (5, false) => (140, 40, 150),
+        (6, true) => (100, 210, 210),
+        (6, false) => (20, 130, 140),
+        (_, true) => (220, 220, 220),
+        (_, false) => (120, 120, 120),
+    };
+    let color = Color32::from_rgb(r, g, b);
+    match (bright, is_dark_mode) {
+        (false, _) => color,
+        (true, true) => color.lerp_to_gamma(Color32::WHITE, 0.3),
+        (true, false) => color.lerp_to_gamma(Color32::BLACK, 0.2),
+    }
+}
+
+/// Colour of the 256 colour palette
+fn palette_color(index: u8, is_dark_mode: bool) -> Color32 {
+    match index {
+        0..=7 => basic_color(index, false, is_dark_mode),
+        8..=15 => basic_color(index - 8, true, is_dark_mode),
+        16..=231 => {
+            let cube = index - 16;
+            Color32::from_rgb(
+                CUBE_LEVELS[(cube / 36) as usize],
+                CUBE_LEVELS[(cube / 6 % 6) as usize],
+                CUBE_LEVELS[(cube % 6) as usize],
+            )
+        }
+        232..=255 => Color32::from_gray(8 + 10 * (index - 232)),
+    }
+}
@@ -1,11 +1,246 @@
-use anyhow::Result;
-use log::{info, debug};
+use anyhow::{Context, Result};
+use futures_util::stream::{BoxStream, StreamExt};
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use crate::attachment::Attachment;
 use crate::price::ModelPricing;
+use crate::sse::SseAssembler;
 
+/// Unparseable stream events tolerated in a row before the response fails
+const MAX_CONSECUTIVE_PARSE_FAILURES: usize = 3;
+
+/// Stream event types met that the app doesn't know, each is logged once
+static UNKNOWN_EVENT_TYPES: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Status the API answers with when it's over capacity
+const OVERLOADED_STATUS: u16 = 529;
+
+/// Tokens a response may take, they come out of the context window
+pub const MAX_OUTPUT_TOKENS: u32 = 4096;
+
+/// Time to connect to the API, a response may then stream for as long as
+/// it keeps sending, the stall timeout catches a dead connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whole time of the requests that don't stream, e.g. batch status
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shorter than the others, a key check is waited for
+const KEY_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Status the API answers with when a rate limit is hit
+const RATE_LIMITED_STATUS: u16 = 429;
+
+/// Share of a rate limit left under which it's reported as low
+const RATE_LIMIT_LOW_FRACTION: f64 = 0.1;
+
+/// A request the API answered with an error status
+#[derive(Debug, thiserror::Error)]
+#[error("API error ({status}): {body}{}", wait_hint(.status, .rate_limits))]
+pub struct ApiError {
+    pub status: reqwest::StatusCode,
+    pub body: String,
+    pub rate_limits: RateLimits,
+}
+
+/// When a rate limited request can be sent again, if the headers say
+fn wait_hint(status: &reqwest::StatusCode, rate_limits: &RateLimits) -> String {
+    if status.as_u16() != RATE_LIMITED_STATUS {
+        return String::new();
+    }
+    match (rate_limits.retry_after, rate_limits.next_reset()) {
+        (Some(seconds), Some(reset)) => format!(" (retry in {}s, the limit resets at {})", seconds, reset),
+        (Some(seconds), None) => format!(" (retry in {}s)", seconds),
+        (None, Some(reset)) => format!(" (the limit resets at {})", reset),
+        (None, None) => String::new(),
+    }
+}
+
+/// A response that stopped arriving, the connection is taken as lost
+#[derive(Debug, thiserror::Error)]
+#[error("Connection lost, nothing received for {0}s. Send again to retry")]
+pub struct StreamStalled(pub u64);
+
+/// The events, failing with `StreamStalled` once nothing, pings included,
+/// arrived for `timeout`. The stream ends there
+pub fn with_stall_timeout(
+    events: BoxStream<'static, Result<StreamingBuffer>>,
+    timeout: Duration,
+) -> BoxStream<'static, Result<StreamingBuffer>> {
+    // the wait lives in the stream, so dropping a pending next() doesn't
+    // restart it
+    futures_util::stream::unfold(Some(events), move |events| async move {
+        let mut events = events?;
+        match tokio::time::timeout(timeout, events.next()).await {
+            Ok(Some(item)) => Some((item, Some(events))),
+            Ok(None) => None,
+            Err(_) => Some((Err(StreamStalled(timeout.as_secs()).into()), None)),
+        }
+    })
+    .boxed()
+}
+
+/// What went wrong with a failed response, shown on its turn
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// the key was refused
+    Authentication,
+    RateLimited,
+    Overloaded,
+    /// over the model's context window
+    PromptTooLong,
+    /// any other request the API turned down
+    InvalidRequest,
+    Server,
+    /// the API couldn't be reached or the connection dropped
+    Network,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn of(error: &anyhow::Error) -> Self {
+        if let Some(api_error) = error.downcast_ref::<ApiError>() {
+            return match api_error.status.as_u16() {
+                _ if ApiError::is_prompt_too_long(error) => Self::PromptTooLong,
+                401 | 403 => Self::Authentication,
+                RATE_LIMITED_STATUS => Self::RateLimited,
+                OVERLOADED_STATUS => Self::Overloaded,
+                500.. => Self::Server,
+                _ => Self::InvalidRequest,
+            };
+        }
+        if let Some(stream_error) = error.downcast_ref::<StreamError>() {
+            return match stream_error.error_type.as_str() {
+                "authentication_error" | "permission_error" => Self::Authentication,
+                "rate_limit_error" => Self::RateLimited,
+                "overloaded_error" => Self::Overloaded,
+                "invalid_request_error" => Self::InvalidRequest,
+                "api_error" => Self::Server,
+                _ => Self::Other,
+            };
+        }
+        if error.is::<StreamStalled>() || error.chain().any(|cause| cause.is::<reqwest::Error>()) {
+            Self::Network
+        } else {
+            Self::Other
+        }
+    }
+
+    /// The i18n key of its label
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Authentication => "error_kind.authentication",
+            Self::RateLimited => "error_kind.rate_limited",
+            Self::Overloaded => "error_kind.overloaded",
+            Self::PromptTooLong => "error_kind.prompt_too_long",
+            Self::InvalidRequest => "error_kind.invalid_request",
+            Self::Server => "error_kind.server",
+            Self::Network => "error_kind.network",
+            Self::Other => "error_kind.other",
+        }
+    }
+}
+
+/// One of the limits of the `anthropic-ratelimit-*` headers
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    pub limit: u64,
+    pub remaining: u64,
+    /// RFC 3339 time by which the limit is replenished
+    pub reset: Option<String>,
+}
+
+impl RateLimit {
+    pub fn is_low(&self) -> bool {
+        (self.remaining as f64) < self.limit as f64 * RATE_LIMIT_LOW_FRACTION
+    }
+}
+
+/// Rate limits of the API key, as reported with the last response
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub requests: Option<RateLimit>,
+    pub tokens: Option<RateLimit>,
+    pub input_tokens: Option<RateLimit>,
+    pub output_tokens: Option<RateLimit>,
+    /// seconds to wait before retrying, sent with a 429
+    pub retry_after: Option<u64>,
+}
+
+impl RateLimits {
+    pub fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let value = |name: &str| headers.get(name)?.to_str().ok().map(str::to_string);
+        let limit = |kind: &str| {
+            let field = |field: &str| value(&format!("anthropic-ratelimit-{}-{}", kind, field));
+            Some(RateLimit {
+                limit: field("limit")?.parse().ok()?,
+                remaining: field("remaining")?.parse().ok()?,
+                reset: field("reset"),
+            })
+        };
+        Self {
+            requests: limit("requests"),
+            tokens: limit("tokens"),
+            input_tokens: limit("input-tokens"),
+            output_tokens: limit("output-tokens"),
+            retry_after: value("retry-after").and_then(|seconds| seconds.parse().ok()),
+        }
+    }
+
+    /// The limits reported, with the i18n key of their name
+    pub fn limits(&self) -> impl Iterator<Item = (&'static str, &RateLimit)> {
+        [
+            ("ratelimit.requests", &self.requests),
+            ("ratelimit.tokens", &self.tokens),
+            ("ratelimit.input_tokens", &self.input_tokens),
+            ("ratelimit.output_tokens", &self.output_tokens),
+        ]
+        .into_iter()
+        .filter_map(|(name, limit)| Some((name, limit.as_ref()?)))
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.limits().any(|(_, limit)| limit.is_low())
+    }
+
+    /// Reset of an exhausted limit, or of any limit when none is
+    fn next_reset(&self) -> Option<&str> {
+        let exhausted = self.limits().filter(|(_, limit)| limit.remaining == 0);
+        exhausted
+            .chain(self.limits())
+            .find_map(|(_, limit)| limit.reset.as_deref())
+    }
+}
+
+/// A response whose headers arrived, its events still to come
+pub struct OpenedStream {
+    pub events: BoxStream<'static, Result<StreamingBuffer>>,
+    /// model that answers
+    pub model: String,
+    pub rate_limits: RateLimits,
+}
+
+impl ApiError {
+    /// Whether the error is the API being overloaded, as a status or as
+    /// an error event once the response streams
+    pub fn is_overloaded(error: &anyhow::Error) -> bool {
+        ErrorKind::of(error) == ErrorKind::Overloaded
+    }
+
+    /// Whether the request was over the model's context window
+    pub fn is_prompt_too_long(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<ApiError>().is_some_and(|e| {
+            e.status == reqwest::StatusCode::BAD_REQUEST && e.body.contains("prompt is too long")
+        })
+    }
+}
+
+#[allow(dead_code)]
 pub enum TokenType { 
     InputToken,
     OutputToken,
@@ -29,36 +264,322 @@ pub enum Role {
 pub struct Message {
     pub role: Role,
     pub content: String,
+    /// files and large pastes sent along with the text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// tokens reported by the API for the response, assistant messages only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ResponseUsage>,
+    /// why the response failed, it's kept out of later requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// what kind of failure `error` is, older saves don't have it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<ErrorKind>,
+    /// model that answered, assistant messages only. Older saves only
+    /// have it when it wasn't the selected one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// API ids of the response, the first request's then one per resume
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub message_ids: Vec<String>,
+    /// shown in the chat only, e.g. the greeting, never sent
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub local: bool,
+    /// passages of attached documents the response cites, assistant
+    /// messages only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
+    /// language picked for a code block the highlighting guessed wrong,
+    /// by the block's index in the message
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub code_languages: BTreeMap<usize, String>,
+    /// how the reader rated the response, kept locally and never sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<Rating>,
+    /// why it was rated so, empty for no note
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub rating_note: String,
+}
+
+/// A response marked good or bad, for the reader's own bookkeeping
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rating {
+    Good,
+    Bad,
+}
+
+/// A passage of an attached document a response cites
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Citation {
+    /// byte offset in the text the citation follows: the message's once
+    /// stored, the delta's while streaming. Not sent by the API
+    #[serde(default)]
+    pub offset: usize,
+    pub cited_text: String,
+    /// name of the attachment
+    #[serde(default)]
+    pub document_title: Option<String>,
+    /// characters of the document quoted, for text documents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_char_index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_char_index: Option<usize>,
+}
+
+fn is_false(flag: &bool) -> bool {
+    !*flag
+}
+
+impl Message {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            attachments: Vec::new(),
+            usage: None,
+            error: None,
+            error_kind: None,
+            model: None,
+            message_ids: Vec::new(),
+            local: false,
+            citations: Vec::new(),
+            code_languages: BTreeMap::new(),
+            rating: None,
+            rating_note: String::new(),
+        }
+    }
+
+    /// A message for the chat only, left out of requests
+    pub fn local(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            local: true,
+            ..Self::new(role, content)
+        }
+    }
+
+    /// Whether it goes into requests: failed responses and local
+    /// messages don't
+    pub fn is_sent(&self) -> bool {
+        self.error.is_none() && !self.local
+    }
+
+    /// Text with attachments inlined as fenced blocks, as it's counted,
+    /// logged and exported. Requests send the attachments as documents
+    pub fn api_content(&self) -> String {
+        let mut content = self.content.clone();
+        for attachment in &self.attachments {
+            if !content.is_empty() {
+                content.push_str("\n\n");
+            }
+            content.push_str(&attachment.as_fenced_block());
+        }
+        content
+    }
+}
+
+/// Message as serialized in a request
+#[derive(Debug, Serialize)]
+struct ApiMessage {
+    role: Role,
+    content: ApiContent,
+}
+
+/// Plain text, or blocks when the message has documents attached
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum ApiContent {
+    Text(String),
+    Blocks(Vec<ApiBlock>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ApiBlock {
+    Text { text: String },
+    /// an attachment the response can cite
+    Document {
+        source: DocumentSource,
+        title: String,
+        citations: CitationsOption,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct DocumentSource {
+    #[serde(rename = "type")]
+    source_type: &'static str,
+    media_type: &'static str,
+    data: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CitationsOption {
+    enabled: bool,
+}
+
+impl From<&Message> for ApiMessage {
+    /// Attachments of a user message go as text documents with citations
+    /// on, anything else as its text
+    fn from(message: &Message) -> Self {
+        let content = if message.role == Role::User && !message.attachments.is_empty() {
+            let documents = message.attachments.iter().map(|attachment| ApiBlock::Document {
+                source: DocumentSource {
+                    source_type: "text",
+                    media_type: "text/plain",
+                    data: attachment.content.clone(),
+                },
+                title: attachment.name.clone(),
+                citations: CitationsOption { enabled: true },
+            });
+            // the API refuses empty text blocks
+            let text = (!message.content.trim().is_empty()).then(|| ApiBlock::Text {
+                text: message.content.clone(),
+            });
+            ApiContent::Blocks(documents.chain(text).collect())
+        } else {
+            ApiContent::Text(message.api_content())
+        };
+        Self {
+            role: message.role.clone(),
+            content,
+        }
+    }
 }
 
 /// Anthropic API request structure
 #[derive(Debug, Serialize)]
 struct AnthropicRequest {
     model: String,
-    messages: Vec<Message>,
+    messages: Vec<ApiMessage>,
     max_tokens: u32,
+    /// system messages of the conversation, the API takes them apart from the turns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
 }
 
 
-/// --- Streaming --- ///
+// --- Message batches --- //
+
+/// One request of a batch, its result comes back under the same `custom_id`
+#[derive(Debug, Serialize)]
+struct BatchRequest {
+    custom_id: String,
+    params: AnthropicRequest,
+}
+
+#[derive(Debug, Serialize)]
+struct CreateBatchRequest {
+    requests: Vec<BatchRequest>,
+}
 
+/// A message batch as the API reports it
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageBatch {
+    pub id: String,
+    /// in_progress, canceling or ended
+    pub processing_status: String,
+    pub request_counts: BatchRequestCounts,
+}
+
+impl MessageBatch {
+    /// Every request is answered, failed, canceled or expired, the results
+    /// can be downloaded
+    pub fn has_ended(&self) -> bool {
+        self.processing_status == "ended"
+    }
+}
+
+/// How many requests of a batch are in each state
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BatchRequestCounts {
+    pub processing: u32,
+    pub succeeded: u32,
+    pub errored: u32,
+    pub canceled: u32,
+    pub expired: u32,
+}
+
+/// One line of a batch's results
 #[derive(Debug, Deserialize)]
-pub struct InputUsage {
-    input_tokens: u32,
+pub struct BatchResultLine {
+    pub custom_id: String,
+    pub result: BatchResult,
 }
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BatchResult {
+    Succeeded { message: BatchMessage },
+    Errored { error: BatchError },
+    Canceled,
+    Expired,
+}
+
+/// A response of a batch, the whole message at once
+#[derive(Debug, Deserialize)]
+pub struct BatchMessage {
+    pub model: String,
+    pub content: Vec<ContentBlock>,
+    pub usage: ResponseUsage,
+}
+
+impl BatchMessage {
+    /// Text of the text blocks, one after the other
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter(|block| block.content_type == "text")
+            .map(|block| block.text.as_str())
+            .collect()
+    }
+}
+
+/// Error of a failed batch request, wrapped like an error response body
+#[derive(Debug, Deserialize)]
+pub struct BatchError {
+    pub error: StreamError,
+}
+
+/// --- Streaming --- ///
+
 #[derive(Debug, Deserialize)]
 pub struct OutputUsage {
     output_tokens: u32,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ResponseUsage {
     pub input_tokens: u32,
     pub output_tokens: u32,
+    /// input tokens written to the prompt cache
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub cache_creation_input_tokens: u32,
+    /// input tokens read from the prompt cache
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub cache_read_input_tokens: u32,
+}
+
+fn is_zero(tokens: &u32) -> bool {
+    *tokens == 0
+}
+
+impl ResponseUsage {
+    /// Add the tokens of another part of the same response
+    pub fn add(&mut self, other: &ResponseUsage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+    }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum StreamEvent {
@@ -92,8 +613,13 @@ pub enum StreamEvent {
     Error {
         error: StreamError,
     },
+
+    /// a type added to the API since, skipped
+    #[serde(other)]
+    Unknown,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct StreamMessage {
     pub id: String,
@@ -102,44 +628,94 @@ pub struct StreamMessage {
     pub role: String,
     pub content: Vec<ContentBlock>,
     pub usage: Option<ResponseUsage>,
+    /// the model answering, as the API names it
+    #[serde(default)]
+    pub model: Option<String>,
 }
 
+/// Change to a content block, only text deltas are shown
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
-pub struct Delta {
-    #[serde(rename = "type")]
-    pub delta_type: String,
-    pub text: String,
+#[serde(tag = "type")]
+pub enum Delta {
+    #[serde(rename = "text_delta")]
+    Text {
+        text: String,
+    },
+    /// arguments of a tool call, streamed as JSON fragments
+    #[serde(rename = "input_json_delta")]
+    InputJson {
+        partial_json: String,
+    },
+    #[serde(rename = "thinking_delta")]
+    Thinking {
+        thinking: String,
+    },
+    /// closes a thinking block
+    #[serde(rename = "signature_delta")]
+    Signature {
+        signature: String,
+    },
+    /// a passage the text block cites, sent before the block's text
+    #[serde(rename = "citations_delta")]
+    Citations {
+        citation: Citation,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+/// Log the type of an event the app doesn't know, the first time it's met
+fn warn_unknown_event(data: &str) {
+    let event_type = serde_json::from_str::<serde_json::Value>(data)
+        .ok()
+        .and_then(|event| Some(event.get("type")?.as_str()?.to_string()))
+        .unwrap_or_default();
+    if UNKNOWN_EVENT_TYPES.lock().unwrap().insert(event_type.clone()) {
+        warn!("Skipping stream events of unknown type {:?}", event_type);
+    }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct MessageDelta {
     pub stop_reason: Option<String>,
     pub stop_sequence: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Error the API reports in the body, as an `error` event once a response streams
+#[derive(Debug, Deserialize, thiserror::Error)]
+#[error("Error event: {message}")]
 pub struct StreamError {
+    /// e.g. overloaded_error, the same types as the error statuses
+    #[serde(rename = "type", default)]
+    pub error_type: String,
     pub message: String,
 }
 
 pub struct StreamingBuffer {
-    pub content: String,
+    pub content: DeltaContent,
     pub usage: Option<ResponseUsage>,
     pub is_complete: bool,
+    /// id the API gives the response, from its first event
+    pub message_id: Option<String>,
+    /// model that answers, echoed by the first event
+    pub model: Option<String>,
+    /// citations of a text block, sent once it's over. Their offsets are
+    /// in this buffer's text
+    pub citations: Vec<Citation>,
 }
 
+// ---
 
-/// ---
-
-/// Struct to get the number of tokens with the count_token endpoint 
-#[deprecated]
+/// Struct to get the number of tokens with the count_token endpoint
 #[derive(Debug, Serialize)]
 struct AntTokCountRequest {
     model: String,
-    messages: Vec<Message>,
+    messages: Vec<ApiMessage>,
 }
 
-#[deprecated]
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct AntTokCountResponse {
     input_tokens: u32,
@@ -147,14 +723,16 @@ struct AntTokCountResponse {
 
 /// Content block in the anth API response
 #[derive(Debug, Deserialize)]
-struct ContentBlock {
+pub struct ContentBlock {
     #[serde(rename = "type")]
     content_type: String,
+    /// only text blocks have text
+    #[serde(default)]
     text: String,
 }
 
-
 /// Response structure from the anthropic API
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct AnthropicResponse {
     id: String,
@@ -171,13 +749,166 @@ pub struct ExtractedResponse {
     pub usage: ResponseUsage,
 }
 
+/// Text of a response's content blocks, kept by block index so deltas of
+/// interleaved or out of order blocks land in their own block
+#[derive(Debug, Default)]
+struct ContentBlocks {
+    blocks: BTreeMap<usize, String>,
+    /// citations of the blocks still streaming, they follow the block's text
+    citations: BTreeMap<usize, Vec<Citation>>,
+}
+
+impl ContentBlocks {
+    /// Add text to a block, returns how the response text changes:
+    /// an append while the block is the last one, a rewrite otherwise
+    fn push(&mut self, index: usize, text: &str) -> DeltaContent {
+        let is_last = self.blocks.keys().next_back().is_none_or(|&last| index >= last);
+        self.blocks.entry(index).or_default().push_str(text);
+        if is_last {
+            DeltaContent::Append(text.to_string())
+        } else {
+            DeltaContent::Rewrite(self.blocks.values().map(String::as_str).collect())
+        }
+    }
+}
+
+/// Model metadata from the /v1/models endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelInfo {
+    pub id: String,
+    pub display_name: String,
+    /// release date, RFC 3339
+    #[serde(default)]
+    pub created_at: Option<String>,
+    /// not part of the documented response, shown when a server provides it
+    #[serde(default)]
+    pub knowledge_cutoff: Option<String>,
+}
+
+/// Text carried by a delta sent to the UI thread
+#[derive(Debug, Clone)]
+pub enum DeltaContent {
+    /// fragment to append to the message being streamed
+    Append(String),
+    /// overwrite the whole message, used by the error path
+    Replace(String),
+    /// the whole response text so far, after a prefill if there is one,
+    /// when a delta landed before the end of it
+    Rewrite(String),
+}
+
+impl DeltaContent {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            DeltaContent::Append(text) | DeltaContent::Replace(text) | DeltaContent::Rewrite(text) => text.is_empty(),
+        }
+    }
+}
+
+impl Default for DeltaContent {
+    fn default() -> Self {
+        DeltaContent::Append(String::new())
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct AppMessageDelta {
-    pub content: String,
+    pub content: DeltaContent,
     pub usage: Option<ResponseUsage>,
     pub is_complete: bool,
+    /// model that answers, set when the response opens on a fallback
+    /// model and again when the API echoes it
+    pub model: Option<String>,
+    /// set with the first delta when the response opens on a fallback
+    /// model, the model the request was sent to in the end
+    pub fallback_model: Option<String>,
+    /// set once, from the headers of the response or of the error
+    pub rate_limits: Option<RateLimits>,
+    /// set with the error when the request failed
+    pub error_kind: Option<ErrorKind>,
+    /// set once, the API's id for the response
+    pub message_id: Option<String>,
+    /// offsets are in this delta's text
+    pub citations: Vec<Citation>,
+}
+
+
+/// Network options shared by every HTTP client the app builds
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    /// explicit proxy for all traffic, takes precedence over HTTP(S)_PROXY
+    pub proxy_url: Option<String>,
+    /// skip TLS certificate checks, only for self-signed internal gateways
+    pub danger_accept_invalid_certs: bool,
 }
 
+impl HttpOptions {
+    /// Client builder with the proxy applied.
+    /// Without an explicit proxy reqwest picks up HTTP_PROXY/HTTPS_PROXY/NO_PROXY itself
+    pub fn client_builder(&self, timeout: Duration) -> Result<reqwest::ClientBuilder> {
+        Ok(self.streaming_client_builder()?.timeout(timeout))
+    }
+
+    /// Client builder without a total timeout, which would also cut a
+    /// response still streaming. Only connecting is timed
+    pub fn streaming_client_builder(&self) -> Result<reqwest::ClientBuilder> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .danger_accept_invalid_certs(self.danger_accept_invalid_certs);
+        if let Some(proxy_url) = &self.proxy_url {
+            let proxy = reqwest::Proxy::all(proxy_url)
+                .with_context(|| format!("Invalid proxy URL: {}", proxy_url))?
+                .no_proxy(reqwest::NoProxy::from_env());
+            builder = builder.proxy(proxy);
+        }
+        Ok(builder)
+    }
+}
+
+pub const DEFAULT_BASE_URL: &str = "https://api.anthropic.com";
+
+/// Version of the API the requests are written against
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Headers the client sets itself, extra headers can't replace them
+const RESERVED_HEADERS: &[&str] = &["x-api-key", "anthropic-version", "anthropic-beta", "content-type"];
+
+/// Whether an extra header can be sent as is: a valid name that isn't
+/// one the client sets, and a valid value
+pub fn check_extra_header(name: &str, value: &str) -> Result<()> {
+    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+        .with_context(|| format!("Invalid header name \"{}\"", name))?;
+    if RESERVED_HEADERS.iter().any(|reserved| reserved.eq_ignore_ascii_case(name)) {
+        anyhow::bail!("The {} header is set by the app, it can't be an extra header", name);
+    }
+    reqwest::header::HeaderValue::from_str(value)
+        .with_context(|| format!("Invalid value for the {} header", name))?;
+    Ok(())
+}
+
+/// Shell variable standing for a header's value, e.g. X_GATEWAY_TOKEN for x-gateway-token
+fn env_var_name(header: &str) -> String {
+    let name: String = header
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+/// The response if its status is a success, the API error otherwise
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let rate_limits = RateLimits::from_headers(response.headers());
+    let body = response.text().await?;
+    Err(ApiError { status, body, rate_limits }.into())
+}
 
 /// Http client for requests to anth
 #[derive(Clone)]
@@ -185,78 +916,188 @@ pub struct AnthropicClient {
     api_key: String,
     client: Arc<reqwest::Client>,
     model: String,
+    /// scheme and host the /v1 endpoints are appended to
+    base_url: String,
+    /// None leaves it to the API
+    temperature: Option<f32>,
+    /// `anthropic-beta` features turned on for message requests
+    beta_flags: Vec<String>,
+    /// sent with every request after the Anthropic ones, e.g. for a gateway
+    extra_headers: Vec<(String, String)>,
 }
 
 
 impl AnthropicClient {
-    pub fn new(model: &str, api_key: String) -> Self {
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(30)) // timeout request in 30 sec
-            .build()
-            .expect("Failed to create HTTP client");
+    pub fn new(model: &str, api_key: String, http: &HttpOptions) -> Result<Self> {
+        // requests that don't stream set their own timeout
+        let client = http
+            .streaming_client_builder()
+            .and_then(|builder| builder.build().context("Failed to create HTTP client"))?;
 
-        Self {
+        Ok(Self {
             api_key,
             client: Arc::new(client),
-            model: model.to_string()
+            model: model.to_string(),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            temperature: None,
+            beta_flags: Vec::new(),
+            extra_headers: Vec::new(),
+        })
+    }
+
+    /// Point the client at another Anthropic-compatible server, e.g. a gateway or a mock
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Beta features to ask for, blank flags are dropped
+    pub fn with_beta_flags(mut self, flags: &[String]) -> Self {
+        self.beta_flags = flags
+            .iter()
+            .map(|flag| flag.trim().to_string())
+            .filter(|flag| !flag.is_empty())
+            .collect();
+        self
+    }
+
+    /// Headers added to every request, invalid ones are left out
+    pub fn with_extra_headers(mut self, headers: &BTreeMap<String, String>) -> Self {
+        self.extra_headers = headers
+            .iter()
+            .filter(|(name, value)| {
+                check_extra_header(name, value)
+                    .inspect_err(|e| warn!("Extra header left out: {:#}", e))
+                    .is_ok()
+            })
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Same client asking another model
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Headers of every request, the key first and the extra ones last.
+    /// The beta flags only go with message requests
+    fn api_headers(&self, message_request: bool) -> Vec<(String, String)> {
+        let mut headers = vec![
+            ("x-api-key".to_string(), self.api_key.clone()),
+            ("anthropic-version".to_string(), ANTHROPIC_VERSION.to_string()),
+        ];
+        if message_request && !self.beta_flags.is_empty() {
+            headers.push(("anthropic-beta".to_string(), self.beta_flags.join(",")));
         }
+        headers.extend(self.extra_headers.iter().cloned());
+        headers
     }
 
-    #[deprecated]
-    pub async fn is_api_key_valid(api_key: String) -> Result<bool> {
-        const API_URL: &str = "https://api.anthropic.com/v1/models";
-
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(5)) // shorter timeout for validation
-            .build()
-            .expect("Failed to create HTTP client");
-
-        let response = client
-            .get(API_URL)
-            .header("x-api-key", api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
+    fn request(&self, method: reqwest::Method, path: &str, message_request: bool) -> reqwest::RequestBuilder {
+        self.api_headers(message_request)
+            .into_iter()
+            .fold(self.client.request(method, self.endpoint(path)), |request, (name, value)| {
+                request.header(name, value)
+            })
+    }
+
+    fn build_request(&self, messages: &[Message]) -> AnthropicRequest {
+        let (system, turns): (Vec<&Message>, Vec<&Message>) =
+            messages.iter().partition(|message| message.role == Role::System);
+        let system = system
+            .iter()
+            .map(|message| message.api_content())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        AnthropicRequest {
+            model: self.model.clone(),
+            messages: turns.into_iter().map(ApiMessage::from).collect(),
+            max_tokens: MAX_OUTPUT_TOKENS,
+            system: (!system.is_empty()).then_some(system),
+            temperature: self.temperature,
+            stream: Some(true),
+        }
+    }
+
+    /// The body of the streaming request for these messages, as sent
+    pub fn request_body(&self, messages: &[Message]) -> Result<String> {
+        serde_json::to_string_pretty(&self.build_request(messages)).context("Failed to serialize the request")
+    }
+
+    /// The streaming request for these messages as a curl command.
+    /// The key is left as $ANTHROPIC_API_KEY and an extra header's value as
+    /// a variable named after the header, e.g. $X_GATEWAY_TOKEN for
+    /// x-gateway-token, secrets are never written out
+    pub fn curl_command(&self, messages: &[Message]) -> Result<String> {
+        let body = self.request_body(messages)?;
+        let quote = |text: &str| format!("'{}'", text.replace('\'', "'\\''"));
+
+        let mut command = format!("curl {}", quote(&self.endpoint("/v1/messages")));
+        let is_extra = |name: &str| self.extra_headers.iter().any(|(extra, _)| extra == name);
+        for (name, value) in self.api_headers(true) {
+            let header = match name.as_str() {
+                "x-api-key" => "\"x-api-key: $ANTHROPIC_API_KEY\"".to_string(),
+                // a gateway header is likely a secret too
+                name if is_extra(name) => format!("{}\"${}\"", quote(&format!("{}: ", name)), env_var_name(name)),
+                _ => quote(&format!("{}: {}", name, value)),
+            };
+            command.push_str(&format!(" \\\n  -H {}", header));
+        }
+        command.push_str(&format!(
+            " \\\n  -H {} \\\n  -d {}",
+            quote("content-type: application/json"),
+            quote(&body)
+        ));
+        Ok(command)
+    }
+
+    /// Whether the API takes the key, by listing the models. False when
+    /// it's refused, an error when the API couldn't say, e.g. offline
+    pub async fn is_api_key_valid(&self) -> Result<bool> {
+        let response = self
+            .request(reqwest::Method::GET, "/v1/models", false)
+            .timeout(KEY_CHECK_TIMEOUT)
             .send()
             .await?;
-
-        let success = response.status().is_success();
-        Ok(success)
+        match response.status() {
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => Ok(false),
+            _ => check_status(response).await.map(|_| true),
+        }
     }
 
     #[deprecated]
+    #[allow(dead_code)]
     pub async fn send_message(&self, messages: Vec<Message>) -> Result<ExtractedResponse> {
-        const API_URL: &str = "https://api.anthropic.com/v1/messages";
-        const MAX_TOKENS: u32 = 4096;
-
         let request = AnthropicRequest {
-            model: self.model.clone(),
-            messages,
-            max_tokens: MAX_TOKENS,
             stream: None,
+            ..self.build_request(&messages)
         };
 
-
         let response = self
-            .client
-            .post(API_URL)
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
+            .request(reqwest::Method::POST, "/v1/messages", true)
+            .timeout(REQUEST_TIMEOUT)
             .header("content-type", "application/json")
             .json(&request)
             .send()
             .await?;
 
-        // let response_text = response.text().await?;
-        // info!("Full response: {}", response_text);
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await?;
-            let error_f = format!("API error ({}): {}", status, error_text);
-            return Err(anyhow::anyhow!(error_f));
-        }
-
-        let anthropic_response: AnthropicResponse = response.json().await?;
+        let anthropic_response: AnthropicResponse = check_status(response).await?.json().await?;
         debug!("Received response: {:?}", anthropic_response);
 
         let mut full_content = String::new();
@@ -266,187 +1107,891 @@ impl AnthropicClient {
             }
         }
 
-        Ok(
-            ExtractedResponse { 
-                content: full_content, 
-                usage: anthropic_response.usage,
+        Ok(ExtractedResponse {
+            content: full_content,
+            usage: anthropic_response.usage,
         })
     }
 
-    pub async fn send_message_streaming(
-        &self,
-        messages: Vec<Message>,
-    ) -> Result<futures_util::stream::BoxStream<'static, Result<StreamingBuffer>>> {
-        use futures_util::stream::{self, StreamExt, TryStreamExt};
-        use tokio::io::{AsyncBufReadExt, BufReader};
-        use tokio_stream::wrappers::LinesStream;
-
-        const API_URL: &str = "https://api.anthropic.com/v1/messages";
-        const MAX_TOKENS: u32 = 4096;
+    #[deprecated]
+    #[allow(dead_code)]
+    pub async fn count_token(&self, message: &str) -> Result<u32> {
+        if message.trim().is_empty() {
+            return Ok(0);
+        }
 
-        let request = AnthropicRequest {
+        let request = AntTokCountRequest {
             model: self.model.clone(),
-            messages,
-            max_tokens: MAX_TOKENS,
-            stream: Some(true),
+            messages: vec![ApiMessage::from(&Message::new(Role::User, message))],
         };
 
         let response = self
-            .client
-            .post(API_URL)
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
+            .request(reqwest::Method::POST, "/v1/messages/count_tokens", false)
+            .timeout(REQUEST_TIMEOUT)
             .header("content-type", "application/json")
             .json(&request)
             .send()
             .await?;
 
+        let anthropic_response: AntTokCountResponse = check_status(response).await?.json().await?;
+        debug!("Received response: {:?}", anthropic_response);
+
+        Ok(anthropic_response.input_tokens)
+    }
+
+    #[deprecated]
+    #[allow(dead_code, deprecated)]
+    pub async fn get_tokens_price(&self, message: &str, toktype: TokenType, model_price: &ModelPricing) -> Result<f64> {
+        let token_count = self.count_token(message).await?;
+        match toktype {
+            TokenType::InputToken => Ok(model_price.input_cost_per_million * (token_count as f64 / 1000000.0)),
+            TokenType::OutputToken => Ok(model_price.output_cost_per_million * (token_count as f64 / 1000000.0)),
+        }
+    }
+
+    /// Submit a batch, one request per conversation in `prompts`. Each
+    /// result has the index of its prompt as `custom_id`
+    pub async fn create_batch(&self, prompts: &[Vec<Message>]) -> Result<MessageBatch> {
+        let requests = prompts
+            .iter()
+            .enumerate()
+            .map(|(idx, messages)| BatchRequest {
+                custom_id: idx.to_string(),
+                params: AnthropicRequest {
+                    stream: None,
+                    ..self.build_request(messages)
+                },
+            })
+            .collect();
+
+        let response = self
+            .request(reqwest::Method::POST, "/v1/messages/batches", true)
+            .timeout(REQUEST_TIMEOUT)
+            .header("content-type", "application/json")
+            .json(&CreateBatchRequest { requests })
+            .send()
+            .await?;
+        check_status(response)
+            .await?
+            .json::<MessageBatch>()
+            .await
+            .context("Failed to parse the created batch")
+    }
+
+    /// Where a batch is at
+    pub async fn get_batch(&self, id: &str) -> Result<MessageBatch> {
+        let response = self
+            .request(reqwest::Method::GET, &format!("/v1/messages/batches/{}", id), false)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await?;
+        check_status(response)
+            .await?
+            .json::<MessageBatch>()
+            .await
+            .context("Failed to parse the batch status")
+    }
+
+    /// Results of an ended batch. Lines that can't be parsed are skipped,
+    /// their requests are left without a result
+    pub async fn batch_results(&self, id: &str) -> Result<Vec<BatchResultLine>> {
+        let response = self
+            .request(reqwest::Method::GET, &format!("/v1/messages/batches/{}/results", id), false)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await?;
+        let body = check_status(response)
+            .await?
+            .text()
+            .await
+            .context("Failed to download the batch results")?;
+        Ok(body
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str::<BatchResultLine>(line) {
+                Ok(result) => Some(result),
+                Err(e) => {
+                    warn!("Could not parse a batch result: {}, data: {}", e, line);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Fetch metadata for a model, e.g. its display name
+    pub async fn get_model_info(&self, model: &str) -> Result<ModelInfo> {
+        let response = self
+            .request(reqwest::Method::GET, &format!("/v1/models/{}", model), false)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await?;
+
         if !response.status().is_success() {
             let status = response.status();
             let error_text = response.text().await?;
-            let error_f = format!("API error ({}): {}", status, error_text);
-            return Err(anyhow::anyhow!(error_f));
+            return Err(anyhow::anyhow!("API error ({}): {}", status, error_text));
+        }
+
+        response
+            .json::<ModelInfo>()
+            .await
+            .context("Failed to parse model info")
+    }
+
+    /// What a parsed stream event means for the UI, None for events it ignores.
+    /// Text is accumulated per content block in `blocks`
+    fn buffer_from_event(event: StreamEvent, blocks: &mut ContentBlocks) -> Option<Result<StreamingBuffer>> {
+        match event {
+            StreamEvent::MessageStart { message } => Some(Ok(StreamingBuffer {
+                content: DeltaContent::default(),
+                usage: message.usage,
+                is_complete: false,
+                message_id: Some(message.id),
+                model: message.model,
+                citations: Vec::new(),
+            })),
+            StreamEvent::Error { error } => Some(Err(error.into())),
+            // a text block may start with some text, usually it's empty
+            StreamEvent::ContentBlockStart { index, content_block } if content_block.content_type == "text" => {
+                Some(Ok(StreamingBuffer {
+                    content: blocks.push(index, &content_block.text),
+                    usage: None,
+                    is_complete: false,
+                    message_id: None,
+                    model: None,
+                    citations: Vec::new(),
+                }))
+            }
+            StreamEvent::ContentBlockDelta { index, delta: Delta::Text { text } } => {
+                Some(Ok(StreamingBuffer {
+                    content: blocks.push(index, &text),
+                    usage: None,
+                    is_complete: false,
+                    message_id: None,
+                    model: None,
+                    citations: Vec::new(),
+                }))
+            }
+            StreamEvent::MessageDelta { usage, .. } => Some(Ok(StreamingBuffer {
+                content: DeltaContent::default(),
+                usage: Some(ResponseUsage {
+                    output_tokens: usage.output_tokens,
+                    ..Default::default()
+                }),
+                is_complete: false,
+                message_id: None,
+                model: None,
+                citations: Vec::new(),
+            })),
+            StreamEvent::MessageStop => Some(Ok(StreamingBuffer {
+                content: DeltaContent::default(),
+                usage: None,
+                is_complete: true,
+                message_id: None,
+                model: None,
+                citations: Vec::new(),
+            })),
+            // passed on empty so the stall watchdog sees the connection is alive
+            StreamEvent::Ping => Some(Ok(StreamingBuffer {
+                content: DeltaContent::default(),
+                usage: None,
+                is_complete: false,
+                message_id: None,
+                model: None,
+                citations: Vec::new(),
+            })),
+            StreamEvent::ContentBlockDelta { index, delta: Delta::Citations { citation } } => {
+                blocks.citations.entry(index).or_default().push(citation);
+                None
+            }
+            // the cited text is in, the citations go after it
+            StreamEvent::ContentBlockStop { index } => {
+                let citations = blocks.citations.remove(&index)?;
+                Some(Ok(StreamingBuffer {
+                    content: DeltaContent::default(),
+                    usage: None,
+                    is_complete: false,
+                    message_id: None,
+                    model: None,
+                    citations,
+                }))
+            }
+            // tool use and thinking aren't shown
+            StreamEvent::ContentBlockStart { .. }
+            | StreamEvent::ContentBlockDelta { .. }
+            | StreamEvent::Unknown => None,
+        }
+    }
+
+    pub async fn send_message_streaming(&self, messages: Vec<Message>) -> Result<OpenedStream> {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+        use tokio_stream::wrappers::LinesStream;
+
+        let request = self.build_request(&messages);
+
+        let response = self
+            .request(reqwest::Method::POST, "/v1/messages", true)
+            .header("content-type", "application/json")
+            .json(&request)
+            .send()
+            .await?;
+
+        let rate_limits = RateLimits::from_headers(response.headers());
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await?;
+            return Err(ApiError { status, body, rate_limits }.into());
         }
 
         let byte_stream = response.bytes_stream();
         let reader = BufReader::new(tokio_util::io::StreamReader::new(byte_stream.map(
-            |result| result.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err)),
+            |result| result.map_err(std::io::Error::other),
         )));
 
         let lines_stream = LinesStream::new(reader.lines());
 
-        let event_stream = lines_stream
-            .filter_map(|line_result| async move {
-                let line = match line_result {
-                    Ok(line) => line,
-                    Err(e) => return Some(Err(anyhow::anyhow!("Error reading stream line {}", e))),
-                };
-
-                if line.is_empty() {
-                    return None;
-                }
+        // None marks the end of the stream, to flush an unterminated event
+        let lines_stream = lines_stream
+            .map(Some)
+            .chain(futures_util::stream::once(async { None }));
 
-                if line.starts_with("event: ") {
-                    let _ = line.strip_prefix("event: ").unwrap_or_default();
-                    None
-                } else if line.starts_with("data: ") {
-                    let data = line.strip_prefix("data: ").unwrap_or_default();
-
-                    match serde_json::from_str::<StreamEvent>(data) {
-                        Ok(StreamEvent::MessageStart {message}) => {
-                            // debug!("Input usage: {:?}", message.usage);
-                            return Some(Ok(StreamingBuffer {
-                                content: String::new(),
-                                usage: message.usage,
-                                is_complete: false,
-                            }));
-                        }
-                        Ok(StreamEvent::Error { error }) => {
-                            return Some(Err(anyhow::anyhow!("Error event: {}", error.message)));
+        let event_stream = lines_stream
+            .scan(
+                (SseAssembler::default(), ContentBlocks::default(), 0usize),
+                |(assembler, blocks, parse_failures), line_result| {
+                    let event = match line_result {
+                        Some(Ok(line)) => assembler.push_line(&line),
+                        Some(Err(e)) => {
+                            let e = anyhow::anyhow!("Error reading stream line {}", e);
+                            return futures_util::future::ready(Some(Some(Err(e))));
                         }
-                        Ok(StreamEvent::ContentBlockDelta { delta, .. }) => {
-                            if delta.delta_type == "text_delta" {
-                                return Some(Ok(StreamingBuffer {
-                                    content: delta.text,
-                                    usage: None,
-                                    is_complete: false,
-                                }));
-                            } else {
-                                return None;
+                        None => assembler.finish(),
+                    };
+
+                    let item = event.and_then(|event| {
+                        match serde_json::from_str::<StreamEvent>(&event.data) {
+                            Ok(stream_event) => {
+                                *parse_failures = 0;
+                                if matches!(stream_event, StreamEvent::Unknown) {
+                                    warn_unknown_event(&event.data);
+                                }
+                                Self::buffer_from_event(stream_event, blocks)
+                            }
+                            Err(e) => {
+                                *parse_failures += 1;
+                                warn!(
+                                    "Could not parse {} event: {}, data: {}",
+                                    event.event.as_deref().unwrap_or("unnamed"),
+                                    e,
+                                    event.data
+                                );
+                                (*parse_failures >= MAX_CONSECUTIVE_PARSE_FAILURES).then(|| {
+                                    Err(anyhow::anyhow!(
+                                        "{} stream events in a row could not be parsed, last error: {}",
+                                        parse_failures,
+                                        e
+                                    ))
+                                })
                             }
                         }
-                        Ok(StreamEvent::MessageDelta { usage, ..}) => {
-                            // debug!("Output usage: {:?}", usage);
-                            return Some(Ok(StreamingBuffer {
-                                content: String::new(),
-                                usage: Some(ResponseUsage {
-                                    input_tokens: 0,
-                                    output_tokens: usage.output_tokens,
-                                }),
-                                is_complete: false,
-                            }));
-                        }
-                        Ok(StreamEvent::MessageStop) => {
-                            return Some(Ok(StreamingBuffer {
-                                content: String::new(),
-                                usage: None,
-                                is_complete: true,
-                            }));
-                        }
-                        _ => {
-                            return None;
+                    });
+                    futures_util::future::ready(Some(item))
+                },
+            )
+            .filter_map(futures_util::future::ready)
+            .boxed();
+
+        Ok(OpenedStream {
+            events: event_stream,
+            model: self.model.clone(),
+            rate_limits,
+        })
+    }
+
+    /// Stream a response, retrying while the model is overloaded and then
+    /// moving down `fallback_models` the same way. An overloaded error event
+    /// before any text counts too. The events fail with `StreamStalled`
+    /// once nothing arrived for `stall_timeout`
+    pub async fn send_message_streaming_with_fallback(
+        &self,
+        messages: Vec<Message>,
+        fallback_models: &[String],
+        overloaded_retries: u32,
+        stall_timeout: Duration,
+    ) -> Result<OpenedStream> {
+        let models = std::iter::once(&self.model)
+            .chain(fallback_models.iter().filter(|model| **model != self.model));
+
+        let mut last_error = None;
+        for model in models {
+            let client = self.clone().with_model(model);
+            for attempt in 0..=overloaded_retries {
+                let opened = match client.send_message_streaming(messages.clone()).await {
+                    Ok(opened) => Self::read_to_first_text(opened, stall_timeout).await,
+                    Err(e) => Err(e),
+                };
+                match opened {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) if ApiError::is_overloaded(&e) => {
+                        warn!("{} is overloaded, attempt {}/{}", model, attempt + 1, overloaded_retries + 1);
+                        last_error = Some(e);
+                        if attempt < overloaded_retries {
+                            tokio::time::sleep(Duration::from_secs(1 << attempt.min(5))).await;
                         }
                     }
-                } else {
-                    return None;
+                    Err(e) => return Err(e),
                 }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No model to send to")))
+    }
+
+    /// The stream with its events read up to the first text, or up to the
+    /// end or an error, and put back. Fails when the first error is the API
+    /// being overloaded, nothing was shown yet so it can be sent again
+    async fn read_to_first_text(mut opened: OpenedStream, stall_timeout: Duration) -> Result<OpenedStream> {
+        // pings count too, so this only fires on a dead connection. Fused,
+        // a response can end before any text and is then polled again
+        let mut events = with_stall_timeout(opened.events, stall_timeout).fuse();
+        let mut read = Vec::new();
+        while let Some(event) = events.next().await {
+            let is_last = match &event {
+                Ok(buffer) => buffer.is_complete || !buffer.content.is_empty(),
+                Err(_) => true,
+            };
+            let event = match event {
+                Err(e) if ApiError::is_overloaded(&e) => return Err(e),
+                event => event,
+            };
+            read.push(event);
+            if is_last {
+                break;
+            }
+        }
+        opened.events = futures_util::stream::iter(read).chain(events).boxed();
+        Ok(opened)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// A canned SSE transcript, one `(event, data)` pair per event
+    fn transcript(events: &[(&str, &str)]) -> String {
+        events
+            .iter()
+            .map(|(event, data)| format!("event: {}\ndata: {}\n\n", event, data))
+            .collect()
+    }
+
+    async fn mock_server(body: String) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/messages"))
+            .and(header("x-api-key", "test-key"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "text/event-stream")
+                    .set_body_string(body),
+            )
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn client(server: &MockServer) -> AnthropicClient {
+        AnthropicClient::new("claude-test", "test-key".to_string(), &HttpOptions::default())
+            .unwrap()
+            .with_base_url(&server.uri())
+    }
+
+    async fn stream_events(body: String) -> Vec<Result<StreamingBuffer>> {
+        let server = mock_server(body).await;
+        let opened = client(&server)
+            .send_message_streaming(vec![Message::new(Role::User, "Hi")])
+            .await
+            .unwrap();
+        opened.events.collect().await
+    }
+
+    fn text(buffers: &[Result<StreamingBuffer>]) -> String {
+        buffers
+            .iter()
+            .filter_map(|buffer| match &buffer.as_ref().ok()?.content {
+                DeltaContent::Append(text) => Some(text.as_str()),
+                _ => None,
             })
-            .boxed();
+            .collect()
+    }
 
-        Ok(event_stream)
+    const MESSAGE_START: &str = r#"{"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude-test-20250101","usage":{"input_tokens":12,"output_tokens":1}}}"#;
+    const BLOCK_START: &str = r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#;
+    const BLOCK_STOP: &str = r#"{"type":"content_block_stop","index":0}"#;
+    const MESSAGE_DELTA: &str = r#"{"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":5}}"#;
+    const MESSAGE_STOP: &str = r#"{"type":"message_stop"}"#;
+
+    fn text_delta(text: &str) -> String {
+        block_delta(0, text)
     }
 
-    #[deprecated]
-    pub async fn count_token(&self, message: &str) -> Result<u32> {
+    fn block_delta(index: usize, text: &str) -> String {
+        format!(
+            r#"{{"type":"content_block_delta","index":{},"delta":{{"type":"text_delta","text":"{}"}}}}"#,
+            index, text
+        )
+    }
 
-        if message.trim().is_empty(){
-            return Ok(0);
+    #[tokio::test]
+    async fn streams_the_event_sequence() {
+        let hello = text_delta("Hello");
+        let world = text_delta(" world");
+        let buffers = stream_events(transcript(&[
+            ("message_start", MESSAGE_START),
+            ("content_block_start", BLOCK_START),
+            ("ping", r#"{"type":"ping"}"#),
+            ("content_block_delta", &hello),
+            ("content_block_delta", &world),
+            ("content_block_stop", BLOCK_STOP),
+            ("message_delta", MESSAGE_DELTA),
+            ("message_stop", MESSAGE_STOP),
+        ]))
+        .await;
+
+        assert_eq!(buffers.len(), 7, "every event but the block stop yields a buffer");
+        assert!(buffers.iter().all(Result::is_ok));
+        let first = buffers[0].as_ref().unwrap();
+        assert_eq!(first.message_id.as_deref(), Some("msg_1"));
+        assert_eq!(first.model.as_deref(), Some("claude-test-20250101"));
+        assert_eq!(text(&buffers), "Hello world");
+        let complete: Vec<bool> = buffers.iter().map(|buffer| buffer.as_ref().unwrap().is_complete).collect();
+        assert_eq!(complete.iter().filter(|&&complete| complete).count(), 1);
+        assert!(complete.last().unwrap());
+    }
+
+    #[tokio::test]
+    async fn parses_usage() {
+        let buffers = stream_events(transcript(&[
+            ("message_start", MESSAGE_START),
+            ("message_delta", MESSAGE_DELTA),
+            ("message_stop", MESSAGE_STOP),
+        ]))
+        .await;
+
+        let usage: Vec<&ResponseUsage> = buffers
+            .iter()
+            .filter_map(|buffer| buffer.as_ref().unwrap().usage.as_ref())
+            .collect();
+        assert_eq!(usage.len(), 2);
+        assert_eq!((usage[0].input_tokens, usage[0].output_tokens), (12, 1));
+        assert_eq!((usage[1].input_tokens, usage[1].output_tokens), (0, 5));
+    }
+
+    #[tokio::test]
+    async fn reports_an_error_event() {
+        let partial = text_delta("Par");
+        let buffers = stream_events(transcript(&[
+            ("message_start", MESSAGE_START),
+            ("content_block_start", BLOCK_START),
+            ("content_block_delta", &partial),
+            ("error", r#"{"type":"error","error":{"type":"api_error","message":"Internal server error"}}"#),
+        ]))
+        .await;
+
+        assert_eq!(text(&buffers), "Par");
+        let error = buffers.last().unwrap().as_ref().err().expect("the stream ends on the error");
+        assert!(error.to_string().contains("Internal server error"), "{}", error);
+    }
+
+    fn event(data: &str) -> StreamEvent {
+        serde_json::from_str(data).unwrap_or_else(|e| panic!("{}: {}", e, data))
+    }
+
+    fn delta(data: &str) -> Delta {
+        match event(data) {
+            StreamEvent::ContentBlockDelta { delta, .. } => delta,
+            other => panic!("not a delta: {:?}", other),
         }
+    }
 
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(10)) // enough to count ugh ?
-            .build()
-            .expect("Failed to create HTTP client");
+    #[test]
+    fn parses_each_event_type() {
+        assert!(matches!(event(MESSAGE_START), StreamEvent::MessageStart { message } if message.id == "msg_1"));
+        assert!(matches!(event(BLOCK_START), StreamEvent::ContentBlockStart { index: 0, .. }));
+        assert!(matches!(event(BLOCK_STOP), StreamEvent::ContentBlockStop { index: 0 }));
+        assert!(matches!(event(MESSAGE_DELTA), StreamEvent::MessageDelta { usage, .. } if usage.output_tokens == 5));
+        assert!(matches!(event(MESSAGE_STOP), StreamEvent::MessageStop));
+        assert!(matches!(event(r#"{"type":"ping"}"#), StreamEvent::Ping));
+        assert!(matches!(
+            event(r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#),
+            StreamEvent::Error { error } if error.message == "Overloaded"
+        ));
+        // a tool use block has no text
+        let tool_start = r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"get_weather","input":{}}}"#;
+        assert!(matches!(event(tool_start), StreamEvent::ContentBlockStart { index: 1, .. }));
+    }
 
-        const API_URL: &str = "https://api.anthropic.com/v1/messages/count_tokens";
+    #[test]
+    fn parses_each_delta_type() {
+        assert!(matches!(delta(&text_delta("Hi")), Delta::Text { text } if text == "Hi"));
+        let input_json = r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"location\": \"San Fra"}}"#;
+        assert!(matches!(delta(input_json), Delta::InputJson { partial_json } if partial_json == r#"{"location": "San Fra"#));
+        let thinking = r#"{"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Let me solve this step by step"}}"#;
+        assert!(matches!(delta(thinking), Delta::Thinking { thinking } if thinking.starts_with("Let me")));
+        let signature = r#"{"type":"content_block_delta","index":0,"delta":{"type":"signature_delta","signature":"EqQBCgIYAhIM1gbcDa9GJwZA2b3h"}}"#;
+        assert!(matches!(delta(signature), Delta::Signature { signature } if signature.starts_with("EqQB")));
+        let citations = r#"{"type":"content_block_delta","index":0,"delta":{"type":"citations_delta","citation":{"type":"char_location","cited_text":"The grass is green.","document_index":0,"document_title":"Notes","start_char_index":0,"end_char_index":19}}}"#;
+        assert!(matches!(
+            delta(citations),
+            Delta::Citations { citation } if citation.cited_text == "The grass is green."
+                && citation.document_title.as_deref() == Some("Notes")
+        ));
+    }
 
-        let request = AntTokCountRequest {
-            model: self.model.clone(),
-            messages: vec![Message {
-                role: Role::User,
-                content: String::from(message),
-            }],
+    #[test]
+    fn tolerates_future_types() {
+        assert!(matches!(event(r#"{"type":"content_block_hologram","index":0,"shape":"cube"}"#), StreamEvent::Unknown));
+        let future_delta = r#"{"type":"content_block_delta","index":0,"delta":{"type":"hologram_delta","shape":"cube"}}"#;
+        assert!(matches!(delta(future_delta), Delta::Unknown));
+        // neither makes it to the UI
+        let mut blocks = ContentBlocks::default();
+        assert!(AnthropicClient::buffer_from_event(event(future_delta), &mut blocks).is_none());
+        assert!(AnthropicClient::buffer_from_event(StreamEvent::Unknown, &mut blocks).is_none());
+    }
+
+    #[test]
+    fn interleaved_blocks_assemble_in_index_order() {
+        let block_start = |index: usize| {
+            format!(r#"{{"type":"content_block_start","index":{},"content_block":{{"type":"text","text":""}}}}"#, index)
         };
+        let events = [
+            block_start(0),
+            block_start(1),
+            block_delta(1, "B1"),
+            block_delta(0, "A1"),
+            block_delta(1, "B2"),
+            block_delta(0, "A2"),
+        ];
+
+        // applied the way the app applies them to the message being streamed
+        let mut blocks = ContentBlocks::default();
+        let mut response = String::new();
+        let mut rewrites = 0;
+        for data in &events {
+            let buffer = AnthropicClient::buffer_from_event(event(data), &mut blocks).unwrap().unwrap();
+            match buffer.content {
+                DeltaContent::Append(text) => response.push_str(&text),
+                DeltaContent::Rewrite(text) => {
+                    rewrites += 1;
+                    response = text;
+                }
+                DeltaContent::Replace(_) => panic!("no error here"),
+            }
+        }
+        assert_eq!(response, "A1A2B1B2");
+        // only the deltas to the earlier block rewrite the text
+        assert_eq!(rewrites, 2);
+    }
 
-        let response = client
-            .post(API_URL)
-            .header("x-api-key", &self.api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&request)
-            .send()
-            .await?;
+    /// A server that opens a response, writes `events` one by one with
+    /// `pause` between them, then keeps the connection open without
+    /// sending anything
+    async fn trickling_server<T: AsRef<[u8]> + Send + 'static>(events: Vec<T>, pause: Duration) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 4096];
+            let _ = socket.read(&mut request).await;
+            let head = "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n";
+            socket.write_all(head.as_bytes()).await.unwrap();
+            for event in events {
+                socket.write_all(event.as_ref()).await.unwrap();
+                tokio::time::sleep(pause).await;
+            }
+            // silent, like a connection that died without closing
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+        format!("http://{}", address)
+    }
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await?;
-            let error_f = format!("API error ({}): {}", status, error_text);
-            return Err(anyhow::anyhow!(error_f));
+    /// The buffers of the trickled events, up to the completing one like the app reads them
+    async fn trickled_events<T: AsRef<[u8]> + Send + 'static>(
+        events: Vec<T>,
+        pause: Duration,
+        stall: Duration,
+    ) -> Vec<Result<StreamingBuffer>> {
+        let uri = trickling_server(events, pause).await;
+        let opened = AnthropicClient::new("claude-test", "test-key".to_string(), &HttpOptions::default())
+            .unwrap()
+            .with_base_url(&uri)
+            .send_message_streaming(vec![Message::new(Role::User, "Hi")])
+            .await
+            .unwrap();
+        let mut events = with_stall_timeout(opened.events, stall);
+        let mut buffers = Vec::new();
+        while let Some(buffer) = events.next().await {
+            let complete = buffer.as_ref().is_ok_and(|buffer| buffer.is_complete);
+            buffers.push(buffer);
+            if complete {
+                break;
+            }
         }
+        buffers
+    }
 
-        let anthropic_response: AntTokCountResponse = response.json().await?;
-        debug!("Received response: {:?}", anthropic_response);
+    #[tokio::test]
+    async fn stalled_stream_fails() {
+        let events = vec![transcript(&[("message_start", MESSAGE_START)])];
+        let buffers = tokio::time::timeout(
+            Duration::from_secs(5),
+            trickled_events(events, Duration::ZERO, Duration::from_millis(300)),
+        )
+        .await
+        .expect("the watchdog ends the stream");
+
+        assert_eq!(buffers.len(), 2);
+        assert!(buffers[0].is_ok());
+        let error = buffers[1].as_ref().err().unwrap();
+        assert!(error.is::<StreamStalled>(), "{}", error);
+        assert_eq!(ErrorKind::of(error), ErrorKind::Network);
+    }
 
-        Ok(anthropic_response.input_tokens)
+    #[tokio::test]
+    async fn slow_stream_with_pings_is_not_stalled() {
+        // the whole response takes longer than the stall timeout, no gap does
+        let ping = transcript(&[("ping", r#"{"type":"ping"}"#)]);
+        let mut events = vec![transcript(&[("message_start", MESSAGE_START)])];
+        events.extend(std::iter::repeat_n(ping, 6));
+        events.push(transcript(&[("message_stop", MESSAGE_STOP)]));
+        let buffers = trickled_events(events, Duration::from_millis(100), Duration::from_millis(400)).await;
+
+        assert!(buffers.iter().all(Result::is_ok));
+        assert!(buffers.last().unwrap().as_ref().unwrap().is_complete);
     }
 
-    #[deprecated]
-    pub async fn get_tokens_price(
-        &self,
-        message: &str,
-        toktype: TokenType,
-        model_price: &ModelPricing,
-    ) -> Result<f64> {
+    #[tokio::test]
+    async fn crlf_terminated_events() {
+        let hello = text_delta("Hello");
+        let body = transcript(&[
+            ("message_start", MESSAGE_START),
+            ("content_block_start", BLOCK_START),
+            ("content_block_delta", &hello),
+            ("content_block_stop", BLOCK_STOP),
+            ("message_stop", MESSAGE_STOP),
+        ]);
+        let buffers = stream_events(body.replace('\n', "\r\n")).await;
+
+        assert!(buffers.iter().all(Result::is_ok));
+        assert_eq!(text(&buffers), "Hello");
+        assert!(buffers.last().unwrap().as_ref().unwrap().is_complete);
+    }
 
-        let token_count = self.count_token(message).await?;
-        match toktype {
-            TokenType::InputToken => {
-                Ok(model_price.input_cost_per_million * (token_count as f64 / 1000000.0))
-            }
-            TokenType::OutputToken => {
-                Ok(model_price.output_cost_per_million * (token_count as f64 / 1000000.0))
-            }
+    #[tokio::test]
+    async fn events_split_anywhere_across_reads() {
+        let delta = text_delta("Ça marche 🦀");
+        let body = transcript(&[
+            ("message_start", MESSAGE_START),
+            ("content_block_start", BLOCK_START),
+            ("content_block_delta", &delta),
+            ("content_block_stop", BLOCK_STOP),
+            ("message_stop", MESSAGE_STOP),
+        ]);
+        // chunks cut through field names, JSON, the blank lines and the
+        // multibyte characters
+        for size in [1, 3, 7, 64] {
+            let chunks: Vec<Vec<u8>> = body.as_bytes().chunks(size).map(<[u8]>::to_vec).collect();
+            let buffers = trickled_events(chunks, Duration::from_millis(1), Duration::from_secs(5)).await;
+
+            assert!(buffers.iter().all(Result::is_ok), "chunks of {}", size);
+            assert_eq!(text(&buffers), "Ça marche 🦀", "chunks of {}", size);
+            assert!(buffers.last().unwrap().as_ref().unwrap().is_complete);
+        }
+    }
+
+    #[test]
+    fn curl_command_leaves_secrets_out() {
+        let headers = BTreeMap::from([
+            ("x-gateway-token".to_string(), "gateway-secret".to_string()),
+            ("1st.party".to_string(), "other-secret".to_string()),
+        ]);
+        let client = AnthropicClient::new("claude-test", "key-secret".to_string(), &HttpOptions::default())
+            .unwrap()
+            .with_beta_flags(&["beta-1".to_string()])
+            .with_extra_headers(&headers);
+        let curl = client.curl_command(&[Message::new(Role::User, "Hi")]).unwrap();
+
+        assert!(!curl.contains("secret"), "{}", curl);
+        assert!(curl.contains(r#"-H "x-api-key: $ANTHROPIC_API_KEY""#), "{}", curl);
+        assert!(curl.contains(r#"-H 'x-gateway-token: '"$X_GATEWAY_TOKEN""#), "{}", curl);
+        assert!(curl.contains(r#"-H '1st.party: '"$_1ST_PARTY""#), "{}", curl);
+        // the Anthropic headers aren't secrets
+        assert!(curl.contains("-H 'anthropic-beta: beta-1'"), "{}", curl);
+    }
+
+    const OVERLOADED: &str = r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+
+    /// A server answering each model with its own transcript
+    async fn models_server(transcripts: &[(&str, String)]) -> MockServer {
+        let server = MockServer::start().await;
+        for (model, body) in transcripts {
+            Mock::given(method("POST"))
+                .and(path("/v1/messages"))
+                .and(body_partial_json(serde_json::json!({ "model": model })))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("content-type", "text/event-stream")
+                        .set_body_string(body.clone()),
+                )
+                .mount(&server)
+                .await;
         }
+        server
     }
 
+    #[tokio::test]
+    async fn overloaded_event_before_text_falls_back() {
+        let hello = text_delta("Hello");
+        let server = models_server(&[
+            ("claude-test", transcript(&[("message_start", MESSAGE_START), ("error", OVERLOADED)])),
+            (
+                "claude-fallback",
+                transcript(&[
+                    ("message_start", MESSAGE_START),
+                    ("content_block_start", BLOCK_START),
+                    ("content_block_delta", &hello),
+                    ("message_stop", MESSAGE_STOP),
+                ]),
+            ),
+        ])
+        .await;
+
+        let opened = client(&server)
+            .send_message_streaming_with_fallback(
+                vec![Message::new(Role::User, "Hi")],
+                &["claude-fallback".to_string()],
+                0,
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        assert_eq!(opened.model, "claude-fallback");
+        let buffers: Vec<_> = opened.events.collect().await;
+        assert!(buffers.iter().all(Result::is_ok));
+        // the events read ahead are put back
+        assert_eq!(buffers[0].as_ref().unwrap().message_id.as_deref(), Some("msg_1"));
+        assert_eq!(text(&buffers), "Hello");
+    }
+
+    #[tokio::test]
+    async fn overloaded_event_after_text_ends_the_response() {
+        let partial = text_delta("Par");
+        let server = models_server(&[(
+            "claude-test",
+            transcript(&[
+                ("message_start", MESSAGE_START),
+                ("content_block_start", BLOCK_START),
+                ("content_block_delta", &partial),
+                ("error", OVERLOADED),
+            ]),
+        )])
+        .await;
+
+        // the text is shown already, sending again would repeat it
+        let opened = client(&server)
+            .send_message_streaming_with_fallback(
+                vec![Message::new(Role::User, "Hi")],
+                &["claude-fallback".to_string()],
+                0,
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        assert_eq!(opened.model, "claude-test");
+        let buffers: Vec<_> = opened.events.collect().await;
+        assert_eq!(text(&buffers), "Par");
+        let error = buffers.last().unwrap().as_ref().err().unwrap();
+        assert_eq!(ErrorKind::of(error), ErrorKind::Overloaded);
+    }
+
+    #[tokio::test]
+    async fn response_closing_before_any_text() {
+        let server = models_server(&[(
+            "claude-test",
+            transcript(&[("message_start", MESSAGE_START), ("ping", r#"{"type":"ping"}"#)]),
+        )])
+        .await;
+
+        let opened = client(&server)
+            .send_message_streaming_with_fallback(vec![Message::new(Role::User, "Hi")], &[], 0, Duration::from_secs(5))
+            .await
+            .unwrap();
+        // the events read ahead come back, then the stream ends
+        let buffers: Vec<_> = opened.events.collect().await;
+        assert_eq!(buffers.len(), 2);
+        assert!(buffers.iter().all(Result::is_ok));
+        assert_eq!(text(&buffers), "");
+    }
+
+    #[tokio::test]
+    async fn overloaded_everywhere_fails() {
+        let overloaded = transcript(&[("message_start", MESSAGE_START), ("error", OVERLOADED)]);
+        let server = models_server(&[("claude-test", overloaded.clone()), ("claude-fallback", overloaded)]).await;
+
+        let error = client(&server)
+            .send_message_streaming_with_fallback(
+                vec![Message::new(Role::User, "Hi")],
+                &["claude-fallback".to_string()],
+                0,
+                Duration::from_secs(5),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(ApiError::is_overloaded(&error), "{}", error);
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reports_an_error_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/messages"))
+            .respond_with(ResponseTemplate::new(401).set_body_string(r#"{"type":"error"}"#))
+            .mount(&server)
+            .await;
+
+        let error = client(&server)
+            .send_message_streaming(vec![Message::new(Role::User, "Hi")])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::of(&error), ErrorKind::Authentication);
+    }
+
+    #[tokio::test]
+    async fn key_check_tells_a_refused_key_from_a_failed_check() {
+        let check = |status: u16| async move {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/v1/models"))
+                .and(header("x-api-key", "test-key"))
+                .respond_with(ResponseTemplate::new(status).set_body_string(r#"{"data":[]}"#))
+                .mount(&server)
+                .await;
+            client(&server).is_api_key_valid().await
+        };
+
+        assert!(check(200).await.unwrap());
+        assert!(!check(401).await.unwrap());
+        assert!(!check(403).await.unwrap());
+        // the key may be fine, the API couldn't say
+        assert!(check(500).await.is_err());
+        assert!(check(429).await.is_err());
+    }
 }
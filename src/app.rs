@@ -1,39 +1,235 @@
+use anyhow::Context as _;
 use futures_util::StreamExt;
 use eframe::{egui, CreationContext};
 use egui::Context;
-use log::{debug, info, error };
-use std::sync::{Arc, Mutex, mpsc};
+use log::{debug, error, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, mpsc};
 use mpsc::Receiver;
 use mpsc::Sender;
 use tokio::sync::mpsc as tokio_mpsc;
+use tokio::sync::{oneshot, Semaphore};
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 use egui::Visuals;
-use std::collections::HashMap;
-use tiktoken_rs::cl100k_base; /// Use ChatGPT tokenizer
-
-use crate::api::{AnthropicClient, AppMessageDelta, Message, Role, TokenType, ResponseUsage, ExtractedResponse};
-use crate::config::{ Config, Theme};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use tiktoken_rs::{cl100k_base, CoreBPE}; // Use ChatGPT tokenizer
+
+use crate::api::{AnthropicClient, ApiError, AppMessageDelta, Citation, ErrorKind, ExtractedResponse, MAX_OUTPUT_TOKENS, DeltaContent, Message, ModelInfo, Rating, Role, TokenType, ResponseUsage, StreamingBuffer};
+use crate::attachment::Attachment;
+use crate::batch::{self, BatchJob, RowStatus};
+use crate::config::{ Config, Profile, ResponseReveal, Theme};
+use crate::conversation::{self, Conversation, ConversationStore, ResponseStream};
+use crate::i18n::{self, tr, tr_args};
+use crate::chat_render::{ChatRenderer, ParsedCache, RenderContext};
+use crate::citation;
+use crate::speech::Speaker;
+use crate::postprocess::{self, ResponsePostProcessor};
+use crate::transcript::{self, LogRecord, ResponseRecorder, TranscriptLog};
+use crate::usage_log::{UsageLog, UsageRecord};
+use crate::mirror::ResponseMirror;
 use crate::ui;
-use crate::price::{fetch_model_pricing, ModelPricing};
+use crate::voice;
+use crate::price::{fetch_model_pricing, find_model_pricing, known_models, merge_pricing, ModelPricing, PRICING_URL};
 
 const STREAM_ERROR_TOKEN: &str = "Err\u{274}r:";
+/// shown in place of a response that completed without any text
+const EMPTY_RESPONSE_PLACEHOLDER: &str = "(no text content)";
+/// stream fragments are forwarded to the UI at most this often...
+const STREAM_FLUSH_INTERVAL: Duration = Duration::from_millis(40);
+/// ...unless this many bytes are pending
+const STREAM_FLUSH_BYTES: usize = 512;
+/// a response revealed once per second is refreshed this often
+const RESPONSE_REVEAL_INTERVAL: Duration = Duration::from_secs(1);
+/// minimum time between two config writes triggered by the settings panel
+const CONFIG_SAVE_DEBOUNCE: Duration = Duration::from_secs(1);
+/// typing pause after which the draft is saved with its conversation
+const DRAFT_SAVE_DEBOUNCE: Duration = Duration::from_secs(2);
+/// the same message sent again within this time is taken for a double send
+const DUPLICATE_SEND_WINDOW: Duration = Duration::from_secs(5);
+
+/// Stream fragments accumulated between two sends to the UI thread
+#[derive(Default)]
+struct DeltaBatch {
+    text: String,
+    /// `text` is the whole response, not a fragment to append
+    rewrite: bool,
+    usage: Option<ResponseUsage>,
+    message_id: Option<String>,
+    model: Option<String>,
+    /// offsets are in `text`
+    citations: Vec<Citation>,
+}
+
+impl DeltaBatch {
+    fn push(&mut self, buffer: StreamingBuffer) {
+        // a buffer's citations follow its text
+        let text_start = self.text.len();
+        match buffer.content {
+            DeltaContent::Append(fragment) => self.text.push_str(&fragment),
+            DeltaContent::Rewrite(text) | DeltaContent::Replace(text) => {
+                self.text = text;
+                self.rewrite = true;
+            }
+        }
+        if let Some(usage) = buffer.usage {
+            self.usage.get_or_insert_with(Default::default).add(&usage);
+        }
+        if buffer.message_id.is_some() {
+            self.message_id = buffer.message_id;
+        }
+        if buffer.model.is_some() {
+            self.model = buffer.model;
+        }
+        self.citations.extend(buffer.citations.into_iter().map(|citation| Citation {
+            offset: text_start + citation.offset,
+            ..citation
+        }));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.text.is_empty()
+            && !self.rewrite
+            && self.usage.is_none()
+            && self.message_id.is_none()
+            && self.model.is_none()
+            && self.citations.is_empty()
+    }
+
+    fn take(&mut self, is_complete: bool) -> AppMessageDelta {
+        let text = std::mem::take(&mut self.text);
+        AppMessageDelta {
+            content: if std::mem::take(&mut self.rewrite) {
+                DeltaContent::Rewrite(text)
+            } else {
+                DeltaContent::Append(text)
+            },
+            usage: self.usage.take(),
+            is_complete,
+            model: self.model.take(),
+            fallback_model: None,
+            rate_limits: None,
+            error_kind: None,
+            message_id: self.message_id.take(),
+            citations: std::mem::take(&mut self.citations),
+        }
+    }
+}
+
+/// Tokenizer for the estimates, loaded on first use
+static CL100K_BASE: OnceLock<Result<CoreBPE, String>> = OnceLock::new();
+
+/// Work for the token counting thread
+enum TokenJob {
+    /// the input being typed, and the staged attachments as sent
+    Draft(String, Vec<String>),
+    /// the conversation history, one text per message
+    Conversation(Vec<String>),
+    /// the model was switched, with its pricing if known
+    Model(String, Option<ModelPricing>),
+}
+
+/// Results of the background token counting
+#[derive(Default)]
+struct TokenEstimates {
+    /// heuristic cost of the draft input
+    input_cost: Option<Result<f64, String>>,
+    /// heuristic token count of the draft input, attachments included
+    input_tokens: Option<usize>,
+    /// heuristic token count of each staged attachment
+    attachment_tokens: Vec<usize>,
+    /// heuristic token count of the conversation history
+    conversation_tokens: usize,
+    /// heuristic token count of each message of the history
+    message_tokens: Vec<usize>,
+}
+
+/// smallest height of the input box
+const MIN_INPUT_HEIGHT: f32 = 80.0;
+/// height the transcript keeps when the input grows
+const MIN_CHAT_HEIGHT: f32 = 120.0;
+/// share of the window the input may grow to
+const INPUT_MAX_FRACTION: f32 = 0.45;
+
+/// First assistant message of a conversation
+const GREETING: &str = "How can I help you?";
+
+/// Messages kept verbatim when the conversation is summarized, the last two turns
+const SUMMARY_KEEP_MESSAGES: usize = 4;
+/// Instruction sent after the turns to summarize
+const SUMMARY_PROMPT: &str = "Summarize our conversation so far. The summary will replace \
+the earlier messages as your only context, so keep the facts, decisions, open questions \
+and any code, names or numbers needed to carry on. Reply with the summary only.";
+/// Start of the system message holding the summary
+const SUMMARY_HEADER: &str = "Summary of the earlier conversation:";
+
+/// Instruction for the title model, the first question follows it
+const TITLE_PROMPT: &str = "Summarize the topic of the message below in at most 4 words, \
+to title the conversation. Reply with the title only, no quotes or punctuation.";
+/// Characters of the first question sent to the title model
+const TITLE_SOURCE_MAX_CHARS: usize = 2000;
+
+/// How long exiting waits for running tasks before abandoning them
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Summary being written for the turns before `split` of a conversation
+struct SummaryRequest {
+    conversation: String,
+    split: usize,
+    receiver: oneshot::Receiver<anyhow::Result<String>>,
+}
+
+/// Text left in the input where a pasted attachment was taken out
+fn attachment_placeholder(name: &str) -> String {
+    format!("[{}]", name)
+}
 
 /// application state
 pub struct ClauChatApp {
     /// user input being typed
     input: String,
 
+    /// start of the assistant's reply, the response continues from it
+    prefill: String,
+
+    /// client and messages of the last request, to rebuild it as a curl
+    /// command. The client is the one the request went through, which
+    /// may not be `client`, e.g. for a regenerate on another model
+    last_request: Option<(AnthropicClient, Vec<Message>)>,
+
+    /// attachments staged for the next message
+    attachments: Vec<Attachment>,
+
+    /// number of pastes turned into attachments, used to name them
+    pasted_count: usize,
+
     /// conversation history
     messages: Vec<Message>,
 
-    /// is the input in the process of sending
-    is_sending: bool,
+    /// indices of pinned messages in the conversation
+    pinned_messages: BTreeSet<usize>,
+
+    /// parsed layout of the rendered messages
+    parsed_messages: ParsedCache,
+
+    /// every conversation by id, the active one's messages live in `messages`
+    conversations: HashMap<String, Conversation>,
+
+    /// id of the conversation shown
+    active_conversation: String,
+
+    /// the active conversation changed since it was last saved
+    conversation_dirty: bool,
+
+    /// saved conversations, None when the data directory isn't usable
+    conversation_store: Option<ConversationStore>,
 
     /// config i.e api key
     config: Config,
 
-    /// tokio runtime
-    runtime: Runtime,
+    /// tokio runtime, taken on exit to shut it down
+    runtime: Option<Runtime>,
 
     /// API client
     client: Option<AnthropicClient>,
@@ -41,10 +237,8 @@ pub struct ClauChatApp {
     /// basic ui state
     ui_state: ui::UiState,
 
-    /// channel for api response thread transit 
-    stream_receiver: Option<tokio_mpsc::Receiver<AppMessageDelta>>,
-    input_sender: Option<Sender<String>>,
-    input_receiver: Option<Receiver<String>>,
+    input_sender: Option<Sender<TokenJob>>,
+    input_receiver: Option<Receiver<TokenJob>>,
 
     /// error message if any
     error: Option<String>,
@@ -52,11 +246,78 @@ pub struct ClauChatApp {
     /// model being used
     model: String,
 
+    /// microphone capture for dictation
+    recorder: Option<voice::Recorder>,
+
+    /// batch of the batch panel, until it's cleared
+    batch: Option<BatchJob>,
+
+    /// transcription of the last recording, until it's done
+    transcription_receiver: Option<oneshot::Receiver<anyhow::Result<String>>>,
+
+    /// whether the API takes the key, asked again from an error chip
+    key_check_receiver: Option<oneshot::Receiver<anyhow::Result<bool>>>,
+
+    /// summary of the active conversation, until it's written
+    summary_request: Option<SummaryRequest>,
+
+    /// writes requests and responses to the log folder, when enabled
+    transcript_log: Option<TranscriptLog>,
+
+    /// appends the usage of each response to usage.jsonl, when enabled
+    usage_log: Option<UsageLog>,
+    mirror: Option<ResponseMirror>,
+
+    /// slots for the responses streaming at once, across conversations
+    request_slots: Arc<Semaphore>,
+
+    /// run in order on each response that completes
+    post_processors: Vec<Box<dyn ResponsePostProcessor>>,
+
+    /// reads messages aloud, None without text-to-speech
+    speaker: Option<Speaker>,
+
+    /// models offered by the selector
+    models: Vec<String>,
+
+    /// metadata of the models fetched so far, by model id
+    model_info: Arc<Mutex<HashMap<String, ModelInfo>>>,
+
     /// token pricing info
-    pricing_data: Option<HashMap<String, ModelPricing>>,
+    pricing_data: HashMap<String, ModelPricing>,
+
+    /// fetched pricing, until it arrives
+    pricing_receiver: Option<oneshot::Receiver<HashMap<String, ModelPricing>>>,
+
+    /// background token counts for the input and the conversation
+    token_estimates: Arc<Mutex<TokenEstimates>>,
+    /// the tokenizer failure was shown
+    token_estimation_notified: bool,
+
+    /// when the config was first modified since the last save
+    config_dirty_since: Option<Instant>,
+
+    /// conversation the last message was sent to, and when
+    last_send: Option<(String, Instant)>,
 
-    /// input cost estimate display
-    input_cost: Arc<Mutex<Option<Result<f64, String>>>>,
+    /// when the input was first changed since the draft was last saved
+    draft_dirty_since: Option<Instant>,
+
+    /// when the text shown of the streaming response was last refreshed
+    response_revealed_at: Option<Instant>,
+
+    /// the prompt was too long and the settings retry it trimmed
+    trim_retry_pending: bool,
+
+    /// conversations whose first answer just completed, to be titled
+    untitled_conversations: Vec<String>,
+
+    /// titles written by the title model, with the conversation and the usage
+    title_sender: tokio_mpsc::UnboundedSender<(String, anyhow::Result<(String, ResponseUsage)>)>,
+    title_receiver: tokio_mpsc::UnboundedReceiver<(String, anyhow::Result<(String, ResponseUsage)>)>,
+
+    /// the last request was already trimmed, it's not retried automatically again
+    last_request_trimmed: bool,
 
 
 }
@@ -69,52 +330,226 @@ impl ClauChatApp {
         let ctx = &cc.egui_ctx;
         ctx.set_visuals(Visuals::dark());
 
-        let config = Config::load().unwrap_or_default();
+        let mut config = Config::load().unwrap_or_default();
+        i18n::set_language(&config.language);
+        if let Some(profile) = Config::profile_arg() {
+            if config.profiles.contains_key(&profile) {
+                config.active_profile = profile;
+            } else {
+                warn!("No profile named {}, using {}", profile, config.active_profile);
+            }
+        }
 
-        const MODEL: &str = "claude-3-7-sonnet-20250219";
-        let price_data = runtime.block_on(async {
-            fetch_model_pricing(Some(MODEL)).await
-        }).unwrap();
+        // fetched in the background, the built-in pricing is used until it
+        // lands. The whole table, any model may be picked later
+        let http_options = config.http_options();
+        let attempts = config.pricing_fetch_attempts;
+        let (pricing_tx, pricing_rx) = oneshot::channel();
+        let repaint_ctx = ctx.clone();
+        runtime.spawn(async move {
+            let price_data = fetch_model_pricing(PRICING_URL, None, &http_options, attempts)
+                .await
+                .unwrap_or_else(|e| {
+                    error!("Could not fetch pricing data: {}", e);
+                    None
+                });
+            if let Some(price_data) = price_data {
+                let _ = pricing_tx.send(price_data);
+                repaint_ctx.request_repaint();
+            }
+        });
 
-        let client = if !config.api_key.is_empty() {
-            Some(AnthropicClient::new(MODEL, config.api_key.clone()))
-        } else {
-            None
+        let conversation_store = ConversationStore::open()
+            .inspect_err(|e| error!("Conversations won't be saved: {:#}", e))
+            .ok();
+        let mut conversations: HashMap<String, Conversation> = conversation_store
+            .as_ref()
+            .and_then(|store| {
+                store
+                    .load_all()
+                    .inspect_err(|e| error!("Could not load conversations: {:#}", e))
+                    .ok()
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .map(|mut conversation| {
+                // saved before the greeting was kept out of requests
+                if let Some(first) = conversation.messages.first_mut() {
+                    if first.role == Role::Assistant && first.content == GREETING {
+                        first.local = true;
+                    }
+                }
+                (conversation.id.clone(), conversation)
+            })
+            .collect();
+        // pick up where the most recent conversation left off
+        let active_conversation = match conversations.values().max_by_key(|c| c.updated_at) {
+            Some(latest) => latest.id.clone(),
+            None => {
+                let id = conversation::new_id(|_| false);
+                let blank = Conversation::new(id.clone(), vec![Message::local(Role::Assistant, GREETING)]);
+                conversations.insert(id.clone(), blank);
+                id
+            }
         };
 
-        let messages = vec![Message {
-            role: Role::Assistant,
-            content: "How can I help you?".to_string(),
-        }];
+        let mut app = Self::with_conversations(config, conversations, active_conversation);
+        app.runtime = Some(runtime);
+        app.conversation_store = conversation_store;
+        app.pricing_receiver = Some(pricing_rx);
+        app.speaker = Speaker::new();
+        app.rebuild_client();
+        app.fetch_model_info();
+        app.sync_transcript_log();
+        app.sync_usage_log();
+        app.sync_mirror();
+        app.post_processors = postprocess::from_config(&app.config);
+        app
+    }
+
+    /// The app showing `active_conversation`, without a runtime, storage
+    /// or speech. `new` adds them, tests do without
+    fn with_conversations(
+        config: Config,
+        conversations: HashMap<String, Conversation>,
+        active_conversation: String,
+    ) -> Self {
+        let input = conversations[&active_conversation].draft.clone();
+        let messages = conversations[&active_conversation].messages.clone();
+        let pinned_messages = conversations[&active_conversation].pinned.clone();
+        // the model picked last with the profile
+        let model = match config.profile().model.as_str() {
+            "" => config.default_model(),
+            model => model.to_string(),
+        };
+        let mut models = known_models();
+        if !models.contains(&model) {
+            models.insert(0, model.clone());
+        }
 
-        let input_cost: Arc<Mutex<Option<Result<f64, String>>>> = Arc::new(Mutex::new(None));
+        let token_estimates = Arc::new(Mutex::new(TokenEstimates::default()));
+        let request_slots = Arc::new(Semaphore::new(config.max_concurrent_requests.max(1)));
+        let (title_sender, title_receiver) = tokio_mpsc::unbounded_channel();
         Self {
-            input: String::new(),
+            input,
+            prefill: String::new(),
+            last_request: None,
+            attachments: Vec::new(),
+            pasted_count: 0,
             messages,
-            is_sending: false,
+            pinned_messages,
+            parsed_messages: ParsedCache::default(),
+            conversations,
+            active_conversation,
+            conversation_dirty: false,
+            conversation_store: None,
             config,
-            runtime,
-            client,
+            runtime: None,
+            client: None,
             ui_state: ui::UiState::default(),
-            stream_receiver: None,
             input_sender: None,
             input_receiver: None,
             error: None,
-            model: MODEL.to_string(),
-            pricing_data: price_data,
-            input_cost,
+            model,
+            models,
+            recorder: None,
+            transcription_receiver: None,
+            key_check_receiver: None,
+            summary_request: None,
+            batch: None,
+            transcript_log: None,
+            usage_log: None,
+            mirror: None,
+            request_slots,
+            post_processors: Vec::new(),
+            speaker: None,
+            model_info: Arc::new(Mutex::new(HashMap::new())),
+            pricing_data: merge_pricing(None),
+            pricing_receiver: None,
+            token_estimates,
+            token_estimation_notified: false,
+            config_dirty_since: None,
+            last_send: None,
+            draft_dirty_since: None,
+            response_revealed_at: None,
+            trim_retry_pending: false,
+            untitled_conversations: Vec::new(),
+            title_sender,
+            title_receiver,
+            last_request_trimmed: false,
         }
     }
 
+    /// Recount the draft after the input changed, and schedule saving it
     fn send_input_required(&mut self) -> Result<(), String> {
+        self.draft_dirty_since.get_or_insert_with(Instant::now);
         // debug!("Sending input to thread");
-        if let Err(e) = self.input_sender.as_ref().unwrap().send(self.input.clone()) {
+        let attachments = self.attachments.iter().map(Attachment::as_fenced_block).collect();
+        let draft = TokenJob::Draft(self.input.clone(), attachments);
+        if let Err(e) = self.input_sender.as_ref().unwrap().send(draft) {
             error!("Error sending input to processing thread: {}", e);
         }
 
         Ok(())
     }
 
+    /// Where the history sent with the next request starts, when the message
+    /// window or the token budget leaves older messages out. The input counts
+    /// against the budget, system messages are always sent
+    fn context_cut(&self) -> Option<ui::ContextCut> {
+        let max_messages = self.config.context_max_messages;
+        let budget = self.config.context_token_budget;
+        if max_messages == 0 && budget == 0 {
+            return None;
+        }
+
+        let estimates = self.token_estimates.lock().unwrap();
+        let tokens_of = |idx: usize| estimates.message_tokens.get(idx).copied().unwrap_or_default();
+        let mut tokens = estimates.input_tokens.unwrap_or_default()
+            + (0..self.messages.len())
+                .filter(|&idx| self.messages[idx].role == Role::System)
+                .map(tokens_of)
+                .sum::<usize>();
+        let mut kept = 0;
+        for (idx, message) in self.messages.iter().enumerate().rev() {
+            if message.role == Role::System || message.local {
+                continue;
+            }
+            tokens += tokens_of(idx);
+            let limit = if budget > 0 && tokens > budget {
+                ui::ContextLimit::Tokens(budget)
+            } else if max_messages > 0 && kept == max_messages {
+                ui::ContextLimit::Messages(max_messages)
+            } else {
+                kept += 1;
+                continue;
+            };
+
+            // an answer isn't sent without its question
+            let mut start = idx + 1;
+            while self.messages.get(start).is_some_and(|message| message.role != Role::User) {
+                start += 1;
+            }
+            return Some(ui::ContextCut { start, limit });
+        }
+        None
+    }
+
+    /// Ask the token thread to recount the conversation history
+    fn refresh_conversation_tokens(&self) {
+        // local messages count for nothing, they keep their index for the context cut
+        let history = self
+            .messages
+            .iter()
+            .map(|message| if message.local { String::new() } else { message.api_content() })
+            .collect();
+        if let Some(sender) = &self.input_sender {
+            if let Err(e) = sender.send(TokenJob::Conversation(history)) {
+                error!("Error sending conversation to processing thread: {}", e);
+            }
+        }
+    }
+
     // fn send_input(&mut self) -> Result<(), String> {
     //     const SEND_INTERVAL: Duration = Duration::from_millis(300);
     //     let time_now = std::time::Instant::now();
@@ -131,17 +566,14 @@ impl ClauChatApp {
 
     pub fn init(&mut self) -> Result<(), String> {
         if self.input_sender.is_none() || self.input_receiver.is_none() {
-            let (tx, rx) = mpsc::channel::<String>();
+            let (tx, rx) = mpsc::channel::<TokenJob>();
             self.input_sender = Some(tx);
             self.input_receiver = Some(rx);
         }
 
-        let input_cost_clone = self.input_cost.clone();
-        let model_price = self
-            .pricing_data
-            .as_ref()
-            .and_then(|pricing_data| pricing_data.get(&self.model).cloned())
-            .unwrap();
+        let estimates_clone = self.token_estimates.clone();
+        let mut model_price = find_model_pricing(Some(&self.pricing_data), &self.model).cloned();
+        let mut model = self.model.clone();
 
         let t_receiver = self
             .input_receiver
@@ -150,211 +582,2434 @@ impl ClauChatApp {
 
         std::thread::spawn(move || {
             loop {
-                if let Ok(input) = t_receiver.recv() {
-                    // debug!("Input: {}", input);
-                    match ClauChatApp::get_tokens_heur_price(
-                        &input,
-                        TokenType::InputToken,
-                        &model_price,
-                    ) {
-                        Ok(_input_cost) => {
-                            let mut input_cost = input_cost_clone.lock().unwrap();
-                            *input_cost = Some(Ok(_input_cost));
-                        }
-                        Err(e) => {
-                            error!("Error: {}", e.to_string());
-                        }
-                    };
-
-                    // std::thread::sleep(std::time::Duration::from_millis(100));
+                match t_receiver.recv() {
+                    Ok(TokenJob::Draft(input, attachments)) => {
+                        // debug!("Input: {}", input);
+                        let attachment_tokens: Vec<usize> = attachments
+                            .iter()
+                            .map(|attachment| ClauChatApp::token_count_heuristic(attachment))
+                            .collect();
+                        let token_count =
+                            ClauChatApp::token_count_heuristic(&input) + attachment_tokens.iter().sum::<usize>();
+                        let input_cost = match &model_price {
+                            Some(model_price) => Ok(ClauChatApp::tokens_price(
+                                token_count,
+                                TokenType::InputToken,
+                                model_price,
+                            )),
+                            None => Err(format!("No pricing data for {}", model)),
+                        };
+                        let mut estimates = estimates_clone.lock().unwrap();
+                        estimates.input_tokens = Some(token_count);
+                        estimates.attachment_tokens = attachment_tokens;
+                        estimates.input_cost = Some(input_cost);
+                    }
+                    Ok(TokenJob::Conversation(history)) => {
+                        let message_tokens: Vec<usize> = history
+                            .iter()
+                            .map(|message| ClauChatApp::token_count_heuristic(message))
+                            .collect();
+                        let mut estimates = estimates_clone.lock().unwrap();
+                        estimates.conversation_tokens = message_tokens.iter().sum();
+                        estimates.message_tokens = message_tokens;
+                    }
+                    Ok(TokenJob::Model(new_model, new_price)) => {
+                        model = new_model;
+                        model_price = new_price;
+                    }
+                    // the app is gone
+                    Err(_) => break,
                 }
             }
 
         });
 
+        self.refresh_conversation_tokens();
         Ok(())
     }
 
-    fn usage_as_cost(&self, usage: &ResponseUsage) -> Result<f64, String> {
-        let model_price = self.pricing_data.as_ref().unwrap().get(&self.model).unwrap();
-        let total = model_price.input_cost_per_million * (usage.input_tokens as f64 / 1000000.0) +
-        model_price.output_cost_per_million * (usage.output_tokens as f64 / 1000000.0);
-        Ok(total)
+    /// Cost of a usage report, None when the model has no pricing data
+    fn usage_as_cost(&self, model: &str, usage: &ResponseUsage) -> Option<f64> {
+        let model_price = find_model_pricing(Some(&self.pricing_data), model)?;
+        Some(model_price.usage_cost(usage))
     }
 
-    fn handle_stream_response(&mut self, content_delta: AppMessageDelta) {
-        if content_delta.content.starts_with(STREAM_ERROR_TOKEN) {
-            error!("{}", content_delta.content);
-            self.error = Some(content_delta.content);
+    /// Apply a streamed delta to its conversation, the active one or one
+    /// streaming in the background
+    fn handle_stream_response(&mut self, id: &str, content_delta: AppMessageDelta) {
+        let is_active = id == self.active_conversation;
+        if let Some(rate_limits) = &content_delta.rate_limits {
+            self.ui_state.rate_limits = Some(rate_limits.clone());
+        }
+        // usage is priced for the model that answered, a fallback one included
+        let answering_model = content_delta
+            .model
+            .clone()
+            .or_else(|| {
+                let messages = if is_active {
+                    &self.messages
+                } else {
+                    &self.conversations.get(id)?.messages
+                };
+                messages.last()?.model.clone()
+            })
+            .unwrap_or_else(|| self.model.clone());
+        let cost = content_delta
+            .usage
+            .as_ref()
+            .map(|usage| self.usage_as_cost(&answering_model, usage));
+        let Some(conversation) = self.conversations.get_mut(id) else {
+            return;
+        };
+        let untitled = conversation.title.is_empty();
+        let prefill_len = conversation.stream.as_ref().map_or(0, |stream| stream.prefill_len);
+        // the active conversation's transcript lives in the app
+        let (messages, pinned) = if is_active {
+            (&mut self.messages, &mut self.pinned_messages)
+        } else {
+            (&mut conversation.messages, &mut conversation.pinned)
+        };
+        let mut streamed = messages.last_mut().filter(|message| message.role == Role::Assistant);
+        // the active conversation's last request went on to the fallback model
+        let last_client = self.last_request.as_mut().filter(|_| is_active).map(|(client, _)| client);
+        if let (Some(fallback_model), Some(client)) = (content_delta.fallback_model, last_client) {
+            *client = client.clone().with_model(&fallback_model);
+        }
+        if let (Some(model), Some(last_message)) = (content_delta.model, streamed.as_deref_mut()) {
+            debug!("Response comes from {}", model);
+            last_message.model = Some(model);
+        }
+        if let (Some(message_id), Some(last_message)) = (content_delta.message_id, streamed.as_deref_mut()) {
+            debug!("Response {} started", message_id);
+            last_message.message_ids.push(message_id);
+        }
+
+        // text before the delta, what follows it is mirrored live
+        let mirror_from = streamed.as_deref().map(|message| message.content.len());
+
+        // a resumed response is held back at first, until what repeats
+        // the end of the partial text can be cut
+        let mut content = content_delta.content;
+        if let Some(stream) = conversation.stream.as_mut() {
+            match (stream.seam.as_mut(), &mut content) {
+                (Some(seam), DeltaContent::Append(fragment)) => {
+                    seam.push_str(&std::mem::take(fragment));
+                }
+                (Some(seam), DeltaContent::Rewrite(text)) => {
+                    *seam = std::mem::take(text);
+                    content = DeltaContent::default();
+                }
+                (Some(_), DeltaContent::Replace(_)) => {}
+                (None, DeltaContent::Rewrite(text)) if stream.seam_overlap > 0 => {
+                    if let Some(rest) = text.get(stream.seam_overlap..) {
+                        *text = rest.to_string();
+                    }
+                }
+                (None, _) => {}
+            }
+            let settled = stream.seam.as_ref().is_some_and(|seam| {
+                seam.len() >= conversation::SEAM_WINDOW
+                    || content_delta.is_complete
+                    || matches!(content, DeltaContent::Replace(_))
+            });
+            if let (true, Some(last_message)) = (settled, streamed.as_deref_mut()) {
+                let held = stream.seam.take().unwrap_or_default();
+                let partial = last_message.content.get(..prefill_len).unwrap_or_default();
+                stream.seam_overlap = conversation::seam_overlap(partial, &held);
+                if stream.seam_overlap > 0 {
+                    debug!("Cut {} repeated bytes at the resume seam", stream.seam_overlap);
+                }
+                last_message.content.push_str(&held[stream.seam_overlap..]);
+                stream.output_tokens += Self::token_count_heuristic(&held[stream.seam_overlap..]);
+            }
+        }
 
-            // Error could happen in the middle of streaming
+        // counted as it arrives, the API only reports the total at the end
+        if let Some(stream) = conversation.stream.as_mut() {
+            match &content {
+                DeltaContent::Append(fragment) => stream.output_tokens += Self::token_count_heuristic(fragment),
+                DeltaContent::Rewrite(text) => stream.output_tokens = Self::token_count_heuristic(text),
+                DeltaContent::Replace(_) => {}
+            }
             if let Some(usage) = &content_delta.usage {
-                error!("There is some usage: {:?}", usage);
-                self.ui_state.total_cost += self.usage_as_cost(usage).unwrap();
+                stream.output_tokens = stream.output_tokens.max(usage.output_tokens as usize);
+            }
+        }
+
+        // where the delta's text starts in the message, its citations are
+        // offset from there
+        let citation_base = match (&content, streamed.as_deref()) {
+            (DeltaContent::Append(_), Some(message)) => message.content.len(),
+            (DeltaContent::Rewrite(_), _) => prefill_len,
+            _ => 0,
+        };
+        let mut failed = false;
+        let rewritten = !matches!(content, DeltaContent::Append(_));
+        match content {
+            DeltaContent::Replace(text) if text.starts_with(STREAM_ERROR_TOKEN) => {
+                error!("{}", text);
+                // the failed turn carries the error, with what arrived before
+                // it. The banner only has it when the turn is gone
+                match streamed {
+                    Some(last_message) => {
+                        let error = text[STREAM_ERROR_TOKEN.len()..].trim_start();
+                        last_message.error = Some(error.to_string());
+                        last_message.error_kind = content_delta.error_kind;
+                    }
+                    None if is_active => self.error = Some(text),
+                    None => {}
+                }
+                if is_active && content_delta.error_kind == Some(ErrorKind::PromptTooLong) {
+                    self.ui_state.prompt_too_long = true;
+                    self.trim_retry_pending = self.config.auto_trim_prompt && !self.last_request_trimmed;
+                }
+                failed = true;
+            }
+            DeltaContent::Replace(text) => {
+                if let Some(last_message) = streamed {
+                    last_message.content = text;
+                }
+            }
+            DeltaContent::Append(fragment) => {
+                if let Some(last_message) = streamed {
+                    last_message.content.push_str(&fragment);
+                }
+            }
+            DeltaContent::Rewrite(text) => {
+                if let Some(last_message) = streamed {
+                    last_message.content.truncate(prefill_len);
+                    last_message.content.push_str(&text);
+                }
+            }
+        }
+
+        // rewrites are left to the end, the final text is mirrored then
+        if let (Some(mirror), Some(from), false) = (self.mirror.as_mut(), mirror_from, failed || rewritten) {
+            if let Some(message) = messages.last().filter(|message| message.role == Role::Assistant) {
+                mirror.delta(id, &message.content, from);
+            }
+        }
+
+        if !content_delta.citations.is_empty() {
+            if let Some(last_message) = messages.last_mut().filter(|message| message.role == Role::Assistant) {
+                last_message.citations.extend(content_delta.citations.into_iter().map(|citation| Citation {
+                    offset: citation_base + citation.offset,
+                    ..citation
+                }));
+            }
+        }
+
+        if let Some(usage) = &content_delta.usage {
+            debug!("There is some usage: {:?}", usage);
+            self.ui_state.total_input_tokens += usage.input_tokens as u64;
+            self.ui_state.total_output_tokens += usage.output_tokens as u64;
+            match cost.flatten() {
+                Some(cost) => self.ui_state.total_cost += cost,
+                None => self.ui_state.total_cost_known = false,
+            }
+            if let Some(last_message) = messages.last_mut() {
+                if last_message.role == Role::Assistant {
+                    last_message.usage.get_or_insert_with(Default::default).add(usage);
+                }
+            }
+        }
+        if !content_delta.is_complete {
+            return;
+        }
+
+        if let Some(log) = &self.usage_log {
+            let usage = messages
+                .last()
+                .filter(|message| message.role == Role::Assistant)
+                .and_then(|message| message.usage.as_ref());
+            if let Some(usage) = usage {
+                let cost = find_model_pricing(Some(&self.pricing_data), &answering_model)
+                    .map(|pricing| pricing.usage_cost(usage));
+                log.record(UsageRecord::new(&answering_model, id, usage, cost));
+            }
+        }
+        conversation.stream = None;
+        let completed = messages
+            .last_mut()
+            .filter(|message| !failed && message.role == Role::Assistant && message.error.is_none());
+        if let Some(last_message) = completed {
+            for processor in &self.post_processors {
+                last_message.content = processor.process(&last_message.content);
+            }
+            let first_answer = messages
+                .iter()
+                .filter(|message| message.role == Role::User && message.is_sent())
+                .count()
+                == 1;
+            if self.config.auto_title && untitled && first_answer {
+                self.untitled_conversations.push(id.to_string());
+            }
+        }
+        if let Some(mirror) = self.mirror.as_mut() {
+            let content = messages
+                .last()
+                .filter(|message| !failed && message.role == Role::Assistant && message.error.is_none())
+                .map(|message| message.content.clone());
+            mirror.finish(id, content);
+        }
+        Self::finish_empty_response(messages, pinned, failed);
+        if is_active {
+            if self.config.auto_read_responses && !failed {
+                self.read_aloud(self.messages.len().saturating_sub(1));
             }
-        } else if let Some(last_message) = self.messages.last_mut() {
-            if last_message.role == Role::Assistant {
-                last_message.content = content_delta.content;
+            self.refresh_conversation_tokens();
+            self.conversation_dirty = true;
+            self.persist_active_conversation();
+        } else {
+            conversation.updated_at = conversation::now_secs();
+            self.save_background_conversation(id);
+        }
+    }
 
-                if let Some(usage) = &content_delta.usage {
-                    debug!("There is some usage: {:?}", usage);
-                    self.ui_state.total_cost += self.usage_as_cost(usage).unwrap();
+    /// Apply every pending fragment of every conversation streaming, so a
+    /// burst costs a single repaint. Returns whether there was any
+    fn poll_streams(&mut self) -> bool {
+        let mut pending_deltas = Vec::new();
+        for conversation in self.conversations.values_mut() {
+            let Some(stream) = &mut conversation.stream else {
+                continue;
+            };
+            loop {
+                match stream.receiver.try_recv() {
+                    Ok(content_delta) => {
+                        let is_complete = content_delta.is_complete;
+                        pending_deltas.push((conversation.id.clone(), content_delta));
+                        if is_complete {
+                            break;
+                        }
+                    }
+                    Err(tokio_mpsc::error::TryRecvError::Empty) => break,
+                    // the task is gone without completing the response, e.g.
+                    // it panicked. Finished as failed, or it would stay sending
+                    Err(tokio_mpsc::error::TryRecvError::Disconnected) => {
+                        pending_deltas.push((conversation.id.clone(), Self::interrupted_delta()));
+                        break;
+                    }
                 }
             }
         }
-        if content_delta.is_complete {
-            self.is_sending = false;
+        let received = !pending_deltas.is_empty();
+        for (id, content_delta) in pending_deltas {
+            self.handle_stream_response(&id, content_delta);
+        }
+        received
+    }
+
+    /// Completes a response whose task ended without saying so
+    fn interrupted_delta() -> AppMessageDelta {
+        AppMessageDelta {
+            content: DeltaContent::Replace(format!("{} Response was interrupted", STREAM_ERROR_TOKEN)),
+            is_complete: true,
+            error_kind: Some(ErrorKind::Other),
+            ..Default::default()
+        }
+    }
+
+    /// Save a conversation that isn't shown, e.g. once its response is over
+    fn save_background_conversation(&mut self, id: &str) {
+        let Some(conversation) = self.conversations.get(id) else {
+            return;
+        };
+        if let Err(e) = self.save_conversation(conversation) {
+            error!("Could not save the conversation: {:#}", e);
+            self.ui_state
+                .push_toast(tr_args("error.save_conversation", &[&format!("{:#}", e)]));
+        }
+    }
+
+    /// Don't leave a blank assistant bubble behind once a response is over:
+    /// drop it when the request failed, otherwise say there was no text.
+    /// The placeholder is local, the API never gets an empty turn
+    fn finish_empty_response(messages: &mut Vec<Message>, pinned: &mut BTreeSet<usize>, failed: bool) {
+        let Some(last_message) = messages.last_mut() else {
+            return;
+        };
+        if last_message.role != Role::Assistant
+            || !last_message.content.trim().is_empty()
+            || last_message.error.is_some()
+        {
+            return;
+        }
+
+        if failed {
+            messages.pop();
+            pinned.remove(&messages.len());
+        } else {
+            debug!("Response completed without any text content");
+            last_message.content = EMPTY_RESPONSE_PLACEHOLDER.to_string();
+            last_message.local = true;
         }
     }
 
     #[deprecated]
+    #[allow(dead_code)]
     fn handle_api_response(&mut self, response: Result<ExtractedResponse, String>) {
         match response {
             Ok(response) => {
-                let assistant_message = Message {
-                    role: Role::Assistant,
-                    content: response.content,
-                };
-                self.ui_state.total_cost += self.usage_as_cost(&response.usage).unwrap();
-                self.messages.push(assistant_message);
+                match self.usage_as_cost(&self.model, &response.usage) {
+                    Some(cost) => self.ui_state.total_cost += cost,
+                    None => self.ui_state.total_cost_known = false,
+                }
+                self.messages.push(Message::new(Role::Assistant, response.content));
             }
             Err(err) => {
                 error!("Failed to get valid response: {}", err);
                 self.error = Some(format!("Failed to get valid response: {}", err));
             }
         }
-        self.is_sending = false;
-        // info!("Total cost: {}", self.ui_state.total_cost);
     }
 
     /// Counting tokens using ChatGPT tokenizer, 
-    /// it matches enough when the Anthropic pricing is applied
-    fn token_count_heuristic(content: &str) -> Result<usize, String> {
-        match cl100k_base() {
-            Ok (bpe)=> {
-                Ok(bpe.encode_ordinary(content).len())
-            }
-            Err(e) => Err(e.to_string()) 
+    /// it matches enough when the Anthropic pricing is applied.
+    /// The BPE is loaded once and reused, loading it takes far longer than encoding.
+    /// When it can't load, a token is taken as 4 characters
+    fn token_count_heuristic(content: &str) -> usize {
+        let bpe = CL100K_BASE.get_or_init(|| {
+            cl100k_base().map_err(|e| {
+                error!("Could not load the tokenizer, tokens are estimated from characters: {:#}", e);
+                format!("{:#}", e)
+            })
+        });
+        match bpe {
+            Ok(bpe) => bpe.encode_ordinary(content).len(),
+            Err(_) => content.chars().count().div_ceil(4),
         }
     }
 
-    fn get_tokens_heur_price(content: &str, toktype: TokenType, model_price :&ModelPricing) -> Result<f64, String> {
-
-        let token_count = ClauChatApp::token_count_heuristic(content)?;
+    fn tokens_price(token_count: usize, toktype: TokenType, model_price :&ModelPricing) -> f64 {
         debug!("Token count: {}", token_count);
         match toktype {
             TokenType::InputToken => {
-                Ok(model_price.input_cost_per_million * (token_count as f64 / 1000000.0))
+                model_price.input_cost_per_million * (token_count as f64 / 1000000.0)
             }
             TokenType::OutputToken => {
-                Ok(model_price.output_cost_per_million * (token_count as f64 / 1000000.0))
+                model_price.output_cost_per_million * (token_count as f64 / 1000000.0)
+            }
+        }
+    }
+
+    /// Estimated input cost of sending the draft: the conversation
+    /// and the draft, by the token heuristic. None without pricing
+    fn estimated_send_cost(&self) -> Option<f64> {
+        let pricing = find_model_pricing(Some(&self.pricing_data), &self.model)?;
+        let tokens = self.ui_state.conversation_tokens + self.ui_state.input_tokens.unwrap_or(0);
+        Some(Self::tokens_price(tokens, TokenType::InputToken, pricing))
+    }
+
+    /// Send the draft, or ask first when it's estimated over the cost threshold
+    fn request_send(&mut self, ctx: &Context) {
+        if self.config.confirm_duplicate_send && self.is_duplicate_send() {
+            debug!("The message just sent is sent again, asking first");
+            self.ui_state.confirm_duplicate_send = true;
+            return;
+        }
+        self.check_send_cost(ctx);
+    }
+
+    /// Whether the draft is the message sent a moment ago, with no answer
+    /// in between. Sending it again after an answer is left alone
+    fn is_duplicate_send(&self) -> bool {
+        let recent = self.last_send.as_ref().is_some_and(|(conversation, sent)| {
+            *conversation == self.active_conversation && sent.elapsed() < DUPLICATE_SEND_WINDOW
+        });
+        if !recent || self.is_sending() {
+            return false;
+        }
+        let Some(last_sent) = self
+            .messages
+            .iter()
+            .rposition(|message| message.role == Role::User && message.is_sent())
+        else {
+            return false;
+        };
+        let answered = self.messages[last_sent + 1..]
+            .iter()
+            .any(|message| message.role == Role::Assistant && message.is_sent());
+        let mut draft = Message::new(Role::User, self.input.clone());
+        draft.attachments = self.attachments.clone();
+        !answered && draft.api_content() == self.messages[last_sent].api_content()
+    }
+
+    /// Send the draft, asking first when it's estimated over the cost threshold
+    fn check_send_cost(&mut self, ctx: &Context) {
+        let threshold = self.config.cost_warning_threshold;
+        match self.estimated_send_cost() {
+            Some(cost) if threshold > 0.0 && cost > threshold && !self.is_sending() => {
+                debug!("Send estimated at ${:.2}, asking first", cost);
+                self.ui_state.confirm_send_cost = Some(cost);
             }
+            _ => self.send_message(ctx),
         }
     }
 
-    fn send_message(&mut self) {
-        if self.input.trim().is_empty() || self.is_sending {
+    fn send_message(&mut self, ctx: &Context) {
+        if (self.input.trim().is_empty() && self.attachments.is_empty()) || self.is_sending() {
+            return;
+        }
+        if self.is_archived() {
+            debug!("Not sending to an archived conversation");
             return;
         }
 
         let client = match &self.client {
-            Some(client) => client,
+            Some(client) => client.clone(),
             None => {
                 error!("API key not configured. Please add it in settings.");
-                self.error = Some("API key not configured. Please add it in settings.".to_string());
+                self.error = Some(tr("error.no_api_key").to_string());
                 return;
             }
         };
 
-        let user_message = Message {
-            role: Role::User,
-            content: self.input.clone(),
-        };
+        let context_start = self.context_cut().map_or(0, |cut| cut.start);
+        let mut user_message = Message::new(Role::User, self.input.clone());
+        user_message.attachments = std::mem::take(&mut self.attachments);
         self.messages.push(user_message);
+        self.last_send = Some((self.active_conversation.clone(), Instant::now()));
         self.error = None;
+        self.conversation_dirty = true;
+        self.persist_active_conversation();
 
         std::mem::take(&mut self.input);
-        self.is_sending = true;
-
-        // clone for async
-        let client = client.clone();
-        let messages = self.messages.clone();
+        self.refresh_conversation_tokens();
+        self.send_input_required().unwrap();
+        // only a message just sent can be taken back
+        let grace = Duration::from_secs(self.config.send_grace_secs);
+        self.request_response(ctx, client, context_start, None, grace);
+    }
 
-        let (tx, rx) = tokio_mpsc::channel::<AppMessageDelta>(100);
-        self.stream_receiver = Some(rx);
+    /// The messages of a request for `history`: the system prompt, what
+    /// falls inside the context and the prefill if there is one. Local
+    /// messages, e.g. the greeting, and failed responses are left out
+    fn request_messages(system_prompt: &str, history: &[Message], context_start: usize, prefill: &str) -> Vec<Message> {
+        let system_prompt = system_prompt.trim();
+        let mut messages: Vec<Message> = (!system_prompt.is_empty())
+            .then(|| Message::new(Role::System, system_prompt))
+            .into_iter()
+            .chain(
+                history
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, message)| *idx >= context_start || message.role == Role::System)
+                    .filter(|(_, message)| message.is_sent())
+                    .map(|(_, message)| message.clone()),
+            )
+            .collect();
+        if !prefill.is_empty() {
+            messages.push(Message::new(Role::Assistant, prefill));
+        }
+        messages
+    }
 
-        // message we are going to dump the string into
-        self.messages.push(Message {
-            role: Role::Assistant,
-            content: String::new(),
-        });
+    /// Show the request sending the draft would make and what it's
+    /// estimated to cost, without calling the API
+    fn preview_send(&mut self) {
+        if self.input.trim().is_empty() && self.attachments.is_empty() {
+            return;
+        }
+        let Some(client) = &self.client else {
+            self.error = Some(tr("error.no_api_key").to_string());
+            return;
+        };
 
-        self.runtime.spawn(async move {
-            let mut content_delta = AppMessageDelta::default();
-
-            match client.send_message_streaming(messages).await {
-                Ok(mut stream) => {
-                    while let Some(chunk_result) = stream.next().await {
-                        match chunk_result {
-                            Ok(buffer) => {
-                                content_delta.content.push_str(&buffer.content);
-                                content_delta.is_complete = buffer.is_complete;
-                                content_delta.usage = buffer.usage;
-                                let _ = tx.send(content_delta.clone()).await;
-                                if content_delta.is_complete {
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                content_delta.content = format!("{} {}", STREAM_ERROR_TOKEN, e);
-                                content_delta.is_complete = true;
-                                let _ = tx.send(content_delta).await;
-                                break;
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    content_delta.content = format!("{} {}", STREAM_ERROR_TOKEN, e);
-                    content_delta.is_complete = true;
-                    let _ = tx.send(content_delta).await;
-                }
+        let context_start = self.context_cut().map_or(0, |cut| cut.start);
+        let mut draft = Message::new(Role::User, self.input.clone());
+        draft.attachments = self.attachments.clone();
+        let mut history = self.messages.clone();
+        history.push(draft);
+        let system_prompt = &self.config.profile().system_prompt;
+        let messages = Self::request_messages(system_prompt, &history, context_start, self.prefill.trim_end());
+        let body = match client.request_body(&messages) {
+            Ok(body) => body,
+            Err(e) => {
+                self.error = Some(format!("{:#}", e));
+                return;
             }
+        };
+
+        let input_tokens = messages
+            .iter()
+            .map(|message| Self::token_count_heuristic(&message.api_content()))
+            .sum();
+        let pricing = find_model_pricing(Some(&self.pricing_data), client.model());
+        self.ui_state.request_preview = Some(ui::RequestPreview {
+            model: client.model().to_string(),
+            body,
+            input_tokens,
+            input_cost: pricing.map(|pricing| Self::tokens_price(input_tokens, TokenType::InputToken, pricing)),
+            max_output_cost: pricing
+                .map(|pricing| Self::tokens_price(MAX_OUTPUT_TOKENS as usize, TokenType::OutputToken, pricing)),
         });
+    }
 
+    /// Request the failed response at the end of the conversation again
+    fn retry_response(&mut self, ctx: &Context) {
+        if self.is_sending() || self.messages.last().is_none_or(|message| message.error.is_none()) {
+            return;
+        }
+        let Some(client) = self.client.clone() else {
+            self.error = Some(tr("error.no_api_key").to_string());
+            return;
+        };
 
-        // let (tx, rx) = mpsc::channel();
-        // self.response_receiver = Some(rx);
-        // self.runtime.spawn(async move {
-        //     match client.send_message(messages).await {
-        //         Ok(response) => {
-        //             debug!("Got some response");
-        //             let _ = tx.send(Ok(response));
-        //         }
-        //         Err(err) => {
-        //             let _ = tx.send(Err(err.to_string()));
-        //         }
-        //     }
-        // });
+        self.messages.pop();
+        self.pinned_messages.remove(&self.messages.len());
+        self.error = None;
+        let context_start = self.context_cut().map_or(0, |cut| cut.start);
+        self.request_response(ctx, client, context_start, None, Duration::ZERO);
+    }
 
+    /// Request the failed response again without the oldest messages, after
+    /// the prompt was over the model's context window
+    fn retry_trimmed(&mut self, ctx: &Context) {
+        if self.is_sending() || self.messages.last().is_none_or(|message| message.error.is_none()) {
+            return;
+        }
+        let Some(client) = self.client.clone() else {
+            self.error = Some(tr("error.no_api_key").to_string());
+            return;
+        };
+        let max_prompt_tokens = find_model_pricing(Some(&self.pricing_data), &self.model)
+            .map(|pricing| pricing.max_prompt_tokens)
+            .filter(|&max_prompt_tokens| max_prompt_tokens != usize::MAX);
+        let Some(max_prompt_tokens) = max_prompt_tokens else {
+            self.ui_state.push_toast(tr("error.trim_unknown_window"));
+            return;
+        };
 
+        // the failed response is the last message, it goes away
+        let history = &self.messages[..self.messages.len() - 1];
+        let count = Self::token_count_heuristic;
+        let tokens: Vec<usize> = history
+            .iter()
+            .map(|message| if message.is_sent() { count(&message.api_content()) } else { 0 })
+            .collect();
+        let reserved = MAX_OUTPUT_TOKENS as usize + count(&self.config.profile().system_prompt);
+        let budget = max_prompt_tokens.saturating_sub(reserved);
+        let Some(start) = conversation::fitting_context_start(history, &tokens, budget) else {
+            self.ui_state.push_toast(tr("error.trim_too_long"));
+            return;
+        };
+        let start = start.max(self.context_cut().map_or(0, |cut| cut.start));
+        let omitted = history[..start]
+            .iter()
+            .filter(|message| message.role != Role::System && message.is_sent())
+            .count();
+        debug!("Retrying without the {} oldest messages, {} tokens available", omitted, budget);
+
+        self.messages.pop();
+        self.pinned_messages.remove(&self.messages.len());
+        self.error = None;
+        self.ui_state.push_toast(tr_args("chat.trimmed_retry", &[&omitted]));
+        self.request_response(ctx, client, start, None, Duration::ZERO);
+        self.last_request_trimmed = true;
     }
 
-    fn save_config(&self) {
-        if let Err(err) = self.config.save() {
-            error!("Failed to save config: {}", err);
+    /// Replace the last response with a new one. The options change the
+    /// model or temperature of this request only
+    fn regenerate_response(&mut self, ctx: &Context, options: ui::RegenerateOptions) {
+        if self.is_sending() || self.messages.last().is_none_or(|message| message.role != Role::Assistant) {
+            return;
+        }
+        let Some(mut client) = self.client.clone() else {
+            self.error = Some(tr("error.no_api_key").to_string());
+            return;
+        };
+        if let Some(model) = &options.model {
+            client = client.with_model(model);
         }
+        if options.temperature.is_some() {
+            client = client.with_temperature(options.temperature);
+        }
+
+        self.messages.pop();
+        self.pinned_messages.remove(&self.messages.len());
+        self.error = None;
+        let context_start = self.context_cut().map_or(0, |cut| cut.start);
+        self.request_response(ctx, client, context_start, None, Duration::ZERO);
     }
 
-    fn update_api_key(&mut self, new_key: String) {
-        self.config.api_key = new_key;
-        if !self.config.api_key.is_empty() {
-            self.client = Some(AnthropicClient::new(&self.model, self.config.api_key.clone()));
-            self.error = None;
+    /// Continue a response cut off by a failure: what arrived is sent as a
+    /// prefill and the continuation lands in the same message
+    fn resume_response(&mut self, ctx: &Context) {
+        let resumable = self.messages.last().is_some_and(|message| {
+            message.role == Role::Assistant && message.error.is_some() && !message.content.trim().is_empty()
+        });
+        if self.is_sending() || !resumable {
+            return;
+        }
+        let Some(client) = self.client.clone() else {
+            self.error = Some(tr("error.no_api_key").to_string());
+            return;
+        };
+
+        // it goes back at the same index, so a pin stays on it
+        let Some(mut partial) = self.messages.pop() else {
+            return;
+        };
+        partial.error = None;
+        partial.error_kind = None;
+        self.error = None;
+        debug!("Resuming the response {:?}", partial.message_ids);
+        // the model that wrote the start writes the rest
+        let client = match &partial.model {
+            Some(model) => client.with_model(model),
+            None => client,
+        };
+        let context_start = self.context_cut().map_or(0, |cut| cut.start);
+        // nothing new to take back, it goes out right away
+        self.request_response(ctx, client, context_start, Some(partial), Duration::ZERO);
+    }
+
+    /// Stream the response to the conversation into a new assistant message,
+    /// or into `resumed` after its text. Messages before `context_start` are
+    /// left out, failed responses too. The request waits out `grace` first,
+    /// the message just sent can be taken back meanwhile
+    fn request_response(
+        &mut self,
+        ctx: &Context,
+        client: AnthropicClient,
+        context_start: usize,
+        resumed: Option<Message>,
+        grace: Duration,
+    ) {
+        // checked before anything changes, a conversation left streaming
+        // with no task behind it would stay in sending forever
+        let Some(runtime) = self.runtime.as_ref().map(|runtime| runtime.handle().clone()) else {
+            warn!("The runtime is shut down, the response isn't requested");
+            return;
+        };
+        self.ui_state.prompt_too_long = false;
+        self.last_request_trimmed = false;
+        // the API rejects a final assistant turn ending with whitespace
+        let prefill = match &resumed {
+            Some(partial) => partial.content.trim_end(),
+            None => self.prefill.trim_end(),
+        }
+        .to_string();
+        let messages =
+            Self::request_messages(&self.config.profile().system_prompt, &self.messages, context_start, &prefill);
+        let repaint_ctx = ctx.clone();
+        self.last_request = Some((client.clone(), messages.clone()));
+
+        let (tx, rx) = tokio_mpsc::channel::<AppMessageDelta>(100);
+        let cancel = CancellationToken::new();
+        let task_cancel = cancel.clone();
+        let stall_timeout = Duration::from_secs(self.config.stream_stall_timeout_secs.max(1));
+
+        // message we are going to dump the string into,
+        // the response continues the prefill so they share the bubble
+        let prefill_len = prefill.len();
+        let seam = resumed.is_some().then(String::new);
+        let mut streamed = resumed.unwrap_or_else(|| Message::new(Role::Assistant, ""));
+        streamed.content = prefill;
+        // set now so the tag shows while it streams, a fallback replaces it
+        streamed.model = Some(client.model().to_string());
+        self.messages.push(streamed);
+
+        let transcript_log = self.transcript_log.clone();
+        let request_slots = self.request_slots.clone();
+        let queued = Arc::new(AtomicBool::new(false));
+        let task_queued = queued.clone();
+        let model = client.model().to_string();
+        let fallback_models = self.config.fallback_models.clone();
+        let overloaded_retries = self.config.overloaded_retries;
+
+        let stream_task = async move {
+            let error_delta = |e: anyhow::Error| AppMessageDelta {
+                error_kind: Some(ErrorKind::of(&e)),
+                content: DeltaContent::Replace(format!("{} {}", STREAM_ERROR_TOKEN, e)),
+                usage: None,
+                is_complete: true,
+                model: None,
+                fallback_model: None,
+                rate_limits: None,
+                message_id: None,
+                citations: Vec::new(),
+            };
+            let opening = async {
+                tokio::time::sleep(grace).await;
+                // the slot is held until the response is over. The semaphore
+                // is never closed, so acquiring only waits
+                let slot = match request_slots.try_acquire() {
+                    Ok(slot) => Some(slot),
+                    Err(_) => {
+                        debug!("Every request slot is taken, waiting for one");
+                        task_queued.store(true, Ordering::Relaxed);
+                        repaint_ctx.request_repaint();
+                        let slot = request_slots.acquire().await.ok();
+                        task_queued.store(false, Ordering::Relaxed);
+                        slot
+                    }
+                };
+                let recorder = transcript_log.as_ref().map(|log| {
+                    log.record(LogRecord::request(&model, &messages));
+                    ResponseRecorder::new()
+                });
+                let opened = client
+                    .send_message_streaming_with_fallback(messages, &fallback_models, overloaded_retries, stall_timeout)
+                    .await;
+                (slot, recorder, opened)
+            };
+            // nothing was received before the response opens, a stop drops the request
+            let (_slot, mut recorder, opened) = tokio::select! {
+                opening = opening => opening,
+                _ = task_cancel.cancelled() => {
+                    debug!("Response stopped before it opened");
+                    let _ = tx
+                        .send(AppMessageDelta {
+                            is_complete: true,
+                            ..Default::default()
+                        })
+                        .await;
+                    repaint_ctx.request_repaint();
+                    return;
+                }
+            };
+            let mut failure = None;
+            let mut answering_model = model.clone();
+
+            match opened {
+                Ok(opened) => {
+                    let _ = tx
+                        .send(AppMessageDelta {
+                            model: (opened.model != model).then(|| opened.model.clone()),
+                            fallback_model: (opened.model != model).then(|| opened.model.clone()),
+                            rate_limits: Some(opened.rate_limits),
+                            ..Default::default()
+                        })
+                        .await;
+                    answering_model = opened.model;
+                    let mut stream = opened.events;
+                    let mut batch = DeltaBatch::default();
+                    let mut flush_deadline: Option<tokio::time::Instant> = None;
+
+                    loop {
+                        // wait for the next chunk, for the pending batch to be due, or for a stop
+                        let deadline = flush_deadline.unwrap_or_else(tokio::time::Instant::now);
+                        let next = tokio::select! {
+                            chunk = stream.next() => Some(chunk),
+                            _ = tokio::time::sleep_until(deadline), if flush_deadline.is_some() => None,
+                            _ = task_cancel.cancelled() => {
+                                // the text batched so far is kept with the rest
+                                debug!("Response stopped");
+                                let _ = tx.send(batch.take(true)).await;
+                                repaint_ctx.request_repaint();
+                                failure = Some("Stopped".to_string());
+                                break;
+                            }
+                        };
+
+                        match next {
+                            None => {
+                                let _ = tx.send(batch.take(false)).await;
+                                repaint_ctx.request_repaint();
+                                flush_deadline = None;
+                            }
+                            Some(Some(Ok(buffer))) => {
+                                if let Some(recorder) = &mut recorder {
+                                    recorder.push(&buffer);
+                                }
+                                let is_complete = buffer.is_complete;
+                                batch.push(buffer);
+                                if is_complete || batch.text.len() >= STREAM_FLUSH_BYTES {
+                                    let _ = tx.send(batch.take(is_complete)).await;
+                                    repaint_ctx.request_repaint();
+                                    flush_deadline = None;
+                                } else if flush_deadline.is_none() {
+                                    flush_deadline =
+                                        Some(tokio::time::Instant::now() + STREAM_FLUSH_INTERVAL);
+                                }
+                                if is_complete {
+                                    break;
+                                }
+                            }
+                            Some(Some(Err(e))) => {
+                                if !batch.is_empty() {
+                                    let _ = tx.send(batch.take(false)).await;
+                                }
+                                failure = Some(format!("{:#}", e));
+                                let _ = tx.send(error_delta(e)).await;
+                                repaint_ctx.request_repaint();
+                                break;
+                            }
+                            Some(None) => {
+                                // stream closed without a message_stop event
+                                let _ = tx.send(batch.take(true)).await;
+                                repaint_ctx.request_repaint();
+                                break;
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    // a 429 says when to try again
+                    if let Some(api_error) = e.downcast_ref::<ApiError>() {
+                        let _ = tx
+                            .send(AppMessageDelta {
+                                rate_limits: Some(api_error.rate_limits.clone()),
+                                ..Default::default()
+                            })
+                            .await;
+                    }
+                    failure = Some(format!("{:#}", e));
+                    let _ = tx.send(error_delta(e)).await;
+                    repaint_ctx.request_repaint();
+                }
+            }
+            if let (Some(log), Some(recorder)) = (&transcript_log, recorder) {
+                log.record(recorder.finish(&answering_model, failure));
+            }
+        };
+
+        // the request waits out the grace period, cancelling takes the message back
+        if let Some(conversation) = self.conversations.get_mut(&self.active_conversation) {
+            conversation.stream = Some(ResponseStream {
+                receiver: rx,
+                cancel: cancel.clone(),
+                prefill_len,
+                send_deadline: (!grace.is_zero()).then(|| Instant::now() + grace),
+                started: Instant::now() + grace,
+                queued,
+                seam,
+                seam_overlap: 0,
+                output_tokens: 0,
+            });
+        }
+
+        runtime.spawn(stream_task);
+
+
+        // let (tx, rx) = mpsc::channel();
+        // self.response_receiver = Some(rx);
+        // self.runtime.spawn(async move {
+        //     match client.send_message(messages).await {
+        //         Ok(response) => {
+        //             debug!("Got some response");
+        //             let _ = tx.send(Ok(response));
+        //         }
+        //         Err(err) => {
+        //             let _ = tx.send(Err(err.to_string()));
+        //         }
+        //     }
+        // });
+
+
+    }
+
+    /// Hold back the text shown of the active response while it streams, as
+    /// the settings ask. With reduce motion it changes once per second at most
+    fn update_revealed_response(&mut self, ctx: &egui::Context) {
+        let reveal = match self.config.response_reveal {
+            ResponseReveal::Live if self.config.reduce_motion => ResponseReveal::EverySecond,
+            reveal => reveal,
+        };
+        let streamed = self
+            .active_stream()
+            .and(self.messages.last())
+            .filter(|message| message.role == Role::Assistant && reveal != ResponseReveal::Live);
+        let Some(streamed) = streamed else {
+            self.ui_state.revealed_response = None;
+            self.response_revealed_at = None;
+            return;
+        };
+
+        if reveal == ResponseReveal::WhenComplete {
+            // nothing shown, the bubble keeps its typing indicator
+            self.ui_state.revealed_response = Some(String::new());
+            return;
+        }
+        let due = self
+            .response_revealed_at
+            .is_none_or(|revealed_at| revealed_at.elapsed() >= RESPONSE_REVEAL_INTERVAL);
+        if due {
+            self.ui_state.revealed_response = Some(streamed.content.clone());
+            self.response_revealed_at = Some(Instant::now());
+        }
+        ctx.request_repaint_after(RESPONSE_REVEAL_INTERVAL);
+    }
+
+    /// Response being streamed into the active conversation
+    fn active_stream(&self) -> Option<&ResponseStream> {
+        self.conversations.get(&self.active_conversation)?.stream.as_ref()
+    }
+
+    /// Whether the active conversation is waiting on a response,
+    /// others may be streaming in the background
+    fn is_sending(&self) -> bool {
+        self.active_stream().is_some()
+    }
+
+    /// Where the active conversation's request is at, for the status bar
+    fn request_phase(&self) -> ui::RequestPhase {
+        let last_message = self.messages.last();
+        if let Some(stream) = self.active_stream() {
+            if stream.is_queued() {
+                return ui::RequestPhase::Queued;
+            }
+            let elapsed = Instant::now().saturating_duration_since(stream.started);
+            let has_text = last_message.is_some_and(|message| {
+                message.role == Role::Assistant && message.content.len() > stream.prefill_len
+            });
+            return if has_text {
+                ui::RequestPhase::Streaming(elapsed)
+            } else {
+                ui::RequestPhase::Waiting(elapsed)
+            };
+        }
+        if self.error.is_some() || last_message.is_some_and(|message| message.error.is_some()) {
+            ui::RequestPhase::Error
+        } else {
+            ui::RequestPhase::Idle
+        }
+    }
+
+    /// Whether the last message is still in its grace period
+    fn send_pending(&self) -> bool {
+        self.active_stream()
+            .and_then(|stream| stream.send_deadline)
+            .is_some_and(|deadline| Instant::now() < deadline)
+    }
+
+    /// Take back a message still in its grace period: the request never
+    /// goes out, and the text and attachments return to the input, ahead
+    /// of whatever was typed meanwhile
+    fn cancel_pending_send(&mut self) {
+        if !self.send_pending() {
+            return;
+        }
+        self.abort_response();
+        // the empty reply placeholder, then the message itself
+        if self.messages.last().is_some_and(|message| message.role == Role::Assistant) {
+            self.messages.pop();
+        }
+        if let Some(message) = self.messages.pop_if(|message| message.role == Role::User) {
+            self.input = if self.input.is_empty() {
+                message.content
+            } else {
+                format!("{}\n{}", message.content, self.input)
+            };
+            let typed = std::mem::replace(&mut self.attachments, message.attachments);
+            self.attachments.extend(typed);
+        }
+        self.pinned_messages.retain(|&idx| idx < self.messages.len());
+        self.last_request = None;
+        // taken back, sending it again isn't a double send
+        self.last_send = None;
+        self.refresh_conversation_tokens();
+        self.send_input_required().unwrap();
+        self.conversation_dirty = true;
+        self.persist_active_conversation();
+    }
+
+    /// Stop the response being streamed, keeping the text received so far
+    fn stop_streaming(&mut self) {
+        if let Some(stream) = self.active_stream() {
+            stream.cancel.cancel();
+        }
+    }
+
+    /// Intercept a paste into the input that is over the configured size
+    /// and stage it as an attachment, leaving a placeholder in the input
+    fn take_large_paste(&mut self, ctx: &Context) {
+        if !ctx.memory(|memory| memory.has_focus(ui::input_id())) {
+            return;
+        }
+
+        let max_lines = self.config.paste_attachment_lines;
+        let max_chars = self.config.paste_attachment_chars;
+        let is_large = |text: &str| text.lines().count() > max_lines || text.chars().count() > max_chars;
+        let pasted = ctx.input_mut(|input| {
+            let idx = input.events.iter().position(|event| {
+                matches!(event, egui::Event::Paste(text) if is_large(text))
+            })?;
+            match input.events.remove(idx) {
+                egui::Event::Paste(text) => Some(text),
+                _ => None,
+            }
+        });
+
+        if let Some(text) = pasted {
+            self.pasted_count += 1;
+            let name = format!("pasted-text-{}.txt", self.pasted_count);
+            debug!("Moved a {} byte paste into {}", text.len(), name);
+            self.input.push_str(&attachment_placeholder(&name));
+            self.attachments.push(Attachment::new(name, text));
+            self.send_input_required().unwrap();
+        }
+    }
+
+    fn handle_attachment_action(&mut self, action: ui::AttachmentAction) {
+        match action {
+            ui::AttachmentAction::KeepInline(idx) => {
+                let attachment = self.attachments.remove(idx);
+                let placeholder = attachment_placeholder(&attachment.name);
+                if self.input.contains(&placeholder) {
+                    self.input = self.input.replacen(&placeholder, &attachment.content, 1);
+                } else {
+                    self.input.push_str(&attachment.content);
+                }
+            }
+            ui::AttachmentAction::Remove(idx) => {
+                let attachment = self.attachments.remove(idx);
+                self.input = self
+                    .input
+                    .replacen(&attachment_placeholder(&attachment.name), "", 1);
+            }
+        }
+        self.send_input_required().unwrap();
+    }
+
+    fn save_config(&mut self) {
+        self.config_dirty_since = None;
+        if let Err(err) = self.config.save() {
+            error!("Failed to save config: {}", err);
+            self.ui_state
+                .push_toast(tr_args("error.save_settings", &[&err]));
+        }
+    }
+
+    fn mark_config_dirty(&mut self) {
+        self.config_dirty_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Keep the window size in the layout as it's resized, a minimized or
+    /// fullscreen window keeps the size it had before
+    fn track_window_size(&mut self, ctx: &Context) {
+        let (rect, minimized, fullscreen) = ctx.input(|i| {
+            let viewport = i.viewport();
+            (viewport.inner_rect, viewport.minimized, viewport.fullscreen)
+        });
+        if minimized == Some(true) || fullscreen == Some(true) {
+            return;
+        }
+        let Some(rect) = rect else {
+            return;
+        };
+        let size = [rect.width(), rect.height()];
+        let known = self.config.ui_layout.window_size;
+        if (0..2).any(|axis| (size[axis] - known[axis]).abs() > 0.5) {
+            self.config.ui_layout.window_size = size;
+            self.mark_config_dirty();
+        }
+    }
+
+    /// Save the config if it has been dirty for long enough, or right away when `force` is set
+    fn flush_config(&mut self, ctx: &Context, force: bool) {
+        if let Some(dirty_since) = self.config_dirty_since {
+            let elapsed = dirty_since.elapsed();
+            if force || elapsed >= CONFIG_SAVE_DEBOUNCE {
+                self.save_config();
+            } else {
+                ctx.request_repaint_after(CONFIG_SAVE_DEBOUNCE - elapsed);
+            }
+        }
+    }
+
+    /// Start or stop the transcript log to follow the setting
+    fn sync_transcript_log(&mut self) {
+        if self.config.log_transcripts == self.transcript_log.is_some() {
+            return;
+        }
+        self.transcript_log = match (&self.runtime, self.config.log_transcripts) {
+            (Some(runtime), true) => TranscriptLog::start(
+                runtime,
+                self.config.transcript_log_max_bytes,
+                self.config.transcript_log_files,
+            )
+            .inspect_err(|e| {
+                error!("Could not start the transcript log: {:#}", e);
+                self.ui_state
+                    .push_toast(tr_args("error.transcript_log", &[&format!("{:#}", e)]));
+            })
+            .ok(),
+            _ => None,
+        };
+    }
+
+    fn sync_usage_log(&mut self) {
+        if self.config.log_usage == self.usage_log.is_some() {
+            return;
+        }
+        self.usage_log = match (&self.runtime, self.config.log_usage) {
+            (Some(runtime), true) => UsageLog::start(runtime)
+                .inspect_err(|e| {
+                    error!("Could not start the usage log: {:#}", e);
+                    self.ui_state
+                        .push_toast(tr_args("error.usage_log", &[&format!("{:#}", e)]));
+                })
+                .ok(),
+            _ => None,
+        };
+    }
+
+    /// Start, restart or stop the response mirror to follow the settings
+    fn sync_mirror(&mut self) {
+        let options = self.config.mirror_options();
+        if options.as_ref() == self.mirror.as_ref().map(ResponseMirror::options) {
+            return;
+        }
+        self.mirror = match (&self.runtime, options) {
+            (Some(runtime), Some(options)) => ResponseMirror::start(runtime, options)
+                .inspect_err(|e| {
+                    error!("Could not start the response mirror: {:#}", e);
+                    self.ui_state
+                        .push_toast(tr_args("error.mirror", &[&format!("{:#}", e)]));
+                })
+                .ok(),
+            _ => None,
+        };
+    }
+
+    fn open_log_folder(&mut self) {
+        let opened = TranscriptLog::dir().and_then(|dir| transcript::open_folder(&dir));
+        if let Err(e) = opened {
+            error!("Could not open the log folder: {:#}", e);
+            self.ui_state
+                .push_toast(tr_args("error.open_log_folder", &[&format!("{:#}", e)]));
+        }
+    }
+
+    /// Carry out a command picked in the palette
+    fn run_palette_action(&mut self, ctx: &Context, action: ui::PaletteAction) {
+        match action {
+            ui::PaletteAction::NewChat => self.new_conversation(),
+            ui::PaletteAction::StopGeneration => self.stop_streaming(),
+            ui::PaletteAction::OpenConversation(id) => self.open_conversation(id),
+            ui::PaletteAction::ClearChat if self.is_archived() => {
+                self.ui_state.push_toast(tr("archived.notice"));
+            }
+            ui::PaletteAction::ClearChat => self.clear_conversation(),
+            ui::PaletteAction::SummarizeConversation => self.summarize_conversation(ctx),
+            ui::PaletteAction::RevertSummary => self.revert_summary(),
+            ui::PaletteAction::ExportChat => match self.export_conversation() {
+                Ok(path) => self
+                    .ui_state
+                    .push_toast(tr_args("toast.exported", &[&path.display()])),
+                Err(e) => {
+                    error!("Could not export the conversation: {:#}", e);
+                    self.error = Some(tr_args("error.export", &[&format!("{:#}", e)]));
+                }
+            },
+            ui::PaletteAction::CopyConversation => {
+                ctx.copy_text(self.conversation_markdown());
+                self.ui_state.push_toast(tr("toast.conversation_copied"));
+            }
+            ui::PaletteAction::CopyCurl => {
+                let curl = match &self.last_request {
+                    Some((client, messages)) => client.curl_command(messages),
+                    None => Err(anyhow::anyhow!(tr("error.curl_nothing_sent"))),
+                };
+                match curl {
+                    Ok(curl) => {
+                        ctx.copy_text(curl);
+                        self.ui_state.push_toast(tr("toast.curl_copied"));
+                    }
+                    Err(e) => self.ui_state.push_toast(tr_args("error.curl", &[&format!("{:#}", e)])),
+                }
+            }
+            ui::PaletteAction::ToggleTheme => {
+                self.config.theme = if self.is_dark_theme(ctx) { Theme::Light } else { Theme::Dark };
+                self.mark_config_dirty();
+            }
+            ui::PaletteAction::SwitchModel if !self.is_sending() => {
+                let current = self.models.iter().position(|model| *model == self.model);
+                let next = current.map_or(0, |idx| (idx + 1) % self.models.len());
+                if let Some(model) = self.models.get(next).cloned() {
+                    self.ui_state.push_toast(tr_args("toast.model", &[&model]));
+                    self.set_model(model);
+                }
+            }
+            ui::PaletteAction::SwitchModel => {
+                self.ui_state.push_toast(tr("toast.model_busy"));
+            }
+            ui::PaletteAction::OpenSettings => self.open_settings(),
+            ui::PaletteAction::OpenBatch => self.ui_state.batch_open = true,
+        }
+    }
+
+    fn open_settings(&mut self) {
+        self.ui_state.settings_open = true;
+        self.ui_state.reset_api_key_field();
+    }
+
+    fn handle_profile_action(&mut self, action: ui::ProfileAction) {
+        if self.is_sending() {
+            self.ui_state.push_toast(tr("error.profile_busy"));
+            return;
+        }
+        match action {
+            ui::ProfileAction::Switch(name) => self.switch_profile(name),
+            ui::ProfileAction::Add(name) => {
+                let profile = Profile {
+                    api_key: String::new(),
+                    ..self.config.profile().clone()
+                };
+                self.config.profiles.insert(name.clone(), profile);
+                self.switch_profile(name);
+            }
+            ui::ProfileAction::Remove => {
+                if self.config.profiles.len() < 2 {
+                    return;
+                }
+                self.config.profiles.remove(&self.config.active_profile);
+                let Some(next) = self.config.profiles.keys().next().cloned() else {
+                    return;
+                };
+                self.switch_profile(next);
+            }
+        }
+    }
+
+    /// Use another profile's key, model and request settings
+    fn switch_profile(&mut self, name: String) {
+        if !self.config.profiles.contains_key(&name) {
+            return;
+        }
+        debug!("Switching to the {} profile", name);
+        self.config.active_profile = name;
+        self.ui_state.reset_api_key_field();
+        self.mark_config_dirty();
+
+        let model = match self.config.profile().model.as_str() {
+            "" => self.model.clone(),
+            model => model.to_string(),
+        };
+        if !self.models.contains(&model) {
+            self.models.insert(0, model.clone());
+        }
+        // rebuilds the client with the profile's key and reprices the estimates
+        self.set_model(model);
+    }
+
+    /// Use another model for the next requests and the estimates
+    fn set_model(&mut self, model: String) {
+        debug!("Switching model to {}", model);
+        if self.config.profile().model != model {
+            self.config.profile_mut().model = model.clone();
+            self.mark_config_dirty();
+        }
+        self.model = model;
+        self.rebuild_client();
+        self.send_model_pricing();
+        self.fetch_model_info();
+    }
+
+    /// Hand the current model and its pricing to the token thread and reprice the draft
+    fn send_model_pricing(&mut self) {
+        if let Some(sender) = &self.input_sender {
+            let pricing = find_model_pricing(Some(&self.pricing_data), &self.model).cloned();
+            if let Err(e) = sender.send(TokenJob::Model(self.model.clone(), pricing)) {
+                error!("Error sending model to processing thread: {}", e);
+            }
+        }
+        self.send_input_required().unwrap();
+    }
+
+    /// Switch to the fetched pricing once the background fetch delivers it
+    fn poll_pricing(&mut self) {
+        let Some(receiver) = &mut self.pricing_receiver else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(remote) => {
+                debug!("Fetched pricing for {} models", remote.len());
+                self.pricing_data = merge_pricing(Some(remote));
+                self.pricing_receiver = None;
+                self.send_model_pricing();
+            }
+            Err(oneshot::error::TryRecvError::Empty) => {}
+            // the fetch gave up, keep the built-in pricing
+            Err(oneshot::error::TryRecvError::Closed) => self.pricing_receiver = None,
+        }
+    }
+
+    /// Fetch the current model's metadata in the background, unless it's cached
+    fn fetch_model_info(&self) {
+        let (Some(client), Some(runtime)) = (&self.client, &self.runtime) else {
+            return;
+        };
+        if self.model_info.lock().unwrap().contains_key(&self.model) {
+            return;
+        }
+
+        let client = client.clone();
+        let model = self.model.clone();
+        let model_info = self.model_info.clone();
+        runtime.spawn(async move {
+            match client.get_model_info(&model).await {
+                Ok(info) => {
+                    model_info.lock().unwrap().insert(model, info);
+                }
+                Err(e) => debug!("Could not fetch model info for {}: {:#}", model, e),
+            }
+        });
+    }
+
+    /// Token and cost totals of the messages in the conversation
+    fn conversation_summary(&self) -> ui::ConversationSummary {
+        let mut summary = ui::ConversationSummary {
+            cost: Some(0.0),
+            ..Default::default()
+        };
+        for message in &self.messages {
+            if message.role == Role::User {
+                summary.turns += 1;
+            }
+            let Some(usage) = &message.usage else {
+                continue;
+            };
+            summary.input_tokens += usage.input_tokens as u64;
+            summary.output_tokens += usage.output_tokens as u64;
+            let model = message.model.as_deref().unwrap_or(&self.model);
+            summary.cost = summary.cost.zip(self.usage_as_cost(model, usage)).map(|(a, b)| a + b);
+        }
+        summary
+    }
+
+    fn toggle_pin(&mut self, idx: usize) {
+        if !self.pinned_messages.remove(&idx) {
+            self.pinned_messages.insert(idx);
+        }
+        self.conversation_dirty = true;
+        self.persist_active_conversation();
+    }
+
+    /// Highlight a code block of a message as another language, kept with
+    /// the conversation
+    fn set_code_language(&mut self, idx: usize, block: usize, language: Option<String>) {
+        let Some(message) = self.messages.get_mut(idx) else {
+            return;
+        };
+        match language {
+            Some(language) => message.code_languages.insert(block, language),
+            None => message.code_languages.remove(&block),
+        };
+        self.conversation_dirty = true;
+        self.persist_active_conversation();
+    }
+
+    /// Rate a response, or take its rating back with its note. The note is
+    /// kept when None
+    fn rate_message(&mut self, idx: usize, rating: Option<Rating>, note: Option<String>) {
+        let Some(message) = self.messages.get_mut(idx) else {
+            return;
+        };
+        message.rating = rating;
+        if let Some(note) = note {
+            message.rating_note = note;
+        }
+        if rating.is_none() {
+            message.rating_note.clear();
+        }
+        self.conversation_dirty = true;
+        self.persist_active_conversation();
+    }
+
+    fn voice_state(&self) -> ui::VoiceState {
+        match (&self.recorder, &self.transcription_receiver) {
+            (Some(recorder), _) => ui::VoiceState::Recording(recorder.elapsed()),
+            (None, Some(_)) => ui::VoiceState::Transcribing,
+            (None, None) => ui::VoiceState::Idle,
+        }
+    }
+
+    fn handle_voice_action(&mut self, ctx: &Context, action: ui::VoiceAction) {
+        match action {
+            ui::VoiceAction::Start => match voice::Recorder::start() {
+                Ok(recorder) => self.recorder = Some(recorder),
+                Err(e) => {
+                    error!("Could not start dictation: {:#}", e);
+                    self.error = Some(format!("{:#}", e));
+                }
+            },
+            ui::VoiceAction::Stop => {
+                let (Some(recorder), Some(runtime)) = (self.recorder.take(), &self.runtime) else {
+                    return;
+                };
+                let recording = recorder.stop();
+                let options = self.config.transcription_options();
+                let http = self.config.http_options();
+                let (tx, rx) = oneshot::channel();
+                let repaint_ctx = ctx.clone();
+                runtime.spawn(async move {
+                    let _ = tx.send(voice::transcribe(recording, options, http).await);
+                    repaint_ctx.request_repaint();
+                });
+                self.transcription_receiver = Some(rx);
+            }
+            ui::VoiceAction::Cancel => {
+                self.recorder = None;
+                self.transcription_receiver = None;
+            }
+        }
+    }
+
+    /// Put a finished transcription into the input
+    fn poll_transcription(&mut self, ctx: &Context) {
+        if self.recorder.is_some() {
+            // keep the elapsed time ticking
+            ctx.request_repaint_after(Duration::from_millis(250));
+        }
+        let Some(receiver) = &mut self.transcription_receiver else {
+            return;
+        };
+        let result = match receiver.try_recv() {
+            Ok(result) => result,
+            Err(oneshot::error::TryRecvError::Empty) => return,
+            Err(oneshot::error::TryRecvError::Closed) => Err(anyhow::anyhow!("Transcription was interrupted")),
+        };
+        self.transcription_receiver = None;
+
+        match result {
+            Ok(text) if !text.is_empty() => {
+                if !self.input.is_empty() && !self.input.ends_with(char::is_whitespace) {
+                    self.input.push(' ');
+                }
+                self.input.push_str(&text);
+                self.send_input_required().unwrap();
+            }
+            Ok(_) => self.ui_state.push_toast(tr("toast.no_speech")),
+            Err(e) => {
+                error!("Transcription failed: {:#}", e);
+                self.error = Some(tr_args("error.transcription", &[&format!("{:#}", e)]));
+            }
+        }
+    }
+
+    /// Ask the API again whether it takes the stored key, after a refusal
+    /// that may have been a glitch. The answer comes as a toast
+    fn recheck_api_key(&mut self, ctx: &Context) {
+        if self.key_check_receiver.is_some() {
+            return;
+        }
+        if self.client.is_none() {
+            self.rebuild_client();
+        }
+        let (Some(client), Some(runtime)) = (self.client.clone(), &self.runtime) else {
+            self.error = Some(tr("error.no_api_key").to_string());
+            return;
+        };
+        let (tx, rx) = oneshot::channel();
+        let repaint_ctx = ctx.clone();
+        runtime.spawn(async move {
+            let _ = tx.send(client.is_api_key_valid().await);
+            repaint_ctx.request_repaint();
+        });
+        self.key_check_receiver = Some(rx);
+    }
+
+    /// Say what the key check found once it's done
+    fn poll_key_check(&mut self) {
+        let Some(receiver) = &mut self.key_check_receiver else {
+            return;
+        };
+        let result = match receiver.try_recv() {
+            Ok(result) => result,
+            Err(oneshot::error::TryRecvError::Empty) => return,
+            Err(oneshot::error::TryRecvError::Closed) => Err(anyhow::anyhow!("Key check was interrupted")),
+        };
+        self.key_check_receiver = None;
+
+        match result {
+            Ok(true) => self.ui_state.push_toast(tr("toast.key_valid")),
+            Ok(false) => self.ui_state.push_toast(tr("toast.key_refused")),
+            Err(e) => {
+                warn!("Could not check the API key: {:#}", e);
+                self.ui_state.push_toast(tr_args("toast.key_check_failed", &[&format!("{:#}", e)]));
+            }
+        }
+    }
+
+    /// Ask the model for a summary of the conversation, to replace all
+    /// but the last turns once it arrives
+    fn summarize_conversation(&mut self, ctx: &Context) {
+        if self.is_sending() || self.summary_request.is_some() {
+            self.ui_state.push_toast(tr("toast.summarize_busy"));
+            return;
+        }
+        if self.is_archived() {
+            self.ui_state.push_toast(tr("archived.notice"));
+            return;
+        }
+        let (Some(client), Some(runtime)) = (self.client.clone(), &self.runtime) else {
+            self.error = Some(tr("error.no_api_key").to_string());
+            return;
+        };
+
+        // the kept part starts with a question
+        let mut split = self.messages.len().saturating_sub(SUMMARY_KEEP_MESSAGES);
+        while split > 0 && self.messages[split].role != Role::User {
+            split -= 1;
+        }
+        if !self.messages[..split].iter().any(|message| message.role == Role::User) {
+            self.ui_state.push_toast(tr("toast.summarize_too_short"));
+            return;
+        }
+
+        let mut messages: Vec<Message> =
+            self.messages[..split].iter().filter(|message| message.is_sent()).cloned().collect();
+        messages.push(Message::new(Role::User, SUMMARY_PROMPT));
+        let (tx, rx) = oneshot::channel();
+        let repaint_ctx = ctx.clone();
+        runtime.spawn(async move {
+            let result = async {
+                let mut stream = client.send_message_streaming(messages).await?.events;
+                let mut summary = String::new();
+                while let Some(buffer) = stream.next().await {
+                    match buffer?.content {
+                        DeltaContent::Append(fragment) => summary.push_str(&fragment),
+                        DeltaContent::Rewrite(text) | DeltaContent::Replace(text) => summary = text,
+                    }
+                }
+                anyhow::Ok(summary)
+            }
+            .await;
+            let _ = tx.send(result);
+            repaint_ctx.request_repaint();
+        });
+
+        self.summary_request = Some(SummaryRequest {
+            conversation: self.active_conversation.clone(),
+            split,
+            receiver: rx,
+        });
+        self.ui_state.push_toast(tr("toast.summarizing"));
+    }
+
+    /// Ask the title model for a short title from the conversation's first
+    /// question, on the runtime. Until it answers, or if it fails, the
+    /// sidebar shows the question shortened
+    fn request_title(&mut self, ctx: &Context, id: String) {
+        let (Some(client), Some(runtime)) = (&self.client, &self.runtime) else {
+            return;
+        };
+        let messages = if id == self.active_conversation {
+            &self.messages
+        } else {
+            match self.conversations.get(&id) {
+                Some(conversation) => &conversation.messages,
+                None => return,
+            }
+        };
+        let Some(question) = messages
+            .iter()
+            .find(|message| message.role == Role::User && message.is_sent())
+            .map(Message::api_content)
+        else {
+            return;
+        };
+        let question: String = question.chars().take(TITLE_SOURCE_MAX_CHARS).collect();
+        let messages = vec![Message::new(Role::User, format!("{}\n\n{}", TITLE_PROMPT, question))];
+        let client = client.clone().with_model(&self.config.title_model).with_temperature(None);
+        let sender = self.title_sender.clone();
+        let repaint_ctx = ctx.clone();
+        runtime.spawn(async move {
+            let result = async {
+                let mut stream = client.send_message_streaming(messages).await?.events;
+                let mut title = String::new();
+                let mut usage = ResponseUsage::default();
+                while let Some(buffer) = stream.next().await {
+                    let buffer = buffer?;
+                    if let Some(part) = &buffer.usage {
+                        usage.add(part);
+                    }
+                    match buffer.content {
+                        DeltaContent::Append(fragment) => title.push_str(&fragment),
+                        DeltaContent::Rewrite(text) | DeltaContent::Replace(text) => title = text,
+                    }
+                }
+                anyhow::Ok((title, usage))
+            }
+            .await;
+            let _ = sender.send((id, result));
+            repaint_ctx.request_repaint();
+        });
+    }
+
+    /// Name the conversations the title model wrote a title for, unless
+    /// they were renamed in the meantime. Its usage counts like any other
+    fn poll_titles(&mut self) {
+        while let Ok((id, result)) = self.title_receiver.try_recv() {
+            let (text, usage) = match result {
+                Ok(answer) => answer,
+                Err(e) => {
+                    warn!("Could not title the conversation {}: {:#}", id, e);
+                    continue;
+                }
+            };
+            let model = self.config.title_model.clone();
+            let cost = self.usage_as_cost(&model, &usage);
+            self.ui_state.total_input_tokens += usage.input_tokens as u64;
+            self.ui_state.total_output_tokens += usage.output_tokens as u64;
+            match cost {
+                Some(cost) => self.ui_state.total_cost += cost,
+                None => self.ui_state.total_cost_known = false,
+            }
+            if let Some(log) = &self.usage_log {
+                log.record(UsageRecord::new(&model, &id, &usage, cost));
+            }
+
+            let untitled = self.conversations.get(&id).is_some_and(|conversation| conversation.title.is_empty());
+            match conversation::clean_title(&text) {
+                Some(title) if untitled => {
+                    debug!("Titled {} \"{}\"", id, title);
+                    self.rename_conversation(id, title);
+                }
+                Some(_) => {}
+                None => warn!("The title model gave no title for {}", id),
+            }
+        }
+    }
+
+    /// Replace the turns before `split` with the summary once it's written.
+    /// The replaced turns are archived with the conversation
+    fn poll_summary(&mut self) {
+        let Some(request) = &mut self.summary_request else {
+            return;
+        };
+        let result = match request.receiver.try_recv() {
+            Ok(result) => result,
+            Err(oneshot::error::TryRecvError::Empty) => return,
+            Err(oneshot::error::TryRecvError::Closed) => Err(anyhow::anyhow!("Summary was interrupted")),
+        };
+        let Some(request) = self.summary_request.take() else {
+            return;
+        };
+
+        let summary = match result {
+            Ok(summary) if !summary.trim().is_empty() => summary,
+            Ok(_) => {
+                self.error = Some(tr_args("error.summarize", &[&EMPTY_RESPONSE_PLACEHOLDER]));
+                return;
+            }
+            Err(e) => {
+                error!("Could not summarize the conversation: {:#}", e);
+                self.error = Some(tr_args("error.summarize", &[&format!("{:#}", e)]));
+                return;
+            }
+        };
+        // the conversation was left or cleared in the meantime
+        if request.conversation != self.active_conversation || request.split > self.messages.len() {
+            debug!("Dropping the summary of {}", request.conversation);
+            return;
+        }
+
+        let split = request.split;
+        let replaced: Vec<Message> = self.messages.drain(..split).collect();
+        self.messages.insert(
+            0,
+            Message::new(Role::System, format!("{}\n\n{}", SUMMARY_HEADER, summary.trim())),
+        );
+        // pins of the replaced turns go with them
+        self.pinned_messages = self
+            .pinned_messages
+            .iter()
+            .filter(|&&idx| idx >= split)
+            .map(|&idx| idx - split + 1)
+            .collect();
+        if let Some(conversation) = self.conversations.get_mut(&self.active_conversation) {
+            // a previous summary is dropped, the turns it replaced are already archived
+            conversation
+                .summarized_turns
+                .extend(replaced.into_iter().filter(|message| message.role != Role::System));
+        }
+
+        self.refresh_conversation_tokens();
+        self.conversation_dirty = true;
+        self.persist_active_conversation();
+        self.ui_state.push_toast(tr("toast.summarized"));
+    }
+
+    /// Put the archived turns back in place of the summary
+    fn revert_summary(&mut self) {
+        let archived = self
+            .conversations
+            .get_mut(&self.active_conversation)
+            .map(|conversation| std::mem::take(&mut conversation.summarized_turns))
+            .unwrap_or_default();
+        if archived.is_empty() {
+            self.ui_state.push_toast(tr("toast.no_summary"));
+            return;
+        }
+
+        let mut pinned: BTreeSet<usize> = std::mem::take(&mut self.pinned_messages);
+        if self.messages.first().is_some_and(|message| message.role == Role::System) {
+            self.messages.remove(0);
+            pinned = pinned.into_iter().filter(|&idx| idx > 0).map(|idx| idx - 1).collect();
+        }
+        self.pinned_messages = pinned.into_iter().map(|idx| idx + archived.len()).collect();
+        self.messages.splice(0..0, archived);
+
+        self.refresh_conversation_tokens();
+        self.conversation_dirty = true;
+        self.persist_active_conversation();
+        self.ui_state.push_toast(tr("toast.summary_reverted"));
+    }
+
+    /// Height of the input box: grows with the draft's lines, up to a cap
+    /// that leaves the transcript at least MIN_CHAT_HEIGHT
+    fn input_height(&self, ui: &egui::Ui) -> f32 {
+        let available = ui.available_height();
+        let cap = (available * INPUT_MAX_FRACTION)
+            .min(available - MIN_CHAT_HEIGHT)
+            .max(MIN_INPUT_HEIGHT);
+        let row_height = ui.text_style_height(&egui::TextStyle::Body);
+        let rows = self.input.lines().count().max(1) + 1;
+        (rows as f32 * row_height + 16.0).clamp(MIN_INPUT_HEIGHT, cap)
+    }
+
+    /// Put a message into the input to rework and send back, replacing
+    /// the draft or after it
+    fn use_as_input(&mut self, ctx: &Context, idx: usize, append: bool) {
+        let Some(message) = self.messages.get(idx) else {
+            return;
+        };
+        let content = message.content.trim().to_string();
+        if append && !self.input.trim().is_empty() {
+            self.input.truncate(self.input.trim_end().len());
+            self.input.push_str("\n\n");
+            self.input.push_str(&content);
+        } else {
+            self.input = content;
+        }
+        ctx.memory_mut(|memory| memory.request_focus(ui::input_id()));
+        self.send_input_required().unwrap();
+    }
+
+    /// Add text to the input as a Markdown blockquote, to ask about it
+    fn quote_in_input(&mut self, ctx: &Context, text: &str) {
+        let quote = text
+            .trim()
+            .lines()
+            .map(|line| format!("> {}", line).trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !self.input.trim().is_empty() {
+            self.input.truncate(self.input.trim_end().len());
+            self.input.push_str("\n\n");
+        } else {
+            self.input.clear();
+        }
+        self.input.push_str(&quote);
+        self.input.push_str("\n\n");
+        ctx.memory_mut(|memory| memory.request_focus(ui::input_id()));
+        self.send_input_required().unwrap();
+    }
+
+    /// Read a message aloud, interrupting the one being read
+    fn read_aloud(&self, idx: usize) {
+        let (Some(speaker), Some(message)) = (&self.speaker, self.messages.get(idx)) else {
+            return;
+        };
+        speaker.speak(idx, ChatRenderer::spoken_text(&message.content));
+    }
+
+    /// Stop a response in flight and the message being read,
+    /// before the transcript is replaced
+    fn abort_response(&mut self) {
+        self.stop_streaming();
+        if let Some(speaker) = &self.speaker {
+            speaker.stop();
+        }
+        if let Some(conversation) = self.conversations.get_mut(&self.active_conversation) {
+            conversation.stream = None;
+        }
+    }
+
+    /// Start over from the greeting, stopping a response in flight
+    fn clear_conversation(&mut self) {
+        self.abort_response();
+        self.summary_request = None;
+        self.messages = vec![Message::local(Role::Assistant, GREETING)];
+        self.pinned_messages.clear();
+        if let Some(conversation) = self.conversations.get_mut(&self.active_conversation) {
+            conversation.summarized_turns.clear();
+        }
+        self.error = None;
+        self.refresh_conversation_tokens();
+        self.conversation_dirty = true;
+        self.persist_active_conversation();
+    }
+
+    /// Copy the transcript into the active conversation's record
+    fn sync_active_conversation(&mut self) {
+        if let Some(conversation) = self.conversations.get_mut(&self.active_conversation) {
+            conversation.messages = self.messages.clone();
+            conversation.pinned = self.pinned_messages.clone();
+            conversation.draft = self.input.clone();
+        }
+    }
+
+    fn save_conversation(&self, conversation: &Conversation) -> anyhow::Result<()> {
+        match &self.conversation_store {
+            Some(store) => store.save(conversation),
+            None => Ok(()),
+        }
+    }
+
+    /// Save the active conversation if it changed. A blank one is only
+    /// written once it has a file, so fresh chats don't pile up on disk
+    fn persist_active_conversation(&mut self) {
+        if !std::mem::take(&mut self.conversation_dirty) {
+            return;
+        }
+        self.sync_active_conversation();
+        let Some(conversation) = self.conversations.get_mut(&self.active_conversation) else {
+            return;
+        };
+        conversation.updated_at = conversation::now_secs();
+        if conversation.title.is_empty() {
+            conversation.title = conversation.default_title().unwrap_or_default();
+        }
+        let is_saved = self
+            .conversation_store
+            .as_ref()
+            .is_some_and(|store| store.contains(&conversation.id));
+        if conversation.is_blank() && !is_saved {
+            return;
+        }
+
+        let conversation = conversation.clone();
+        if let Err(e) = self.save_conversation(&conversation) {
+            error!("Could not save the conversation: {:#}", e);
+            self.ui_state
+                .push_toast(tr_args("error.save_conversation", &[&format!("{:#}", e)]));
+        }
+    }
+
+    /// Save the active conversation before another one is shown. Its
+    /// response keeps streaming in the background
+    fn leave_active_conversation(&mut self) {
+        // a message still in its grace period goes back to the draft
+        self.cancel_pending_send();
+        if self.is_sending() {
+            // what arrived so far is saved, the rest when the response is over
+            self.conversation_dirty = true;
+        }
+        self.persist_active_conversation();
+        self.flush_draft(None, true);
+    }
+
+    /// Stop every response, keeping what arrived, and save their conversations
+    /// Let in-flight responses wind down instead of dropping the runtime
+    /// under tasks that still hold their sender
+    fn shut_down_runtime(&mut self) {
+        self.abort_all_responses();
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+        }
+    }
+
+    fn abort_all_responses(&mut self) {
+        let streaming: Vec<String> = self
+            .conversations
+            .values()
+            .filter(|conversation| conversation.stream.is_some())
+            .map(|conversation| conversation.id.clone())
+            .collect();
+        for id in streaming {
+            let Some(conversation) = self.conversations.get_mut(&id) else {
+                continue;
+            };
+            if let Some(stream) = conversation.stream.take() {
+                stream.cancel.cancel();
+            }
+            // an empty bubble is dropped
+            Self::finish_empty_response(&mut conversation.messages, &mut conversation.pinned, true);
+            self.save_background_conversation(&id);
+        }
+    }
+
+    /// Show a conversation from the map, dropping the current transcript
+    fn show_conversation(&mut self, id: String) {
+        let Some(conversation) = self.conversations.get(&id) else {
+            return;
+        };
+        self.ui_state.prompt_too_long = false;
+        self.messages = conversation.messages.clone();
+        self.pinned_messages = conversation.pinned.clone();
+        self.input = conversation.draft.clone();
+        // the message being read belongs to the transcript being replaced
+        if let Some(speaker) = &self.speaker {
+            speaker.stop();
+        }
+        self.active_conversation = id;
+        self.conversation_dirty = false;
+        self.last_request = None;
+        self.error = None;
+        self.refresh_conversation_tokens();
+        self.send_input_required().unwrap();
+        self.draft_dirty_since = None;
+    }
+
+    /// Save the active conversation's draft once typing has paused for
+    /// long enough, or right away when `force` is set. A blank conversation
+    /// without a file only gets one for a non-empty draft
+    fn flush_draft(&mut self, ctx: Option<&Context>, force: bool) {
+        let Some(dirty_since) = self.draft_dirty_since else {
+            return;
+        };
+        let elapsed = dirty_since.elapsed();
+        if !force && elapsed < DRAFT_SAVE_DEBOUNCE {
+            if let Some(ctx) = ctx {
+                ctx.request_repaint_after(DRAFT_SAVE_DEBOUNCE - elapsed);
+            }
+            return;
+        }
+        self.draft_dirty_since = None;
+
+        self.sync_active_conversation();
+        let Some(conversation) = self.conversations.get(&self.active_conversation) else {
+            return;
+        };
+        let is_saved = self
+            .conversation_store
+            .as_ref()
+            .is_some_and(|store| store.contains(&conversation.id));
+        if conversation.is_blank() && !is_saved && conversation.draft.is_empty() {
+            return;
+        }
+        if let Err(e) = self.save_conversation(conversation) {
+            error!("Could not save the draft: {:#}", e);
+        }
+    }
+
+    /// Add an empty conversation starting with the greeting, returns its id
+    fn insert_blank_conversation(&mut self) -> String {
+        let id = conversation::new_id(|id| self.conversations.contains_key(id));
+        let blank = Conversation::new(id.clone(), vec![Message::local(Role::Assistant, GREETING)]);
+        self.conversations.insert(id.clone(), blank);
+        id
+    }
+
+    /// Save the current conversation and switch to another one
+    fn open_conversation(&mut self, id: String) {
+        if id == self.active_conversation || !self.conversations.contains_key(&id) {
+            return;
+        }
+        self.leave_active_conversation();
+        self.show_conversation(id);
+    }
+
+    /// Switch to a fresh conversation with an empty composer
+    fn new_conversation(&mut self) {
+        self.leave_active_conversation();
+        let id = self.insert_blank_conversation();
+        self.show_conversation(id);
+        self.prefill.clear();
+        self.attachments.clear();
+        self.send_input_required().unwrap();
+    }
+
+    /// Give a conversation a new title, kept in memory only once it's saved
+    fn rename_conversation(&mut self, id: String, title: String) {
+        let title = title.trim();
+        if title.is_empty() {
+            return;
+        }
+        if id == self.active_conversation {
+            self.sync_active_conversation();
+        }
+        let Some(mut renamed) = self.conversations.get(&id).cloned() else {
+            return;
+        };
+        renamed.title = title.to_string();
+
+        match self.save_conversation(&renamed) {
+            Ok(()) => {
+                // the copy has no stream, the title is set on the original
+                if let Some(conversation) = self.conversations.get_mut(&id) {
+                    conversation.title = renamed.title;
+                }
+            }
+            Err(e) => {
+                error!("Could not rename the conversation: {:#}", e);
+                self.ui_state
+                    .push_toast(tr_args("error.save_conversation", &[&format!("{:#}", e)]));
+            }
+        }
+    }
+
+    /// Whether the active conversation is archived, so read only
+    fn is_archived(&self) -> bool {
+        self.conversations
+            .get(&self.active_conversation)
+            .is_some_and(|conversation| conversation.archived)
+    }
+
+    /// Archive a conversation or bring it back, saved right away
+    fn set_archived(&mut self, id: String, archived: bool) {
+        if id == self.active_conversation {
+            self.sync_active_conversation();
+        }
+        let Some(mut changed) = self.conversations.get(&id).cloned() else {
+            return;
+        };
+        changed.archived = archived;
+
+        match self.save_conversation(&changed) {
+            Ok(()) => {
+                if let Some(conversation) = self.conversations.get_mut(&id) {
+                    conversation.archived = archived;
+                }
+            }
+            Err(e) => {
+                error!("Could not save the conversation: {:#}", e);
+                self.ui_state
+                    .push_toast(tr_args("error.save_conversation", &[&format!("{:#}", e)]));
+            }
+        }
+    }
+
+    /// Save a copy of a conversation under a new id, added to the
+    /// sidebar only once its file is written
+    fn duplicate_conversation(&mut self, id: String) {
+        if id == self.active_conversation {
+            self.sync_active_conversation();
+        }
+        let Some(original) = self.conversations.get(&id) else {
+            return;
+        };
+        let title = if original.title.is_empty() {
+            original.default_title().unwrap_or_else(|| tr("sidebar.untitled").to_string())
         } else {
-            self.client = None;
+            original.title.clone()
+        };
+        let mut copy = original.clone();
+        copy.id = conversation::new_id(|id| self.conversations.contains_key(id));
+        copy.title = tr_args("conversation.copy_title", &[&title]);
+        copy.updated_at = conversation::now_secs();
+        // a copy is a way to carry on from an archived one
+        copy.archived = false;
+
+        match self.save_conversation(&copy) {
+            Ok(()) => {
+                self.conversations.insert(copy.id.clone(), copy);
+            }
+            Err(e) => {
+                error!("Could not duplicate the conversation: {:#}", e);
+                self.ui_state
+                    .push_toast(tr_args("error.save_conversation", &[&format!("{:#}", e)]));
+            }
         }
-        self.save_config();
+    }
+
+    /// Delete a conversation and its file. The active one is replaced by
+    /// the most recent remaining conversation, or a fresh chat
+    fn delete_conversation(&mut self, id: String) {
+        let deleted = match &self.conversation_store {
+            Some(store) => store.delete(&id),
+            None => Ok(()),
+        };
+        if let Err(e) = deleted {
+            error!("Could not delete the conversation: {:#}", e);
+            self.ui_state
+                .push_toast(tr_args("error.delete_conversation", &[&format!("{:#}", e)]));
+            return;
+        }
+        let stream = self.conversations.remove(&id).and_then(|conversation| conversation.stream);
+        if let Some(stream) = stream {
+            stream.cancel.cancel();
+        }
+
+        if id == self.active_conversation {
+            let next = match self.conversations.values().max_by_key(|c| c.updated_at) {
+                Some(latest) => latest.id.clone(),
+                None => self.insert_blank_conversation(),
+            };
+            self.show_conversation(next);
+        }
+    }
+
+    /// Conversations as the sidebar lists them, most recently changed first
+    fn conversations_by_recency(conversations: &HashMap<String, Conversation>) -> Vec<&Conversation> {
+        let mut conversations: Vec<&Conversation> = conversations.values().collect();
+        conversations.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| b.id.cmp(&a.id)));
+        conversations
+    }
+
+    /// Every tag in use, for completion and the sidebar filter
+    fn known_tags(&self) -> BTreeSet<String> {
+        self.conversations
+            .values()
+            .flat_map(|conversation| conversation.tags.iter().cloned())
+            .collect()
+    }
+
+    fn handle_tag_action(&mut self, action: ui::TagAction) {
+        let Some(conversation) = self.conversations.get_mut(&self.active_conversation) else {
+            return;
+        };
+        match action {
+            ui::TagAction::Add(tag) => {
+                let tag = conversation::normalize_tag(&tag);
+                if tag.is_empty() || conversation.tags.contains(&tag) {
+                    return;
+                }
+                conversation.tags.push(tag);
+            }
+            ui::TagAction::Remove(tag) => conversation.tags.retain(|existing| *existing != tag),
+        }
+        self.conversation_dirty = true;
+        self.persist_active_conversation();
+    }
+
+    fn handle_conversation_action(&mut self, action: ui::ConversationAction) {
+        match action {
+            ui::ConversationAction::New => self.new_conversation(),
+            ui::ConversationAction::Open(id) => self.open_conversation(id),
+            ui::ConversationAction::Rename(id, title) => self.rename_conversation(id, title),
+            ui::ConversationAction::Duplicate(id) => self.duplicate_conversation(id),
+            ui::ConversationAction::SetArchived(id, archived) => self.set_archived(id, archived),
+            ui::ConversationAction::Delete(id) => self.delete_conversation(id),
+        }
+    }
+
+    /// The conversation as Markdown, for copying and exporting
+    /// Citations are written as footnotes, numbered across the conversation
+    fn conversation_markdown(&self) -> String {
+        let mut footnotes = 0;
+        self.messages
+            .iter()
+            .map(|message| {
+                let speaker = match (&message.role, &message.model) {
+                    (Role::User, _) => "You".to_string(),
+                    (Role::Assistant, Some(model)) => format!("Claude ({})", model),
+                    (Role::Assistant, None) => "Claude".to_string(),
+                    (Role::System, _) => "System".to_string(),
+                };
+                let first = footnotes + 1;
+                footnotes += message.citations.len();
+                let mut content =
+                    citation::with_markers(&message.api_content(), &message.citations, |number| {
+                        format!("[^{}]", first + number - 1)
+                    })
+                    .into_owned();
+                for (idx, cited) in message.citations.iter().enumerate() {
+                    content.push_str(&format!(
+                        "\n\n[^{}]: {}: \u{201C}{}\u{201D}",
+                        first + idx,
+                        cited.document_title.as_deref().unwrap_or(tr("citation.untitled")),
+                        cited.cited_text.split_whitespace().collect::<Vec<_>>().join(" ")
+                    ));
+                }
+                let rating = match message.rating {
+                    Some(Rating::Good) => "good",
+                    Some(Rating::Bad) => "bad",
+                    None => return format!("**{}:**\n\n{}", speaker, content),
+                };
+                let note = match message.rating_note.as_str() {
+                    "" => String::new(),
+                    note => format!(": {}", note.split_whitespace().collect::<Vec<_>>().join(" ")),
+                };
+                format!("**{}:**\n\n{}\n\n> Rated {}{}", speaker, content, rating, note)
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n---\n\n")
+    }
+
+    /// Open the side popup on a citation of a message, with the passage
+    /// in the attachment it quotes
+    fn show_citation(&mut self, idx: usize, citation: usize) {
+        let Some(cited) = self.messages.get(idx).and_then(|message| message.citations.get(citation)) else {
+            return;
+        };
+        let document = citation::cited_document(&self.messages, idx, cited);
+        let (before, quoted, after) = citation::excerpt(document.map(|attachment| attachment.content.as_str()), cited);
+        self.ui_state.citation_view = Some(ui::CitationView {
+            number: citation + 1,
+            title: cited.document_title.clone().unwrap_or_else(|| tr("citation.untitled").to_string()),
+            before,
+            quoted,
+            after,
+        });
+    }
+
+    fn handle_batch_action(&mut self, ctx: &Context, action: ui::BatchAction) {
+        match action {
+            ui::BatchAction::Submit => self.submit_batch(ctx),
+            ui::BatchAction::OpenAsChat(idx) => self.open_batch_row(idx),
+            ui::BatchAction::ExportCsv => match self.export_batch() {
+                Ok(path) => self
+                    .ui_state
+                    .push_toast(tr_args("toast.exported", &[&path.display()])),
+                Err(e) => {
+                    error!("Could not export the batch: {:#}", e);
+                    self.ui_state.push_toast(tr_args("error.batch_export", &[&format!("{:#}", e)]));
+                }
+            },
+            ui::BatchAction::Clear => {
+                self.batch = None;
+                self.ui_state.batch_selected.clear();
+            }
+        }
+    }
+
+    /// Send the template over each input of the batch panel as one batch
+    fn submit_batch(&mut self, ctx: &Context) {
+        if self.batch.is_some() {
+            return;
+        }
+        let (Some(client), Some(runtime)) = (self.client.clone(), &self.runtime) else {
+            self.ui_state.push_toast(tr("error.no_api_key"));
+            return;
+        };
+        let inputs = batch::parse_inputs(&self.ui_state.batch_inputs);
+        if inputs.is_empty() {
+            return;
+        }
+        debug!("Submitting a batch of {} requests", inputs.len());
+        self.batch = Some(BatchJob::submit(
+            runtime,
+            client,
+            ctx,
+            &self.config.profile().system_prompt,
+            &self.ui_state.batch_template,
+            inputs,
+        ));
+        self.ui_state.batch_selected.clear();
+    }
+
+    /// Take in the batch's progress. Answered rows are priced at the batch
+    /// discount and counted in the session totals and the usage log
+    fn poll_batch(&mut self) {
+        let Some(job) = &mut self.batch else {
+            return;
+        };
+        for idx in job.poll() {
+            let row = &mut job.rows[idx];
+            let RowStatus::Succeeded { model, usage, .. } = &row.status else {
+                continue;
+            };
+            let cost = find_model_pricing(Some(&self.pricing_data), model)
+                .map(|pricing| pricing.usage_cost(usage) * batch::BATCH_PRICE_FACTOR);
+            row.cost = cost;
+            self.ui_state.total_input_tokens += usage.input_tokens as u64;
+            self.ui_state.total_output_tokens += usage.output_tokens as u64;
+            match cost {
+                Some(cost) => self.ui_state.total_cost += cost,
+                None => self.ui_state.total_cost_known = false,
+            }
+            if let Some(log) = &self.usage_log {
+                log.record(UsageRecord::new(model, job.id.as_deref().unwrap_or_default(), usage, cost));
+            }
+        }
+    }
+
+    /// Add the text files dropped on the app to the batch inputs
+    fn take_batch_drop(&mut self, ctx: &Context) {
+        let dropped = ctx.input_mut(|input| std::mem::take(&mut input.raw.dropped_files));
+        for file in dropped {
+            let text = match (&file.path, &file.bytes) {
+                (Some(path), _) => std::fs::read_to_string(path)
+                    .with_context(|| format!("Could not read {}", path.display())),
+                (None, Some(bytes)) => String::from_utf8(bytes.to_vec()).context("Not a text file"),
+                (None, None) => continue,
+            };
+            match text {
+                Ok(text) => {
+                    if !self.ui_state.batch_inputs.is_empty() && !self.ui_state.batch_inputs.ends_with('\n') {
+                        self.ui_state.batch_inputs.push('\n');
+                    }
+                    self.ui_state.batch_inputs.push_str(&text);
+                }
+                Err(e) => {
+                    warn!("Could not load batch inputs: {:#}", e);
+                    self.ui_state.push_toast(tr_args("error.batch_load", &[&format!("{:#}", e)]));
+                }
+            }
+        }
+    }
+
+    /// Open an answered batch row as a new conversation, to carry on from it
+    fn open_batch_row(&mut self, idx: usize) {
+        let Some(row) = self.batch.as_ref().and_then(|job| job.rows.get(idx)) else {
+            return;
+        };
+        let RowStatus::Succeeded { text, model, usage } = &row.status else {
+            return;
+        };
+        let mut answer = Message::new(Role::Assistant, text.clone());
+        answer.usage = Some(usage.clone());
+        answer.model = Some(model.clone());
+        let id = conversation::new_id(|id| self.conversations.contains_key(id));
+        let conversation = Conversation::new(id.clone(), vec![Message::new(Role::User, row.prompt.clone()), answer]);
+
+        if let Err(e) = self.save_conversation(&conversation) {
+            error!("Could not save the batch conversation: {:#}", e);
+            self.ui_state
+                .push_toast(tr_args("error.save_conversation", &[&format!("{:#}", e)]));
+            return;
+        }
+        self.conversations.insert(id.clone(), conversation);
+        self.open_conversation(id);
+    }
+
+    /// Write the picked batch rows, or all of them, to a CSV file in the
+    /// exports directory
+    fn export_batch(&self) -> anyhow::Result<std::path::PathBuf> {
+        let Some(job) = &self.batch else {
+            anyhow::bail!("No batch to export");
+        };
+        let csv = if self.ui_state.batch_selected.is_empty() {
+            job.to_csv(0..job.rows.len())
+        } else {
+            job.to_csv(self.ui_state.batch_selected.iter().copied())
+        };
+        let dir = Config::exports_dir()?;
+        let path = dir.join(format!("batch-{}.csv", conversation::now_secs()));
+        std::fs::write(&path, csv).with_context(|| format!("Could not write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Write the conversation to a Markdown file in the exports directory
+    fn export_conversation(&self) -> anyhow::Result<std::path::PathBuf> {
+        let dir = Config::exports_dir()?;
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let path = dir.join(format!("chat-{}.md", stamp));
+        std::fs::write(&path, self.conversation_markdown())
+            .with_context(|| format!("Could not write {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// (Re)create the API client from the current key and network settings
+    fn rebuild_client(&mut self) {
+        self.client = None;
+        let profile = self.config.profile();
+        if profile.api_key.is_empty() {
+            return;
+        }
+
+        match AnthropicClient::new(&self.model, profile.api_key.clone(), &self.config.http_options()) {
+            Ok(client) => {
+                self.client = Some(
+                    client
+                        .with_base_url(self.config.api_base_url())
+                        .with_beta_flags(&self.config.beta_flags)
+                        .with_extra_headers(&self.config.extra_headers)
+                        .with_temperature(profile.temperature),
+                );
+                self.error = None;
+            }
+            Err(e) => {
+                error!("Could not create API client: {:#}", e);
+                self.error = Some(tr_args("error.client", &[&format!("{:#}", e)]));
+            }
+        }
+    }
+
+    fn update_api_key(&mut self, new_key: String) {
+        self.config.profile_mut().api_key = new_key;
+        self.rebuild_client();
+        self.mark_config_dirty();
     }
 
     fn apply_font_size(&self, ctx:&Context) {
@@ -364,28 +3019,128 @@ impl ClauChatApp {
         });
         ctx.set_style(style) ;
     }
+
+    /// Whether the dark visuals apply, asking the OS when the theme follows it
+    fn is_dark_theme(&self, ctx: &Context) -> bool {
+        match self.config.theme {
+            Theme::Dark => true,
+            Theme::Light => false,
+            Theme::System => ctx.system_theme().is_none_or(|theme| theme == egui::Theme::Dark),
+        }
+    }
+
+    /// Turn off egui's built-in animations when reduced motion is requested
+    fn apply_motion(&self, ctx: &Context) {
+        let animation_time = if self.config.reduce_motion {
+            0.0
+        } else {
+            egui::Style::default().animation_time
+        };
+        ctx.style_mut(|style| style.animation_time = animation_time);
+    }
 }
 
 impl eframe::App for ClauChatApp {
     fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame){
-        match self.config.theme {
-            Theme::Dark => {
-                ctx.set_visuals(Visuals::dark());
+        // the OS theme is re-read every frame, so a change there shows right away
+        if self.is_dark_theme(ctx) {
+            ctx.set_visuals(Visuals::dark());
+        } else {
+            ctx.set_visuals(Visuals::light());
+        }
+
+
+        self.track_window_size(ctx);
+        self.poll_pricing();
+        self.poll_transcription(ctx);
+        self.poll_key_check();
+        self.ui_state.checking_key = self.key_check_receiver.is_some();
+        self.poll_summary();
+        self.poll_batch();
+        self.ui_state.tts_available = self.speaker.is_some();
+        self.ui_state.speaking_message = self.speaker.as_ref().and_then(Speaker::speaking);
+        if self.ui_state.speaking_message.is_some() {
+            // notice when playback ends
+            ctx.request_repaint_after(Duration::from_millis(250));
+        }
+
+        let estimates = self.token_estimates.lock().unwrap();
+        self.ui_state.input_tokens = estimates.input_tokens;
+        self.ui_state.attachment_tokens.clone_from(&estimates.attachment_tokens);
+        self.ui_state.conversation_tokens = estimates.conversation_tokens;
+        match &estimates.input_cost {
+            Some(Ok(input_cost)) => {
+                self.ui_state.input_cost_display = Some(*input_cost);
+                self.ui_state.input_cost_unknown = None;
             }
-            Theme::Light => {
-                ctx.set_visuals(Visuals::light());
+            Some(Err(reason)) => {
+                self.ui_state.input_cost_display = None;
+                self.ui_state.input_cost_unknown = Some(reason.clone());
             }
+            None => {}
         }
+        drop(estimates);
+        // said once, the estimates go on from characters
+        if !self.token_estimation_notified {
+            if let Some(Err(e)) = CL100K_BASE.get() {
+                self.token_estimation_notified = true;
+                self.ui_state.push_toast(tr_args("error.token_estimation", &[e]));
+            }
+        }
+        // messages left out of the context don't count towards the next request
+        self.ui_state.context_cut = self.context_cut();
+        self.ui_state.conversation_archived = self.is_archived();
+        self.ui_state.output_tokens = self
+            .conversations
+            .get(&self.active_conversation)
+            .and_then(|conversation| conversation.stream.as_ref())
+            .map(|stream| stream.output_tokens);
+        if let Some(cut) = &self.ui_state.context_cut {
+            let estimates = self.token_estimates.lock().unwrap();
+            self.ui_state.conversation_tokens = estimates
+                .message_tokens
+                .iter()
+                .zip(&self.messages)
+                .enumerate()
+                .filter(|(idx, (_, message))| *idx >= cut.start || message.role == Role::System)
+                .map(|(_, (tokens, _))| tokens)
+                .sum();
+        }
+        self.ui_state.context_window = find_model_pricing(Some(&self.pricing_data), &self.model)
+            .map(|pricing| pricing.max_prompt_tokens)
+            .filter(|&max_prompt_tokens| max_prompt_tokens != usize::MAX);
 
-
-        if let Some(Ok(input_cost)) = &*self.input_cost.lock().unwrap() {
-            self.ui_state.input_cost_display = Some(*input_cost);
+        if self.poll_streams() {
+            ctx.request_repaint();
+        }
+        self.update_revealed_response(ctx);
+        if std::mem::take(&mut self.trim_retry_pending) {
+            self.retry_trimmed(ctx);
+        }
+        for id in std::mem::take(&mut self.untitled_conversations) {
+            self.request_title(ctx, id);
+        }
+        self.poll_titles();
+        if let Some(e) = self.mirror.as_mut().and_then(ResponseMirror::take_error) {
+            self.ui_state.push_toast(tr_args("error.mirror", &[&e]));
         }
 
-        if let Some(receiver) = &mut self.stream_receiver {
-            if let Ok(content_delta) = receiver.try_recv() {
-                self.handle_stream_response(content_delta);
-                ctx.request_repaint(); // repaint ?
+        // Esc stops the response before anything else gets it, the settings
+        // included. Open dialogs keep it to close themselves
+        let dialog_open = self.ui_state.palette_open
+            || self.ui_state.citation_view.is_some()
+            || self.ui_state.confirm_send_cost.is_some()
+            || self.ui_state.confirm_duplicate_send
+            || self.ui_state.request_preview.is_some()
+            || self.ui_state.confirm_quit;
+        if self.is_sending()
+            && !dialog_open
+            && ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::Escape))
+        {
+            if self.send_pending() {
+                self.cancel_pending_send();
+            } else {
+                self.stop_streaming();
             }
         }
 
@@ -397,47 +3152,722 @@ impl eframe::App for ClauChatApp {
         //     }
         // }
 
+        let known_tags = self.known_tags();
+        let mut conversation_action = None;
+        if self.config.ui_layout.sidebar_open {
+            let sidebar = egui::SidePanel::left("conversations")
+                .resizable(true)
+                .default_width(self.config.ui_layout.sidebar_width)
+                .show(ctx, |ui| {
+                    let conversations = Self::conversations_by_recency(&self.conversations);
+                    ui::render_conversation_sidebar(
+                        ui,
+                        &mut self.ui_state,
+                        &conversations,
+                        &known_tags,
+                        &self.active_conversation,
+                        |action| conversation_action = Some(action),
+                    );
+                });
+            let width = sidebar.response.rect.width();
+            if (width - self.config.ui_layout.sidebar_width).abs() > 0.5 {
+                self.config.ui_layout.sidebar_width = width;
+                self.mark_config_dirty();
+            }
+        }
+        if let Some(action) = conversation_action {
+            self.handle_conversation_action(action);
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             let mut update_api_key_action: Option<String> = None;
 
             // apply font size
             self.apply_font_size(ctx);
-            ui::render_header(ui, &mut self.ui_state, &mut self.config, |new_key| {
-                update_api_key_action = Some(new_key);
-            });
+            self.apply_motion(ctx);
+            let settings_was_open = self.ui_state.settings_open;
+            let active_pricing = find_model_pricing(Some(&self.pricing_data), &self.model);
+            let model_info = self.model_info.lock().unwrap();
+            let model_choice = ui::ModelChoice {
+                current: &self.model,
+                models: &self.models,
+                info: model_info.get(&self.model),
+                enabled: !self.is_sending(),
+            };
+            let mut model_change = None;
+            ui::render_header(
+                ui,
+                &mut self.ui_state,
+                &mut self.config,
+                active_pricing,
+                model_choice,
+                |new_key| update_api_key_action = Some(new_key),
+                |model| model_change = Some(model),
+            );
+            drop(model_info);
+            if let Some(model) = model_change {
+                self.set_model(model);
+            }
 
             if let Some(new_key) = update_api_key_action {
                 self.update_api_key(new_key);
             }
 
+            if let Some(action) = self.ui_state.profile_action.take() {
+                self.handle_profile_action(action);
+            }
+            if std::mem::take(&mut self.ui_state.client_changed) {
+                self.rebuild_client();
+            }
+            if std::mem::take(&mut self.ui_state.config_dirty) {
+                self.mark_config_dirty();
+                self.sync_transcript_log();
+                self.sync_usage_log();
+                self.sync_mirror();
+                self.post_processors = postprocess::from_config(&self.config);
+                self.config.apply_log_level();
+            }
+            if std::mem::take(&mut self.ui_state.open_log_folder) {
+                self.open_log_folder();
+            }
+            let settings_closed = settings_was_open && !self.ui_state.settings_open;
+            self.flush_config(ctx, settings_closed);
+            self.flush_draft(Some(ctx), false);
+
+            let mut tag_actions = Vec::new();
+            let active_tags = self
+                .conversations
+                .get(&self.active_conversation)
+                .map(|conversation| conversation.tags.as_slice())
+                .unwrap_or_default();
+            ui::render_tag_bar(ui, &mut self.ui_state, active_tags, &known_tags, |action| {
+                tag_actions.push(action)
+            });
+            for action in tag_actions {
+                self.handle_tag_action(action);
+            }
+
+            ui::render_context_bar(ui, &self.ui_state);
+
+            if self.config.danger_accept_invalid_certs {
+                ui::render_insecure_tls_warning(ui);
+            }
+
             if let Some(error) = &self.error {
                 ui::render_error(ui, error);
             }
 
-            //
-            ui.vertical(|ui| {
-                ui::render_chat_area(ui, &self.messages);
+            // the status bar and composer are laid out first at the bottom,
+            // the transcript takes whatever height is left
+            self.ui_state.status_bar_visible = self.config.show_status_bar;
+            self.ui_state.dry_run = self.config.dry_run;
+            if self.config.show_status_bar {
+                egui::TopBottomPanel::bottom("status_bar")
+                    .frame(egui::Frame::new())
+                    .resizable(false)
+                    .show_inside(ui, |ui| {
+                        let status = ui::StatusBar {
+                            model: &self.model,
+                            endpoint: self.config.api_base_url(),
+                            phase: self.request_phase(),
+                        };
+                        ui::render_status_bar(ui, &self.ui_state, &status);
+                    });
+            }
+            let input_height = self.input_height(ui);
+            egui::TopBottomPanel::bottom("composer")
+                .frame(egui::Frame::new())
+                .resizable(false)
+                .show_separator_line(false)
+                .show_inside(ui, |ui| {
+                    let summary_open = self.config.ui_layout.summary_open;
+                    ui::render_conversation_summary(
+                        ui,
+                        &self.conversation_summary(),
+                        &mut self.config.ui_layout.summary_open,
+                    );
+                    if self.config.ui_layout.summary_open != summary_open {
+                        self.mark_config_dirty();
+                    }
+
+                    if self.ui_state.conversation_archived {
+                        let mut unarchive = false;
+                        ui::render_archived_bar(ui, || unarchive = true);
+                        if unarchive {
+                            self.set_archived(self.active_conversation.clone(), false);
+                        }
+                        return;
+                    }
+
+                    let mut should_send_input = false;
+
+                    let remaining = self
+                        .active_stream()
+                        .and_then(|stream| stream.send_deadline)
+                        .and_then(|deadline| deadline.checked_duration_since(Instant::now()));
+                    if let Some(remaining) = remaining {
+                        let mut cancel = false;
+                        ui::render_send_countdown(ui, remaining, || cancel = true);
+                        if cancel {
+                            self.cancel_pending_send();
+                        }
+                        ctx.request_repaint_after(Duration::from_millis(100));
+                    }
+
+                    ui::render_prefill_input(ui, &mut self.prefill);
+
+                    let mut attachment_action = None;
+                    ui::render_attachment_chips(ui, &self.attachments, &self.ui_state.attachment_tokens, |action| {
+                        attachment_action = Some(action);
+                    });
+                    if let Some(action) = attachment_action {
+                        self.handle_attachment_action(action);
+                    }
+
+                    if voice::available() {
+                        let mut voice_action = None;
+                        ui::render_voice_controls(ui, self.voice_state(), |action| {
+                            voice_action = Some(action);
+                        });
+                        if let Some(action) = voice_action {
+                            self.handle_voice_action(ctx, action);
+                        }
+                    }
 
-                let mut should_send_message = false;
-                let mut should_send_input = false;
+                    self.take_large_paste(ctx);
 
-                ui::render_input_area(ui, &mut self.input, 
-                    &self.ui_state, self.is_sending, || {
-                    should_send_message = true;
-                }, || {
-                        should_send_input = true;
+                    let send_state = if self.is_sending() {
+                        ui::SendState::Sending
+                    } else if self.client.is_none() {
+                        ui::SendState::Blocked(tr("input.no_api_key"))
+                    } else if self.input.trim().is_empty() && self.attachments.is_empty() {
+                        ui::SendState::Blocked(tr("input.empty"))
+                    } else {
+                        ui::SendState::Ready
+                    };
+                    let mut input_action = None;
+                    ui.allocate_ui(egui::vec2(ui.available_width(), input_height), |ui| {
+                        ui::render_input_area(ui, &mut self.input, 
+                            &self.ui_state, send_state, self.config.show_input_stats, |action| {
+                            input_action = Some(action);
+                        }, || {
+                                should_send_input = true;
+                            });
                     });
-                if should_send_message {
-                    self.send_message();
+                    match input_action {
+                        Some(ui::InputAction::Send) if self.config.dry_run => self.preview_send(),
+                        Some(ui::InputAction::Send) => self.request_send(ctx),
+                        Some(ui::InputAction::Preview) => self.preview_send(),
+                        Some(ui::InputAction::Stop) if self.send_pending() => self.cancel_pending_send(),
+                        Some(ui::InputAction::Stop) => self.stop_streaming(),
+                        None => {}
+                    }
+                    if should_send_input {
+                        self.send_input_required().unwrap();
+                    }
+                });
+
+            let mut message_action = None;
+            ui::render_chat_area(
+                ui,
+                &self.messages,
+                &self.pinned_messages,
+                &self.ui_state,
+                self.is_sending(),
+                &mut RenderContext {
+                    config: &self.config,
+                    parsed: &mut self.parsed_messages,
+                    models: &self.models,
+                    model: &self.model,
+                },
+                |idx, action| message_action = Some((idx, action)),
+            );
+            self.parsed_messages.evict_unused();
+            match message_action {
+                Some((idx, ui::MessageAction::TogglePin)) => self.toggle_pin(idx),
+                Some((idx, ui::MessageAction::Speak)) => self.read_aloud(idx),
+                Some((_, ui::MessageAction::Quote(text))) => self.quote_in_input(ctx, &text),
+                Some((idx, ui::MessageAction::UseAsInput(append))) => self.use_as_input(ctx, idx, append),
+                Some((_, ui::MessageAction::OpenContextSettings)) => self.open_settings(),
+                Some((_, ui::MessageAction::Retry)) => self.retry_response(ctx),
+                Some((_, ui::MessageAction::RetryTrimmed)) => self.retry_trimmed(ctx),
+                Some((_, ui::MessageAction::Resume)) => self.resume_response(ctx),
+                Some((_, ui::MessageAction::RecheckKey)) => self.recheck_api_key(ctx),
+                Some((_, ui::MessageAction::Regenerate(options))) => self.regenerate_response(ctx, options),
+                Some((idx, ui::MessageAction::ShowCitation(citation))) => self.show_citation(idx, citation),
+                Some((idx, ui::MessageAction::SetCodeLanguage(block, language))) => {
+                    self.set_code_language(idx, block, language)
                 }
-                if should_send_input {
-                    self.send_input_required().unwrap();
+                Some((idx, ui::MessageAction::Rate(rating))) => self.rate_message(idx, rating, None),
+                Some((idx, ui::MessageAction::RateNote(note))) => {
+                    let rating = self.messages.get(idx).and_then(|message| message.rating);
+                    self.rate_message(idx, rating, Some(note))
                 }
-            });
+                Some((_, ui::MessageAction::StopSpeaking)) => {
+                    if let Some(speaker) = &self.speaker {
+                        speaker.stop();
+                    }
+                }
+                None => {}
+            }
+        });
+
+        // F6 moves the focus between the tags, the messages and the input
+        // shift first, the plain shortcut would match it too
+        let (focus_previous, focus_next) = ctx.input_mut(|i| {
+            (
+                i.consume_shortcut(&egui::KeyboardShortcut::new(egui::Modifiers::SHIFT, egui::Key::F6)),
+                i.consume_shortcut(&egui::KeyboardShortcut::new(egui::Modifiers::NONE, egui::Key::F6)),
+            )
+        });
+        if focus_next || focus_previous {
+            let target = ui::FocusTarget::cycle(ui::FocusTarget::focused(ctx), focus_previous);
+            ctx.memory_mut(|memory| memory.request_focus(target.id()));
+        }
+
+        let toggle_palette = ctx.input_mut(|i| {
+            i.consume_shortcut(&egui::KeyboardShortcut::new(egui::Modifiers::COMMAND, egui::Key::K))
+                | i.consume_shortcut(&egui::KeyboardShortcut::new(egui::Modifiers::COMMAND, egui::Key::P))
+        });
+        if toggle_palette {
+            self.ui_state.palette_open = !self.ui_state.palette_open;
+        }
+        let mut palette_action = None;
+        let conversations = Self::conversations_by_recency(&self.conversations);
+        ui::render_command_palette(ctx, &mut self.ui_state, &conversations, |action| {
+            palette_action = Some(action);
         });
+        if let Some(action) = palette_action {
+            self.run_palette_action(ctx, action);
+        }
+
+        if self.ui_state.batch_open {
+            self.take_batch_drop(ctx);
+            let mut batch_actions = Vec::new();
+            ui::render_batch_window(ctx, &mut self.ui_state, self.batch.as_ref(), self.client.is_some(), |action| {
+                batch_actions.push(action)
+            });
+            for action in batch_actions {
+                self.handle_batch_action(ctx, action);
+            }
+        }
+
+        let mut duplicate_confirmed = false;
+        ui::render_duplicate_confirmation(ctx, &mut self.ui_state, || duplicate_confirmed = true);
+        if duplicate_confirmed {
+            self.check_send_cost(ctx);
+        }
+        let mut send_confirmed = false;
+        ui::render_send_confirmation(ctx, &mut self.ui_state, || send_confirmed = true);
+        // the preview already showed the cost, it's sent as is
+        ui::render_request_preview(ctx, &mut self.ui_state, || send_confirmed = true);
+        if send_confirmed {
+            self.send_message(ctx);
+        }
+
+        // closing would drop the responses still streaming, ask first
+        if ctx.input(|i| i.viewport().close_requested())
+            && self.conversations.values().any(|conversation| conversation.stream.is_some())
+        {
+            ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+            self.ui_state.confirm_quit = true;
+        }
+        let mut quit_confirmed = false;
+        ui::render_quit_confirmation(ctx, &mut self.ui_state, || quit_confirmed = true);
+        if quit_confirmed {
+            // nothing streams anymore, so this close goes through
+            self.leave_active_conversation();
+            self.abort_all_responses();
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+
+        ui::render_citation_popup(ctx, &mut self.ui_state);
+        ui::render_toasts(ctx, &mut self.ui_state, self.config.reduce_motion);
     }
 
     fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
         self.save_config();
+        self.leave_active_conversation();
+        self.shut_down_runtime();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::HttpOptions;
+
+    const CONVERSATION: &str = "test";
+
+    /// The app showing one conversation, without a runtime or storage
+    fn app_with(messages: Vec<Message>) -> ClauChatApp {
+        let conversation = Conversation::new(CONVERSATION.to_string(), messages);
+        let conversations = HashMap::from([(CONVERSATION.to_string(), conversation)]);
+        let mut app = ClauChatApp::with_conversations(Config::default(), conversations, CONVERSATION.to_string());
+        app.init().unwrap();
+        app
+    }
+
+    /// Start a response in the active conversation the way `request_response`
+    /// does, the sender stands for the stream task
+    fn start_stream(app: &mut ClauChatApp) -> tokio_mpsc::Sender<AppMessageDelta> {
+        let (tx, rx) = tokio_mpsc::channel(100_000);
+        app.messages.push(Message::new(Role::Assistant, ""));
+        app.conversations.get_mut(CONVERSATION).unwrap().stream = Some(ResponseStream {
+            receiver: rx,
+            cancel: CancellationToken::new(),
+            prefill_len: 0,
+            send_deadline: None,
+            started: Instant::now(),
+            queued: Arc::new(AtomicBool::new(false)),
+            seam: None,
+            seam_overlap: 0,
+            output_tokens: 0,
+        });
+        tx
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![Message::local(Role::Assistant, GREETING), Message::new(Role::User, "Hello")]
+    }
+
+    /// Server-sent events answering with the given text fragments, all in one body
+    fn response_body(fragments: &[&str]) -> String {
+        let mut events = vec![
+            r#"event: message_start
+data: {"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude-test","usage":{"input_tokens":12,"output_tokens":1}}}"#.to_string(),
+            r#"event: content_block_start
+data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#.to_string(),
+        ];
+        events.extend(fragments.iter().map(|fragment| {
+            format!(
+                "event: content_block_delta\ndata: {}",
+                serde_json::json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": fragment}})
+            )
+        }));
+        events.push(
+            r#"event: content_block_stop
+data: {"type":"content_block_stop","index":0}"#
+                .to_string(),
+        );
+        events.push(
+            r#"event: message_delta
+data: {"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":5}}"#
+                .to_string(),
+        );
+        events.push("event: message_stop\ndata: {\"type\":\"message_stop\"}".to_string());
+        events.iter().map(|event| format!("{}\n\n", event)).collect()
+    }
+
+    fn event_stream(body: String) -> wiremock::ResponseTemplate {
+        wiremock::ResponseTemplate::new(200)
+            .insert_header("content-type", "text/event-stream")
+            .set_body_string(body)
+    }
+
+    /// Request a response from a server answering with `response`, through
+    /// the real stream task, and poll it the way `update` does until it's
+    /// over. A stop is pressed right away when `stop` is set
+    fn respond(app: &mut ClauChatApp, response: wiremock::ResponseTemplate, stop: bool) {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer};
+
+        let runtime = Runtime::new().unwrap();
+        let server = runtime.block_on(async {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/v1/messages"))
+                .respond_with(response)
+                .mount(&server)
+                .await;
+            server
+        });
+        app.runtime = Some(runtime);
+        let client = AnthropicClient::new("claude-test", "test-key".to_string(), &HttpOptions::default())
+            .unwrap()
+            .with_base_url(&server.uri());
+        app.request_response(&Context::default(), client, 0, None, Duration::ZERO);
+        if stop {
+            app.stop_streaming();
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while app.is_sending() {
+            assert!(Instant::now() < deadline, "the response never ended");
+            app.poll_streams();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        app.runtime.take().unwrap().block_on(async { drop(server) });
+    }
+
+    #[test]
+    fn response_task_dying_ends_the_stream() {
+        let mut app = app_with(conversation());
+        let tx = start_stream(&mut app);
+        tx.try_send(AppMessageDelta {
+            content: DeltaContent::Append("Par".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        // the task panics, its sender goes without a completing delta
+        drop(tx);
+
+        assert!(app.poll_streams());
+        assert!(!app.is_sending());
+        let last = app.messages.last().unwrap();
+        assert_eq!(last.content, "Par");
+        assert_eq!(last.error.as_deref(), Some("Response was interrupted"));
+        // nothing is left to poll
+        assert!(!app.poll_streams());
+    }
+
+    #[test]
+    fn completed_response_isnt_interrupted() {
+        let mut app = app_with(conversation());
+        let tx = start_stream(&mut app);
+        tx.try_send(AppMessageDelta {
+            content: DeltaContent::Append("Done".to_string()),
+            is_complete: true,
+            ..Default::default()
+        })
+        .unwrap();
+        drop(tx);
+
+        app.poll_streams();
+        assert!(!app.is_sending());
+        let last = app.messages.last().unwrap();
+        assert_eq!((last.content.as_str(), last.error.as_ref()), ("Done", None));
+    }
+
+    fn append(fragment: &str) -> AppMessageDelta {
+        AppMessageDelta {
+            content: DeltaContent::Append(fragment.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Stream `count` small fragments in one go, how long applying them took
+    fn apply_appends(count: usize) -> Duration {
+        let mut app = app_with(conversation());
+        let tx = start_stream(&mut app);
+        for _ in 0..count {
+            tx.try_send(append("word ")).unwrap();
+        }
+        tx.try_send(AppMessageDelta {
+            is_complete: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let started = Instant::now();
+        app.poll_streams();
+        let elapsed = started.elapsed();
+        assert!(!app.is_sending());
+        assert_eq!(app.messages.last().unwrap().content.len(), count * "word ".len());
+        elapsed
+    }
+
+    #[test]
+    fn thousands_of_deltas_assemble_the_response() {
+        let mut app = app_with(conversation());
+        let tx = start_stream(&mut app);
+        let mut expected = String::new();
+        for idx in 0..5_000 {
+            let fragment = format!("{} ", idx);
+            let content = match idx {
+                // the whole text again, as after a resumed stream settles
+                _ if idx % 500 == 250 => {
+                    expected.push_str(&fragment);
+                    DeltaContent::Rewrite(expected.clone())
+                }
+                2_500 => {
+                    expected = fragment.clone();
+                    DeltaContent::Replace(fragment)
+                }
+                _ => {
+                    expected.push_str(&fragment);
+                    DeltaContent::Append(fragment)
+                }
+            };
+            tx.try_send(AppMessageDelta {
+                content,
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        tx.try_send(AppMessageDelta {
+            content: DeltaContent::Append("end".to_string()),
+            is_complete: true,
+            ..Default::default()
+        })
+        .unwrap();
+        expected.push_str("end");
+
+        assert!(app.poll_streams());
+        assert!(!app.is_sending());
+        let last = app.messages.last().unwrap();
+        assert_eq!(last.content, expected);
+        assert!(last.error.is_none());
+    }
+
+    #[test]
+    fn applying_deltas_is_linear() {
+        // best of a few runs, against scheduling noise
+        let time = |count| (0..3).map(|_| apply_appends(count)).min().unwrap();
+        let (small, large) = (time(5_000), time(40_000));
+        // 8x the deltas, a quadratic cost would take ~64x as long
+        assert!(large < small * 24, "{:?} for 5k deltas, {:?} for 40k", small, large);
+    }
+
+    #[test]
+    fn text_batched_with_the_completion_is_kept() {
+        let mut app = app_with(conversation());
+        // every fragment lands in the same flush window as message_stop
+        respond(&mut app, event_stream(response_body(&["Hel", "lo, ", "wor", "ld!"])), false);
+
+        let last = app.messages.last().unwrap();
+        assert_eq!((last.content.as_str(), last.error.as_ref()), ("Hello, world!", None));
+        assert_eq!(last.model.as_deref(), Some("claude-test"));
+    }
+
+    /// The placeholder of a response without text stays out of the next request
+    fn assert_placeholder_is_local(app: &ClauChatApp) {
+        assert!(!app.is_sending());
+        let last = app.messages.last().unwrap();
+        assert_eq!(last.content, EMPTY_RESPONSE_PLACEHOLDER);
+        assert!(!last.is_sent());
+        let messages = ClauChatApp::request_messages("", &app.messages, 0, "");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, Role::User);
+    }
+
+    #[test]
+    fn response_without_text_isnt_sent_back() {
+        let mut app = app_with(conversation());
+        let tx = start_stream(&mut app);
+        tx.try_send(AppMessageDelta {
+            is_complete: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        app.poll_streams();
+        assert_placeholder_is_local(&app);
+    }
+
+    #[test]
+    fn stop_before_the_response_opens_isnt_sent_back() {
+        let mut app = app_with(conversation());
+        let never_opens = event_stream(response_body(&["Hello"])).set_delay(Duration::from_secs(30));
+        respond(&mut app, never_opens, true);
+
+        assert_placeholder_is_local(&app);
+    }
+
+    /// Start a response that's still in its grace period
+    fn start_pending_send(app: &mut ClauChatApp) {
+        start_stream(app);
+        let stream = app.conversations.get_mut(CONVERSATION).unwrap().stream.as_mut().unwrap();
+        stream.send_deadline = Some(Instant::now() + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn cancelled_send_goes_before_what_was_typed_since() {
+        let mut app = app_with(conversation());
+        start_pending_send(&mut app);
+        app.input = "and a follow-up".to_string();
+
+        app.cancel_pending_send();
+        assert!(!app.is_sending());
+        assert_eq!(app.input, "Hello\nand a follow-up");
+        assert_eq!(app.messages.len(), 1);
+    }
+
+    #[test]
+    fn cancelled_send_fills_an_empty_input() {
+        let mut app = app_with(conversation());
+        start_pending_send(&mut app);
+
+        app.cancel_pending_send();
+        assert_eq!(app.input, "Hello");
+    }
+
+    #[test]
+    fn rechecked_key_is_reported() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mut app = app_with(conversation());
+        let runtime = Runtime::new().unwrap();
+        let server = runtime.block_on(async {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/v1/models"))
+                .respond_with(ResponseTemplate::new(401))
+                .mount(&server)
+                .await;
+            server
+        });
+        app.runtime = Some(runtime);
+        app.client = Some(
+            AnthropicClient::new("claude-test", "test-key".to_string(), &HttpOptions::default())
+                .unwrap()
+                .with_base_url(&server.uri()),
+        );
+
+        app.recheck_api_key(&Context::default());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while app.key_check_receiver.is_some() {
+            assert!(Instant::now() < deadline, "the key check never ended");
+            app.poll_key_check();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let toasts: Vec<&str> = app.ui_state.toasts.iter().map(|toast| toast.text.as_str()).collect();
+        assert_eq!(toasts, [tr("toast.key_refused")]);
+        // the client stays, the key can be checked or used again
+        assert!(app.client.is_some());
+        app.runtime.take().unwrap().block_on(async { drop(server) });
+    }
+
+    #[test]
+    fn exit_mid_response_stops_it_and_the_runtime() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer};
+
+        // the server outlives the app's runtime
+        let server_runtime = Runtime::new().unwrap();
+        let server = server_runtime.block_on(async {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .respond_with(event_stream(response_body(&["Hello"])).set_delay(Duration::from_secs(30)))
+                .mount(&server)
+                .await;
+            server
+        });
+        let mut app = app_with(conversation());
+        app.runtime = Some(Runtime::new().unwrap());
+        let client = AnthropicClient::new("claude-test", "test-key".to_string(), &HttpOptions::default())
+            .unwrap()
+            .with_base_url(&server.uri());
+        app.request_response(&Context::default(), client, 0, None, Duration::ZERO);
+        assert!(app.is_sending());
+
+        let started = Instant::now();
+        app.shut_down_runtime();
+        assert!(started.elapsed() < RUNTIME_SHUTDOWN_TIMEOUT * 2);
+        assert!(app.runtime.is_none());
+        assert!(app.conversations.values().all(|conversation| conversation.stream.is_none()));
+        server_runtime.block_on(async { drop(server) });
+    }
+
+    #[test]
+    fn fresh_conversation_sends_only_the_first_message() {
+        let history = vec![Message::local(Role::Assistant, GREETING), Message::new(Role::User, "Hello")];
+        let messages = ClauChatApp::request_messages("", &history, 0, "");
+        let client = AnthropicClient::new("claude-test", "test-key".to_string(), &HttpOptions::default()).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&client.request_body(&messages).unwrap()).unwrap();
+
+        assert_eq!(body["messages"], serde_json::json!([{"role": "user", "content": "Hello"}]));
+        assert!(body.get("system").is_none());
     }
 }
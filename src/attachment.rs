@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// Text content staged alongside a user message, sent as a fenced block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub content: String,
+}
+
+impl Attachment {
+    pub fn new(name: String, content: String) -> Self {
+        Self { name, content }
+    }
+
+    /// How the attachment is written into the message sent to the API
+    pub fn as_fenced_block(&self) -> String {
+        format!("{}:\n```\n{}\n```", self.name, self.content.trim_end())
+    }
+
+    /// Short description for chips, e.g. "1.2 KB, 40 lines"
+    pub fn summary(&self) -> String {
+        format!(
+            "{}, {} lines",
+            format_size(self.content.len()),
+            self.content.lines().count()
+        )
+    }
+}
+
+pub fn format_size(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
@@ -0,0 +1,239 @@
+use eframe::egui;
+use log::{debug, warn};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use crate::api::{AnthropicClient, BatchRequestCounts, BatchResult, BatchResultLine, Message, ResponseUsage, Role};
+use crate::i18n::tr;
+
+/// Part of the template replaced by each input
+pub const INPUT_PLACEHOLDER: &str = "{input}";
+
+/// Batched requests are billed at half the usual price
+pub const BATCH_PRICE_FACTOR: f64 = 0.5;
+
+/// Wait between status checks, a batch takes minutes to hours
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where one input of a batch is at
+#[derive(Debug, Clone)]
+pub enum RowStatus {
+    Pending,
+    Succeeded {
+        text: String,
+        model: String,
+        usage: ResponseUsage,
+    },
+    Failed(String),
+}
+
+/// One input of a batch and its answer
+#[derive(Debug, Clone)]
+pub struct BatchRow {
+    pub input: String,
+    /// the template with the input in place, what was asked
+    pub prompt: String,
+    pub status: RowStatus,
+    /// dollars at the batch price, None until answered or when the
+    /// model's pricing isn't known
+    pub cost: Option<f64>,
+}
+
+/// What the task following a batch reports
+#[derive(Debug)]
+enum BatchUpdate {
+    Created(String),
+    Progress(BatchRequestCounts),
+    Results(Vec<BatchResultLine>),
+    Failed(String),
+}
+
+/// A batch submitted from the batch panel, followed on the runtime until
+/// its results are downloaded
+pub struct BatchJob {
+    /// set once the API accepted the batch
+    pub id: Option<String>,
+    pub rows: Vec<BatchRow>,
+    pub counts: BatchRequestCounts,
+    /// why the batch as a whole failed, e.g. it couldn't be created
+    pub error: Option<String>,
+    /// the results are in, or the batch failed
+    pub done: bool,
+    receiver: mpsc::UnboundedReceiver<BatchUpdate>,
+}
+
+/// The template with the input in place. An empty template asks the input as is
+pub fn fill_template(template: &str, input: &str) -> String {
+    if template.trim().is_empty() {
+        input.to_string()
+    } else {
+        template.replace(INPUT_PLACEHOLDER, input)
+    }
+}
+
+/// Inputs of the pasted or loaded list, one per non-blank line
+pub fn parse_inputs(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl BatchJob {
+    /// Create the batch and follow it until its results are in. The
+    /// system prompt, if any, goes with every request
+    pub fn submit(
+        runtime: &Runtime,
+        client: AnthropicClient,
+        ctx: &egui::Context,
+        system_prompt: &str,
+        template: &str,
+        inputs: Vec<String>,
+    ) -> Self {
+        let rows: Vec<BatchRow> = inputs
+            .into_iter()
+            .map(|input| BatchRow {
+                prompt: fill_template(template, &input),
+                input,
+                status: RowStatus::Pending,
+                cost: None,
+            })
+            .collect();
+        let system_prompt = system_prompt.trim();
+        let prompts: Vec<Vec<Message>> = rows
+            .iter()
+            .map(|row| {
+                (!system_prompt.is_empty())
+                    .then(|| Message::new(Role::System, system_prompt))
+                    .into_iter()
+                    .chain(std::iter::once(Message::new(Role::User, row.prompt.clone())))
+                    .collect()
+            })
+            .collect();
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let repaint_ctx = ctx.clone();
+        runtime.spawn(async move {
+            let send = |update| {
+                let _ = sender.send(update);
+                repaint_ctx.request_repaint();
+            };
+            let batch = match client.create_batch(&prompts).await {
+                Ok(batch) => batch,
+                Err(e) => return send(BatchUpdate::Failed(format!("{:#}", e))),
+            };
+            debug!("Created batch {} of {} requests", batch.id, prompts.len());
+            send(BatchUpdate::Created(batch.id.clone()));
+            send(BatchUpdate::Progress(batch.request_counts.clone()));
+
+            let mut ended = batch.has_ended();
+            while !ended {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                // the job was cleared, nobody waits for the results
+                if sender.is_closed() {
+                    return;
+                }
+                match client.get_batch(&batch.id).await {
+                    Ok(status) => {
+                        ended = status.has_ended();
+                        send(BatchUpdate::Progress(status.request_counts));
+                    }
+                    // checked again at the next interval
+                    Err(e) => warn!("Could not check batch {}: {:#}", batch.id, e),
+                }
+            }
+            match client.batch_results(&batch.id).await {
+                Ok(results) => send(BatchUpdate::Results(results)),
+                Err(e) => send(BatchUpdate::Failed(format!("{:#}", e))),
+            }
+        });
+
+        Self {
+            id: None,
+            rows,
+            counts: BatchRequestCounts::default(),
+            error: None,
+            done: false,
+            receiver,
+        }
+    }
+
+    /// Take in what the task reported, returns the rows answered since
+    /// the last call so their cost can be counted
+    pub fn poll(&mut self) -> Vec<usize> {
+        let mut answered = Vec::new();
+        while let Ok(update) = self.receiver.try_recv() {
+            match update {
+                BatchUpdate::Created(id) => self.id = Some(id),
+                BatchUpdate::Progress(counts) => self.counts = counts,
+                BatchUpdate::Results(results) => {
+                    for line in results {
+                        let idx = line.custom_id.parse::<usize>().ok().filter(|&idx| idx < self.rows.len());
+                        let Some(idx) = idx else {
+                            warn!("Batch result for an unknown request {}", line.custom_id);
+                            continue;
+                        };
+                        self.rows[idx].status = match line.result {
+                            BatchResult::Succeeded { message } => {
+                                answered.push(idx);
+                                RowStatus::Succeeded {
+                                    text: message.text(),
+                                    model: message.model,
+                                    usage: message.usage,
+                                }
+                            }
+                            BatchResult::Errored { error } => RowStatus::Failed(error.error.message),
+                            BatchResult::Canceled => RowStatus::Failed(tr("batch.canceled").to_string()),
+                            BatchResult::Expired => RowStatus::Failed(tr("batch.expired").to_string()),
+                        };
+                    }
+                    for row in &mut self.rows {
+                        if matches!(row.status, RowStatus::Pending) {
+                            row.status = RowStatus::Failed(tr("batch.no_result").to_string());
+                        }
+                    }
+                    self.done = true;
+                }
+                BatchUpdate::Failed(error) => {
+                    self.error = Some(error);
+                    self.done = true;
+                }
+            }
+        }
+        answered
+    }
+
+    /// The rows as CSV: input, status, output, tokens and cost
+    pub fn to_csv(&self, rows: impl Iterator<Item = usize>) -> String {
+        let mut csv = String::from("input,status,output,input_tokens,output_tokens,cost\n");
+        for row in rows.filter_map(|idx| self.rows.get(idx)) {
+            let (status, output, usage) = match &row.status {
+                RowStatus::Pending => ("pending", "", None),
+                RowStatus::Succeeded { text, usage, .. } => ("succeeded", text.as_str(), Some(usage)),
+                RowStatus::Failed(error) => ("failed", error.as_str(), None),
+            };
+            let fields = [
+                csv_field(&row.input),
+                status.to_string(),
+                csv_field(output),
+                usage.map(|usage| usage.input_tokens.to_string()).unwrap_or_default(),
+                usage.map(|usage| usage.output_tokens.to_string()).unwrap_or_default(),
+                row.cost.map(|cost| format!("{:.6}", cost)).unwrap_or_default(),
+            ];
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// A field quoted when it has a separator, quote or line break in it
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
@@ -1,22 +1,156 @@
-use eframe::egui::{self, Color32, RichText, TextFormat, Ui};
-use log::{debug, info, error};
+use eframe::egui::{self, text::LayoutJob, Color32, RichText, TextFormat, Ui};
+use log::warn;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
 use std::ops::Range;
 
+use crate::ansi;
+use crate::config::Config;
 use crate::syntax_lit::SyntaxHighlighter;
+use crate::i18n::tr;
+
+/// Languages offered to highlight a code block with, when its tag is
+/// missing or the guess is wrong
+const CODE_LANGUAGES: &[&str] = &[
+    "bash", "c", "c++", "c#", "css", "diff", "go", "html", "java", "javascript", "json", "kotlin", "lua",
+    "markdown", "php", "python", "ruby", "rust", "sql", "typescript", "xml", "yaml", "plaintext",
+];
+
+/// Piece of a message, rendered as a label or a code block
+enum Segment {
+    Text(String),
+    Code {
+        /// byte offset of the block in the message, identifies it across frames
+        start: usize,
+        code: String,
+        language: Option<String>,
+    },
+}
+
+/// Highlights code blocks for `HighlightCache`
+#[derive(Default)]
+struct CodeHighlighter;
+
+impl egui::cache::ComputerMut<(&str, Option<&str>, bool), LayoutJob> for CodeHighlighter {
+    fn compute(&mut self, (code, language, is_dark_mode): (&str, Option<&str>, bool)) -> LayoutJob {
+        if language.is_some_and(ChatRenderer::is_diff_language) {
+            return ChatRenderer::diff_layout_job(code, is_dark_mode);
+        }
+        // terminal output is shown in its own colours
+        if language.is_some_and(ChatRenderer::is_ansi_language) || ansi::has_escapes(code) {
+            let default = if is_dark_mode { Color32::from_gray(210) } else { Color32::from_gray(40) };
+            return ChatRenderer::layout_job(ansi::colored_spans(code, default, is_dark_mode));
+        }
+        ChatRenderer::layout_job(SyntaxHighlighter::highlight_code(code, language, is_dark_mode))
+    }
+}
+
+/// Highlighted code blocks, entries not used during a frame are dropped
+type HighlightCache = egui::cache::FrameCache<LayoutJob, CodeHighlighter>;
+
+/// How a message is laid out, worked out once per content
+enum ParsedContent {
+    /// content that couldn't be segmented safely
+    Plain(String),
+    /// a whole message of bare JSON, trimmed
+    BareJson(String),
+    Segments(Vec<Segment>),
+}
+
+/// Parsed messages kept across frames, keyed by a hash of their content,
+/// so only a message whose text changes (the one streaming) is parsed again
+#[derive(Default)]
+pub struct ParsedCache {
+    parsed: HashMap<u64, Arc<ParsedContent>>,
+    /// entries rendered since the last eviction
+    used: HashSet<u64>,
+}
+
+impl ParsedCache {
+    fn get(&mut self, content: &str) -> Arc<ParsedContent> {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        let key = hasher.finish();
+        self.used.insert(key);
+        self.parsed
+            .entry(key)
+            .or_insert_with(|| Arc::new(ChatRenderer::parse(content)))
+            .clone()
+    }
+
+    /// Drop the messages not rendered since the last call, e.g. the
+    /// earlier states of a streamed message or a closed conversation
+    pub fn evict_unused(&mut self) {
+        let used = std::mem::take(&mut self.used);
+        self.parsed.retain(|key, _| used.contains(key));
+    }
+}
+
+/// What rendering messages needs from the app
+pub struct RenderContext<'a> {
+    pub config: &'a Config,
+    pub parsed: &'a mut ParsedCache,
+    /// offered to regenerate a response with
+    pub models: &'a [String],
+    /// the selected model, answers from another one are tagged
+    pub model: &'a str,
+}
+
+/// What the reader did in a message's content
+#[derive(Default)]
+pub struct ContentResponse {
+    /// text to quote in the reply
+    pub quote: Option<String>,
+    /// a code block's language was picked, None going back to its tag
+    pub code_language: Option<(usize, Option<String>)>,
+}
 
 /// Support for rendering different types of message content
 pub struct ChatRenderer;
 
 impl ChatRenderer {
 
-    /// Render highlighted code into a UI
+    /// Render highlighted code into a UI,
+    /// `id` keeps the block's "Format" toggle across frames.
+    /// Blocks over the config's size limits are shown as plain monospace.
+    /// With `picked`, the language chosen for the block, a dropdown offers
+    /// another one, returned when it changes
     fn render_highlighted_code(
         ui: &mut egui::Ui,
+        id: egui::Id,
         code: &str,
-        language: Option<&str>,
+        tag: Option<&str>,
+        picked: Option<Option<&str>>,
+        config: &Config,
         is_dark_mode: bool,
-    ) {
-        let highlighted = SyntaxHighlighter::highlight_code(code, language, is_dark_mode);
+    ) -> Option<Option<String>> {
+        let mut choice = None;
+        let language = picked.flatten().or(tag);
+        let formatted = Self::pretty_json(code, language);
+        let mut show_formatted =
+            formatted.is_some() && ui.data(|data| data.get_temp::<bool>(id).unwrap_or(false));
+        let shown = match &formatted {
+            Some(formatted) if show_formatted => formatted.as_str(),
+            _ => code,
+        };
+        let shown = Self::normalize_for_display(shown, config.tab_width_for(language));
+        // highlighting runs line by line on the UI thread, a minified file would freeze it
+        let highlight = config.should_highlight(&shown);
+        let job = if highlight {
+            // cached across frames, keyed by the text, language and theme
+            ui.ctx().memory_mut(|memory| {
+                memory
+                    .caches
+                    .cache::<HighlightCache>()
+                    .get((shown.as_str(), language, is_dark_mode))
+            })
+        } else if ansi::has_escapes(&shown) {
+            Self::layout_job(vec![(ansi::strip(&shown), ui.visuals().text_color())])
+        } else {
+            Self::layout_job(vec![(shown, ui.visuals().text_color())])
+        };
         
         // Determine background color based on theme
         let bg_color = if is_dark_mode {
@@ -26,7 +160,7 @@ impl ChatRenderer {
         };
         
         // Create a frame for the code block
-        let code_frame = egui::Frame::none()
+        let code_frame = egui::Frame::new()
             .fill(bg_color)
             .stroke(egui::Stroke::new(1.0, Color32::from_gray(100)))
             .inner_margin(egui::epaint::Marginf::same(8.0))
@@ -34,60 +168,299 @@ impl ChatRenderer {
             ;
             
         code_frame.show(ui, |ui| {
-            // Show language if available
-            if let Some(lang) = language {
-                ui.label(
-                    RichText::new(lang)
-                        .color(if is_dark_mode { Color32::LIGHT_GRAY } else { Color32::DARK_GRAY })
-                        .small()
-                );
-                ui.separator();
-            }
-            
-            // Render the highlighted code
-            let mut job = egui::text::LayoutJob::default();
-            
-            for (text, color) in highlighted {
-                let text_format = TextFormat {
-                    font_id: egui::FontId::monospace(14.0),
-                    color,
-                    ..Default::default()
-                };
-                
-                job.append(&text, 0.0, text_format);
-            }
+            // Show language if available, with the block actions
+            ui.horizontal(|ui| {
+                if let Some(lang) = language {
+                    ui.label(
+                        RichText::new(lang)
+                            .color(if is_dark_mode { Color32::LIGHT_GRAY } else { Color32::DARK_GRAY })
+                            .small()
+                    );
+                }
+                if !highlight {
+                    ui.label(RichText::new(tr("code.highlight_skipped")).weak().small());
+                }
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    // copies the original text, not the formatted one
+                    if ui.small_button(tr("code.copy")).clicked() {
+                        ui.ctx().copy_text(code.to_string());
+                    }
+                    if let Some(picked) = picked {
+                        egui::ComboBox::from_id_salt(id.with("language"))
+                            .selected_text(RichText::new(picked.unwrap_or(tr("code.language_auto"))).small())
+                            .width(90.0)
+                            .show_ui(ui, |ui| {
+                                if ui.selectable_label(picked.is_none(), tr("code.language_auto")).clicked() {
+                                    choice = Some(None);
+                                }
+                                for &option in CODE_LANGUAGES {
+                                    if ui.selectable_label(picked == Some(option), option).clicked() {
+                                        choice = Some(Some(option.to_string()));
+                                    }
+                                }
+                            })
+                            .response
+                            .on_hover_text(tr("code.language_hint"));
+                    }
+                    if let Some(formatted) = &formatted {
+                        if show_formatted && ui.small_button(tr("code.copy_formatted")).clicked() {
+                            ui.ctx().copy_text(formatted.clone());
+                        }
+                        if ui.toggle_value(&mut show_formatted, tr("code.format")).changed() {
+                            ui.data_mut(|data| data.insert_temp(id, show_formatted));
+                        }
+                    }
+                });
+            });
+            ui.separator();
             
             ui.label(job);
         });
+        choice.filter(|choice| choice.as_deref() != picked.flatten())
     }
 
-    /// Renders message content with code blocks
-    pub fn render_message_content(ui: &mut Ui, content: &str) {
+    /// Monospace layout of highlighted code
+    fn layout_job(highlighted: Vec<(String, Color32)>) -> LayoutJob {
+        let mut job = LayoutJob::default();
+        for (text, color) in highlighted {
+            let text_format = TextFormat {
+                font_id: egui::FontId::monospace(14.0),
+                color,
+                ..Default::default()
+            };
+            job.append(&text, 0.0, text_format);
+        }
+        job
+    }
+
+    fn is_diff_language(language: &str) -> bool {
+        language.eq_ignore_ascii_case("diff") || language.eq_ignore_ascii_case("patch")
+    }
+
+    fn is_ansi_language(language: &str) -> bool {
+        ["ansi", "console", "terminal"]
+            .iter()
+            .any(|ansi| language.eq_ignore_ascii_case(ansi))
+    }
+
+    /// Monospace layout of a diff, added lines in green and removed ones
+    /// in red, each on a faint background of their color
+    fn diff_layout_job(code: &str, is_dark_mode: bool) -> LayoutJob {
+        let (added, removed, hunk, text) = if is_dark_mode {
+            (
+                Color32::from_rgb(140, 220, 140),
+                Color32::from_rgb(240, 130, 130),
+                Color32::from_rgb(120, 180, 240),
+                Color32::from_gray(210),
+            )
+        } else {
+            (
+                Color32::from_rgb(20, 120, 20),
+                Color32::from_rgb(170, 30, 30),
+                Color32::from_rgb(30, 90, 170),
+                Color32::from_gray(40),
+            )
+        };
+
+        let mut job = LayoutJob::default();
+        for line in code.split_inclusive('\n') {
+            // the file headers start like changed lines but aren't
+            let is_header = ["+++", "---", "diff ", "index "].iter().any(|prefix| line.starts_with(prefix));
+            let (color, background) = match line.chars().next() {
+                _ if is_header => (text, Color32::TRANSPARENT),
+                Some('+') => (added, added.gamma_multiply(0.12)),
+                Some('-') => (removed, removed.gamma_multiply(0.12)),
+                Some('@') if line.starts_with("@@") => (hunk, Color32::TRANSPARENT),
+                _ => (text, Color32::TRANSPARENT),
+            };
+            let text_format = TextFormat {
+                font_id: egui::FontId::monospace(14.0),
+                color,
+                background,
+                ..Default::default()
+            };
+            job.append(line, 0.0, text_format);
+        }
+        job
+    }
+
+    /// Work out how a message is laid out: its text and code blocks,
+    /// a bare JSON block, or plain text when it can't be segmented safely
+    fn parse(content: &str) -> ParsedContent {
+        // CRLF content, e.g. imported from a Windows file, would leave
+        // stray \r in labels, code and language tags
+        let content = if content.contains('\r') {
+            Cow::Owned(content.replace("\r\n", "\n"))
+        } else {
+            Cow::Borrowed(content)
+        };
+        let content = content.as_ref();
+
+        let Some(segments) = Self::segments(content) else {
+            warn!("Could not segment a message, showing it as plain text");
+            return ParsedContent::Plain(content.to_string());
+        };
+
+        // a whole message of bare JSON is shown as a json block
+        let has_code = segments.iter().any(|segment| matches!(segment, Segment::Code { .. }));
+        if !has_code && Self::pretty_json(content, None).is_some() {
+            return ParsedContent::BareJson(content.trim().to_string());
+        }
+        ParsedContent::Segments(segments)
+    }
+
+    /// Text that can be selected like a label, with a context menu
+    /// to quote the selection. Returns the selection when it's quoted
+    fn render_selectable_text(ui: &mut Ui, id: egui::Id, text: &str, monospace: bool) -> Option<String> {
+        let mut read_only = text;
+        let font = if monospace {
+            egui::TextStyle::Monospace
+        } else {
+            egui::TextStyle::Body
+        };
+        let response = egui::TextEdit::multiline(&mut read_only)
+            .id(id)
+            .font(font)
+            .frame(false)
+            .desired_rows(1)
+            .desired_width(f32::INFINITY)
+            .show(ui)
+            .response;
+
+        let mut quote = None;
+        response.context_menu(|ui| {
+            // read from the stored state, the edit loses focus to the menu
+            let selected = egui::TextEdit::load_state(ui.ctx(), id)
+                .and_then(|state| state.cursor.char_range())
+                .map(|range| {
+                    let [start, end] = range.sorted();
+                    text.chars()
+                        .skip(start.index)
+                        .take(end.index - start.index)
+                        .collect::<String>()
+                })
+                .filter(|selected| !selected.trim().is_empty());
+            if ui
+                .add_enabled(selected.is_some(), egui::Button::new(tr("message.quote")))
+                .clicked()
+            {
+                quote = selected;
+                ui.close_menu();
+            }
+        });
+        quote
+    }
+
+    /// Renders message content with code blocks, parsed once per content,
+    /// or as the exact markdown in monospace when `raw` is set.
+    /// `code_languages` are the languages picked for its code blocks
+    pub fn render_message_content(
+        ui: &mut Ui,
+        content: &str,
+        code_languages: &BTreeMap<usize, String>,
+        render: &mut RenderContext,
+        raw: bool,
+    ) -> ContentResponse {
+        let mut response = ContentResponse::default();
+        if raw {
+            response.quote = Self::render_selectable_text(ui, ui.make_persistent_id("raw_text"), content, true);
+            return response;
+        }
+
+        let config = render.config;
+        match &*render.parsed.get(content) {
+            ParsedContent::Plain(text) => {
+                response.quote = Self::render_selectable_text(ui, ui.make_persistent_id("plain_text"), text, false);
+            }
+            ParsedContent::BareJson(json) => {
+                let id = ui.make_persistent_id("bare_json");
+                ChatRenderer::render_highlighted_code(ui, id, json, Some("json"), None, config, true);
+            }
+            ParsedContent::Segments(segments) => {
+                let mut block = 0;
+                for (idx, segment) in segments.iter().enumerate() {
+                    match segment {
+                        Segment::Text(text) => {
+                            let id = ui.make_persistent_id(("text_segment", idx));
+                            response.quote = Self::render_selectable_text(ui, id, text, false).or(response.quote);
+                        }
+                        Segment::Code { start, code, language } => {
+                            // Render code block with special formatting
+                            let id = ui.make_persistent_id(("code_block", start));
+                            let choice = ChatRenderer::render_highlighted_code(
+                                ui,
+                                id,
+                                code,
+                                language.as_deref(),
+                                Some(code_languages.get(&block).map(String::as_str)),
+                                config,
+                                true,
+                            );
+                            if let Some(choice) = choice {
+                                response.code_language = Some((block, choice));
+                            }
+                            block += 1;
+                        }
+                    }
+                }
+            }
+        }
+        response
+    }
+
+    /// Message text to read aloud, code blocks are announced
+    /// rather than read character by character
+    pub fn spoken_text(content: &str) -> String {
+        let content = content.replace("\r\n", "\n");
+        let Some(segments) = Self::segments(&content) else {
+            return content;
+        };
+
+        segments
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text,
+                Segment::Code { code, .. } => {
+                    let lines = code.lines().count();
+                    let unit = if lines == 1 { "line" } else { "lines" };
+                    format!("\nCode block, {} {}.\n", lines, unit)
+                }
+            })
+            .collect()
+    }
+
+    /// Split a message into text and code blocks.
+    /// Slicing is checked, None instead of a panic on a bad boundary
+    fn segments(content: &str) -> Option<Vec<Segment>> {
+        let mut segments = Vec::new();
         let mut last_end = 0;
 
         // Find code blocks using markdown syntax ```
         for (block_range, language) in Self::find_code_blocks(content) {
-            // Render text before code block
-            if last_end < block_range.start {
-                ui.label(RichText::new(&content[last_end..block_range.start]));
+            // skip invalid or overlapping ranges
+            if block_range.end <= block_range.start || block_range.start < last_end {
+                continue;
             }
 
-            // skip invalid range
-            if block_range.end <= block_range.start {
-                continue;
+            // text before the code block
+            if last_end < block_range.start {
+                segments.push(Segment::Text(content.get(last_end..block_range.start)?.to_string()));
             }
 
-            // Render code block with special formatting
-            let code_content =
-                ChatRenderer::extract_code(&content[block_range.clone()], language.as_deref());
-            ChatRenderer::render_highlighted_code(ui, &code_content, language.as_deref(), true);
+            segments.push(Segment::Code {
+                start: block_range.start,
+                code: Self::extract_code(content.get(block_range.clone())?),
+                language,
+            });
             last_end = block_range.end;
         }
 
-        // Render remaining text after last code block
+        // remaining text after the last code block
         if last_end < content.len() {
-            ui.label(RichText::new(&content[last_end..]));
+            segments.push(Segment::Text(content.get(last_end..)?.to_string()));
         }
+
+        Some(segments)
     }
 
     /// Find code blocks in the message content
@@ -131,10 +504,9 @@ impl ChatRenderer {
 
         for &(line_start, line_end) in line_positions.iter() {
             // Extract line safely
-            let line = if line_end > line_start {
-                &content[line_start..line_end]
-            } else {
-                continue; // Skip empty lines to avoid range errors
+            // Skip empty lines and, defensively, anything off a char boundary
+            let Some(line) = content.get(line_start..line_end).filter(|line| !line.is_empty()) else {
+                continue;
             };
 
             let line_trimmed = line.trim();
@@ -145,6 +517,7 @@ impl ChatRenderer {
                     start_idx = line_start;
 
                     // Extract language if specified (safely)
+                    // trim also drops a lone \r, so "rust\r" can't reach the highlighter
                     let lang = match line_trimmed.strip_prefix("```") {
                         Some(remainder) => remainder.trim(),
                         None => "",
@@ -174,23 +547,230 @@ impl ChatRenderer {
         blocks
     }
 
-    fn extract_code(text: &str, lang: Option<&str>) -> String {
-        // Find the start marker position
-        let start_marker = format!("```{}", lang.unwrap_or(""));
-        let start_pos = match text.find(&start_marker) {
-            Some(pos) => pos + start_marker.len(),
-            None => return String::new(), // Start marker not found
-        };
+    /// Expand tabs to `tab_width` columns and drop trailing whitespace,
+    /// for display only, copying keeps the original text
+    fn normalize_for_display(code: &str, tab_width: usize) -> String {
+        let mut normalized = String::with_capacity(code.len());
+        for (idx, line) in code.lines().enumerate() {
+            if idx > 0 {
+                normalized.push('\n');
+            }
+            let mut column = 0;
+            for c in line.trim_end().chars() {
+                if c == '\t' {
+                    let spaces = tab_width - column % tab_width;
+                    normalized.extend(std::iter::repeat_n(' ', spaces));
+                    column += spaces;
+                } else {
+                    normalized.push(c);
+                    column += 1;
+                }
+            }
+        }
+        normalized
+    }
+
+    /// Indented version of a JSON block, None when the block isn't valid JSON
+    /// or is already formatted. Key order and number formatting are kept as is
+    fn pretty_json(code: &str, language: Option<&str>) -> Option<String> {
+        let code = code.trim();
+        let is_json = language.is_some_and(|lang| lang.eq_ignore_ascii_case("json"));
+        if !(is_json || code.starts_with('{') || code.starts_with('[')) {
+            return None;
+        }
+        serde_json::from_str::<serde::de::IgnoredAny>(code).ok()?;
 
-        // Find the end marker position
-        let end_marker = "```";
-        let end_pos = match text[start_pos..].find(end_marker) {
-            Some(pos) => start_pos + pos,
-            None => text.len(), // Take everything if there is no end mark
+        let mut pretty = String::with_capacity(code.len() * 2);
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        let newline = |pretty: &mut String, depth: usize| {
+            pretty.push('\n');
+            pretty.push_str(&"  ".repeat(depth));
         };
 
-        // Extract and trim the content between markers
-        text[start_pos..end_pos].trim().to_string()
+        let mut chars = code.chars().peekable();
+        while let Some(c) = chars.next() {
+            if in_string {
+                pretty.push(c);
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '"' => {
+                    in_string = true;
+                    pretty.push(c);
+                }
+                '{' | '[' => {
+                    pretty.push(c);
+                    // keep empty objects and arrays on one line
+                    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                        chars.next();
+                    }
+                    if matches!(chars.peek(), Some('}' | ']')) {
+                        pretty.push(chars.next().unwrap_or_default());
+                    } else {
+                        depth += 1;
+                        newline(&mut pretty, depth);
+                    }
+                }
+                '}' | ']' => {
+                    depth = depth.saturating_sub(1);
+                    newline(&mut pretty, depth);
+                    pretty.push(c);
+                }
+                ',' => {
+                    pretty.push(c);
+                    newline(&mut pretty, depth);
+                }
+                ':' => pretty.push_str(": "),
+                c if c.is_whitespace() => {}
+                c => pretty.push(c),
+            }
+        }
+
+        (pretty != code).then_some(pretty)
+    }
+
+    /// Code of a fenced block: the lines between the opening fence
+    /// and the closing one, if the block is closed
+    fn extract_code(text: &str) -> String {
+        // the language was already read from the opening fence line
+        let body = text.split_once('\n').map_or("", |(_, body)| body);
+
+        let mut lines: Vec<&str> = body.lines().collect();
+        if lines.last().is_some_and(|line| line.trim_start().starts_with("```")) {
+            lines.pop();
+        }
+
+        // keep the first line's indentation, only drop surrounding blank lines
+        lines.join("\n").trim_start_matches(['\n', '\r']).trim_end().to_string()
     }
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Where each segment starts in `content`, checking they tile it: the
+    /// first starts at 0, each text is the content up to the next piece
+    /// and each code block starts on its fence
+    fn check_tiling(content: &str, segments: &[Segment]) {
+        if content.is_empty() {
+            assert!(segments.is_empty());
+            return;
+        }
+        // a text runs up to the next code block, or to the end
+        let mut end = content.len();
+        let mut starts = vec![0; segments.len()];
+        for (idx, segment) in segments.iter().enumerate().rev() {
+            starts[idx] = match segment {
+                Segment::Text(text) => {
+                    let start = end.checked_sub(text.len()).expect("text longer than what's left");
+                    assert_eq!(content.get(start..end), Some(text.as_str()));
+                    start
+                }
+                Segment::Code { start, .. } => {
+                    let block = content.get(*start..end).expect("code block on char boundaries");
+                    assert!(block.trim_start().starts_with("```"), "block without a fence: {:?}", block);
+                    *start
+                }
+            };
+            end = starts[idx];
+        }
+        assert_eq!(starts.first(), Some(&0), "pieces cover the start of {:?}", content);
+        assert!(starts.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    /// Text made of fences, line breaks and multibyte characters, the
+    /// pieces the segmentation has to cut between
+    fn fenced_text() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            Just("```".to_string()),
+            Just("```rust".to_string()),
+            Just("```日本語".to_string()),
+            Just("\n".to_string()),
+            Just("\r\n".to_string()),
+            Just("  ".to_string()),
+            Just("é".to_string()),
+            Just("🦀".to_string()),
+            "\\PC{0,6}",
+        ];
+        prop::collection::vec(piece, 0..40).prop_map(|pieces| pieces.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn segments_tile_fenced_text(content in fenced_text()) {
+            let segments = ChatRenderer::segments(&content).expect("segments of valid UTF-8");
+            check_tiling(&content, &segments);
+        }
+
+        #[test]
+        fn segments_tile_any_text(content in any::<String>()) {
+            let segments = ChatRenderer::segments(&content).expect("segments of valid UTF-8");
+            check_tiling(&content, &segments);
+        }
+    }
+
+    #[test]
+    fn multibyte_fence_and_emoji_before_it() {
+        let content = "🦀\n```日本語\nlet x = 1;\n```\nafter";
+        let segments = ChatRenderer::segments(content).unwrap();
+        check_tiling(content, &segments);
+        assert!(matches!(
+            &segments[1],
+            Segment::Code { code, language, .. } if code == "let x = 1;" && language.as_deref() == Some("日本語")
+        ));
+    }
+
+    fn parsed_segments(content: &str) -> Vec<Segment> {
+        match ChatRenderer::parse(content) {
+            ParsedContent::Segments(segments) => segments,
+            _ => panic!("not segmented: {:?}", content),
+        }
+    }
+
+    #[test]
+    fn crlf_fences_language_and_code() {
+        let content = "Before:\r\n```rust\r\nfn main() {\r\n    println!();\r\n}\r\n```\r\nAfter\r\n";
+        let segments = parsed_segments(content);
+        assert_eq!(segments.len(), 3);
+        assert!(matches!(&segments[0], Segment::Text(text) if text == "Before:\n"));
+        assert!(matches!(
+            &segments[1],
+            Segment::Code { code, language, .. }
+                if code == "fn main() {\n    println!();\n}" && language.as_deref() == Some("rust")
+        ));
+        assert!(matches!(&segments[2], Segment::Text(text) if text == "After\n"));
+    }
+
+    #[test]
+    fn crlf_matches_lf() {
+        let lf = "Two blocks\n```\nplain\n```\ntext\n```py\nx = 1\n```";
+        let crlf = lf.replace('\n', "\r\n");
+        let (lf, crlf) = (parsed_segments(lf), parsed_segments(&crlf));
+        assert_eq!(lf.len(), crlf.len());
+        for (lf, crlf) in lf.iter().zip(&crlf) {
+            match (lf, crlf) {
+                (Segment::Text(lf), Segment::Text(crlf)) => assert_eq!(lf, crlf),
+                (
+                    Segment::Code { code, language, .. },
+                    Segment::Code { code: crlf_code, language: crlf_language, .. },
+                ) => {
+                    assert_eq!(code, crlf_code);
+                    assert_eq!(language, crlf_language);
+                }
+                _ => panic!("segments differ"),
+            }
+        }
+        assert!(ChatRenderer::spoken_text("```\r\na\r\nb\r\n```").contains("Code block, 2 lines."));
+    }
+}
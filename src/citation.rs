@@ -0,0 +1,99 @@
+use std::borrow::Cow;
+
+use crate::api::{Citation, Message};
+use crate::attachment::Attachment;
+
+/// Characters of the document shown on each side of a quoted passage
+const EXCERPT_CONTEXT_CHARS: usize = 300;
+
+const SUPERSCRIPT_DIGITS: [char; 10] = [
+    '\u{2070}', '\u{00B9}', '\u{00B2}', '\u{00B3}', '\u{2074}',
+    '\u{2075}', '\u{2076}', '\u{2077}', '\u{2078}', '\u{2079}',
+];
+
+/// A number written in superscript digits, like a footnote marker
+pub fn superscript(number: usize) -> String {
+    number
+        .to_string()
+        .bytes()
+        .map(|digit| SUPERSCRIPT_DIGITS[(digit - b'0') as usize])
+        .collect()
+}
+
+/// The text with a marker after each cited passage. Citations are numbered
+/// from 1 in their order, `marker` writes the number
+pub fn with_markers<'a>(content: &'a str, citations: &[Citation], marker: impl Fn(usize) -> String) -> Cow<'a, str> {
+    if citations.is_empty() {
+        return Cow::Borrowed(content);
+    }
+    let mut numbered: Vec<(usize, usize)> = citations
+        .iter()
+        .enumerate()
+        .map(|(idx, citation)| {
+            // offsets past a later edit of the text, e.g. trimmed whitespace,
+            // go to the nearest character before
+            let mut offset = citation.offset.min(content.len());
+            while !content.is_char_boundary(offset) {
+                offset -= 1;
+            }
+            (offset, idx + 1)
+        })
+        .collect();
+    numbered.sort();
+
+    let mut marked = String::with_capacity(content.len() + numbered.len() * 4);
+    let mut last = 0;
+    for (offset, number) in numbered {
+        marked.push_str(&content[last..offset]);
+        marked.push_str(&marker(number));
+        last = offset;
+    }
+    marked.push_str(&content[last..]);
+    Cow::Owned(marked)
+}
+
+/// The attachment a citation of the message at `idx` quotes: the latest
+/// one with its title in the messages up to it
+pub fn cited_document<'a>(messages: &'a [Message], idx: usize, citation: &Citation) -> Option<&'a Attachment> {
+    let title = citation.document_title.as_deref()?;
+    messages
+        .get(..=idx)?
+        .iter()
+        .rev()
+        .flat_map(|message| message.attachments.iter().rev())
+        .find(|attachment| attachment.name == title)
+}
+
+/// The quoted passage with some of the document around it: the text
+/// before, the passage and the text after. Without the document, or when
+/// the passage isn't found in it, only the cited text
+pub fn excerpt(document: Option<&str>, citation: &Citation) -> (String, String, String) {
+    let quoted_only = || (String::new(), citation.cited_text.clone(), String::new());
+    let Some(document) = document else {
+        return quoted_only();
+    };
+    let chars: Vec<char> = document.chars().collect();
+    let range = match (citation.start_char_index, citation.end_char_index) {
+        (Some(start), Some(end)) if start < end && end <= chars.len() => start..end,
+        // the indices are missing or don't fit, look for the text instead
+        _ => match document.find(citation.cited_text.as_str()) {
+            Some(byte_start) if !citation.cited_text.is_empty() => {
+                let start = document[..byte_start].chars().count();
+                start..start + citation.cited_text.chars().count()
+            }
+            _ => return quoted_only(),
+        },
+    };
+
+    let from = range.start.saturating_sub(EXCERPT_CONTEXT_CHARS);
+    let to = (range.end + EXCERPT_CONTEXT_CHARS).min(chars.len());
+    let mut before: String = chars[from..range.start].iter().collect();
+    if from > 0 {
+        before.insert(0, '\u{2026}');
+    }
+    let mut after: String = chars[range.end..to].iter().collect();
+    if to < chars.len() {
+        after.push('\u{2026}');
+    }
+    (before, chars[range].iter().collect(), after)
+}
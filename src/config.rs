@@ -1,15 +1,200 @@
 use anyhow::{Context, Result};
 use log::info;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use crate::api::{check_extra_header, HttpOptions, DEFAULT_BASE_URL};
+use crate::mirror::MirrorOptions;
+use crate::voice::TranscriptionOptions;
+
+/// File next to the executable that turns on portable mode
+const PORTABLE_MARKER: &str = "clauchat.portable";
+
+/// Model used when neither the profile nor `default_model` names one
+pub const DEFAULT_MODEL: &str = "claude-3-7-sonnet-20250219";
+
+/// Profile a config from before profiles becomes
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Stands in for a missing active profile
+static EMPTY_PROFILE: Profile = Profile {
+    api_key: String::new(),
+    model: String::new(),
+    system_prompt: String::new(),
+    temperature: None,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    /// key of a config from before profiles, moved to the default profile on load
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub api_key: String,
+    /// named setups picked in the header, e.g. a personal and a work key
+    pub profiles: BTreeMap<String, Profile>,
+    /// profile in use, remembered across runs
+    pub active_profile: String,
     pub theme: Theme,
+    /// UI language code like "fr", empty to follow the system locale
+    pub language: String,
     pub font_size: f32,
+    /// disable animations and use static indicators instead
+    pub reduce_motion: bool,
+    /// how a response's text shows up while it streams
+    pub response_reveal: ResponseReveal,
+    /// show character/word/line counts next to the input
+    pub show_input_stats: bool,
+    /// show messages as their raw markdown instead of rendered
+    pub show_raw_markdown: bool,
+    /// show the model, request phase and session totals below the composer
+    pub show_status_bar: bool,
+    /// read each response aloud once it completes, needs the tts feature
+    pub auto_read_responses: bool,
+    /// proxy for all requests, empty to use the HTTP(S)_PROXY env vars
+    pub proxy_url: String,
+    /// Anthropic-compatible API server, empty for the official endpoint
+    pub base_url: String,
+    /// sent joined in the anthropic-beta header of message requests, e.g.
+    /// "prompt-caching-2024-07-31". Blank ones are ignored
+    pub beta_flags: Vec<String>,
+    /// headers added to every request, e.g. a gateway's key or an org id
+    pub extra_headers: BTreeMap<String, String>,
+    /// pastes with more lines than this become an attachment
+    pub paste_attachment_lines: usize,
+    /// pastes with more characters than this become an attachment
+    pub paste_attachment_chars: usize,
+    /// accept self-signed/invalid TLS certificates, dev and internal gateways only
+    pub danger_accept_invalid_certs: bool,
+    /// tries at fetching the pricing table on startup before using the built-in one
+    pub pricing_fetch_attempts: usize,
+    /// OpenAI-compatible transcription endpoint for dictation, voice feature only
+    pub transcription_url: String,
+    pub transcription_api_key: String,
+    pub transcription_model: String,
+    /// local ggml whisper model, used instead of the endpoint with the whisper feature
+    pub whisper_model_path: String,
+    /// seconds a sent message waits before the request goes out, so it can
+    /// be taken back, 0 to send right away
+    pub send_grace_secs: u64,
+    /// ask before sending when the estimated input cost of a turn is over
+    /// this many dollars, 0 to never ask
+    pub cost_warning_threshold: f64,
+    /// ask before sending the message just sent again, when it wasn't
+    /// answered in between
+    pub confirm_duplicate_send: bool,
+    /// sending shows the request and its estimated cost instead of
+    /// calling the API
+    pub dry_run: bool,
+    /// abort a response when nothing, pings included, arrives for this long
+    pub stream_stall_timeout_secs: u64,
+    /// times a request is sent again while the model is overloaded
+    pub overloaded_retries: u32,
+    /// models tried in order when the selected one stays overloaded, none by default
+    pub fallback_models: Vec<String>,
+    /// responses streamed at once across conversations, later ones wait
+    /// for a slot. Read at startup
+    pub max_concurrent_requests: usize,
+    /// only the last this many messages are sent with a request, 0 to send them all
+    pub context_max_messages: usize,
+    /// older messages are left out once the history and the input are over
+    /// this many tokens, 0 for no limit
+    pub context_token_budget: usize,
+    /// when the prompt is over the context window, send it again right
+    /// away without the oldest messages
+    pub auto_trim_prompt: bool,
+    /// append every request and response to logs/transcript.jsonl, they
+    /// may contain sensitive content
+    pub log_transcripts: bool,
+    /// append the tokens and cost of each response to usage.jsonl
+    pub log_usage: bool,
+    /// ask `title_model` for a short title after a conversation's first
+    /// answer, it costs a little
+    pub auto_title: bool,
+    /// cheap model writing the titles
+    pub title_model: String,
+    /// model of a profile that hasn't picked one, empty for the built-in default
+    pub default_model: String,
+    /// append each completed response to this file, empty for none
+    pub mirror_path: String,
+    /// `mirror_path` is a folder with a file per conversation
+    pub mirror_per_conversation: bool,
+    /// write responses to the mirror as they stream, for `tail -f`
+    pub mirror_live: bool,
+    /// the transcript log is rotated past this size
+    pub transcript_log_max_bytes: u64,
+    /// transcript log files kept, the one being written included
+    pub transcript_log_files: usize,
+    /// level of the app's own logs: error, warn, info, debug or trace.
+    /// RUST_LOG overrides it
+    pub log_level: String,
+    /// also write the logs to logs/clauchat.log, replaced at each start
+    pub log_to_file: bool,
+    /// strip the whitespace at the end of each line of a completed response
+    pub trim_trailing_whitespace: bool,
+    /// columns a tab expands to in code blocks
+    pub tab_width: usize,
+    /// tab width for specific code block languages, e.g. {"go": 8}
+    pub language_tab_widths: BTreeMap<String, usize>,
+    /// code blocks larger than this many bytes are shown without highlighting
+    pub highlight_max_bytes: usize,
+    /// code blocks with a line longer than this, e.g. minified code, are shown without highlighting
+    pub highlight_max_line_chars: usize,
+    /// window and panel sizes, saved as they change
+    pub ui_layout: UiLayout,
+}
+
+/// Layout of the window kept across runs
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct UiLayout {
+    /// inner size of the window, in points
+    pub window_size: [f32; 2],
+    pub sidebar_open: bool,
+    pub sidebar_width: f32,
+    /// the conversation summary above the composer is expanded
+    pub summary_open: bool,
+}
+
+impl Default for UiLayout {
+    fn default() -> Self {
+        Self {
+            window_size: [800.0, 600.0],
+            sidebar_open: true,
+            sidebar_width: 180.0,
+            summary_open: false,
+        }
+    }
+}
+
+impl UiLayout {
+    /// The window can't be made smaller than this
+    pub const MIN_WINDOW_SIZE: [f32; 2] = [640.0, 480.0];
+    const MAX_WINDOW_SIZE: f32 = 16_384.0;
+    const SIDEBAR_WIDTH_RANGE: (f32, f32) = (100.0, 800.0);
+
+    /// The layout with values out of range, e.g. from a hand edit, back
+    /// to their defaults so the window stays usable
+    pub fn clamped(&self) -> Self {
+        let default = Self::default();
+        let within = |value: f32, min: f32, max: f32| value.is_finite() && (min..=max).contains(&value);
+        let window_fits = (0..2).all(|axis| {
+            within(self.window_size[axis], Self::MIN_WINDOW_SIZE[axis], Self::MAX_WINDOW_SIZE)
+        });
+        let (min_width, max_width) = Self::SIDEBAR_WIDTH_RANGE;
+        Self {
+            window_size: if window_fits { self.window_size } else { default.window_size },
+            sidebar_open: self.sidebar_open,
+            sidebar_width: if within(self.sidebar_width, min_width, max_width) {
+                self.sidebar_width
+            } else {
+                default.sidebar_width
+            },
+            summary_open: self.summary_open,
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -18,31 +203,276 @@ pub enum Theme {
     Light,
 
     #[serde(rename = "dark")]
-    #[default]
     Dark,
+
+    /// follow the OS appearance, dark when it's unknown
+    #[serde(rename = "system")]
+    #[default]
+    System,
+}
+
+/// How a response's text shows up while it streams
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseReveal {
+    /// as it arrives
+    #[default]
+    Live,
+    /// refreshed once per second
+    EverySecond,
+    /// all at once when the response is over, a progress indicator until then
+    WhenComplete,
+}
+
+/// Key, model and request settings switched together
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub api_key: String,
+    /// model selected last with this profile, empty for the app's default
+    pub model: String,
+    /// sent as the system prompt of every request, empty for none
+    pub system_prompt: String,
+    /// sampling temperature, None for the API's default
+    pub temperature: Option<f32>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             api_key: String::new(),
+            profiles: BTreeMap::from([(DEFAULT_PROFILE.to_string(), Profile::default())]),
+            active_profile: DEFAULT_PROFILE.to_string(),
             theme: Theme::default(),
+            language: String::new(),
             font_size: 16.0,
+            reduce_motion: false,
+            response_reveal: ResponseReveal::Live,
+            show_input_stats: false,
+            show_raw_markdown: false,
+            show_status_bar: true,
+            auto_read_responses: false,
+            proxy_url: String::new(),
+            base_url: String::new(),
+            beta_flags: Vec::new(),
+            extra_headers: BTreeMap::new(),
+            paste_attachment_lines: 200,
+            paste_attachment_chars: 10_000,
+            danger_accept_invalid_certs: false,
+            pricing_fetch_attempts: 4,
+            transcription_url: String::new(),
+            transcription_api_key: String::new(),
+            transcription_model: "whisper-1".to_string(),
+            whisper_model_path: String::new(),
+            send_grace_secs: 3,
+            cost_warning_threshold: 0.5,
+            confirm_duplicate_send: true,
+            dry_run: false,
+            stream_stall_timeout_secs: 60,
+            overloaded_retries: 2,
+            fallback_models: Vec::new(),
+            max_concurrent_requests: 3,
+            context_max_messages: 0,
+            context_token_budget: 0,
+            auto_trim_prompt: false,
+            log_transcripts: false,
+            log_usage: false,
+            auto_title: false,
+            title_model: "claude-3-5-haiku-latest".to_string(),
+            default_model: String::new(),
+            mirror_path: String::new(),
+            mirror_per_conversation: false,
+            mirror_live: false,
+            trim_trailing_whitespace: false,
+            transcript_log_max_bytes: 5_000_000,
+            transcript_log_files: 3,
+            log_level: "info".to_string(),
+            log_to_file: false,
+            tab_width: 4,
+            language_tab_widths: BTreeMap::new(),
+            highlight_max_bytes: 100_000,
+            highlight_max_line_chars: 5_000,
+            ui_layout: UiLayout::default(),
         }
     }
 }
 
 impl Config {
+    pub fn transcription_options(&self) -> TranscriptionOptions {
+        TranscriptionOptions {
+            whisper_model_path: self.whisper_model_path.clone(),
+            url: self.transcription_url.clone(),
+            api_key: self.transcription_api_key.clone(),
+            model: self.transcription_model.clone(),
+        }
+    }
+
+    /// Model a profile starts with, `default_model` or the built-in one
+    pub fn default_model(&self) -> String {
+        match self.default_model.trim() {
+            "" => DEFAULT_MODEL.to_string(),
+            model => model.to_string(),
+        }
+    }
+
+    /// Where to mirror responses, None when it's off
+    pub fn mirror_options(&self) -> Option<MirrorOptions> {
+        let path = self.mirror_path.trim();
+        (!path.is_empty()).then(|| MirrorOptions {
+            path: PathBuf::from(path),
+            per_conversation: self.mirror_per_conversation,
+            live: self.mirror_live,
+        })
+    }
+
+    /// Tab width for a code block, the language override if there is one
+    pub fn tab_width_for(&self, language: Option<&str>) -> usize {
+        language
+            .and_then(|lang| self.language_tab_widths.get(&lang.to_lowercase()))
+            .copied()
+            .unwrap_or(self.tab_width)
+            .max(1)
+    }
+
+    /// Whether a code block is small enough to run the syntax highlighter on
+    pub fn should_highlight(&self, code: &str) -> bool {
+        code.len() <= self.highlight_max_bytes
+            && code.lines().all(|line| line.len() <= self.highlight_max_line_chars)
+    }
+
+    /// Level of the app's logs, info when the setting isn't a level name
+    pub fn log_level_filter(&self) -> log::LevelFilter {
+        self.log_level.parse().unwrap_or(log::LevelFilter::Info)
+    }
+
+    /// Switch the app's logs to the configured level, unless RUST_LOG decides
+    pub fn apply_log_level(&self) {
+        if std::env::var_os("RUST_LOG").is_none() {
+            log::set_max_level(self.log_level_filter());
+        }
+    }
+
+    pub fn api_base_url(&self) -> &str {
+        match self.base_url.trim() {
+            "" => DEFAULT_BASE_URL,
+            base_url => base_url,
+        }
+    }
+
+    pub fn http_options(&self) -> HttpOptions {
+        let proxy_url = self.proxy_url.trim();
+        HttpOptions {
+            proxy_url: (!proxy_url.is_empty()).then(|| proxy_url.to_string()),
+            danger_accept_invalid_certs: self.danger_accept_invalid_certs,
+        }
+    }
+
+    /// The profile in use
+    pub fn profile(&self) -> &Profile {
+        self.profiles.get(&self.active_profile).unwrap_or(&EMPTY_PROFILE)
+    }
+
+    pub fn profile_mut(&mut self) -> &mut Profile {
+        self.profiles.entry(self.active_profile.clone()).or_default()
+    }
+
+    /// Profile named by `--profile <name>` on the command line
+    pub fn profile_arg() -> Option<String> {
+        let mut args = std::env::args().skip(1);
+        args.find(|arg| arg == "--profile")?;
+        args.next()
+    }
+
+    /// Move the key of a config from before profiles to the default
+    /// profile, and fall back to an existing profile if the active one is gone
+    fn migrate_profiles(&mut self) {
+        let legacy_key = std::mem::take(&mut self.api_key);
+        if !legacy_key.is_empty() {
+            let profile = self.profiles.entry(DEFAULT_PROFILE.to_string()).or_default();
+            if profile.api_key.is_empty() {
+                info!("Moving the API key to the {} profile", DEFAULT_PROFILE);
+                profile.api_key = legacy_key;
+            }
+        }
+        if self.profiles.is_empty() {
+            self.profiles.insert(DEFAULT_PROFILE.to_string(), Profile::default());
+        }
+        if !self.profiles.contains_key(&self.active_profile) {
+            self.active_profile = self.profiles.keys().next().cloned().unwrap_or_default();
+        }
+    }
+
+    /// Directory of the executable when running in portable mode, i.e. with
+    /// a `clauchat.portable` file next to it or the `--portable` flag
+    pub fn portable_dir() -> Option<PathBuf> {
+        Self::portable_dir_for(Self::exe_dir()?, std::env::args().skip(1))
+    }
+
+    fn exe_dir() -> Option<PathBuf> {
+        std::env::current_exe().ok()?.parent().map(Path::to_path_buf)
+    }
+
+    /// `portable_dir` for an executable directory and the arguments after
+    /// the program name
+    fn portable_dir_for(exe_dir: PathBuf, mut args: impl Iterator<Item = String>) -> Option<PathBuf> {
+        let portable = args.any(|arg| arg == "--portable") || exe_dir.join(PORTABLE_MARKER).exists();
+        portable.then_some(exe_dir)
+    }
+
+    /// Where everything the app persists lives: next to the executable in
+    /// portable mode, else the user's config directory. Created if missing
+    pub fn data_dir() -> Result<PathBuf> {
+        let data_dir = Self::data_dir_for(Self::exe_dir(), std::env::args().skip(1), dirs::config_dir())?;
+
+        if !data_dir.exists() {
+            fs::create_dir_all(&data_dir).context("Failed to create config dir")?;
+        }
+
+        Ok(data_dir)
+    }
+
+    /// `data_dir` for an executable directory, the arguments after the
+    /// program name and the user's config directory, without creating it
+    fn data_dir_for(
+        exe_dir: Option<PathBuf>,
+        args: impl Iterator<Item = String>,
+        config_dir: Option<PathBuf>,
+    ) -> Result<PathBuf> {
+        match exe_dir.and_then(|exe_dir| Self::portable_dir_for(exe_dir, args)) {
+            Some(exe_dir) => Ok(exe_dir),
+            None => Ok(config_dir.context("Could not find config directory")?.join("clauchat")),
+        }
+    }
+
+    /// Where the app and transcript logs are written
+    pub fn logs_dir() -> Result<PathBuf> {
+        let logs_dir = Self::data_dir()?.join("logs");
+        if !logs_dir.exists() {
+            fs::create_dir_all(&logs_dir).context("Failed to create the logs dir")?;
+        }
+        Ok(logs_dir)
+    }
+
     pub fn config_path() -> Result<PathBuf> {
-        let config_dir = dirs::config_dir()
-            .context("Could not find config directory")?
-            .join("clauchat");
+        Ok(Self::data_dir()?.join("config.json"))
+    }
+
+    /// Where exported conversations are written, under the documents folder if there is one,
+    /// or in an exports folder of the data dir in portable mode
+    pub fn exports_dir() -> Result<PathBuf> {
+        let exports_dir = match Self::portable_dir() {
+            Some(_) => Self::data_dir()?.join("exports"),
+            None => dirs::document_dir()
+                .or_else(dirs::data_dir)
+                .context("Could not find a documents directory")?
+                .join("clauchat"),
+        };
 
-        if !config_dir.exists() {
-            fs::create_dir_all(&config_dir).context("Failed to create config dir")?;
+        if !exports_dir.exists() {
+            fs::create_dir_all(&exports_dir).context("Failed to create exports dir")?;
         }
 
-        Ok(config_dir.join("config.json"))
+        Ok(exports_dir)
     }
 
     pub fn load() -> Result<Self> {
@@ -51,21 +481,99 @@ impl Config {
             Ok(Self::default())
         } else {
             let config_file = File::open(&config_path).context("Failed to open config file")?;
-            let config =
+            let mut config: Self =
                 serde_json::from_reader(config_file).context("Could not deserialize config")?;
+            config.migrate_profiles();
+            config.ui_layout = config.ui_layout.clamped();
             info!("Configuration loaded from {}", config_path.display());
             Ok(config)
         }
     }
 
+    /// Write the config through a temporary file renamed over it, so an
+    /// interrupted save leaves the previous config whole
     pub fn save(&self) -> Result<()> {
+        for (name, value) in &self.extra_headers {
+            // the whole error is shown, the cause says which header
+            check_extra_header(name, value)
+                .map_err(|e| anyhow::anyhow!("{:#}, fix extra_headers in the config file", e))?;
+        }
         let config_path = Self::config_path()?;
+        let tmp_path = config_path.with_extension("json.tmp");
         let json = serde_json::to_string_pretty(self).context("Failed to serialize config")?;
-        let mut file = File::create(&config_path)?;
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("Could not write {}", tmp_path.display()))?;
         file.write_all(json.as_bytes())
             .context("Failed to write to file")?;
+        // on disk before the rename, or a crash could leave an empty config
+        file.sync_all().context("Failed to write to file")?;
+        drop(file);
+        fs::rename(&tmp_path, &config_path)
+            .with_context(|| format!("Could not write {}", config_path.display()))?;
         info!("Configuration saved to {}", config_path.display());
         Ok(())
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn portable_with_the_flag() {
+        let exe_dir = tempfile::tempdir().unwrap();
+        let data_dir = Config::data_dir_for(
+            Some(exe_dir.path().to_path_buf()),
+            args(&["--verbose", "--portable"]),
+            Some(PathBuf::from("/home/user/.config")),
+        );
+        assert_eq!(data_dir.unwrap(), exe_dir.path());
+    }
+
+    #[test]
+    fn portable_with_the_marker() {
+        let exe_dir = tempfile::tempdir().unwrap();
+        File::create(exe_dir.path().join(PORTABLE_MARKER)).unwrap();
+        // the user's config directory isn't needed
+        let data_dir = Config::data_dir_for(Some(exe_dir.path().to_path_buf()), args(&[]), None);
+        assert_eq!(data_dir.unwrap(), exe_dir.path());
+    }
+
+    #[test]
+    fn installed_uses_the_config_dir() {
+        let exe_dir = tempfile::tempdir().unwrap();
+        let config_dir = PathBuf::from("/home/user/.config");
+        let data_dir = Config::data_dir_for(
+            Some(exe_dir.path().to_path_buf()),
+            args(&["--verbose"]),
+            Some(config_dir.clone()),
+        );
+        assert_eq!(data_dir.unwrap(), config_dir.join("clauchat"));
+        // nor is the executable's directory
+        let data_dir = Config::data_dir_for(None, args(&["--portable"]), Some(config_dir.clone()));
+        assert_eq!(data_dir.unwrap(), config_dir.join("clauchat"));
+        assert!(Config::data_dir_for(Some(exe_dir.path().to_path_buf()), args(&[]), None).is_err());
+    }
+
+    #[test]
+    fn summary_state_survives_a_reload() {
+        // a config saved before the field existed
+        let layout: UiLayout = serde_json::from_str(r#"{"sidebar_open":false,"sidebar_width":250.0}"#).unwrap();
+        assert!(!layout.summary_open);
+
+        let layout = UiLayout {
+            summary_open: true,
+            sidebar_width: 5_000.0,
+            ..UiLayout::default()
+        };
+        let reloaded: UiLayout = serde_json::from_str(&serde_json::to_string(&layout).unwrap()).unwrap();
+        let reloaded = reloaded.clamped();
+        assert!(reloaded.summary_open);
+        assert_eq!(reloaded.sidebar_width, UiLayout::default().sidebar_width);
+    }
+}
@@ -0,0 +1,356 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::api::{AppMessageDelta, Message, Role};
+use crate::config::Config;
+
+/// Characters of the first user message used as a title
+const TITLE_MAX_CHARS: usize = 40;
+
+/// Bytes at the start of a resumed response compared with the end of the
+/// partial text, a longer repeat isn't looked for
+pub const SEAM_WINDOW: usize = 200;
+/// Shortest repeat cut at the seam, shorter ones are likely on purpose
+const SEAM_MIN_OVERLAP: usize = 4;
+
+/// Seconds since the unix epoch
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// A response being streamed into a conversation's last message
+#[derive(Debug)]
+pub struct ResponseStream {
+    pub receiver: mpsc::Receiver<AppMessageDelta>,
+    /// stops the response
+    pub cancel: CancellationToken,
+    /// bytes of the streamed message that are the prefill, kept on rewrites
+    pub prefill_len: usize,
+    /// when the request actually goes out, until then it can be taken back
+    pub send_deadline: Option<Instant>,
+    /// when the request went out or will, after the grace period
+    pub started: Instant,
+    /// set while the request waits for a slot, others use them all
+    pub queued: Arc<AtomicBool>,
+    /// start of a resumed response, held back until it's long enough to
+    /// cut what repeats the end of the partial text
+    pub seam: Option<String>,
+    /// bytes cut from the start of a resumed response
+    pub seam_overlap: usize,
+    /// estimated tokens of this request's answer, the prefill aside, to
+    /// compare with max_tokens
+    pub output_tokens: usize,
+}
+
+/// A chat listed in the sidebar, saved as one JSON file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: String,
+    /// empty until the first user message names it
+    #[serde(default)]
+    pub title: String,
+    /// unix seconds of the last change, the sidebar lists newest first
+    #[serde(default)]
+    pub updated_at: u64,
+    pub messages: Vec<Message>,
+    /// indices of pinned messages
+    #[serde(default)]
+    pub pinned: BTreeSet<usize>,
+    /// unsent input, restored when the conversation is opened again
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub draft: String,
+    /// free-form labels the sidebar filters by, lowercase
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// turns replaced by a summary, kept so the summary can be reverted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub summarized_turns: Vec<Message>,
+    /// read only, nothing can be sent until it's unarchived
+    #[serde(default)]
+    pub archived: bool,
+    /// response on its way, it keeps streaming while another conversation is shown
+    #[serde(skip)]
+    pub stream: Option<ResponseStream>,
+}
+
+/// A copy doesn't take over the response being streamed
+impl Clone for Conversation {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            title: self.title.clone(),
+            updated_at: self.updated_at,
+            messages: self.messages.clone(),
+            pinned: self.pinned.clone(),
+            draft: self.draft.clone(),
+            tags: self.tags.clone(),
+            summarized_turns: self.summarized_turns.clone(),
+            archived: self.archived,
+            stream: None,
+        }
+    }
+}
+
+impl ResponseStream {
+    pub fn is_queued(&self) -> bool {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+impl Conversation {
+    pub fn new(id: String, messages: Vec<Message>) -> Self {
+        Self {
+            id,
+            title: String::new(),
+            updated_at: now_secs(),
+            messages,
+            pinned: BTreeSet::new(),
+            draft: String::new(),
+            tags: Vec::new(),
+            summarized_turns: Vec::new(),
+            archived: false,
+            stream: None,
+        }
+    }
+
+    /// Nothing was asked yet, only the greeting is there
+    pub fn is_blank(&self) -> bool {
+        !self.messages.iter().any(|message| message.role == Role::User)
+    }
+
+    /// Title from the first line of the first user message, if there is one
+    pub fn default_title(&self) -> Option<String> {
+        let first = self.messages.iter().find(|message| message.role == Role::User)?;
+        let line = first.content.lines().find(|line| !line.trim().is_empty())?.trim();
+        let mut title: String = line.chars().take(TITLE_MAX_CHARS).collect();
+        if line.chars().count() > TITLE_MAX_CHARS {
+            title.push('\u{2026}');
+        }
+        Some(title)
+    }
+}
+
+/// Title out of a model's answer: its first line without quotes, markdown
+/// or a final period, shortened like the default title. None when nothing's left
+pub fn clean_title(text: &str) -> Option<String> {
+    let line = text.lines().find(|line| !line.trim().is_empty())?;
+    let line = line
+        .trim()
+        .trim_start_matches(['#', ' '])
+        .trim_matches(['"', '\'', '*', '`'])
+        .trim_end_matches('.')
+        .trim();
+    if line.is_empty() {
+        return None;
+    }
+    let mut title: String = line.chars().take(TITLE_MAX_CHARS).collect();
+    if line.chars().count() > TITLE_MAX_CHARS {
+        title.push('\u{2026}');
+    }
+    Some(title)
+}
+
+/// Start of the messages to send so they fit in `budget` tokens, leaving
+/// out the oldest first. System messages are always sent and count against
+/// the budget, `tokens` has the size of each message. The kept history
+/// opens with a user turn. None when the last message alone doesn't fit
+pub fn fitting_context_start(messages: &[Message], tokens: &[usize], budget: usize) -> Option<usize> {
+    let sized = || messages.iter().zip(tokens.iter().copied());
+    let mut total: usize = sized()
+        .filter(|(message, _)| message.role == Role::System)
+        .map(|(_, tokens)| tokens)
+        .sum();
+
+    let mut start = None;
+    for (idx, (message, tokens)) in sized().enumerate().rev() {
+        if message.role == Role::System {
+            continue;
+        }
+        if total + tokens > budget {
+            break;
+        }
+        total += tokens;
+        start = Some(idx);
+    }
+
+    let mut start = start?;
+    while messages.get(start).is_some_and(|message| message.role != Role::User) {
+        start += 1;
+    }
+    (start < messages.len()).then_some(start)
+}
+
+/// Bytes at the start of `continuation` that repeat the end of `partial`,
+/// so a resumed response doesn't say them twice. Only a repeat of whole
+/// words counts
+pub fn seam_overlap(partial: &str, continuation: &str) -> usize {
+    let longest = SEAM_WINDOW.min(partial.len()).min(continuation.len());
+    (SEAM_MIN_OVERLAP..=longest)
+        .rev()
+        .filter(|&len| continuation.is_char_boundary(len))
+        .find(|&len| {
+            let repeat = &continuation[..len];
+            let Some(before) = partial.strip_suffix(repeat) else {
+                return false;
+            };
+            (repeat.starts_with(char::is_whitespace)
+                    || before.is_empty()
+                    || before.ends_with(char::is_whitespace))
+                && continuation[len..].chars().next().is_none_or(|next| !next.is_alphanumeric())
+        })
+        .unwrap_or(0)
+}
+
+/// Tag as stored: trimmed, lowercase, inner whitespace collapsed
+pub fn normalize_tag(tag: &str) -> String {
+    tag.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Id for a new conversation that no other conversation uses
+pub fn new_id(taken: impl Fn(&str) -> bool) -> String {
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or_default();
+    (millis..)
+        .map(|stamp| format!("{:x}", stamp))
+        .find(|id| !taken(id))
+        .unwrap_or_default()
+}
+
+/// Conversations saved as `<id>.json` files in the data directory
+pub struct ConversationStore {
+    dir: PathBuf,
+}
+
+impl ConversationStore {
+    pub fn open() -> Result<Self> {
+        let dir = Config::data_dir()?.join("conversations");
+        if !dir.exists() {
+            fs::create_dir_all(&dir).context("Failed to create conversations dir")?;
+        }
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Whether the conversation has a file yet
+    pub fn contains(&self, id: &str) -> bool {
+        self.path(id).exists()
+    }
+
+    /// Every readable conversation, unreadable files are skipped
+    pub fn load_all(&self) -> Result<Vec<Conversation>> {
+        let mut conversations = Vec::new();
+        for entry in fs::read_dir(&self.dir).context("Could not list saved conversations")? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let loaded = fs::read_to_string(&path)
+                .context("Failed to read conversation file")
+                .and_then(|json| {
+                    serde_json::from_str::<Conversation>(&json)
+                        .context("Could not deserialize conversation")
+                });
+            match loaded {
+                Ok(conversation) => conversations.push(conversation),
+                Err(e) => warn!("Skipping {}: {:#}", path.display(), e),
+            }
+        }
+        debug!("Loaded {} conversations", conversations.len());
+        Ok(conversations)
+    }
+
+    /// Write a conversation through a temporary file, so a failed
+    /// write leaves the previous version in place
+    pub fn save(&self, conversation: &Conversation) -> Result<()> {
+        let path = self.path(&conversation.id);
+        let tmp_path = path.with_extension("json.tmp");
+        let json = serde_json::to_string_pretty(conversation).context("Failed to serialize conversation")?;
+        fs::write(&tmp_path, json)
+            .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("Could not write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Remove a conversation's file, one that was never saved is fine
+    pub fn delete(&self, id: &str) -> Result<()> {
+        let path = self.path(id);
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("Could not delete {}", path.display())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A system prompt of 10 tokens, then turns of 100, 200, 50, 80 and 30:
+    /// 470 tokens in all
+    fn history() -> (Vec<Message>, Vec<usize>) {
+        let sized = [
+            (Role::System, 10),
+            (Role::User, 100),
+            (Role::Assistant, 200),
+            (Role::User, 50),
+            (Role::Assistant, 80),
+            (Role::User, 30),
+        ];
+        sized
+            .into_iter()
+            .map(|(role, tokens)| (Message::new(role, "text"), tokens))
+            .unzip()
+    }
+
+    #[test]
+    fn everything_fits() {
+        let (messages, tokens) = history();
+        assert_eq!(fitting_context_start(&messages, &tokens, 10_000), Some(1));
+        assert_eq!(fitting_context_start(&messages, &tokens, 470), Some(1));
+    }
+
+    #[test]
+    fn oldest_turns_go_first() {
+        let (messages, tokens) = history();
+        // one token short drops the first user turn, its answer goes with
+        // it so the kept history opens with a user turn
+        assert_eq!(fitting_context_start(&messages, &tokens, 469), Some(3));
+        assert_eq!(fitting_context_start(&messages, &tokens, 170), Some(3));
+        assert_eq!(fitting_context_start(&messages, &tokens, 169), Some(5));
+    }
+
+    #[test]
+    fn system_prompt_counts_against_the_budget() {
+        let (messages, tokens) = history();
+        assert_eq!(fitting_context_start(&messages, &tokens, 40), Some(5));
+        // the last message fits alone, not with the system prompt
+        assert_eq!(fitting_context_start(&messages, &tokens, 39), None);
+        assert_eq!(fitting_context_start(&messages, &tokens, 0), None);
+    }
+
+    #[test]
+    fn without_system_prompt() {
+        let (messages, tokens) = history();
+        assert_eq!(fitting_context_start(&messages[1..], &tokens[1..], 30), Some(4));
+        assert_eq!(fitting_context_start(&messages[1..], &tokens[1..], 29), None);
+        assert_eq!(fitting_context_start(&[], &[], 100), None);
+    }
+}
@@ -0,0 +1,693 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+/// Languages the UI is translated to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    English = 0,
+    French = 1,
+}
+
+impl Language {
+    pub const ALL: &'static [Language] = &[Language::English, Language::French];
+
+    /// Code stored in the config
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::French => "fr",
+        }
+    }
+
+    /// Name of the language, in that language
+    pub fn native_name(&self) -> &'static str {
+        match self {
+            Language::English => "English",
+            Language::French => "Français",
+        }
+    }
+
+    /// Language for a code like "fr" or a locale like "fr-FR" / "fr_FR.UTF-8"
+    pub fn from_code(code: &str) -> Option<Self> {
+        let prefix = code.split(['-', '_', '.']).next().unwrap_or_default().to_lowercase();
+        Self::ALL.iter().copied().find(|language| language.code() == prefix)
+    }
+
+    fn catalog(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Language::English => EN,
+            Language::French => FR,
+        }
+    }
+}
+
+static LANGUAGE: AtomicU8 = AtomicU8::new(Language::English as u8);
+
+/// Use the language of a config code, the system locale when it's empty,
+/// English when neither is translated
+pub fn set_language(code: &str) {
+    let language = if code.is_empty() {
+        sys_locale::get_locale().and_then(|locale| Language::from_code(&locale))
+    } else {
+        Language::from_code(code)
+    }
+    .unwrap_or(Language::English);
+    LANGUAGE.store(language as u8, Ordering::Relaxed);
+}
+
+pub fn language() -> Language {
+    match LANGUAGE.load(Ordering::Relaxed) {
+        1 => Language::French,
+        _ => Language::English,
+    }
+}
+
+fn catalogs() -> &'static [HashMap<&'static str, &'static str>] {
+    static CATALOGS: OnceLock<Vec<HashMap<&'static str, &'static str>>> = OnceLock::new();
+    CATALOGS.get_or_init(|| {
+        Language::ALL
+            .iter()
+            .map(|language| language.catalog().iter().copied().collect())
+            .collect()
+    })
+}
+
+/// UI string for a key in the current language. Falls back to English,
+/// then to the key itself, never panics
+pub fn tr(key: &'static str) -> &'static str {
+    let catalogs = catalogs();
+    catalogs[language() as usize]
+        .get(key)
+        .or_else(|| catalogs[Language::English as usize].get(key))
+        .copied()
+        .unwrap_or(key)
+}
+
+/// `tr` with each "{}" of the string replaced by the next argument
+pub fn tr_args(key: &'static str, args: &[&dyn Display]) -> String {
+    let mut args = args.iter();
+    let mut parts = tr(key).split("{}");
+    let mut text = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        if let Some(arg) = args.next() {
+            text.push_str(&arg.to_string());
+        }
+        text.push_str(part);
+    }
+    text
+}
+
+const EN: &[(&str, &str)] = &[
+    // header and settings
+    ("header.settings", "Settings"),
+    ("header.pinned", "\u{2605} Pinned"),
+    ("header.pinned_hint", "Show only pinned messages"),
+    ("model.no_info", "No model info yet"),
+    ("model.released", "Released: {}"),
+    ("model.cutoff", "Knowledge cutoff: {}"),
+    ("settings.title", "Settings"),
+    ("settings.pricing", "Pricing:"),
+    ("settings.pricing_line", "{}: ${} in / ${} out per M tokens ({})"),
+    ("settings.pricing_unknown", "unknown for this model"),
+    ("settings.profile", "Profile:"),
+    ("settings.profile_name_hint", "new profile"),
+    ("settings.profile_add", "Add"),
+    ("settings.profile_remove", "Remove"),
+    ("settings.system_prompt", "System prompt:"),
+    ("settings.system_prompt_hint", "sent with every request of this profile"),
+    ("settings.temperature", "Temperature"),
+    ("header.profile_hint", "Profile: key, model and system prompt"),
+    ("header.sidebar_hint", "Show or hide the conversation list"),
+    ("settings.api_key", "API Key:"),
+    ("settings.api_key_hint", "new API key, Enter to save"),
+    ("settings.api_key_missing", "not set"),
+    ("settings.api_key_change", "Change"),
+    ("settings.api_url", "API URL:"),
+    ("settings.beta_flags", "Beta features:"),
+    ("settings.beta_flags_hint", "Comma-separated, sent in the anthropic-beta header of every message request"),
+    ("settings.proxy", "Proxy:"),
+    ("settings.insecure_tls", "DANGER: accept invalid TLS certificates"),
+    (
+        "settings.insecure_tls_hint",
+        "Disables certificate verification for every request. Only use this with a trusted internal gateway.",
+    ),
+    ("settings.theme", "Theme:"),
+    ("settings.theme_light", "Light"),
+    ("settings.theme_dark", "Dark"),
+    ("settings.theme_system", "System"),
+    ("settings.language", "Language:"),
+    ("settings.language_system", "System"),
+    ("settings.reduce_motion", "Reduce motion"),
+    ("settings.response_reveal", "Show responses:"),
+    ("settings.reveal_live", "As they stream"),
+    ("settings.reveal_every_second", "Every second"),
+    ("settings.reveal_when_complete", "When complete"),
+    ("settings.input_stats", "Show input counts"),
+    ("settings.raw_markdown", "Show raw markdown"),
+    ("settings.status_bar", "Show status bar"),
+    ("settings.auto_read", "Read responses aloud"),
+    ("settings.font_size", "Font Size:"),
+    ("settings.tab_width", "Tab width:"),
+    ("settings.tab_width_hint", "Columns a tab takes in code blocks"),
+    ("settings.context", "Context:"),
+    ("settings.context_messages", "last messages"),
+    ("settings.context_messages_hint", "Only this many recent messages are sent, 0 to send them all"),
+    ("settings.context_tokens", "token budget"),
+    ("settings.context_tokens_hint", "Older messages are left out past this many tokens, 0 for no limit"),
+    ("settings.auto_trim", "Trim when too long"),
+    ("settings.auto_trim_hint", "When the prompt is over the model's context window, send it again right away without the oldest messages"),
+    ("settings.log_level", "Log level:"),
+    ("settings.log_to_file", "Also log to a file"),
+    ("settings.log_to_file_restart", "(the file takes effect after a restart)"),
+    ("settings.log_transcripts", "Log transcripts (may contain sensitive content)"),
+    ("settings.log_transcripts_hint", "Appends every request and response to a JSONL file in the log folder. The API key is never written"),
+    ("settings.log_usage", "Log usage"),
+    ("settings.trim_whitespace", "Trim trailing whitespace"),
+    ("settings.trim_whitespace_hint", "Removes the spaces at the end of each line of a response once it completes"),
+    ("settings.log_usage_hint", "Appends the tokens and cost of each response to usage.jsonl next to the config file"),
+    ("settings.auto_title", "Title new conversations with"),
+    ("settings.auto_title_hint", "After the first answer, the model set here writes a title of a few words from the first message. It costs a fraction of a cent per conversation"),
+    ("settings.dry_run", "Preview instead of sending"),
+    ("settings.dry_run_hint", "Send shows the exact request and its estimated cost without calling the API, it can be sent from there"),
+    ("settings.confirm_duplicate", "Ask before sending the same message twice"),
+    ("settings.confirm_duplicate_hint", "Sending again the message just sent, with no answer in between, asks first"),
+    ("settings.mirror_path", "Mirror responses to:"),
+    ("settings.mirror_path_hint", "file path, empty for none"),
+    ("settings.mirror_per_conversation", "One file per conversation"),
+    ("settings.mirror_per_conversation_hint", "The path is a folder, each conversation's responses go to <conversation id>.txt in it"),
+    ("settings.mirror_live", "Write as it streams"),
+    ("settings.mirror_live_hint", "Append the text as it arrives, so tail -f shows the answer being written"),
+    ("settings.open_log_folder", "Open log folder"),
+    ("context.divider", "Messages above aren't sent"),
+    ("context.cut_messages", "Only the last {} messages are sent with the next request. Click to change"),
+    ("context.cut_tokens", "The history and input are over the {} token budget, older messages are left out. Click to change"),
+    // command palette
+    ("palette.title", "Command palette"),
+    ("palette.hint", "Type a command..."),
+    ("palette.no_match", "No matching command"),
+    ("palette.new_chat", "New chat"),
+    ("palette.stop", "Stop generation"),
+    ("palette.open_conversation", "Open conversation"),
+    ("palette.clear", "Clear messages"),
+    ("palette.summarize", "Summarize conversation"),
+    ("palette.revert_summary", "Revert summary"),
+    ("palette.export", "Export conversation to Markdown"),
+    ("palette.copy_conversation", "Copy conversation"),
+    ("palette.copy_curl", "Copy last request as cURL"),
+    ("palette.toggle_theme", "Toggle theme"),
+    ("palette.switch_model", "Switch to the next model"),
+    ("palette.open_settings", "Open settings"),
+    ("palette.open_batch", "Run a batch"),
+    ("batch.title", "Batch"),
+    ("batch.inputs", "Inputs, one per line. Drop a text file here to add its lines"),
+    ("batch.inputs_hint", "One input per line"),
+    ("batch.template", "Template"),
+    ("batch.template_hint", "e.g. Translate to French: {input}. Empty sends each input as is"),
+    ("batch.no_inputs", "Add at least one input"),
+    ("batch.no_placeholder", "The template needs {input} where each input goes"),
+    ("batch.submit", "Submit batch"),
+    ("batch.count", "{} requests, billed at half price"),
+    ("batch.failed", "The batch failed: {}"),
+    ("batch.creating", "Creating the batch…"),
+    ("batch.progress", "Batch {}: {} processing, {} succeeded, {} failed. Checked every 30 seconds"),
+    ("batch.ended", "Batch {} ended: {} succeeded, {} failed"),
+    ("batch.cost", "${} at the batch price"),
+    ("batch.cost_partial", ", some answers couldn't be priced"),
+    ("batch.export_all", "Export CSV"),
+    ("batch.export_selected", "Export {} rows as CSV"),
+    ("batch.clear", "New batch"),
+    ("batch.clear_hint", "Forget these results to start another batch"),
+    ("batch.pending", "waiting"),
+    ("batch.tokens", "{} in / {} out"),
+    ("batch.open_chat", "Open as chat"),
+    ("batch.canceled", "canceled"),
+    ("batch.expired", "expired before it was processed"),
+    ("batch.no_result", "no result was returned"),
+    // conversation sidebar
+    ("sidebar.new_chat", "+ New chat"),
+    ("sidebar.untitled", "New chat"),
+    ("sidebar.rename", "Rename"),
+    ("sidebar.duplicate", "Duplicate"),
+    ("sidebar.archive", "Archive"),
+    ("sidebar.unarchive", "Unarchive"),
+    ("archived.notice", "This conversation is archived and read only."),
+    ("archived.unarchive", "Unarchive to continue"),
+    ("sidebar.delete", "Delete"),
+    ("sidebar.delete_title", "Delete conversation"),
+    ("sidebar.delete_confirm", "Delete \"{}\"? This can't be undone."),
+    ("conversation.copy_title", "{} (copy)"),
+    ("dialog.cancel", "Cancel"),
+    ("confirm.title", "Expensive request"),
+    ("confirm.cost", "This request is estimated at ${}, send it?"),
+    ("confirm.duplicate_title", "Sent twice?"),
+    ("confirm.duplicate", "You just sent this, send it again?"),
+    ("confirm.send_again", "Send again"),
+    ("quit.title", "Quit"),
+    ("quit.streaming", "A response is still streaming."),
+    ("quit.stop_and_quit", "Stop and quit"),
+    ("quit.keep_waiting", "Keep waiting"),
+    ("tags.hint", "+ tag"),
+    ("tags.remove", "Remove tag"),
+    // banners and bars
+    ("input.tokens", "\u{2248} {} tokens"),
+    ("input.attachment_tokens", "Of the draft's tokens, those of the staged attachments"),
+    ("context.hover", "Context window: ~{} / {} tokens ({}%)"),
+    ("summary.turn", "turn"),
+    ("summary.turns", "turns"),
+    ("summary.cost_unknown", "cost unknown"),
+    ("summary.input_tokens", "Input tokens"),
+    ("summary.output_tokens", "Output tokens"),
+    ("summary.cost", "Cost"),
+    ("status.idle", "Idle"),
+    ("status.waiting", "Waiting for the first token"),
+    ("status.queued", "Queued behind other responses"),
+    ("sidebar.queued", "(queued)"),
+    ("status.streaming", "Streaming"),
+    ("status.error", "Error"),
+    ("status.session_tokens", "Session: {} in / {} out"),
+    ("banner.warning", "\u{26A0} Warning: "),
+    (
+        "banner.insecure_tls",
+        "TLS certificate verification is disabled. Turn it off in Settings unless you trust this network.",
+    ),
+    ("banner.error", "Error: "),
+    // transcript
+    ("role.user", "You"),
+    ("role.assistant", "Claude"),
+    ("role.system", "System"),
+    ("message.pin", "Pin message"),
+    ("message.unpin", "Unpin message"),
+    ("message.read_aloud", "Read aloud"),
+    ("message.use_as_input", "Use as input, Shift-click to add it after the draft"),
+    ("message.rate_good", "Good answer, click again to take it back"),
+    ("message.rate_bad", "Bad answer, click again to take it back"),
+    ("message.rating_note", "Note on the rating"),
+    ("message.rating_note_hint", "why this answer is good or bad"),
+    ("message.rating_note_save", "Save"),
+    ("message.output_tokens", "~{} / {} tokens"),
+    ("message.output_tokens_hint", "Estimated tokens of the answer so far against the most it may take. At the limit it's cut off, Resume gets the rest"),
+    ("citation.untitled", "document"),
+    ("message.stop_reading", "Stop reading"),
+    ("message.quote", "Quote in reply"),
+    ("message.raw", "Show the raw markdown"),
+    ("message.retry", "\u{27F3} Retry"),
+    ("message.regenerate", "Regenerate"),
+    ("message.regenerate_hint", "Regenerate this response"),
+    ("message.regenerate_model", "Model for this response:"),
+    ("message.regenerate_same_model", "Same as the conversation"),
+    ("message.regenerate_with", "Regenerate with these"),
+    ("message.retry_trimmed", "\u{27F3} Retry without the oldest messages"),
+    ("message.resume", "\u{23E9} Resume"),
+    ("message.resume_hint", "Continue the response from where it stopped"),
+    ("message.recheck_key", "Re-check key"),
+    ("message.recheck_key_hint", "Ask the API again whether it takes the saved key"),
+    ("error_kind.authentication", "API key refused"),
+    ("error_kind.rate_limited", "Rate limited"),
+    ("error_kind.overloaded", "API overloaded"),
+    ("error_kind.prompt_too_long", "Prompt too long"),
+    ("error_kind.invalid_request", "Request refused"),
+    ("error_kind.server", "API server error"),
+    ("error_kind.network", "Connection problem"),
+    ("error_kind.other", "Response failed"),
+    ("chat.trimmed_retry", "Sent again without the {} oldest messages"),
+    ("error.trim_unknown_window", "The model's context window isn't known, it can't be trimmed to fit"),
+    ("error.trim_too_long", "Even the last message alone is over the context window"),
+    ("chat.no_pinned", "No pinned messages"),
+    ("code.copy", "Copy"),
+    ("code.language_auto", "auto"),
+    ("code.language_hint", "Highlight this block as another language"),
+    ("code.copy_formatted", "Copy formatted"),
+    ("code.format", "Format"),
+    ("code.highlight_skipped", "highlighting skipped (too large)"),
+    // composer
+    ("voice.dictate", "Dictate"),
+    ("voice.recording", "Recording {}:{}"),
+    ("voice.done", "Done"),
+    ("voice.done_hint", "Stop and transcribe"),
+    ("voice.cancel", "Cancel"),
+    ("voice.transcribing", "Transcribing..."),
+    ("attachment.keep_inline", "Keep inline"),
+    ("attachment.keep_inline_hint", "Undo, put the text back into the input"),
+    ("attachment.remove", "Remove"),
+    ("prefill.label", "Prefill:"),
+    ("prefill.hint", "Claude's reply will continue from this text, e.g. \"{\" to force JSON"),
+    ("prefill.placeholder", "Start of Claude's reply (optional)"),
+    ("input.placeholder", "Ask anything..."),
+    ("input.send", "Send"),
+    ("input.send_hint", "Send (Shift+Enter), Alt+click to preview the request instead"),
+    ("input.preview", "Preview"),
+    ("input.preview_hint", "Show the request and its estimated cost, nothing is sent"),
+    ("preview.title", "Request preview"),
+    ("preview.model", "Model"),
+    ("preview.input_tokens", "Input tokens"),
+    ("preview.input_cost", "Input cost"),
+    ("preview.max_output_cost", "Output cost, at most"),
+    ("preview.max_output_cost_hint", "If the answer takes all {} output tokens allowed"),
+    ("preview.copy", "Copy JSON"),
+    ("input.stop", "Stop"),
+    ("input.stop_hint", "Stop the response (Esc)"),
+    ("input.sending_in", "Sending in {}\u{2026}"),
+    ("input.no_api_key", "No API key, add it in settings"),
+    ("input.empty", "Type a message first"),
+    ("input.pricing_unknown", "Pricing unknown for this model\nInput: {} tokens\nOutput: {} tokens"),
+    ("input.lines", "{} lines"),
+    ("input.words", "{} words"),
+    ("input.chars", "{} chars"),
+    // app messages
+    ("error.no_api_key", "API key not configured. Please add it in settings."),
+    ("error.client", "Could not create API client: {}"),
+    ("error.save_settings", "Could not save settings: {}"),
+    ("error.export", "Could not export the conversation: {}"),
+    ("error.batch_export", "Could not export the batch: {}"),
+    ("error.batch_load", "Could not load the inputs: {}"),
+    ("error.transcription", "Transcription failed: {}"),
+    ("error.summarize", "Could not summarize the conversation: {}"),
+    ("error.transcript_log", "Transcripts won't be logged: {}"),
+    ("error.usage_log", "Usage won't be logged: {}"),
+    ("error.token_estimation", "Token estimation unavailable, counts and costs are rough guesses from the length: {}"),
+    ("error.mirror", "Responses can't be mirrored: {}"),
+    ("status.rate_limit_low", "\u{26A0} Rate limit low"),
+    ("ratelimit.requests", "Requests"),
+    ("ratelimit.tokens", "Tokens"),
+    ("ratelimit.input_tokens", "Input tokens"),
+    ("ratelimit.output_tokens", "Output tokens"),
+    ("ratelimit.line", "{}: {} of {} left"),
+    ("ratelimit.reset", ", resets at {}"),
+    ("ratelimit.retry_after", "Retry in {}s"),
+    ("error.profile_busy", "Profiles can't change while a response streams"),
+    ("error.open_log_folder", "Could not open the log folder: {}"),
+    ("error.curl", "Can't copy the request: {}"),
+    ("error.curl_nothing_sent", "Nothing was sent yet"),
+    ("toast.exported", "Exported to {}"),
+    ("toast.conversation_copied", "Conversation copied"),
+    ("toast.curl_copied", "cURL command copied, the key reads $ANTHROPIC_API_KEY and extra headers read as variables"),
+    ("toast.model", "Model: {}"),
+    ("toast.key_valid", "API key accepted, Retry sends the message again"),
+    ("toast.key_refused", "The API still refuses the key, change it in the settings"),
+    ("toast.key_check_failed", "Could not check the API key: {}"),
+    ("toast.model_busy", "Can't switch models while a response streams"),
+    ("toast.summarizing", "Summarizing the conversation\u{2026}"),
+    ("toast.summarized", "Earlier turns replaced by a summary"),
+    ("toast.summary_reverted", "Summary reverted, the original turns are back"),
+    ("toast.summarize_busy", "Wait for the response to finish before summarizing"),
+    ("toast.summarize_too_short", "Not enough turns to summarize yet"),
+    ("toast.no_summary", "This conversation has no summary to revert"),
+    ("toast.no_speech", "No speech recognized"),
+    ("error.save_conversation", "Could not save the conversation: {}"),
+    ("error.delete_conversation", "Could not delete the conversation: {}"),
+];
+
+const FR: &[(&str, &str)] = &[
+    ("header.settings", "Réglages"),
+    ("header.pinned", "\u{2605} Épinglés"),
+    ("header.pinned_hint", "Afficher seulement les messages épinglés"),
+    ("model.no_info", "Pas encore d'infos sur le modèle"),
+    ("model.released", "Sortie : {}"),
+    ("model.cutoff", "Connaissances jusqu'à : {}"),
+    ("settings.title", "Réglages"),
+    ("settings.pricing", "Tarifs :"),
+    ("settings.pricing_line", "{} : {} $ en entrée / {} $ en sortie par M de jetons ({})"),
+    ("settings.pricing_unknown", "inconnus pour ce modèle"),
+    ("settings.profile", "Profil :"),
+    ("settings.profile_name_hint", "nouveau profil"),
+    ("settings.profile_add", "Ajouter"),
+    ("settings.profile_remove", "Supprimer"),
+    ("settings.system_prompt", "Prompt système :"),
+    ("settings.system_prompt_hint", "envoyé avec chaque requête de ce profil"),
+    ("settings.temperature", "Température"),
+    ("header.profile_hint", "Profil : clé, modèle et prompt système"),
+    ("header.sidebar_hint", "Afficher ou masquer la liste des conversations"),
+    ("settings.api_key", "Clé d'API :"),
+    ("settings.api_key_hint", "nouvelle clé d'API, Entrée pour enregistrer"),
+    ("settings.api_key_missing", "non définie"),
+    ("settings.api_key_change", "Changer"),
+    ("settings.api_url", "URL de l'API :"),
+    ("settings.beta_flags", "Fonctions bêta :"),
+    ("settings.beta_flags_hint", "Séparées par des virgules, envoyées dans l'en-tête anthropic-beta de chaque requête de message"),
+    ("settings.proxy", "Proxy :"),
+    ("settings.insecure_tls", "DANGER : accepter les certificats TLS invalides"),
+    (
+        "settings.insecure_tls_hint",
+        "Désactive la vérification des certificats pour toutes les requêtes. À n'utiliser qu'avec une passerelle interne de confiance.",
+    ),
+    ("settings.theme", "Thème :"),
+    ("settings.theme_light", "Clair"),
+    ("settings.theme_dark", "Sombre"),
+    ("settings.theme_system", "Système"),
+    ("settings.language", "Langue :"),
+    ("settings.language_system", "Système"),
+    ("settings.reduce_motion", "Réduire les animations"),
+    ("settings.response_reveal", "Afficher les réponses :"),
+    ("settings.reveal_live", "Au fil de l'eau"),
+    ("settings.reveal_every_second", "Chaque seconde"),
+    ("settings.reveal_when_complete", "Une fois terminées"),
+    ("settings.input_stats", "Afficher les compteurs de saisie"),
+    ("settings.raw_markdown", "Afficher le markdown brut"),
+    ("settings.status_bar", "Afficher la barre d'état"),
+    ("settings.auto_read", "Lire les réponses à voix haute"),
+    ("settings.font_size", "Taille du texte :"),
+    ("settings.tab_width", "Largeur des tabulations :"),
+    ("settings.tab_width_hint", "Colonnes occupées par une tabulation dans le code"),
+    ("settings.context", "Contexte :"),
+    ("settings.context_messages", "derniers messages"),
+    ("settings.context_messages_hint", "Seuls ces derniers messages sont envoyés, 0 pour tous les envoyer"),
+    ("settings.context_tokens", "budget de jetons"),
+    ("settings.context_tokens_hint", "Les messages plus anciens sont omis au-delà de ce nombre de jetons, 0 sans limite"),
+    ("settings.auto_trim", "Réduire si trop long"),
+    ("settings.auto_trim_hint", "Quand le prompt dépasse la fenêtre de contexte du modèle, le renvoyer aussitôt sans les plus anciens messages"),
+    ("settings.log_level", "Niveau de journalisation :"),
+    ("settings.log_to_file", "Journaliser aussi dans un fichier"),
+    ("settings.log_to_file_restart", "(le fichier s'applique après un redémarrage)"),
+    ("settings.log_transcripts", "Journaliser les échanges (contenu potentiellement sensible)"),
+    ("settings.log_transcripts_hint", "Ajoute chaque requête et réponse à un fichier JSONL du dossier des journaux. La clé d'API n'y est jamais écrite"),
+    ("settings.log_usage", "Journaliser la consommation"),
+    ("settings.trim_whitespace", "Supprimer les espaces en fin de ligne"),
+    ("settings.trim_whitespace_hint", "Retire les espaces en fin de chaque ligne d'une réponse une fois terminée"),
+    ("settings.log_usage_hint", "Ajoute les jetons et le coût de chaque réponse à usage.jsonl, à côté du fichier de configuration"),
+    ("settings.auto_title", "Titrer les nouvelles conversations avec"),
+    ("settings.auto_title_hint", "Après la première réponse, le modèle indiqué ici écrit un titre de quelques mots à partir du premier message. Cela coûte une fraction de centime par conversation"),
+    ("settings.dry_run", "Aperçu au lieu d'envoyer"),
+    ("settings.dry_run_hint", "Envoyer montre la requête exacte et son coût estimé sans appeler l'API, elle peut être envoyée de là"),
+    ("settings.confirm_duplicate", "Demander avant d'envoyer deux fois le même message"),
+    ("settings.confirm_duplicate_hint", "Renvoyer le message qui vient d'être envoyé, sans réponse entre les deux, demande d'abord"),
+    ("settings.mirror_path", "Copier les réponses dans :"),
+    ("settings.mirror_path_hint", "chemin du fichier, vide pour aucun"),
+    ("settings.mirror_per_conversation", "Un fichier par conversation"),
+    ("settings.mirror_per_conversation_hint", "Le chemin est un dossier, les réponses de chaque conversation vont dans <id de la conversation>.txt"),
+    ("settings.mirror_live", "Écrire au fil de l'eau"),
+    ("settings.mirror_live_hint", "Ajouter le texte dès qu'il arrive, pour que tail -f montre la réponse en cours d'écriture"),
+    ("settings.open_log_folder", "Ouvrir le dossier des journaux"),
+    ("context.divider", "Les messages au-dessus ne sont pas envoyés"),
+    ("context.cut_messages", "Seuls les {} derniers messages sont envoyés avec la prochaine requête. Cliquez pour modifier"),
+    ("context.cut_tokens", "L'historique et la saisie dépassent le budget de {} jetons, les messages plus anciens sont omis. Cliquez pour modifier"),
+    ("palette.title", "Palette de commandes"),
+    ("palette.hint", "Tapez une commande..."),
+    ("palette.no_match", "Aucune commande ne correspond"),
+    ("palette.new_chat", "Nouvelle conversation"),
+    ("palette.clear", "Effacer les messages"),
+    ("palette.summarize", "Résumer la conversation"),
+    ("palette.revert_summary", "Annuler le résumé"),
+    ("palette.export", "Exporter la conversation en Markdown"),
+    ("palette.copy_conversation", "Copier la conversation"),
+    ("palette.copy_curl", "Copier la dernière requête en cURL"),
+    ("palette.toggle_theme", "Changer de thème"),
+    ("palette.switch_model", "Passer au modèle suivant"),
+    ("palette.open_settings", "Ouvrir les réglages"),
+    ("palette.open_batch", "Lancer un lot"),
+    ("batch.title", "Lot"),
+    ("batch.inputs", "Entrées, une par ligne. Déposez un fichier texte ici pour ajouter ses lignes"),
+    ("batch.inputs_hint", "Une entrée par ligne"),
+    ("batch.template", "Modèle de requête"),
+    ("batch.template_hint", "ex. Traduis en anglais : {input}. Vide, chaque entrée est envoyée telle quelle"),
+    ("batch.no_inputs", "Ajoutez au moins une entrée"),
+    ("batch.no_placeholder", "Le modèle doit contenir {input} à la place de chaque entrée"),
+    ("batch.submit", "Envoyer le lot"),
+    ("batch.count", "{} requêtes, facturées à moitié prix"),
+    ("batch.failed", "Le lot a échoué : {}"),
+    ("batch.creating", "Création du lot…"),
+    ("batch.progress", "Lot {} : {} en cours, {} réussies, {} en échec. Vérifié toutes les 30 secondes"),
+    ("batch.ended", "Lot {} terminé : {} réussies, {} en échec"),
+    ("batch.cost", "{} $ au tarif des lots"),
+    ("batch.cost_partial", ", certaines réponses n'ont pas pu être chiffrées"),
+    ("batch.export_all", "Exporter en CSV"),
+    ("batch.export_selected", "Exporter {} lignes en CSV"),
+    ("batch.clear", "Nouveau lot"),
+    ("batch.clear_hint", "Oublier ces résultats pour lancer un autre lot"),
+    ("batch.pending", "en attente"),
+    ("batch.tokens", "{} entrée / {} sortie"),
+    ("batch.open_chat", "Ouvrir en discussion"),
+    ("batch.canceled", "annulée"),
+    ("batch.expired", "expirée avant d'être traitée"),
+    ("batch.no_result", "aucun résultat renvoyé"),
+    ("palette.stop", "Arrêter la génération"),
+    ("palette.open_conversation", "Ouvrir la discussion"),
+    ("sidebar.new_chat", "+ Nouvelle discussion"),
+    ("sidebar.untitled", "Nouvelle discussion"),
+    ("sidebar.rename", "Renommer"),
+    ("sidebar.duplicate", "Dupliquer"),
+    ("sidebar.archive", "Archiver"),
+    ("sidebar.unarchive", "Désarchiver"),
+    ("archived.notice", "Cette conversation est archivée, en lecture seule."),
+    ("archived.unarchive", "Désarchiver pour continuer"),
+    ("sidebar.delete", "Supprimer"),
+    ("sidebar.delete_title", "Supprimer la discussion"),
+    ("sidebar.delete_confirm", "Supprimer « {} » ? C'est définitif."),
+    ("conversation.copy_title", "{} (copie)"),
+    ("dialog.cancel", "Annuler"),
+    ("confirm.title", "Requête coûteuse"),
+    ("confirm.cost", "Cette requête est estimée à {} $, l'envoyer ?"),
+    ("confirm.duplicate_title", "Envoyé deux fois ?"),
+    ("confirm.duplicate", "Vous venez d'envoyer ce message, l'envoyer à nouveau ?"),
+    ("confirm.send_again", "Renvoyer"),
+    ("quit.title", "Quitter"),
+    ("quit.streaming", "Une réponse est encore en cours."),
+    ("quit.stop_and_quit", "Arrêter et quitter"),
+    ("quit.keep_waiting", "Continuer d'attendre"),
+    ("tags.hint", "+ étiquette"),
+    ("tags.remove", "Retirer l'étiquette"),
+    ("input.tokens", "\u{2248} {} jetons"),
+    ("input.attachment_tokens", "Parmi les jetons du brouillon, ceux des pièces jointes"),
+    ("context.hover", "Fenêtre de contexte : ~{} / {} jetons ({} %)"),
+    ("summary.turn", "tour"),
+    ("summary.turns", "tours"),
+    ("summary.cost_unknown", "coût inconnu"),
+    ("summary.input_tokens", "Jetons en entrée"),
+    ("summary.output_tokens", "Jetons en sortie"),
+    ("summary.cost", "Coût"),
+    ("status.idle", "Inactif"),
+    ("status.waiting", "En attente du premier jeton"),
+    ("status.queued", "En file derrière d'autres réponses"),
+    ("sidebar.queued", "(en file)"),
+    ("status.streaming", "Réception"),
+    ("status.error", "Erreur"),
+    ("status.session_tokens", "Session : {} en entrée / {} en sortie"),
+    ("banner.warning", "\u{26A0} Attention : "),
+    (
+        "banner.insecure_tls",
+        "La vérification des certificats TLS est désactivée. Réactivez-la dans les réglages sauf si vous faites confiance à ce réseau.",
+    ),
+    ("banner.error", "Erreur : "),
+    ("role.user", "Vous"),
+    ("role.assistant", "Claude"),
+    ("role.system", "Système"),
+    ("message.pin", "Épingler le message"),
+    ("message.unpin", "Désépingler le message"),
+    ("message.read_aloud", "Lire à voix haute"),
+    ("message.use_as_input", "Reprendre dans la saisie, Maj-clic pour l'ajouter après le brouillon"),
+    ("message.rate_good", "Bonne réponse, recliquer pour annuler"),
+    ("message.rate_bad", "Mauvaise réponse, recliquer pour annuler"),
+    ("message.rating_note", "Note sur l'évaluation"),
+    ("message.rating_note_hint", "pourquoi cette réponse est bonne ou mauvaise"),
+    ("message.rating_note_save", "Enregistrer"),
+    ("message.output_tokens", "~{} / {} tokens"),
+    ("message.output_tokens_hint", "Tokens estimés de la réponse jusqu'ici face au maximum permis. À la limite elle est coupée, Reprendre donne la suite"),
+    ("citation.untitled", "document"),
+    ("message.stop_reading", "Arrêter la lecture"),
+    ("message.quote", "Citer dans la réponse"),
+    ("message.raw", "Afficher le markdown brut"),
+    ("message.retry", "\u{27F3} Réessayer"),
+    ("message.regenerate", "Régénérer"),
+    ("message.regenerate_hint", "Régénérer cette réponse"),
+    ("message.regenerate_model", "Modèle pour cette réponse :"),
+    ("message.regenerate_same_model", "Celui de la conversation"),
+    ("message.regenerate_with", "Régénérer avec ceux-ci"),
+    ("message.retry_trimmed", "\u{27F3} Réessayer sans les plus anciens messages"),
+    ("message.resume", "\u{23E9} Reprendre"),
+    ("message.resume_hint", "Continuer la réponse là où elle s'est arrêtée"),
+    ("message.recheck_key", "Revérifier la clé"),
+    ("message.recheck_key_hint", "Redemander à l'API si elle accepte la clé enregistrée"),
+    ("error_kind.authentication", "Clé API refusée"),
+    ("error_kind.rate_limited", "Limite de débit atteinte"),
+    ("error_kind.overloaded", "API surchargée"),
+    ("error_kind.prompt_too_long", "Prompt trop long"),
+    ("error_kind.invalid_request", "Requête refusée"),
+    ("error_kind.server", "Erreur du serveur de l'API"),
+    ("error_kind.network", "Problème de connexion"),
+    ("error_kind.other", "Échec de la réponse"),
+    ("chat.trimmed_retry", "Renvoyé sans les {} plus anciens messages"),
+    ("error.trim_unknown_window", "La fenêtre de contexte du modèle est inconnue, impossible de l'ajuster"),
+    ("error.trim_too_long", "Le dernier message seul dépasse déjà la fenêtre de contexte"),
+    ("chat.no_pinned", "Aucun message épinglé"),
+    ("code.copy", "Copier"),
+    ("code.language_auto", "auto"),
+    ("code.language_hint", "Colorer ce bloc comme un autre langage"),
+    ("code.copy_formatted", "Copier formaté"),
+    ("code.format", "Formater"),
+    ("code.highlight_skipped", "coloration ignorée (trop volumineux)"),
+    ("voice.dictate", "Dicter"),
+    ("voice.recording", "Enregistrement {}:{}"),
+    ("voice.done", "Terminer"),
+    ("voice.done_hint", "Arrêter et transcrire"),
+    ("voice.cancel", "Annuler"),
+    ("voice.transcribing", "Transcription..."),
+    ("attachment.keep_inline", "Garder dans le texte"),
+    ("attachment.keep_inline_hint", "Annuler, remettre le texte dans la saisie"),
+    ("attachment.remove", "Retirer"),
+    ("prefill.label", "Début imposé :"),
+    ("prefill.hint", "La réponse de Claude continuera ce texte, p. ex. \"{\" pour forcer du JSON"),
+    ("prefill.placeholder", "Début de la réponse de Claude (facultatif)"),
+    ("input.placeholder", "Posez votre question..."),
+    ("input.send", "Envoyer"),
+    ("input.send_hint", "Envoyer (Maj+Entrée), Alt+clic pour plutôt voir la requête"),
+    ("input.preview", "Aperçu"),
+    ("input.preview_hint", "Montrer la requête et son coût estimé, rien n'est envoyé"),
+    ("preview.title", "Aperçu de la requête"),
+    ("preview.model", "Modèle"),
+    ("preview.input_tokens", "Tokens en entrée"),
+    ("preview.input_cost", "Coût en entrée"),
+    ("preview.max_output_cost", "Coût en sortie, au plus"),
+    ("preview.max_output_cost_hint", "Si la réponse prend les {} tokens de sortie permis"),
+    ("preview.copy", "Copier le JSON"),
+    ("input.stop", "Arrêter"),
+    ("input.stop_hint", "Arrêter la réponse (Échap)"),
+    ("input.sending_in", "Envoi dans {}\u{2026}"),
+    ("input.no_api_key", "Pas de clé d'API, ajoutez-la dans les réglages"),
+    ("input.empty", "Écrivez d'abord un message"),
+    ("input.pricing_unknown", "Tarifs inconnus pour ce modèle\nEntrée : {} jetons\nSortie : {} jetons"),
+    ("input.lines", "{} lignes"),
+    ("input.words", "{} mots"),
+    ("input.chars", "{} caractères"),
+    ("error.no_api_key", "Clé d'API absente. Ajoutez-la dans les réglages."),
+    ("error.client", "Impossible de créer le client d'API : {}"),
+    ("error.save_settings", "Impossible d'enregistrer les réglages : {}"),
+    ("error.export", "Impossible d'exporter la conversation : {}"),
+    ("error.batch_export", "Impossible d'exporter le lot : {}"),
+    ("error.batch_load", "Impossible de charger les entrées : {}"),
+    ("error.transcription", "La transcription a échoué : {}"),
+    ("error.summarize", "Impossible de résumer la conversation : {}"),
+    ("error.transcript_log", "Les échanges ne seront pas journalisés : {}"),
+    ("error.usage_log", "La consommation ne sera pas journalisée : {}"),
+    ("error.token_estimation", "Estimation des tokens indisponible, nombres et coûts sont approximés d'après la longueur : {}"),
+    ("error.mirror", "Les réponses ne peuvent pas être copiées : {}"),
+    ("status.rate_limit_low", "\u{26A0} Limite de débit bientôt atteinte"),
+    ("ratelimit.requests", "Requêtes"),
+    ("ratelimit.tokens", "Jetons"),
+    ("ratelimit.input_tokens", "Jetons en entrée"),
+    ("ratelimit.output_tokens", "Jetons en sortie"),
+    ("ratelimit.line", "{} : {} restants sur {}"),
+    ("ratelimit.reset", ", réinitialisé à {}"),
+    ("ratelimit.retry_after", "Réessayer dans {} s"),
+    ("error.profile_busy", "Les profils ne peuvent pas changer pendant une réponse"),
+    ("error.open_log_folder", "Impossible d'ouvrir le dossier des journaux : {}"),
+    ("error.curl", "Impossible de copier la requête : {}"),
+    ("error.curl_nothing_sent", "Rien n'a encore été envoyé"),
+    ("toast.exported", "Exporté dans {}"),
+    ("toast.conversation_copied", "Conversation copiée"),
+    ("toast.curl_copied", "Commande cURL copiée, la clé s'écrit $ANTHROPIC_API_KEY et les en-têtes supplémentaires en variables"),
+    ("toast.model", "Modèle : {}"),
+    ("toast.key_valid", "Clé API acceptée, Réessayer renvoie le message"),
+    ("toast.key_refused", "L'API refuse toujours la clé, changez-la dans les réglages"),
+    ("toast.key_check_failed", "Impossible de vérifier la clé API : {}"),
+    ("toast.model_busy", "Impossible de changer de modèle pendant une réponse"),
+    ("toast.summarizing", "Résumé de la conversation\u{2026}"),
+    ("toast.summarized", "Les tours précédents sont remplacés par un résumé"),
+    ("toast.summary_reverted", "Résumé annulé, les tours d'origine sont rétablis"),
+    ("toast.summarize_busy", "Attendez la fin de la réponse avant de résumer"),
+    ("toast.summarize_too_short", "Pas encore assez de tours à résumer"),
+    ("toast.no_summary", "Cette conversation n'a pas de résumé à annuler"),
+    ("toast.no_speech", "Aucune parole reconnue"),
+    ("error.save_conversation", "Impossible d'enregistrer la discussion : {}"),
+    ("error.delete_conversation", "Impossible de supprimer la discussion : {}"),
+];
@@ -1,16 +1,32 @@
 use eframe::{egui, NativeOptions};
 use log::info;
 use egui::ViewportBuilder;
+use std::fs::File;
+use std::io::{self, Write};
 
+mod ansi;
+mod batch;
 mod api;
+mod attachment;
 mod config;
+mod conversation;
+mod i18n;
 mod syntax_lit;
 mod chat_render;
+mod citation;
 mod ui;
+mod voice;
 mod price;
+mod speech;
+mod postprocess;
+mod transcript;
+mod usage_log;
+mod mirror;
+mod sse;
 mod app;
 
 use crate::app::ClauChatApp;
+use crate::config::{Config, UiLayout};
 
 //TODO:
 //-[] change colors of light theme
@@ -21,18 +37,55 @@ use crate::app::ClauChatApp;
 //-[] implement claude temperature setting
 //-[] implement prompt caching
 
+/// Log lines go to stderr and to the log file
+struct Tee(File);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _ = io::stderr().write_all(buf);
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _ = io::stderr().flush();
+        self.0.flush()
+    }
+}
+
+/// Logs of the dependencies are kept to warnings, the app's own go up to
+/// the configured level, which the settings can change while running.
+/// RUST_LOG replaces all of it
+fn init_logger(config: &Config) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn,clauchat=trace"));
+    builder.format_timestamp_secs();
+
+    if config.log_to_file {
+        let log_file = Config::logs_dir().and_then(|dir| Ok(File::create(dir.join("clauchat.log"))?));
+        match log_file {
+            Ok(file) => {
+                builder.target(env_logger::Target::Pipe(Box::new(Tee(file))));
+            }
+            Err(e) => eprintln!("Logging to stderr only, no log file: {:#}", e),
+        }
+    }
+
+    builder.init();
+    config.apply_log_level();
+}
+
 fn main() -> Result<(), eframe::Error> {
     dotenv::dotenv().ok();
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug"))
-        .format_timestamp_secs()
-        .init();
+    let config = Config::load().unwrap_or_default();
+    init_logger(&config);
 
     info!("Starting ClauChat app");
 
     let options = NativeOptions {
         viewport: ViewportBuilder::default()
-            .with_inner_size([800.0, 600.0])
-            .with_min_inner_size([640.0, 480.0])
+            .with_inner_size(config.ui_layout.window_size)
+            .with_min_inner_size(UiLayout::MIN_WINDOW_SIZE)
             .with_resizable(true)
             .with_decorations(true)
             .with_transparent(false)
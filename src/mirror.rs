@@ -0,0 +1,187 @@
+use anyhow::{Context, Result};
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use crate::transcript::append_line;
+
+/// Opens each response written to the mirror, the text follows
+const ENTRY_HEADER: &str = "---\nrole: assistant\n";
+
+/// Where responses are mirrored and how
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirrorOptions {
+    /// the file, or the folder with per-conversation files
+    pub path: PathBuf,
+    /// one `<conversation id>.txt` file per conversation in `path`
+    pub per_conversation: bool,
+    /// write the text as it streams, not once the response is over
+    pub live: bool,
+}
+
+#[derive(Debug)]
+enum MirrorEvent {
+    /// text appended to a streaming response
+    Delta { conversation: String, text: String },
+    /// a response is over, with its final text unless it failed
+    Finish { conversation: String, content: Option<String> },
+}
+
+/// Appends completed responses to a file, for other tools to read. Files
+/// are written on a task of the runtime, the first error is reported
+/// once and later ones only logged
+pub struct ResponseMirror {
+    options: MirrorOptions,
+    sender: mpsc::UnboundedSender<MirrorEvent>,
+    errors: mpsc::UnboundedReceiver<String>,
+    /// conversations with a response being written live
+    streaming: HashSet<String>,
+}
+
+impl ResponseMirror {
+    pub fn start(runtime: &Runtime, options: MirrorOptions) -> Result<Self> {
+        if options.per_conversation {
+            std::fs::create_dir_all(&options.path)
+                .with_context(|| format!("Could not create {}", options.path.display()))?;
+        }
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<MirrorEvent>();
+        let (error_sender, errors) = mpsc::unbounded_channel();
+        let task_options = options.clone();
+        runtime.spawn(async move {
+            let mut writer = MirrorWriter::default();
+            let mut reported = false;
+            while let Some(event) = receiver.recv().await {
+                if let Err(e) = writer.write(&task_options, event).await {
+                    warn!("Could not write the response mirror: {:#}", e);
+                    if !reported {
+                        reported = true;
+                        let _ = error_sender.send(format!("{:#}", e));
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            options,
+            sender,
+            errors,
+            streaming: HashSet::new(),
+        })
+    }
+
+    pub fn options(&self) -> &MirrorOptions {
+        &self.options
+    }
+
+    /// Text appended to a response from `from` on, only written when
+    /// mirroring live. The response's first text goes with what was
+    /// there before it, e.g. a prefill
+    pub fn delta(&mut self, conversation: &str, content: &str, from: usize) {
+        if !self.options.live || content.len() <= from {
+            return;
+        }
+        let text = if self.streaming.insert(conversation.to_string()) {
+            content
+        } else {
+            &content[from..]
+        };
+        // the writer only stops with the runtime
+        let _ = self.sender.send(MirrorEvent::Delta {
+            conversation: conversation.to_string(),
+            text: text.to_string(),
+        });
+    }
+
+    /// The response is over, `content` is None when it failed
+    pub fn finish(&mut self, conversation: &str, content: Option<String>) {
+        self.streaming.remove(conversation);
+        let _ = self.sender.send(MirrorEvent::Finish {
+            conversation: conversation.to_string(),
+            content,
+        });
+    }
+
+    /// The first write error, once
+    pub fn take_error(&mut self) -> Option<String> {
+        self.errors.try_recv().ok()
+    }
+}
+
+/// A response being written live into a file
+struct LiveEntry {
+    conversation: String,
+    written: String,
+}
+
+/// State of the writer task. A file takes one live response at a time,
+/// others that end meanwhile are written after it so entries never mix
+#[derive(Default)]
+struct MirrorWriter {
+    live: HashMap<PathBuf, LiveEntry>,
+    held: HashMap<PathBuf, Vec<String>>,
+}
+
+impl MirrorWriter {
+    async fn write(&mut self, options: &MirrorOptions, event: MirrorEvent) -> Result<()> {
+        match event {
+            MirrorEvent::Delta { conversation, text } => {
+                let path = file_path(options, &conversation);
+                match self.live.get_mut(&path) {
+                    Some(entry) if entry.conversation == conversation => {
+                        entry.written.push_str(&text);
+                        append_line(&path, &text).await
+                    }
+                    // written whole once it's over
+                    Some(_) => Ok(()),
+                    None => {
+                        self.live.insert(
+                            path.clone(),
+                            LiveEntry {
+                                conversation,
+                                written: text.clone(),
+                            },
+                        );
+                        append_line(&path, &format!("{}{}", ENTRY_HEADER, text)).await
+                    }
+                }
+            }
+            MirrorEvent::Finish { conversation, content } => {
+                let path = file_path(options, &conversation);
+                let is_live = self.live.get(&path).is_some_and(|entry| entry.conversation == conversation);
+                if !is_live {
+                    let Some(content) = content else {
+                        return Ok(());
+                    };
+                    let entry = format!("{}{}\n", ENTRY_HEADER, content);
+                    if self.live.contains_key(&path) {
+                        self.held.entry(path).or_default().push(entry);
+                        return Ok(());
+                    }
+                    return append_line(&path, &entry).await;
+                }
+
+                let written = self.live.remove(&path).map(|entry| entry.written).unwrap_or_default();
+                let mut tail = String::from("\n");
+                // the final text differs from what streamed, e.g. it was
+                // rewritten or post-processed: it follows in full
+                if let Some(content) = content.filter(|content| *content != written) {
+                    tail.push_str(&format!("{}{}\n", ENTRY_HEADER, content));
+                }
+                for entry in self.held.remove(&path).unwrap_or_default() {
+                    tail.push_str(&entry);
+                }
+                append_line(&path, &tail).await
+            }
+        }
+    }
+}
+
+fn file_path(options: &MirrorOptions, conversation: &str) -> PathBuf {
+    if options.per_conversation {
+        Path::new(&options.path).join(format!("{}.txt", conversation))
+    } else {
+        options.path.clone()
+    }
+}
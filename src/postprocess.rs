@@ -0,0 +1,23 @@
+/// Transform applied to each assistant response once it completes, before
+/// it's shown as final, saved and read aloud
+pub trait ResponsePostProcessor {
+    fn process(&self, content: &str) -> String;
+}
+
+/// Drops the whitespace at the end of every line and of the response
+pub struct TrimTrailingWhitespace;
+
+impl ResponsePostProcessor for TrimTrailingWhitespace {
+    fn process(&self, content: &str) -> String {
+        content.lines().map(str::trim_end).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// Processors the config turns on, in the order they run
+pub fn from_config(config: &crate::config::Config) -> Vec<Box<dyn ResponsePostProcessor>> {
+    let mut processors: Vec<Box<dyn ResponsePostProcessor>> = Vec::new();
+    if config.trim_trailing_whitespace {
+        processors.push(Box::new(TrimTrailingWhitespace));
+    }
+    processors
+}
@@ -1,7 +1,11 @@
 use anyhow::{Context, Result};
-use log::{debug, error, info};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::api::{HttpOptions, ResponseUsage};
 
 // Define a struct to hold the pricing information for a model
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,33 +15,218 @@ pub struct ModelPricing {
     pub output_cost_per_million: f64,
     pub max_prompt_tokens: usize,
     pub max_output_tokens: usize,
+    #[serde(default)]
+    pub source: PricingSource,
+}
+
+/// Where a model's pricing came from
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum PricingSource {
+    /// table compiled into the binary, used offline
+    #[default]
+    Builtin,
+    /// fetched from the remote pricing table
+    Remote,
+}
+
+impl std::fmt::Display for PricingSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PricingSource::Builtin => write!(f, "built-in"),
+            PricingSource::Remote => write!(f, "fetched"),
+        }
+    }
+}
+
+/// Prompt cache writes and reads, relative to the input price
+const CACHE_WRITE_MULTIPLIER: f64 = 1.25;
+const CACHE_READ_MULTIPLIER: f64 = 0.1;
+
+impl ModelPricing {
+    /// Whether both costs were present in the table
+    pub fn has_costs(&self) -> bool {
+        self.input_cost_per_million >= 0.0 && self.output_cost_per_million >= 0.0
+    }
+
+    /// Dollars charged for a response's tokens
+    pub fn usage_cost(&self, usage: &ResponseUsage) -> f64 {
+        let input = usage.input_tokens as f64
+            + usage.cache_creation_input_tokens as f64 * CACHE_WRITE_MULTIPLIER
+            + usage.cache_read_input_tokens as f64 * CACHE_READ_MULTIPLIER;
+        (self.input_cost_per_million * input + self.output_cost_per_million * usage.output_tokens as f64)
+            / 1_000_000.0
+    }
+}
+
+pub const PRICING_URL: &str =
+    "https://raw.githubusercontent.com/AgentOps-AI/tokencost/refs/heads/main/pricing_table.md";
+const PRICING_FETCH_TIMEOUT: Duration = Duration::from_secs(8);
+/// wait before the first retry, doubled after each failure
+const PRICING_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Claude pricing shipped with the app: (model, $ in / M, $ out / M, context, max output)
+const BUILTIN_PRICING: &[(&str, f64, f64, usize, usize)] = &[
+    ("claude-opus-4-20250514", 15.0, 75.0, 200_000, 32_000),
+    ("claude-sonnet-4-20250514", 3.0, 15.0, 200_000, 64_000),
+    ("claude-3-7-sonnet-20250219", 3.0, 15.0, 200_000, 64_000),
+    ("claude-3-5-sonnet-20241022", 3.0, 15.0, 200_000, 8_192),
+    ("claude-3-5-haiku-20241022", 0.8, 4.0, 200_000, 8_192),
+    ("claude-3-opus-20240229", 15.0, 75.0, 200_000, 4_096),
+    ("claude-3-haiku-20240307", 0.25, 1.25, 200_000, 4_096),
+];
+
+/// Models of the built-in pricing table, newest first
+pub fn known_models() -> Vec<String> {
+    BUILTIN_PRICING
+        .iter()
+        .map(|&(model_name, ..)| model_name.to_string())
+        .collect()
+}
+
+/// Pricing table compiled into the binary, available without network access
+pub fn builtin_pricing() -> HashMap<String, ModelPricing> {
+    BUILTIN_PRICING
+        .iter()
+        .map(|&(model_name, input_cost, output_cost, max_prompt_tokens, max_output_tokens)| {
+            let pricing = ModelPricing {
+                model_name: model_name.to_string(),
+                input_cost_per_million: input_cost,
+                output_cost_per_million: output_cost,
+                max_prompt_tokens,
+                max_output_tokens,
+                source: PricingSource::Builtin,
+            };
+            (model_name.to_string(), pricing)
+        })
+        .collect()
+}
+
+/// Built-in pricing with fetched entries taking precedence,
+/// also over built-in entries whose name only matches once normalized
+pub fn merge_pricing(
+    remote: Option<HashMap<String, ModelPricing>>,
+) -> HashMap<String, ModelPricing> {
+    let remote = remote.unwrap_or_default();
+    let remote_names: HashSet<String> = remote
+        .iter()
+        .filter(|(_, pricing)| pricing.has_costs())
+        .map(|(name, _)| normalize_model_name(name))
+        .collect();
+
+    let mut pricing = builtin_pricing();
+    pricing.retain(|name, _| !remote_names.contains(&normalize_model_name(name)));
+    pricing.extend(remote);
+    pricing
+}
+
+/// Set once the missing-pricing warning has been logged
+static PRICING_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Lowercase a model id and drop provider prefixes, date stamps and "-latest",
+/// so "claude-3-5-haiku-20241022" and "anthropic/claude-3-5-haiku-latest" compare equal
+pub fn normalize_model_name(name: &str) -> String {
+    let name = name.trim().to_lowercase();
+    let name = name.rsplit('/').next().unwrap_or_default();
+    let name = name.trim_start_matches("anthropic.");
+
+    name.split(['-', '@'])
+        .filter(|part| {
+            let is_date = part.len() == 8 && part.chars().all(|c| c.is_ascii_digit());
+            !part.is_empty() && *part != "latest" && !is_date
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Look up the pricing for a model, falling back to a normalized name match.
+/// Among the names that match once normalized, the shortest wins, then the
+/// last in order, i.e. the newest date. Logs a warning the first time a
+/// model can't be priced
+pub fn find_model_pricing<'a>(
+    pricing_data: Option<&'a HashMap<String, ModelPricing>>,
+    model: &str,
+) -> Option<&'a ModelPricing> {
+    let found = pricing_data.and_then(|pricing_data| {
+        pricing_data.get(model).filter(|pricing| pricing.has_costs()).or_else(|| {
+            let wanted = normalize_model_name(model);
+            pricing_data
+                .iter()
+                .filter(|(name, pricing)| pricing.has_costs() && normalize_model_name(name) == wanted)
+                .min_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| b.cmp(a)))
+                .map(|(_, pricing)| pricing)
+        })
+    });
+
+    if found.is_none() && !PRICING_WARNED.swap(true, Ordering::Relaxed) {
+        warn!("No pricing data for model {}, costs will not be shown", model);
+    }
+    found
+}
+
+/// Spread a retry delay by +/-25%, so clients that failed together don't retry together
+fn jittered(delay: Duration) -> Duration {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or_default();
+    delay.mul_f64(0.75 + (nanos % 1000) as f64 / 2000.0)
 }
 
-/// Fetch and parse model pricing from a markdown table at the given URL
+/// Fetch and parse model pricing from a markdown table at the given URL,
+/// trying up to `attempts` times with a jittered exponential backoff
 pub async fn fetch_model_pricing(
+    url: &str,
     model_name: Option<&str>,
+    http: &HttpOptions,
+    attempts: usize,
 ) -> Result<Option<HashMap<String, ModelPricing>>> {
-    let url =
-        "https://raw.githubusercontent.com/AgentOps-AI/tokencost/refs/heads/main/pricing_table.md";
-    // Fetch the markdown content
-    debug!("Fetching pricing data from {}", url);
-    let response = match reqwest::get(url).await {
-        Ok(response) => response,
-        Err(e) => {
-            debug!("Could not fetch pricing data from {}, Error: {}", url, e);
-            return Ok(None);
+    let client = http
+        .client_builder(PRICING_FETCH_TIMEOUT)?
+        .build()
+        .context("Failed to create HTTP client")?;
+
+    // Fetch the markdown content, retrying transient network failures
+    let attempts = attempts.max(1);
+    let mut markdown_content = None;
+    let mut delay = PRICING_RETRY_BASE_DELAY;
+    for attempt in 1..=attempts {
+        info!("Fetching pricing data from {} (attempt {}/{})", url, attempt, attempts);
+        // an error page would be parsed as the table: server errors are retried, anything else gives up
+        let result: Result<String> = match client.get(url).send().await {
+            Ok(response) if response.status().is_success() => response.text().await.map_err(Into::into),
+            Ok(response) if response.status().is_server_error() => {
+                Err(anyhow::anyhow!("server error {}", response.status()))
+            }
+            Ok(response) => {
+                warn!("Pricing data unavailable from {}, status {}", url, response.status());
+                return Ok(None);
+            }
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(text) => {
+                markdown_content = Some(text);
+                break;
+            }
+            Err(e) => warn!("Could not fetch pricing data from {}, Error: {}", url, e),
         }
+        if attempt < attempts {
+            tokio::time::sleep(jittered(delay)).await;
+            delay *= 2;
+        }
+    }
+    let Some(markdown_content) = markdown_content else {
+        return Ok(None);
     };
 
-    let markdown_content = response
-        .text()
-        .await
-        .context("Failed to extract text from response")?;
-
     // Parse the markdown table and extract pricing information
-    Ok(Some(
-        parse_pricing_table(&markdown_content, model_name).unwrap(),
-    ))
+    match parse_pricing_table(&markdown_content, model_name) {
+        Ok(models) => Ok(Some(models)),
+        Err(e) => {
+            warn!("Could not parse pricing data from {}: {}", url, e);
+            Ok(None)
+        }
+    }
 }
 
 /// Parse a markdown table containing model pricing information
@@ -47,23 +236,23 @@ fn parse_pricing_table(
 ) -> Result<HashMap<String, ModelPricing>> {
     let mut models = HashMap::new();
     let mut lines = markdown.lines();
-    let mut found_table_header = false;
+    let mut cost_multiplier = None;
 
     // Find the table header
-    while let Some(line) = lines.next() {
+    for line in lines.by_ref() {
         if line.starts_with("| Model Name") {
-            found_table_header = true;
+            cost_multiplier = Some(cost_unit_multiplier(line));
             // Skip the separator line
             let _ = lines.next();
             break;
         }
     }
 
-    if !found_table_header {
+    let Some(cost_multiplier) = cost_multiplier else {
         return Err(anyhow::anyhow!(
             "Could not find the pricing table header in the markdown"
         ));
-    }
+    };
 
     // Process table rows
     for line in lines {
@@ -89,14 +278,25 @@ fn parse_pricing_table(
             // Extract the model name
             let model_name = columns[0].trim().to_string();
 
-            // Parse the pricing information
-            // Note: Handle possible variations in the formatting
-            let input_cost = parse_cost(columns[1])?;
-            let output_cost = parse_cost(columns[2])?;
-
-            // Parse the token limits
-            let max_prompt_tokens = parse_token_limit(columns[3])?;
-            let max_output_tokens = parse_token_limit(columns[4])?;
+            // Parse the pricing information, a malformed row is skipped
+            // rather than failing the whole table
+            let parsed = parse_cost(columns[1]).and_then(|input_cost| {
+                Ok((
+                    input_cost,
+                    parse_cost(columns[2])?,
+                    parse_token_limit(columns[3])?,
+                    parse_token_limit(columns[4])?,
+                ))
+            });
+            let (input_cost, output_cost, max_prompt_tokens, max_output_tokens) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    debug!("Skipping pricing row for {}: {}", model_name, e);
+                    continue;
+                }
+            };
+            let to_per_million = |cost: f64| if cost < 0.0 { cost } else { cost * cost_multiplier };
+            let (input_cost, output_cost) = (to_per_million(input_cost), to_per_million(output_cost));
 
             // Create the model pricing entry
             let pricing = ModelPricing {
@@ -105,6 +305,7 @@ fn parse_pricing_table(
                 output_cost_per_million: output_cost,
                 max_prompt_tokens,
                 max_output_tokens,
+                source: PricingSource::Remote,
             };
 
             // Use the model name as the key
@@ -121,22 +322,39 @@ fn parse_pricing_table(
     Ok(models)
 }
 
-/// Parse a cost string like "$15.00" or "15.00" to a f64
+/// Factor turning the table's cost unit into a per-million-tokens cost,
+/// based on the header saying "per 1K" or "per 1M" tokens
+fn cost_unit_multiplier(header: &str) -> f64 {
+    let header = header.to_lowercase().replace(' ', "");
+    if header.contains("per1k") || header.contains("/1k") {
+        1000.0
+    } else {
+        1.0
+    }
+}
+
+/// Parse a cost string like "$15.00" or "15.00" to a f64,
+/// -1.0 stands for an unknown cost
 fn parse_cost(cost_str: &str) -> Result<f64> {
+    let cost_str = cost_str.trim().to_lowercase();
+    match cost_str.as_str() {
+        "nan" | "n/a" | "unlimited" | "--" | "-" | "" => {
+            return Ok(-1.0);
+        }
+        _ => {} // Continue with normal parsing
+    }
+
     // Remove the dollar sign and any other non-numeric characters except the decimal point
     let cleaned = cost_str
-        .replace('$', "")
         .chars()
-        .filter(|c| c.is_digit(10) || *c == '.')
+        .filter(|c| c.is_ascii_digit() || *c == '.')
         .collect::<String>();
 
-    // Parse the string to a f64
-    match cost_str.to_string().as_str() {
-        "nan" | "n/a" | "unlimited" | "--" | "-" | "" => {
-            return Ok(-1.0);
-        }
-        _ => {} // Continue with normal parsing
+    // no digits at all, e.g. "free?" or "see docs"
+    if !cleaned.chars().any(|c| c.is_ascii_digit()) {
+        return Ok(-1.0);
     }
+
     cleaned
         .parse::<f64>()
         .context(format!("Failed to parse cost value: {}", cost_str))
@@ -186,3 +404,201 @@ fn parse_token_limit(limit_str: &str) -> Result<usize> {
         .parse::<usize>()
         .context(format!("Failed to parse token limit: {}", limit_str))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pricing(names: &[&str]) -> HashMap<String, ModelPricing> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| {
+                let pricing = ModelPricing {
+                    model_name: name.to_string(),
+                    input_cost_per_million: idx as f64,
+                    output_cost_per_million: idx as f64,
+                    max_prompt_tokens: 200_000,
+                    max_output_tokens: 8_192,
+                    source: PricingSource::Remote,
+                };
+                (name.to_string(), pricing)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_real_model_ids() {
+        let pairs = [
+            ("claude-3-5-haiku-20241022", "anthropic/claude-3-5-haiku-latest"),
+            ("claude-3-7-sonnet-20250219", "claude-3-7-sonnet-latest"),
+            ("claude-3-5-sonnet-20240620", "claude-3-5-sonnet@20240620"),
+            ("claude-3-opus-20240229", "Claude-3-Opus"),
+            ("claude-sonnet-4-20250514", "anthropic/claude-sonnet-4"),
+            ("claude-3-haiku-20240307", "anthropic.claude-3-haiku-20240307"),
+        ];
+        for (model, table_name) in pairs {
+            assert_eq!(normalize_model_name(model), normalize_model_name(table_name), "{} / {}", model, table_name);
+        }
+        assert_eq!(normalize_model_name("anthropic/claude-3-5-haiku-latest"), "claude-3-5-haiku");
+        // other models stay apart
+        assert_ne!(normalize_model_name("claude-3-5-sonnet-20241022"), normalize_model_name("claude-3-sonnet-20240229"));
+        assert_ne!(normalize_model_name("claude-3-5-haiku-20241022"), normalize_model_name("claude-3-haiku-20240307"));
+    }
+
+    #[test]
+    fn finds_a_table_name_for_a_model_id() {
+        let table = pricing(&["anthropic/claude-3-5-haiku-latest", "claude-3-opus"]);
+        let found = find_model_pricing(Some(&table), "claude-3-5-haiku-20241022").unwrap();
+        assert_eq!(found.model_name, "anthropic/claude-3-5-haiku-latest");
+        assert_eq!(find_model_pricing(Some(&table), "claude-3-opus-20240229").unwrap().model_name, "claude-3-opus");
+        assert!(find_model_pricing(Some(&table), "claude-3-5-sonnet-20241022").is_none());
+        assert!(find_model_pricing(None, "claude-3-opus").is_none());
+    }
+
+    #[test]
+    fn exact_name_comes_first() {
+        let table = pricing(&["claude-3-5-sonnet", "claude-3-5-sonnet-20241022"]);
+        let found = find_model_pricing(Some(&table), "claude-3-5-sonnet-20241022").unwrap();
+        assert_eq!(found.model_name, "claude-3-5-sonnet-20241022");
+    }
+
+    #[test]
+    fn tie_break_is_deterministic() {
+        let names = [
+            "anthropic/claude-3-5-sonnet-latest",
+            "claude-3-5-sonnet-20240620",
+            "claude-3-5-sonnet-20241022",
+            "claude-3-5-sonnet-latest",
+        ];
+        // each map hashes with its own random keys, so iterates in its own order
+        for _ in 0..20 {
+            let table = pricing(&names);
+            let found = find_model_pricing(Some(&table), "claude-3-5-sonnet").unwrap();
+            assert_eq!(found.model_name, "claude-3-5-sonnet-latest");
+            // names as long as each other, the newest date wins
+            let table = pricing(&names[1..3]);
+            let found = find_model_pricing(Some(&table), "claude-3-5-sonnet").unwrap();
+            assert_eq!(found.model_name, "claude-3-5-sonnet-20241022");
+        }
+    }
+
+    #[test]
+    fn malformed_cost_cells_are_unknown() {
+        for cell in [" --", "--", "-", "N/A", "n/a", "NaN", "Unlimited", "", "see docs", "free?"] {
+            assert_eq!(parse_cost(cell).unwrap(), -1.0, "{:?}", cell);
+        }
+        assert_eq!(parse_cost("$15.00").unwrap(), 15.0);
+        assert_eq!(parse_cost(" $0.25 ").unwrap(), 0.25);
+        assert!(parse_cost("$1.2.3").is_err());
+    }
+
+    #[test]
+    fn cost_unit_from_the_header() {
+        assert_eq!(cost_unit_multiplier("| Model Name | Prompt Cost (USD) per 1M tokens |"), 1.0);
+        assert_eq!(cost_unit_multiplier("| Model Name | Prompt Cost (USD) per 1K tokens |"), 1000.0);
+        assert_eq!(cost_unit_multiplier("| Model Name | Input $/1k |"), 1000.0);
+        assert_eq!(cost_unit_multiplier("| Model Name | Input Cost |"), 1.0);
+    }
+
+    const HEADER: &str = "| Model Name | Prompt Cost (USD) per 1M tokens | Completion Cost (USD) per 1M tokens | Max Prompt Tokens | Max Output Tokens |\n|---|---|---|---|---|\n";
+
+    #[test]
+    fn bad_rows_are_skipped_not_priced_at_zero() {
+        let table = format!(
+            "{}{}",
+            HEADER,
+            "| claude-3-opus | $15 | $75 | 200,000 | 4096 |\n\
+             | broken-cost | $1.2.3 | $5 | 200k | 4096 |\n\
+             | broken-limit | $1 | $5 | lots | 4096 |\n\
+             | unpriced | N/A |  -- | 200k | 4096 |\n\
+             | too | short |\n"
+        );
+        let models = parse_pricing_table(&table, None).unwrap();
+
+        let opus = &models["claude-3-opus"];
+        assert_eq!((opus.input_cost_per_million, opus.output_cost_per_million), (15.0, 75.0));
+        assert_eq!((opus.max_prompt_tokens, opus.max_output_tokens), (200_000, 4096));
+        assert!(!models.contains_key("broken-cost"));
+        assert!(!models.contains_key("broken-limit"));
+        assert!(!models.contains_key("too"));
+        // kept as unknown, never costed
+        assert!(!models["unpriced"].has_costs());
+        assert!(find_model_pricing(Some(&models), "unpriced").is_none());
+        assert!(models.values().all(|pricing| pricing.input_cost_per_million != 0.0));
+    }
+
+    #[test]
+    fn per_1k_table_is_normalized_to_per_million() {
+        let table = HEADER.replace("1M", "1K") + "| claude-3-haiku | $0.00025 | $0.00125 | 200k | 4k |\n";
+        let haiku = &parse_pricing_table(&table, None).unwrap()["claude-3-haiku"];
+        assert!((haiku.input_cost_per_million - 0.25).abs() < 1e-9);
+        assert!((haiku.output_cost_per_million - 1.25).abs() < 1e-9);
+        assert_eq!((haiku.max_prompt_tokens, haiku.max_output_tokens), (200_000, 4_000));
+    }
+
+    #[test]
+    fn table_without_usable_rows_fails() {
+        assert!(parse_pricing_table("no table here", None).is_err());
+        let table = format!("{}| broken | $1.2.3 | $5 | 200k | 4096 |\n", HEADER);
+        assert!(parse_pricing_table(&table, None).is_err());
+    }
+
+    async fn fetch_with_statuses(statuses: &[u16]) -> (Option<HashMap<String, ModelPricing>>, usize) {
+        let table = format!("{}| claude-3-opus | $15 | $75 | 200k | 4096 |\n", HEADER);
+        fetch_after_statuses(statuses, table).await
+    }
+
+    async fn fetch_after_statuses(
+        statuses: &[u16],
+        table: String,
+    ) -> (Option<HashMap<String, ModelPricing>>, usize) {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        for &status in statuses {
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(status))
+                .up_to_n_times(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(table))
+            .mount(&server)
+            .await;
+        let pricing = fetch_model_pricing(&server.uri(), None, &HttpOptions::default(), 3)
+            .await
+            .unwrap();
+        (pricing, server.received_requests().await.unwrap().len())
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (pricing, requests) = fetch_with_statuses(&[500]).await;
+        assert_eq!(requests, 2);
+        assert!(pricing.unwrap().contains_key("claude-3-opus"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let (pricing, requests) = fetch_with_statuses(&[503, 502, 500]).await;
+        assert_eq!(requests, 3);
+        assert!(pricing.is_none());
+    }
+
+    #[tokio::test]
+    async fn unparsable_table_means_no_pricing() {
+        let (pricing, requests) = fetch_after_statuses(&[], "<html>Not a table</html>".to_string()).await;
+        assert_eq!(requests, 1);
+        assert!(pricing.is_none());
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (pricing, requests) = fetch_with_statuses(&[404]).await;
+        assert_eq!(requests, 1);
+        assert!(pricing.is_none());
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+
+/// What the speech thread is asked to do
+#[cfg_attr(not(feature = "tts"), allow(dead_code))]
+enum SpeechCommand {
+    /// read a message aloud, interrupting the one playing
+    Speak(usize, String),
+    Stop,
+}
+
+/// Reads messages aloud with the platform text-to-speech, one at a time.
+/// The engine lives on its own thread so playback never blocks the UI
+#[cfg_attr(not(feature = "tts"), allow(dead_code))]
+pub struct Speaker {
+    sender: std::sync::mpsc::Sender<SpeechCommand>,
+    /// index of the message being read, cleared when playback ends
+    speaking: Arc<Mutex<Option<usize>>>,
+}
+
+#[cfg_attr(not(feature = "tts"), allow(dead_code))]
+impl Speaker {
+    /// Start the speech thread, None when the app was built without the
+    /// `tts` feature or the platform engine is unavailable
+    pub fn new() -> Option<Self> {
+        #[cfg(feature = "tts")]
+        {
+            Self::spawn()
+        }
+        #[cfg(not(feature = "tts"))]
+        {
+            None
+        }
+    }
+
+    #[cfg(feature = "tts")]
+    fn spawn() -> Option<Self> {
+        use log::{debug, error};
+        use std::time::Duration;
+
+        // engine creation is checked here, so a missing backend hides the feature
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (sender, receiver) = std::sync::mpsc::channel::<SpeechCommand>();
+        let speaking = Arc::new(Mutex::new(None));
+        let thread_speaking = speaking.clone();
+
+        std::thread::spawn(move || {
+            let mut tts = match tts::Tts::default() {
+                Ok(tts) => {
+                    let _ = ready_tx.send(true);
+                    tts
+                }
+                Err(e) => {
+                    error!("Text-to-speech is unavailable: {}", e);
+                    let _ = ready_tx.send(false);
+                    return;
+                }
+            };
+
+            loop {
+                match receiver.recv_timeout(Duration::from_millis(200)) {
+                    Ok(SpeechCommand::Speak(idx, text)) => {
+                        debug!("Reading message {} aloud", idx);
+                        match tts.speak(text, true) {
+                            Ok(_) => *thread_speaking.lock().unwrap() = Some(idx),
+                            Err(e) => error!("Could not read the message aloud: {}", e),
+                        }
+                    }
+                    Ok(SpeechCommand::Stop) => {
+                        if let Err(e) = tts.stop() {
+                            error!("Could not stop reading: {}", e);
+                        }
+                        *thread_speaking.lock().unwrap() = None;
+                    }
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                        if !tts.is_speaking().unwrap_or(false) {
+                            *thread_speaking.lock().unwrap() = None;
+                        }
+                    }
+                    // the app is gone
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+
+        ready_rx
+            .recv()
+            .unwrap_or(false)
+            .then_some(Self { sender, speaking })
+    }
+
+    pub fn speak(&self, idx: usize, text: String) {
+        let _ = self.sender.send(SpeechCommand::Speak(idx, text));
+    }
+
+    pub fn stop(&self) {
+        let _ = self.sender.send(SpeechCommand::Stop);
+    }
+
+    /// Index of the message being read, if any
+    pub fn speaking(&self) -> Option<usize> {
+        *self.speaking.lock().unwrap()
+    }
+}
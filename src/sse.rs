@@ -0,0 +1,131 @@
+/// One server-sent event, dispatched once its terminating blank line is read
+#[derive(Debug, Clone, Default)]
+pub struct SseEvent {
+    /// value of the `event:` field, None when the event had none
+    pub event: Option<String>,
+    /// `data:` fields joined with newlines
+    pub data: String,
+}
+
+/// Accumulates SSE fields line by line until the blank line that ends an event.
+/// Lines are expected without their line terminator
+#[derive(Debug, Default)]
+pub struct SseAssembler {
+    event: Option<String>,
+    data: Option<String>,
+}
+
+impl SseAssembler {
+    /// Feed one line, returns the event it completes, if any
+    pub fn push_line(&mut self, line: &str) -> Option<SseEvent> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        if line.is_empty() {
+            return self.dispatch();
+        }
+
+        // comment line, used as a keep-alive by some servers
+        if line.starts_with(':') {
+            return None;
+        }
+
+        // "field: value", the single space after the colon is not part of the value
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => match &mut self.data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            },
+            // id and retry are of no use for a single request
+            _ => {}
+        }
+        None
+    }
+
+    /// Event still being assembled when the stream ended without a blank line
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        self.dispatch()
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        // an event without data is not dispatched, per spec
+        let data = self.data.take()?;
+        Some(SseEvent { event, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Events the lines of `text` dispatch, the last one flushed at the end
+    fn events(text: &str) -> Vec<SseEvent> {
+        let mut assembler = SseAssembler::default();
+        let mut events: Vec<SseEvent> = text.split('\n').filter_map(|line| assembler.push_line(line)).collect();
+        events.extend(assembler.finish());
+        events
+    }
+
+    #[test]
+    fn joins_multi_line_data() {
+        let events = events("event: message\ndata: first\ndata:second\ndata\ndata:  indented\n\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.as_deref(), Some("message"));
+        // one space after the colon is dropped, a field alone is an empty value
+        assert_eq!(events[0].data, "first\nsecond\n\n indented");
+    }
+
+    #[test]
+    fn dispatches_on_the_blank_line_only() {
+        let mut assembler = SseAssembler::default();
+        assert!(assembler.push_line("event: content_block_delta").is_none());
+        assert!(assembler.push_line("data: {\"a\":").is_none());
+        assert!(assembler.push_line(": keep-alive").is_none());
+        assert!(assembler.push_line("data: 1}").is_none());
+        let event = assembler.push_line("").expect("the blank line ends the event");
+        assert_eq!(event.event.as_deref(), Some("content_block_delta"));
+        assert_eq!(event.data, "{\"a\":\n1}");
+        // nothing is left over for the next event
+        assert!(assembler.push_line("").is_none());
+        assert!(assembler.finish().is_none());
+    }
+
+    #[test]
+    fn fields_do_not_leak_across_events() {
+        let events = events("event: ping\ndata: 1\n\ndata: 2\n\n");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event, None);
+        assert_eq!(events[1].data, "2");
+    }
+
+    #[test]
+    fn skips_events_without_data() {
+        let events = events("event: ping\n\nid: 7\nretry: 1000\n\n: comment\n\n");
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn crlf_terminated_lines() {
+        let events = events("event: message\r\ndata: a\r\ndata: b\r\n\r\ndata: c\r\n\r\n");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event.as_deref(), Some("message"));
+        assert_eq!(events[0].data, "a\nb");
+        assert_eq!(events[1].data, "c");
+    }
+
+    #[test]
+    fn flushes_an_unterminated_event() {
+        let events = events("data: 1\n\ndata: last");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].data, "last");
+    }
+}
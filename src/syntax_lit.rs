@@ -1,5 +1,5 @@
 use eframe::egui::Color32;
-use log::{debug, error};
+use log::error;
 use std::sync::OnceLock;
 use syntect::highlighting::{Theme, ThemeSet};
 use syntect::parsing::{SyntaxReference, SyntaxSet};
@@ -9,42 +9,133 @@ use syntect::util::LinesWithEndings;
 static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
 static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
 
+/// Code block tags mapped to the name of the syntax highlighting them.
+/// Languages without a syntax of their own borrow the closest one
+const LANGUAGE_ALIASES: &[(&str, &str)] = &[
+    ("c", "C"),
+    ("h", "C"),
+    ("c++", "C++"),
+    ("cpp", "C++"),
+    ("cxx", "C++"),
+    ("hpp", "C++"),
+    ("cs", "C#"),
+    ("csharp", "C#"),
+    ("c#", "C#"),
+    ("objective-c", "Objective-C"),
+    ("objc", "Objective-C"),
+    ("objective-c++", "Objective-C++"),
+    ("js", "JavaScript"),
+    ("javascript", "JavaScript"),
+    ("jsx", "JavaScript"),
+    ("mjs", "JavaScript"),
+    ("cjs", "JavaScript"),
+    ("ts", "JavaScript"),
+    ("typescript", "JavaScript"),
+    ("tsx", "JavaScript"),
+    ("json5", "JSON"),
+    ("jsonc", "JSON"),
+    ("py", "Python"),
+    ("python3", "Python"),
+    ("rb", "Ruby"),
+    ("rs", "Rust"),
+    ("go", "Go"),
+    ("golang", "Go"),
+    ("kotlin", "Java"),
+    ("kt", "Java"),
+    ("sh", "Bourne Again Shell (bash)"),
+    ("bash", "Bourne Again Shell (bash)"),
+    ("zsh", "Bourne Again Shell (bash)"),
+    ("shell", "Bourne Again Shell (bash)"),
+    ("console", "Bourne Again Shell (bash)"),
+    ("shellsession", "Bourne Again Shell (bash)"),
+    ("dockerfile", "Bourne Again Shell (bash)"),
+    ("docker", "Bourne Again Shell (bash)"),
+    ("yml", "YAML"),
+    ("md", "Markdown"),
+    ("tex", "LaTeX"),
+    ("bat", "Batch File"),
+    ("cmd", "Batch File"),
+    ("make", "Makefile"),
+    ("patch", "Diff"),
+    ("svg", "XML"),
+    ("plaintext", "Plain Text"),
+    ("plain", "Plain Text"),
+    ("text", "Plain Text"),
+    ("txt", "Plain Text"),
+];
+
+/// Lines of an untagged block looked at to guess its language
+const DETECTION_LINES: usize = 10;
+
 pub struct SyntaxHighlighter;
 
 impl SyntaxHighlighter {
     /// Get the syntax set, initializing it if necessary
     fn syntax_set() -> &'static SyntaxSet {
-        SYNTAX_SET.get_or_init(|| SyntaxSet::load_defaults_newlines())
+        SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
     }
 
     /// Get the theme set, initializing it if necessary
     fn theme_set() -> &'static ThemeSet {
-        THEME_SET.get_or_init(|| ThemeSet::load_defaults())
+        THEME_SET.get_or_init(ThemeSet::load_defaults)
     }
 
     /// Get the appropriate syntax for a given language
     fn get_syntax_for_language(language: &str) -> Option<&'static SyntaxReference> {
         let syntax_set = Self::syntax_set();
-        
-        // Try direct match first
-        if let Some(syntax) = syntax_set.find_syntax_by_token(language) {
+        let language = language.trim().to_lowercase();
+
+        // aliases first, the token lookup takes e.g. "h" for Objective-C
+        let alias = LANGUAGE_ALIASES
+            .iter()
+            .find(|(tag, _)| *tag == language)
+            .and_then(|(_, name)| syntax_set.find_syntax_by_name(name));
+        if alias.is_some() {
+            return alias;
+        }
+        if let Some(syntax) = syntax_set.find_syntax_by_token(&language) {
             return Some(syntax);
         }
-        
-        // Try common language aliases
-        let language = match language.to_lowercase().as_str() {
-            "js" => "javascript",
-            "ts" => "typescript",
-            "py" => "python",
-            "rb" => "ruby",
-            "rs" => "rust",
-            "sh" => "bash",
-            "c++" | "cpp" | "c" => "c++",
-            "cs" => "csharp",
-            _ => language,
+
+        // a file name as the tag, e.g. "main.rs"
+        let extension = language.rsplit_once('.').map(|(_, extension)| extension)?;
+        syntax_set.find_syntax_by_extension(extension)
+    }
+
+    /// Guess the syntax of an untagged block: shebangs and the like first,
+    /// then the markers of common languages in its first lines
+    fn detect_syntax(code: &str) -> Option<&'static SyntaxReference> {
+        let syntax_set = Self::syntax_set();
+        if let Some(syntax) = syntax_set.find_syntax_by_first_line(code) {
+            return Some(syntax);
+        }
+
+        let lines: Vec<&str> = code.lines().map(str::trim).filter(|line| !line.is_empty()).take(DETECTION_LINES).collect();
+        let first = *lines.first()?;
+        let count = |markers: &[&str]| {
+            lines
+                .iter()
+                .filter(|line| markers.iter().any(|marker| line.starts_with(marker)))
+                .count()
         };
-        
-        syntax_set.find_syntax_by_token(language)
+        let braces = lines.iter().filter(|line| line.ends_with('{') || line.ends_with('}')).count();
+        let colons = lines.iter().filter(|line| line.ends_with(':')).count();
+
+        let scores = [
+            ("Rust", count(&["fn ", "pub fn ", "let ", "let mut ", "use ", "impl ", "struct ", "#[", "mod "]) + braces / 2),
+            ("Python", count(&["def ", "import ", "from ", "class ", "elif ", "print("]) + colons),
+            ("JavaScript", count(&["function ", "const ", "export ", "console.", "import {", "=> "]) + braces / 2),
+            ("Go", count(&["func ", "package ", "import (", "fmt."]) + braces / 2),
+            ("C++", count(&["#include", "#define", "int main", "std::", "template<"]) + braces / 2),
+            ("Java", count(&["public class", "public static", "private ", "@Override", "System.out"])),
+            ("Bourne Again Shell (bash)", count(&["$ ", "sudo ", "cd ", "export ", "echo ", "apt ", "npm ", "cargo ", "git "])),
+            ("SQL", count(&["SELECT ", "INSERT ", "CREATE ", "UPDATE ", "DELETE ", "FROM ", "WHERE "])),
+            ("HTML", count(&["<html", "<div", "<!DOCTYPE", "<head", "<body", "<p>", "<span"])),
+            ("JSON", usize::from(first.starts_with('{') || first.starts_with('[')) * count(&["\""])),
+        ];
+        let (name, score) = scores.into_iter().max_by_key(|(_, score)| *score)?;
+        // a single marker is too weak, prose says "let" or "from" too
+        (score >= 2).then(|| syntax_set.find_syntax_by_name(name)).flatten()
     }
 
     /// Get the current theme
@@ -72,7 +163,7 @@ impl SyntaxHighlighter {
                 .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
         } else {
             // Try to detect the language if not specified
-            syntax_set.find_syntax_by_first_line(code)
+            Self::detect_syntax(code)
                 .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
         };
         
@@ -106,3 +197,64 @@ impl SyntaxHighlighter {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax_name(language: &str) -> Option<&'static str> {
+        SyntaxHighlighter::get_syntax_for_language(language).map(|syntax| syntax.name.as_str())
+    }
+
+    #[test]
+    fn aliases_resolve_to_their_syntax() {
+        let expected = [
+            ("jsx", "JavaScript"),
+            ("tsx", "JavaScript"),
+            ("golang", "Go"),
+            ("yml", "YAML"),
+            ("dockerfile", "Bourne Again Shell (bash)"),
+            ("shell", "Bourne Again Shell (bash)"),
+            ("console", "Bourne Again Shell (bash)"),
+            ("plaintext", "Plain Text"),
+            ("objective-c", "Objective-C"),
+            ("kotlin", "Java"),
+            ("c", "C"),
+            ("h", "C"),
+            ("c++", "C++"),
+            ("cpp", "C++"),
+            // case and spacing of the tag don't matter
+            (" Rust ", "Rust"),
+        ];
+        for (tag, name) in expected {
+            assert_eq!(syntax_name(tag), Some(name), "tag {:?}", tag);
+        }
+    }
+
+    #[test]
+    fn every_alias_names_a_bundled_syntax() {
+        for (tag, name) in LANGUAGE_ALIASES {
+            assert!(
+                SyntaxHighlighter::syntax_set().find_syntax_by_name(name).is_some(),
+                "{:?} maps to {:?}, which isn't bundled",
+                tag,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn falls_back_to_tokens_and_extensions() {
+        assert_eq!(syntax_name("python"), Some("Python"));
+        assert_eq!(syntax_name("main.rs"), Some("Rust"));
+        assert_eq!(syntax_name("no-such-language"), None);
+    }
+
+    #[test]
+    fn detects_untagged_blocks() {
+        let detected = |code: &str| SyntaxHighlighter::detect_syntax(code).map(|syntax| syntax.name.as_str());
+        assert_eq!(detected("#!/usr/bin/env python3\nprint(1)"), Some("Python"));
+        assert_eq!(detected("use std::io;\n\nfn main() {\n    let x = 1;\n}"), Some("Rust"));
+        assert_eq!(detected("let me know from where it came"), None);
+    }
+}
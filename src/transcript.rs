@@ -0,0 +1,223 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use crate::api::{DeltaContent, Message, ResponseUsage, StreamingBuffer};
+use crate::config::Config;
+
+/// Name of the file being written, rotated ones get a number before the extension
+const LOG_STEM: &str = "transcript";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+/// One line of the transcript log. Only what was sent and received is
+/// recorded, never the request headers and so never the API key
+#[derive(Debug, Serialize)]
+pub struct LogRecord {
+    /// unix milliseconds
+    pub timestamp: u128,
+    pub direction: Direction,
+    pub model: String,
+    /// messages of a request
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<Message>,
+    /// text of a response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// stream events that carried text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deltas: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ResponseUsage>,
+    /// from the request going out to the end of the response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn now_millis() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or_default()
+}
+
+impl LogRecord {
+    pub fn request(model: &str, messages: &[Message]) -> Self {
+        Self {
+            timestamp: now_millis(),
+            direction: Direction::Request,
+            model: model.to_string(),
+            messages: messages.to_vec(),
+            content: None,
+            deltas: None,
+            usage: None,
+            latency_ms: None,
+            error: None,
+        }
+    }
+}
+
+/// Collects a streamed response into the record logged once it ends
+pub struct ResponseRecorder {
+    started: Instant,
+    text: String,
+    deltas: usize,
+    usage: Option<ResponseUsage>,
+}
+
+impl ResponseRecorder {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            text: String::new(),
+            deltas: 0,
+            usage: None,
+        }
+    }
+
+    pub fn push(&mut self, buffer: &StreamingBuffer) {
+        match &buffer.content {
+            DeltaContent::Append(fragment) if fragment.is_empty() => {}
+            DeltaContent::Append(fragment) => {
+                self.text.push_str(fragment);
+                self.deltas += 1;
+            }
+            DeltaContent::Rewrite(text) | DeltaContent::Replace(text) => {
+                self.text.clone_from(text);
+                self.deltas += 1;
+            }
+        }
+        if let Some(usage) = &buffer.usage {
+            self.usage.get_or_insert_with(Default::default).add(usage);
+        }
+    }
+
+    pub fn finish(self, model: &str, error: Option<String>) -> LogRecord {
+        LogRecord {
+            timestamp: now_millis(),
+            direction: Direction::Response,
+            model: model.to_string(),
+            messages: Vec::new(),
+            content: Some(self.text),
+            deltas: Some(self.deltas),
+            usage: self.usage,
+            latency_ms: Some(self.started.elapsed().as_millis()),
+            error,
+        }
+    }
+}
+
+/// Appends records as JSON lines to `logs/transcript.jsonl` in the data
+/// directory. Writing happens on a task of the runtime, never on the UI thread
+#[derive(Clone)]
+pub struct TranscriptLog {
+    sender: mpsc::UnboundedSender<LogRecord>,
+}
+
+impl TranscriptLog {
+    pub fn dir() -> Result<PathBuf> {
+        Config::logs_dir()
+    }
+
+    /// Start the writer task. A file over `max_bytes` is rotated, keeping
+    /// `keep_files` files including the one being written
+    pub fn start(runtime: &Runtime, max_bytes: u64, keep_files: usize) -> Result<Self> {
+        let dir = Self::dir()?;
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<LogRecord>();
+        runtime.spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                if let Err(e) = write_record(&dir, &record, max_bytes, keep_files).await {
+                    warn!("Could not write the transcript log: {:#}", e);
+                }
+            }
+        });
+        Ok(Self { sender })
+    }
+
+    pub fn record(&self, record: LogRecord) {
+        // the writer only stops with the runtime
+        let _ = self.sender.send(record);
+    }
+}
+
+fn log_path(dir: &Path, generation: usize) -> PathBuf {
+    match generation {
+        0 => dir.join(format!("{}.jsonl", LOG_STEM)),
+        n => dir.join(format!("{}.{}.jsonl", LOG_STEM, n)),
+    }
+}
+
+async fn write_record(dir: &Path, record: &LogRecord, max_bytes: u64, keep_files: usize) -> Result<()> {
+    let mut line = serde_json::to_string(record).context("Failed to serialize the record")?;
+    line.push('\n');
+
+    let path = log_path(dir, 0);
+    let size = tokio::fs::metadata(&path).await.map(|meta| meta.len()).unwrap_or(0);
+    if size > 0 && size + line.len() as u64 > max_bytes {
+        rotate(dir, keep_files).await?;
+    }
+
+    append_line(&path, &line).await
+}
+
+/// Append a line to a file, creating it if needed
+pub async fn append_line(path: &Path, line: &str) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    file.write_all(line.as_bytes()).await?;
+    // readers following the file see it right away
+    file.flush().await?;
+    Ok(())
+}
+
+/// Shift every file one generation up, dropping the oldest
+async fn rotate(dir: &Path, keep_files: usize) -> Result<()> {
+    let oldest = keep_files.max(1) - 1;
+    match tokio::fs::remove_file(log_path(dir, oldest)).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("Could not remove the oldest log"),
+    }
+    for generation in (0..oldest).rev() {
+        let from = log_path(dir, generation);
+        if tokio::fs::try_exists(&from).await.unwrap_or(false) {
+            tokio::fs::rename(&from, log_path(dir, generation + 1))
+                .await
+                .with_context(|| format!("Could not rotate {}", from.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Show a folder in the system file manager
+pub fn open_folder(path: &Path) -> Result<()> {
+    #[cfg(target_os = "windows")]
+    let program = "explorer";
+    #[cfg(target_os = "macos")]
+    let program = "open";
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let program = "xdg-open";
+
+    std::process::Command::new(program)
+        .arg(path)
+        .spawn()
+        .with_context(|| format!("Could not run {}", program))?;
+    Ok(())
+}
@@ -1,164 +1,2503 @@
-use eframe::egui::{self, epaint::Marginf, Button, Align, Color32, Layout, RichText, ScrollArea, TextEdit, Ui};
-use log::{debug, error, info};
+use eframe::egui::{self, epaint::Marginf, Align, Color32, Layout, RichText, ScrollArea, TextEdit, Ui};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
 
-use crate::api::{Message, Role};
-use crate::config::{Config, Theme};
-use crate::chat_render::ChatRenderer;
+use crate::api::{ErrorKind, Message, ModelInfo, RateLimits, Rating, Role, MAX_OUTPUT_TOKENS};
+use crate::attachment::{format_size, Attachment};
+use crate::batch::{self, BatchJob, RowStatus};
+use crate::citation;
+use crate::config::{Config, ResponseReveal, Theme};
+use crate::conversation::Conversation;
+use crate::chat_render::{ChatRenderer, RenderContext};
+use crate::i18n::{self, tr, tr_args, Language};
+use crate::price::{normalize_model_name, ModelPricing};
+
+const TOAST_DURATION: Duration = Duration::from_secs(4);
+const TOAST_FADE: Duration = Duration::from_millis(500);
+/// Points the arrow keys scroll the focused message list by
+const KEY_SCROLL_STEP: f32 = 40.0;
+/// Height the staged attachment chips take before they scroll
+const ATTACHMENT_STRIP_MAX_HEIGHT: f32 = 72.0;
+/// Characters of a batch input or answer shown in its table cell
+const BATCH_CELL_MAX_CHARS: usize = 60;
+
+/// Share of max_tokens past which the output bar warns of a cut
+const OUTPUT_WARNING_FRACTION: f32 = 0.8;
+
+/// Short-lived notification shown in the corner of the window
+#[derive(Clone)]
+pub struct Toast {
+    pub text: String,
+    pub created: Instant,
+}
 
 // UI states
 #[derive(Clone)]
 pub struct UiState {
     pub settings_open: bool,
+    /// new key being typed, only while `api_key_editing`
     pub api_key_buffer: String,
+    /// the key field is open for a new key, the saved one stays masked otherwise
+    pub api_key_editing: bool,
     pub input_cost_display: Option<f64>,
+    /// why the input cost can't be estimated, e.g. no pricing for the model
+    pub input_cost_unknown: Option<String>,
     pub total_cost: f64,
+    /// false once a response couldn't be priced, tokens are shown instead
+    pub total_cost_known: bool,
+    pub total_input_tokens: u64,
+    pub total_output_tokens: u64,
+    /// heuristic token count of the draft input, attachments included
+    pub input_tokens: Option<usize>,
+    /// heuristic token count of each staged attachment, once counted
+    pub attachment_tokens: Vec<usize>,
+    /// heuristic token count of the conversation history
+    pub conversation_tokens: usize,
+    /// context window of the active model, if known
+    pub context_window: Option<usize>,
+    /// the status bar shows the session totals, the input doesn't repeat them
+    pub status_bar_visible: bool,
+    /// text shown of the streaming response when it's held back, None to show it live
+    pub revealed_response: Option<String>,
+    /// rate limits reported with the last response
+    pub rate_limits: Option<RateLimits>,
+    /// the last response of the active conversation failed for a prompt over the context window
+    pub prompt_too_long: bool,
+    /// where older messages fall out of the context of the next request
+    pub context_cut: Option<ContextCut>,
+    /// the active conversation is archived, so read only
+    pub conversation_archived: bool,
+    /// the key is being checked again after the API refused it
+    pub checking_key: bool,
+    /// a settings control changed the config and it needs saving
+    pub config_dirty: bool,
+    pub toasts: Vec<Toast>,
+    /// filter the transcript down to pinned messages
+    pub show_pinned_only: bool,
+    /// settings the API client is built with were edited, it must be rebuilt
+    pub client_changed: bool,
+    /// the settings asked to show the transcript log folder
+    pub open_log_folder: bool,
+    /// profile change asked from the header or the settings
+    pub profile_action: Option<ProfileAction>,
+    /// name typed for a new profile
+    pub new_profile_name: String,
+    /// the app was built with text-to-speech and the engine started
+    pub tts_available: bool,
+    /// message being read aloud
+    pub speaking_message: Option<usize>,
+    pub palette_open: bool,
+    /// text typed in the command palette
+    pub palette_filter: String,
+    /// highlighted row of the filtered palette actions
+    pub palette_selected: usize,
+    /// conversation being renamed in the sidebar, with the title being typed
+    pub renaming_conversation: Option<(String, String)>,
+    /// conversation waiting for its deletion to be confirmed
+    pub confirm_delete: Option<String>,
+    /// sidebar shows only conversations with all of these tags
+    pub tag_filter: BTreeSet<String>,
+    /// tag being typed for the active conversation
+    pub tag_input: String,
+    /// estimated cost of a send waiting to be confirmed
+    pub confirm_send_cost: Option<f64>,
+    /// the message just sent is being sent again, waiting to be confirmed
+    pub confirm_duplicate_send: bool,
+    /// the window was closed while responses are streaming
+    pub confirm_quit: bool,
+    pub batch_open: bool,
+    /// inputs of the next batch, one per line
+    pub batch_inputs: String,
+    /// prompt each input of the batch is put in, at `{input}`
+    pub batch_template: String,
+    /// result rows picked for the CSV export, none means all
+    pub batch_selected: BTreeSet<usize>,
+    /// cited passage shown in the side popup
+    pub citation_view: Option<CitationView>,
+    /// sending only previews the request, mirrors the setting
+    pub dry_run: bool,
+    /// estimated tokens of the answer streaming in the active conversation
+    pub output_tokens: Option<usize>,
+    /// request shown instead of sent
+    pub request_preview: Option<RequestPreview>,
+}
+
+/// A citation's passage with the text around it in its document
+#[derive(Clone)]
+pub struct CitationView {
+    pub number: usize,
+    pub title: String,
+    pub before: String,
+    pub quoted: String,
+    pub after: String,
+}
+
+/// A request built but not sent, with what it's estimated to cost
+#[derive(Clone)]
+pub struct RequestPreview {
+    pub model: String,
+    /// the JSON body as it would be sent
+    pub body: String,
+    pub input_tokens: usize,
+    /// None when the model's pricing isn't known
+    pub input_cost: Option<f64>,
+    /// cost if the answer takes every output token allowed
+    pub max_output_cost: Option<f64>,
+}
+
+impl Default for UiState {
+    fn default() -> Self {
+        Self{
+            settings_open: false,
+            api_key_buffer: String::new(),
+            api_key_editing: false,
+            input_cost_display: None,
+            input_cost_unknown: None,
+            total_cost: 0.0,
+            total_cost_known: true,
+            total_input_tokens: 0,
+            total_output_tokens: 0,
+            input_tokens: None,
+            attachment_tokens: Vec::new(),
+            conversation_tokens: 0,
+            context_window: None,
+            status_bar_visible: false,
+            revealed_response: None,
+            rate_limits: None,
+            prompt_too_long: false,
+            context_cut: None,
+            conversation_archived: false,
+            checking_key: false,
+            config_dirty: false,
+            toasts: Vec::new(),
+            show_pinned_only: false,
+            client_changed: false,
+            open_log_folder: false,
+            profile_action: None,
+            new_profile_name: String::new(),
+            tts_available: false,
+            speaking_message: None,
+            palette_open: false,
+            palette_filter: String::new(),
+            palette_selected: 0,
+            renaming_conversation: None,
+            confirm_delete: None,
+            tag_filter: BTreeSet::new(),
+            tag_input: String::new(),
+            confirm_send_cost: None,
+            confirm_duplicate_send: false,
+            confirm_quit: false,
+            batch_open: false,
+            batch_inputs: String::new(),
+            batch_template: String::new(),
+            batch_selected: BTreeSet::new(),
+            citation_view: None,
+            dry_run: false,
+            request_preview: None,
+            output_tokens: None,
+        }
+    }
+
+}
+
+impl UiState {
+    /// Close the key field, dropping what was typed, e.g. when the
+    /// settings open or the profile changes
+    pub fn reset_api_key_field(&mut self) {
+        self.api_key_buffer.clear();
+        self.api_key_editing = false;
+    }
+
+    pub fn push_toast(&mut self, text: impl Into<String>) {
+        self.toasts.push(Toast {
+            text: text.into(),
+            created: Instant::now(),
+        });
+    }
+}
+
+/// What the header's model selector shows
+pub struct ModelChoice<'a> {
+    pub current: &'a str,
+    pub models: &'a [String],
+    /// metadata of the current model, once fetched
+    pub info: Option<&'a ModelInfo>,
+    /// switching is disabled while a response streams
+    pub enabled: bool,
+}
+
+/// The start and end of a key, enough to tell keys apart, e.g.
+/// "sk-ant-\u{2026}9f2". None without a key
+fn mask_api_key(key: &str) -> Option<String> {
+    let key = key.trim();
+    if key.is_empty() {
+        return None;
+    }
+    let prefix = key.strip_prefix("sk-ant-").map_or("", |_| "sk-ant-");
+    let chars: Vec<char> = key[prefix.len()..].chars().collect();
+    // too short to show any of it without giving most of it away
+    if chars.len() < 12 {
+        return Some(format!("{}\u{2026}", prefix));
+    }
+    let suffix: String = chars[chars.len() - 3..].iter().collect();
+    Some(format!("{}\u{2026}{}", prefix, suffix))
+}
+
+pub fn render_header(
+    ui: &mut Ui,
+    ui_state: &mut UiState,
+    config: &mut Config,
+    active_pricing: Option<&ModelPricing>,
+    model_choice: ModelChoice,
+    on_api_key_change: impl FnOnce(String),
+    on_model_change: impl FnOnce(String),
+) {
+    ui.horizontal(|ui| {
+        // ui.heading("ClauChat");
+        if ui
+            .toggle_value(&mut config.ui_layout.sidebar_open, "\u{2630}")
+            .on_hover_text(tr("header.sidebar_hint"))
+            .changed()
+        {
+            ui_state.config_dirty = true;
+        }
+        if config.profiles.len() > 1 {
+            let mut profile = config.active_profile.clone();
+            // like the model, it can't change under a response
+            let combo = ui.add_enabled_ui(model_choice.enabled, |ui| {
+                egui::ComboBox::from_id_salt("profile_selector")
+                    .selected_text(&profile)
+                    .show_ui(ui, |ui| {
+                        for name in config.profiles.keys() {
+                            ui.selectable_value(&mut profile, name.clone(), name);
+                        }
+                    })
+            });
+            combo.response.on_hover_text(tr("header.profile_hint"));
+            if profile != config.active_profile {
+                ui_state.profile_action = Some(ProfileAction::Switch(profile));
+            }
+        }
+
+        let mut selected = model_choice.current.to_string();
+        let combo = ui.add_enabled_ui(model_choice.enabled, |ui| {
+            egui::ComboBox::from_id_salt("model_selector")
+                .selected_text(model_choice.current)
+                .width(220.0)
+                .show_ui(ui, |ui| {
+                    for model in model_choice.models {
+                        ui.selectable_value(&mut selected, model.clone(), model);
+                    }
+                })
+        });
+        combo.response.on_hover_text(model_tooltip(model_choice.current, model_choice.info));
+        if selected != model_choice.current {
+            on_model_change(selected);
+        }
+
+        ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+            if ui.button(tr("header.settings")).clicked() {
+                ui_state.settings_open = !ui_state.settings_open;
+                ui_state.reset_api_key_field();
+            }
+
+            ui.toggle_value(&mut ui_state.show_pinned_only, tr("header.pinned"))
+                .on_hover_text(tr("header.pinned_hint"));
+        });
+    });
+
+    ui.separator();
+
+    if ui_state.settings_open {
+        egui::Frame::new()
+            // .fill(ui.style().visuals.extreme_bg_color)
+            .show(ui, |ui| {
+                ui.heading(tr("settings.title"));
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.pricing"));
+                    match active_pricing {
+                        Some(pricing) => ui.label(tr_args(
+                            "settings.pricing_line",
+                            &[
+                                &pricing.model_name,
+                                &format!("{:.2}", pricing.input_cost_per_million),
+                                &format!("{:.2}", pricing.output_cost_per_million),
+                                &pricing.source,
+                            ],
+                        )),
+                        None => ui.label(RichText::new(tr("settings.pricing_unknown")).weak()),
+                    };
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.profile"));
+                    ui.label(RichText::new(&config.active_profile).strong());
+                    ui.add(
+                        TextEdit::singleline(&mut ui_state.new_profile_name)
+                            .desired_width(120.0)
+                            .hint_text(tr("settings.profile_name_hint")),
+                    );
+                    let name = ui_state.new_profile_name.trim().to_string();
+                    let can_add = !name.is_empty() && !config.profiles.contains_key(&name);
+                    if ui.add_enabled(can_add, egui::Button::new(tr("settings.profile_add"))).clicked() {
+                        ui_state.profile_action = Some(ProfileAction::Add(name));
+                        ui_state.new_profile_name.clear();
+                    }
+                    if ui
+                        .add_enabled(config.profiles.len() > 1, egui::Button::new(tr("settings.profile_remove")))
+                        .clicked()
+                    {
+                        ui_state.profile_action = Some(ProfileAction::Remove);
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.api_key"));
+                    if !ui_state.api_key_editing {
+                        match mask_api_key(&config.profile().api_key) {
+                            Some(masked) => ui.monospace(masked),
+                            None => ui.label(RichText::new(tr("settings.api_key_missing")).weak()),
+                        };
+                        if ui.button(tr("settings.api_key_change")).clicked() {
+                            ui_state.api_key_buffer.clear();
+                            ui_state.api_key_editing = true;
+                        }
+                        return;
+                    }
+
+                    let api_key_response = ui.add(
+                        TextEdit::singleline(&mut ui_state.api_key_buffer)
+                            .password(true)
+                            .hint_text(tr("settings.api_key_hint")),
+                    );
+                    if !api_key_response.has_focus() && !api_key_response.lost_focus() {
+                        api_key_response.request_focus();
+                    }
+                    // committed on Enter or when leaving the field, Esc keeps the old key
+                    if api_key_response.lost_focus() {
+                        let cancelled = ui.input(|i| i.key_pressed(egui::Key::Escape));
+                        let new_key = std::mem::take(&mut ui_state.api_key_buffer).trim().to_string();
+                        if !cancelled && !new_key.is_empty() && new_key != config.profile().api_key {
+                            on_api_key_change(new_key);
+                        }
+                        ui_state.api_key_editing = false;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.system_prompt"));
+                    let prompt_response = ui.add(
+                        TextEdit::multiline(&mut config.profile_mut().system_prompt)
+                            .desired_rows(2)
+                            .hint_text(tr("settings.system_prompt_hint")),
+                    );
+                    if prompt_response.changed() {
+                        ui_state.config_dirty = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    let profile = config.profile_mut();
+                    let mut custom = profile.temperature.is_some();
+                    if ui.checkbox(&mut custom, tr("settings.temperature")).changed() {
+                        profile.temperature = custom.then_some(1.0);
+                        ui_state.config_dirty = true;
+                        ui_state.client_changed = true;
+                    }
+                    if let Some(temperature) = &mut profile.temperature {
+                        if ui.add(egui::Slider::new(temperature, 0.0..=1.0).step_by(0.05)).changed() {
+                            ui_state.config_dirty = true;
+                            ui_state.client_changed = true;
+                        }
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.api_url"));
+                    let base_url_response = ui.add(
+                        TextEdit::singleline(&mut config.base_url)
+                            .hint_text("https://api.anthropic.com"),
+                    );
+                    if base_url_response.changed() {
+                        ui_state.config_dirty = true;
+                    }
+                    if base_url_response.lost_focus() {
+                        ui_state.client_changed = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.beta_flags"));
+                    let mut flags = config.beta_flags.join(", ");
+                    let flags_response = ui
+                        .add(TextEdit::singleline(&mut flags).hint_text("prompt-caching-2024-07-31"))
+                        .on_hover_text(tr("settings.beta_flags_hint"));
+                    if flags_response.changed() {
+                        config.beta_flags = flags.split(',').map(|flag| flag.trim().to_string()).collect();
+                        ui_state.config_dirty = true;
+                    }
+                    if flags_response.lost_focus() {
+                        ui_state.client_changed = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.proxy"));
+                    let proxy_response = ui.add(
+                        TextEdit::singleline(&mut config.proxy_url)
+                            .hint_text("http://proxy:8080 (default: HTTPS_PROXY)"),
+                    );
+                    if proxy_response.changed() {
+                        ui_state.config_dirty = true;
+                    }
+                    if proxy_response.lost_focus() {
+                        ui_state.client_changed = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    let insecure_response = ui.checkbox(
+                        &mut config.danger_accept_invalid_certs,
+                        RichText::new(tr("settings.insecure_tls")).color(Color32::RED),
+                    );
+                    if insecure_response
+                        .on_hover_text(tr("settings.insecure_tls_hint"))
+                        .changed()
+                    {
+                        ui_state.config_dirty = true;
+                        ui_state.client_changed = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.language"));
+                    if ui
+                        .selectable_label(config.language.is_empty(), tr("settings.language_system"))
+                        .clicked()
+                    {
+                        config.language.clear();
+                        i18n::set_language(&config.language);
+                        ui_state.config_dirty = true;
+                    }
+                    for language in Language::ALL {
+                        if ui
+                            .selectable_label(config.language == language.code(), language.native_name())
+                            .clicked()
+                        {
+                            config.language = language.code().to_string();
+                            i18n::set_language(&config.language);
+                            ui_state.config_dirty = true;
+                        }
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.theme"));
+                    let current_theme = config.theme.clone();
+                    if ui
+                        .selectable_label(matches!(current_theme, Theme::Light), tr("settings.theme_light"))
+                        .clicked()
+                    {
+                        config.theme = Theme::Light;
+                        ui_state.config_dirty = true;
+                    }
+
+                    if ui
+                        .selectable_label(matches!(current_theme, Theme::Dark), tr("settings.theme_dark"))
+                        .clicked()
+                    {
+                        config.theme = Theme::Dark;
+                        ui_state.config_dirty = true;
+                    }
+
+                    if ui
+                        .selectable_label(matches!(current_theme, Theme::System), tr("settings.theme_system"))
+                        .clicked()
+                    {
+                        config.theme = Theme::System;
+                        ui_state.config_dirty = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.response_reveal"));
+                    let reveals = [
+                        (ResponseReveal::Live, tr("settings.reveal_live")),
+                        (ResponseReveal::EverySecond, tr("settings.reveal_every_second")),
+                        (ResponseReveal::WhenComplete, tr("settings.reveal_when_complete")),
+                    ];
+                    for (reveal, label) in reveals {
+                        if ui.selectable_label(config.response_reveal == reveal, label).clicked() {
+                            config.response_reveal = reveal;
+                            ui_state.config_dirty = true;
+                        }
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    if ui.checkbox(&mut config.reduce_motion, tr("settings.reduce_motion")).changed() {
+                        ui_state.config_dirty = true;
+                    }
+                    if ui.checkbox(&mut config.show_input_stats, tr("settings.input_stats")).changed() {
+                        ui_state.config_dirty = true;
+                    }
+                    if ui.checkbox(&mut config.show_raw_markdown, tr("settings.raw_markdown")).changed() {
+                        ui_state.config_dirty = true;
+                    }
+                    if ui.checkbox(&mut config.show_status_bar, tr("settings.status_bar")).changed() {
+                        ui_state.config_dirty = true;
+                    }
+                    if ui_state.tts_available
+                        && ui
+                            .checkbox(&mut config.auto_read_responses, tr("settings.auto_read"))
+                            .changed()
+                    {
+                        ui_state.config_dirty = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.font_size"));
+                    let slider_response =
+                        ui.add(egui::Slider::new(&mut config.font_size, 12.0..=24.0).step_by(1.0));
+                    if slider_response.changed() {
+                        ui_state.config_dirty = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.context"));
+                    ui.label(tr("settings.context_messages"));
+                    if ui
+                        .add(egui::DragValue::new(&mut config.context_max_messages))
+                        .on_hover_text(tr("settings.context_messages_hint"))
+                        .changed()
+                    {
+                        ui_state.config_dirty = true;
+                    }
+                    ui.label(tr("settings.context_tokens"));
+                    if ui
+                        .add(egui::DragValue::new(&mut config.context_token_budget).speed(100))
+                        .on_hover_text(tr("settings.context_tokens_hint"))
+                        .changed()
+                    {
+                        ui_state.config_dirty = true;
+                    }
+                    if ui
+                        .checkbox(&mut config.auto_trim_prompt, tr("settings.auto_trim"))
+                        .on_hover_text(tr("settings.auto_trim_hint"))
+                        .changed()
+                    {
+                        ui_state.config_dirty = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.log_level"));
+                    egui::ComboBox::from_id_salt("log_level")
+                        .selected_text(config.log_level_filter().as_str().to_lowercase())
+                        .show_ui(ui, |ui| {
+                            for level in LOG_LEVELS {
+                                if ui.selectable_label(config.log_level == *level, *level).clicked() {
+                                    config.log_level = level.to_string();
+                                    ui_state.config_dirty = true;
+                                }
+                            }
+                        });
+                    if ui.checkbox(&mut config.log_to_file, tr("settings.log_to_file")).changed() {
+                        ui_state.config_dirty = true;
+                    }
+                    ui.label(RichText::new(tr("settings.log_to_file_restart")).weak());
+                });
+
+                ui.horizontal(|ui| {
+                    let log_response = ui.checkbox(
+                        &mut config.log_transcripts,
+                        RichText::new(tr("settings.log_transcripts")).color(Color32::YELLOW),
+                    );
+                    if log_response.on_hover_text(tr("settings.log_transcripts_hint")).changed() {
+                        ui_state.config_dirty = true;
+                    }
+                    if ui.button(tr("settings.open_log_folder")).clicked() {
+                        ui_state.open_log_folder = true;
+                    }
+                });
+                if ui
+                    .checkbox(&mut config.trim_trailing_whitespace, tr("settings.trim_whitespace"))
+                    .on_hover_text(tr("settings.trim_whitespace_hint"))
+                    .changed()
+                {
+                    ui_state.config_dirty = true;
+                }
+                if ui
+                    .checkbox(&mut config.dry_run, tr("settings.dry_run"))
+                    .on_hover_text(tr("settings.dry_run_hint"))
+                    .changed()
+                {
+                    ui_state.config_dirty = true;
+                }
+                if ui
+                    .checkbox(&mut config.confirm_duplicate_send, tr("settings.confirm_duplicate"))
+                    .on_hover_text(tr("settings.confirm_duplicate_hint"))
+                    .changed()
+                {
+                    ui_state.config_dirty = true;
+                }
+                if ui
+                    .checkbox(&mut config.log_usage, tr("settings.log_usage"))
+                    .on_hover_text(tr("settings.log_usage_hint"))
+                    .changed()
+                {
+                    ui_state.config_dirty = true;
+                }
+                ui.horizontal(|ui| {
+                    if ui
+                        .checkbox(&mut config.auto_title, tr("settings.auto_title"))
+                        .on_hover_text(tr("settings.auto_title_hint"))
+                        .changed()
+                    {
+                        ui_state.config_dirty = true;
+                    }
+                    let model_response = ui.add_enabled(
+                        config.auto_title,
+                        TextEdit::singleline(&mut config.title_model).desired_width(200.0),
+                    );
+                    if model_response.changed() {
+                        ui_state.config_dirty = true;
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.mirror_path"));
+                    let path_response = ui.add(
+                        TextEdit::singleline(&mut config.mirror_path)
+                            .hint_text(tr("settings.mirror_path_hint"))
+                            .desired_width(260.0),
+                    );
+                    if path_response.changed() {
+                        ui_state.config_dirty = true;
+                    }
+                });
+                ui.horizontal(|ui| {
+                    let mirroring = !config.mirror_path.trim().is_empty();
+                    if ui
+                        .add_enabled(
+                            mirroring,
+                            egui::Checkbox::new(&mut config.mirror_per_conversation, tr("settings.mirror_per_conversation")),
+                        )
+                        .on_hover_text(tr("settings.mirror_per_conversation_hint"))
+                        .changed()
+                    {
+                        ui_state.config_dirty = true;
+                    }
+                    if ui
+                        .add_enabled(mirroring, egui::Checkbox::new(&mut config.mirror_live, tr("settings.mirror_live")))
+                        .on_hover_text(tr("settings.mirror_live_hint"))
+                        .changed()
+                    {
+                        ui_state.config_dirty = true;
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label(tr("settings.tab_width"));
+                    let slider_response = ui
+                        .add(egui::Slider::new(&mut config.tab_width, 1..=8))
+                        .on_hover_text(tr("settings.tab_width_hint"));
+                    if slider_response.changed() {
+                        ui_state.config_dirty = true;
+                    }
+                });
+
+                ui.separator();
+            });
+    }
+}
+
+fn model_tooltip(model: &str, info: Option<&ModelInfo>) -> String {
+    let Some(info) = info else {
+        return format!("{}\n{}", model, tr("model.no_info"));
+    };
+    let mut tooltip = format!("{}\n{}", info.display_name, info.id);
+    if let Some(created_at) = &info.created_at {
+        tooltip.push('\n');
+        tooltip.push_str(&tr_args("model.released", &[&created_at.get(..10).unwrap_or(created_at)]));
+    }
+    if let Some(cutoff) = &info.knowledge_cutoff {
+        tooltip.push('\n');
+        tooltip.push_str(&tr_args("model.cutoff", &[cutoff]));
+    }
+    tooltip
+}
+
+/// Levels offered for the app's logs
+const LOG_LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+/// Changes to the profiles, applied by the app
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProfileAction {
+    Switch(String),
+    /// a new profile from the active one, without its key
+    Add(String),
+    /// the active profile
+    Remove,
+}
+
+/// Actions listed in the command palette
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaletteAction {
+    NewChat,
+    StopGeneration,
+    ClearChat,
+    /// replace the earlier turns with a summary written by the model
+    SummarizeConversation,
+    RevertSummary,
+    ExportChat,
+    CopyConversation,
+    CopyCurl,
+    ToggleTheme,
+    SwitchModel,
+    OpenSettings,
+    OpenBatch,
+    /// jump to a conversation, listed after the commands
+    OpenConversation(String),
+}
+
+impl PaletteAction {
+    /// Commands, the conversations are added when the palette opens
+    pub const ALL: &'static [PaletteAction] = &[
+        PaletteAction::NewChat,
+        PaletteAction::StopGeneration,
+        PaletteAction::ClearChat,
+        PaletteAction::SummarizeConversation,
+        PaletteAction::RevertSummary,
+        PaletteAction::ExportChat,
+        PaletteAction::CopyConversation,
+        PaletteAction::CopyCurl,
+        PaletteAction::ToggleTheme,
+        PaletteAction::SwitchModel,
+        PaletteAction::OpenSettings,
+        PaletteAction::OpenBatch,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            PaletteAction::NewChat => tr("palette.new_chat"),
+            PaletteAction::StopGeneration => tr("palette.stop"),
+            PaletteAction::ClearChat => tr("palette.clear"),
+            PaletteAction::SummarizeConversation => tr("palette.summarize"),
+            PaletteAction::RevertSummary => tr("palette.revert_summary"),
+            PaletteAction::ExportChat => tr("palette.export"),
+            PaletteAction::CopyConversation => tr("palette.copy_conversation"),
+            PaletteAction::CopyCurl => tr("palette.copy_curl"),
+            PaletteAction::ToggleTheme => tr("palette.toggle_theme"),
+            PaletteAction::SwitchModel => tr("palette.switch_model"),
+            PaletteAction::OpenSettings => tr("palette.open_settings"),
+            PaletteAction::OpenBatch => tr("palette.open_batch"),
+            PaletteAction::OpenConversation(_) => tr("palette.open_conversation"),
+        }
+    }
+}
+
+/// Match `filter` as a subsequence of `label`, ignoring case.
+/// Lower scores are better, None when it doesn't match
+fn fuzzy_score(label: &str, filter: &str) -> Option<usize> {
+    let label = label.to_lowercase();
+    let mut chars = label.char_indices();
+    let mut first = None;
+    let mut last = 0;
+    for wanted in filter.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let (idx, _) = chars.find(|(_, c)| *c == wanted)?;
+        first.get_or_insert(idx);
+        last = idx;
+    }
+    // tighter and earlier matches rank first
+    Some(first.map_or(0, |first| first + (last - first)))
+}
+
+/// Ctrl+K / Ctrl+P overlay listing the app commands and the conversations,
+/// filtered by typing. Arrows move the selection, Enter runs it and Escape
+/// closes the palette
+pub fn render_command_palette(
+    ctx: &egui::Context,
+    ui_state: &mut UiState,
+    conversations: &[&Conversation],
+    on_action: impl FnOnce(PaletteAction),
+) {
+    if !ui_state.palette_open {
+        return;
+    }
+
+    let commands = PaletteAction::ALL
+        .iter()
+        .map(|action| (action.label().to_string(), action.clone()));
+    let jumps = conversations.iter().map(|conversation| {
+        (
+            format!("\u{1F4AC} {}", conversation_title(conversation)),
+            PaletteAction::OpenConversation(conversation.id.clone()),
+        )
+    });
+    // stable sort, commands stay ahead of conversations on equal scores
+    let mut actions: Vec<(usize, String, PaletteAction)> = commands
+        .chain(jumps)
+        .filter_map(|(label, action)| Some((fuzzy_score(&label, &ui_state.palette_filter)?, label, action)))
+        .collect();
+    actions.sort_by_key(|(score, ..)| *score);
+
+    let (up, down, enter, escape) = ctx.input(|i| {
+        (
+            i.key_pressed(egui::Key::ArrowUp),
+            i.key_pressed(egui::Key::ArrowDown),
+            i.key_pressed(egui::Key::Enter),
+            i.key_pressed(egui::Key::Escape),
+        )
+    });
+    if down {
+        ui_state.palette_selected += 1;
+    }
+    if up {
+        ui_state.palette_selected = ui_state.palette_selected.saturating_sub(1);
+    }
+    ui_state.palette_selected = ui_state.palette_selected.min(actions.len().saturating_sub(1));
+
+    let mut chosen = None;
+    egui::Window::new(tr("palette.title"))
+        .title_bar(false)
+        .resizable(false)
+        .collapsible(false)
+        .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 40.0))
+        .fixed_size(egui::vec2(360.0, 0.0))
+        .show(ctx, |ui| {
+            let filter_response = ui.add(
+                TextEdit::singleline(&mut ui_state.palette_filter)
+                    .hint_text(tr("palette.hint"))
+                    .desired_width(f32::INFINITY),
+            );
+            filter_response.request_focus();
+            if filter_response.changed() {
+                ui_state.palette_selected = 0;
+            }
+
+            ui.separator();
+            ScrollArea::vertical().max_height(240.0).show(ui, |ui| {
+                if actions.is_empty() {
+                    ui.label(RichText::new(tr("palette.no_match")).weak());
+                }
+                for (row, (_, label, action)) in actions.iter().enumerate() {
+                    let selected = row == ui_state.palette_selected;
+                    let response = ui.selectable_label(selected, label);
+                    if selected && (up || down) {
+                        response.scroll_to_me(None);
+                    }
+                    if response.clicked() {
+                        chosen = Some(action.clone());
+                    }
+                }
+            });
+        });
+
+    if enter {
+        chosen = chosen.or_else(|| {
+            actions
+                .get(ui_state.palette_selected)
+                .map(|(.., action)| action.clone())
+        });
+    }
+    if chosen.is_some() || escape {
+        ui_state.palette_open = false;
+        ui_state.palette_filter.clear();
+        ui_state.palette_selected = 0;
+    }
+    if let Some(action) = chosen {
+        on_action(action);
+    }
+}
+
+/// Tag edit on the active conversation
+pub enum TagAction {
+    Add(String),
+    Remove(String),
+}
+
+/// Tags of the active conversation, with a field to add one.
+/// Known tags matching what's typed are offered as completions
+pub fn render_tag_bar(
+    ui: &mut Ui,
+    ui_state: &mut UiState,
+    tags: &[String],
+    known_tags: &BTreeSet<String>,
+    mut on_action: impl FnMut(TagAction),
+) {
+    ui.horizontal_wrapped(|ui| {
+        for tag in tags {
+            egui::Frame::group(ui.style())
+                .inner_margin(Marginf::symmetric(4.0, 0.0))
+                .show(ui, |ui| {
+                    ui.label(RichText::new(format!("#{}", tag)).small());
+                    if ui.small_button("\u{2715}").on_hover_text(tr("tags.remove")).clicked() {
+                        on_action(TagAction::Remove(tag.clone()));
+                    }
+                });
+        }
+
+        let response = ui.add(
+            TextEdit::singleline(&mut ui_state.tag_input)
+                .id(tag_input_id())
+                .hint_text(tr("tags.hint"))
+                .desired_width(100.0),
+        );
+        if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+            let tag = std::mem::take(&mut ui_state.tag_input);
+            if !tag.trim().is_empty() {
+                on_action(TagAction::Add(tag));
+            }
+        }
+
+        let typed = ui_state.tag_input.trim().to_lowercase();
+        if typed.is_empty() {
+            return;
+        }
+        let completions = known_tags
+            .iter()
+            .filter(|tag| tag.starts_with(&typed) && !tags.contains(tag))
+            .take(5);
+        for tag in completions {
+            if ui.small_button(format!("#{}", tag)).clicked() {
+                ui_state.tag_input.clear();
+                on_action(TagAction::Add(tag.clone()));
+            }
+        }
+    });
+}
+
+/// What the user asked to do from the conversation sidebar
+pub enum ConversationAction {
+    New,
+    Open(String),
+    Rename(String, String),
+    Duplicate(String),
+    SetArchived(String, bool),
+    /// deletion was confirmed
+    Delete(String),
+}
+
+fn conversation_title(conversation: &Conversation) -> &str {
+    if conversation.title.is_empty() {
+        tr("sidebar.untitled")
+    } else {
+        &conversation.title
+    }
+}
+
+/// Conversations, newest first, with the active one selected, filtered
+/// by the tag chips at the top. Double-click or the context menu renames
+/// one inline, Enter keeps the new title and Escape drops it
+pub fn render_conversation_sidebar(
+    ui: &mut Ui,
+    ui_state: &mut UiState,
+    conversations: &[&Conversation],
+    known_tags: &BTreeSet<String>,
+    active: &str,
+    mut on_action: impl FnMut(ConversationAction),
+) {
+    if ui.button(tr("sidebar.new_chat")).clicked() {
+        on_action(ConversationAction::New);
+    }
+
+    // a tag whose last conversation is gone can't filter anything
+    ui_state.tag_filter.retain(|tag| known_tags.contains(tag));
+    if !known_tags.is_empty() {
+        ui.horizontal_wrapped(|ui| {
+            for tag in known_tags {
+                let selected = ui_state.tag_filter.contains(tag);
+                if ui.selectable_label(selected, RichText::new(format!("#{}", tag)).small()).clicked() {
+                    if selected {
+                        ui_state.tag_filter.remove(tag);
+                    } else {
+                        ui_state.tag_filter.insert(tag.clone());
+                    }
+                }
+            }
+        });
+    }
+    ui.separator();
+
+    ScrollArea::vertical().show(ui, |ui| {
+        for conversation in conversations {
+            if !ui_state.tag_filter.iter().all(|tag| conversation.tags.contains(tag)) {
+                continue;
+            }
+            let id = &conversation.id;
+            let edit_id = egui::Id::new(("rename_conversation", id));
+
+            if let Some((renaming, title)) = &mut ui_state.renaming_conversation {
+                if renaming == id {
+                    let response = ui.add(
+                        TextEdit::singleline(title)
+                            .id(edit_id)
+                            .desired_width(f32::INFINITY),
+                    );
+                    if response.lost_focus() {
+                        if !ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                            on_action(ConversationAction::Rename(id.clone(), title.clone()));
+                        }
+                        ui_state.renaming_conversation = None;
+                    }
+                    continue;
+                }
+            }
+
+            let mut label = conversation_title(conversation).to_string();
+            match &conversation.stream {
+                Some(stream) if stream.is_queued() => {
+                    label.push(' ');
+                    label.push_str(tr("sidebar.queued"));
+                }
+                // still streaming, maybe in the background
+                Some(_) => label.push_str(" \u{2026}"),
+                None => {}
+            }
+            let label = if conversation.archived {
+                RichText::new(label).weak()
+            } else {
+                RichText::new(label)
+            };
+            let response = ui.selectable_label(id == active, label);
+            let mut rename = response.double_clicked();
+            if response.clicked() {
+                on_action(ConversationAction::Open(id.clone()));
+            }
+            response.context_menu(|ui| {
+                if ui.button(tr("sidebar.rename")).clicked() {
+                    rename = true;
+                    ui.close_menu();
+                }
+                if ui.button(tr("sidebar.duplicate")).clicked() {
+                    on_action(ConversationAction::Duplicate(id.clone()));
+                    ui.close_menu();
+                }
+                let archive_label = if conversation.archived {
+                    tr("sidebar.unarchive")
+                } else {
+                    tr("sidebar.archive")
+                };
+                // not while a response is being written into it
+                if ui.add_enabled(conversation.stream.is_none(), egui::Button::new(archive_label)).clicked() {
+                    on_action(ConversationAction::SetArchived(id.clone(), !conversation.archived));
+                    ui.close_menu();
+                }
+                if ui.button(tr("sidebar.delete")).clicked() {
+                    ui_state.confirm_delete = Some(id.clone());
+                    ui.close_menu();
+                }
+            });
+            if rename {
+                ui_state.renaming_conversation = Some((id.clone(), conversation.title.clone()));
+                ui.memory_mut(|memory| memory.request_focus(edit_id));
+            }
+        }
+    });
+
+    let Some(id) = ui_state.confirm_delete.clone() else {
+        return;
+    };
+    let Some(conversation) = conversations.iter().find(|conversation| conversation.id == id) else {
+        ui_state.confirm_delete = None;
+        return;
+    };
+    egui::Window::new(tr("sidebar.delete_title"))
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ui.ctx(), |ui| {
+            ui.label(tr_args("sidebar.delete_confirm", &[&conversation_title(conversation)]));
+            ui.horizontal(|ui| {
+                if ui.button(RichText::new(tr("sidebar.delete")).color(Color32::RED)).clicked() {
+                    on_action(ConversationAction::Delete(id.clone()));
+                    ui_state.confirm_delete = None;
+                }
+                if ui.button(tr("dialog.cancel")).clicked() {
+                    ui_state.confirm_delete = None;
+                }
+            });
+        });
+}
+
+/// Modal asking to confirm a send estimated over the cost threshold
+pub fn render_send_confirmation(ctx: &egui::Context, ui_state: &mut UiState, on_confirm: impl FnOnce()) {
+    let Some(cost) = ui_state.confirm_send_cost else {
+        return;
+    };
+
+    let mut confirmed = false;
+    let escape = ctx.input(|i| i.key_pressed(egui::Key::Escape));
+    egui::Window::new(tr("confirm.title"))
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ctx, |ui| {
+            ui.label(tr_args("confirm.cost", &[&format!("{:.2}", cost)]));
+            ui.horizontal(|ui| {
+                confirmed = ui.button(tr("input.send")).clicked();
+                if ui.button(tr("dialog.cancel")).clicked() || escape {
+                    ui_state.confirm_send_cost = None;
+                }
+            });
+        });
+
+    if confirmed {
+        ui_state.confirm_send_cost = None;
+        on_confirm();
+    }
+}
+
+/// Modal asking to confirm sending again the message just sent
+pub fn render_duplicate_confirmation(ctx: &egui::Context, ui_state: &mut UiState, on_confirm: impl FnOnce()) {
+    if !ui_state.confirm_duplicate_send {
+        return;
+    }
+
+    let mut confirmed = false;
+    let escape = ctx.input(|i| i.key_pressed(egui::Key::Escape));
+    egui::Window::new(tr("confirm.duplicate_title"))
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ctx, |ui| {
+            ui.label(tr("confirm.duplicate"));
+            ui.horizontal(|ui| {
+                confirmed = ui.button(tr("confirm.send_again")).clicked();
+                if ui.button(tr("dialog.cancel")).clicked() || escape {
+                    ui_state.confirm_duplicate_send = false;
+                }
+            });
+        });
+
+    if confirmed {
+        ui_state.confirm_duplicate_send = false;
+        on_confirm();
+    }
+}
+
+/// The request a send would make and its estimated cost, sent from here
+/// once it looks right
+pub fn render_request_preview(ctx: &egui::Context, ui_state: &mut UiState, on_send: impl FnOnce()) {
+    let Some(preview) = &ui_state.request_preview else {
+        return;
+    };
+
+    let mut open = true;
+    let mut send = false;
+    let escape = ctx.input(|i| i.key_pressed(egui::Key::Escape));
+    let cost = |cost: Option<f64>| cost.map_or_else(|| "?".to_string(), |cost| format!("${:.6}", cost));
+    egui::Window::new(tr("preview.title"))
+        .id(egui::Id::new("request_preview"))
+        .open(&mut open)
+        .collapsible(false)
+        .default_size(egui::vec2(560.0, 480.0))
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ctx, |ui| {
+            egui::Grid::new("request_preview_grid").num_columns(2).show(ui, |ui| {
+                ui.label(tr("preview.model"));
+                ui.monospace(&preview.model);
+                ui.end_row();
+                ui.label(tr("preview.input_tokens"));
+                ui.label(format!("~{}", preview.input_tokens));
+                ui.end_row();
+                ui.label(tr("preview.input_cost"));
+                ui.label(cost(preview.input_cost));
+                ui.end_row();
+                ui.label(tr("preview.max_output_cost"));
+                ui.label(cost(preview.max_output_cost))
+                    .on_hover_text(tr_args("preview.max_output_cost_hint", &[&MAX_OUTPUT_TOKENS]));
+                ui.end_row();
+            });
+            ui.separator();
+            ScrollArea::vertical().max_height(320.0).show(ui, |ui| {
+                let mut body = preview.body.as_str();
+                ui.add(
+                    TextEdit::multiline(&mut body)
+                        .code_editor()
+                        .desired_width(f32::INFINITY),
+                );
+            });
+            ui.horizontal(|ui| {
+                send = ui.button(tr("input.send")).clicked();
+                if ui.button(tr("preview.copy")).clicked() {
+                    ctx.copy_text(preview.body.clone());
+                }
+            });
+        });
+
+    if send {
+        ui_state.request_preview = None;
+        on_send();
+    } else if !open || escape {
+        ui_state.request_preview = None;
+    }
+}
+
+/// Modal asking whether to quit while responses are streaming
+pub fn render_quit_confirmation(ctx: &egui::Context, ui_state: &mut UiState, on_quit: impl FnOnce()) {
+    if !ui_state.confirm_quit {
+        return;
+    }
+
+    let mut quit = false;
+    let escape = ctx.input(|i| i.key_pressed(egui::Key::Escape));
+    egui::Window::new(tr("quit.title"))
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ctx, |ui| {
+            ui.label(tr("quit.streaming"));
+            ui.horizontal(|ui| {
+                quit = ui.button(RichText::new(tr("quit.stop_and_quit")).color(Color32::RED)).clicked();
+                if ui.button(tr("quit.keep_waiting")).clicked() || escape {
+                    ui_state.confirm_quit = false;
+                }
+            });
+        });
+
+    if quit {
+        ui_state.confirm_quit = false;
+        on_quit();
+    }
+}
+
+/// What the batch panel asks of the app
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatchAction {
+    Submit,
+    /// open a row's question and answer as a new conversation
+    OpenAsChat(usize),
+    /// write the picked rows, or all of them, to a CSV file
+    ExportCsv,
+    /// forget the finished batch to start another
+    Clear,
+}
+
+/// First line of a table cell, shortened
+fn batch_cell(text: &str) -> String {
+    let line = text.lines().find(|line| !line.trim().is_empty()).unwrap_or_default().trim();
+    let mut cell: String = line.chars().take(BATCH_CELL_MAX_CHARS).collect();
+    if line.chars().count() > BATCH_CELL_MAX_CHARS || text.trim().lines().count() > 1 {
+        cell.push('\u{2026}');
+    }
+    cell
+}
+
+/// Window running one prompt template over a list of inputs with the
+/// Message Batches API, then listing the answers. Until a batch is
+/// submitted it takes the inputs and the template, a text file dropped on
+/// the app while it's open is added to the inputs
+pub fn render_batch_window(
+    ctx: &egui::Context,
+    ui_state: &mut UiState,
+    job: Option<&BatchJob>,
+    has_client: bool,
+    mut on_action: impl FnMut(BatchAction),
+) {
+    let mut open = ui_state.batch_open;
+    egui::Window::new(tr("batch.title"))
+        .open(&mut open)
+        .default_width(640.0)
+        .show(ctx, |ui| match job {
+            None => render_batch_form(ui, ui_state, has_client, &mut on_action),
+            Some(job) => render_batch_results(ui, ui_state, job, &mut on_action),
+        });
+    ui_state.batch_open = open;
+}
+
+fn render_batch_form(ui: &mut Ui, ui_state: &mut UiState, has_client: bool, on_action: &mut impl FnMut(BatchAction)) {
+    ui.label(tr("batch.inputs"));
+    ScrollArea::vertical()
+        .id_salt("batch_inputs")
+        .max_height(160.0)
+        .show(ui, |ui| {
+            ui.add(
+                TextEdit::multiline(&mut ui_state.batch_inputs)
+                    .hint_text(tr("batch.inputs_hint"))
+                    .desired_rows(6)
+                    .desired_width(f32::INFINITY),
+            );
+        });
+
+    ui.label(tr("batch.template"));
+    ui.add(
+        TextEdit::multiline(&mut ui_state.batch_template)
+            .hint_text(tr("batch.template_hint"))
+            .desired_rows(3)
+            .desired_width(f32::INFINITY),
+    );
+
+    let inputs = batch::parse_inputs(&ui_state.batch_inputs).len();
+    let template = ui_state.batch_template.trim();
+    let blocked = if !has_client {
+        Some(tr("input.no_api_key"))
+    } else if inputs == 0 {
+        Some(tr("batch.no_inputs"))
+    } else if !template.is_empty() && !template.contains(batch::INPUT_PLACEHOLDER) {
+        Some(tr("batch.no_placeholder"))
+    } else {
+        None
+    };
+
+    ui.horizontal(|ui| {
+        let submit = ui.add_enabled(blocked.is_none(), egui::Button::new(tr("batch.submit")));
+        let submit = match blocked {
+            Some(reason) => submit.on_disabled_hover_text(reason),
+            None => submit,
+        };
+        if submit.clicked() {
+            on_action(BatchAction::Submit);
+        }
+        ui.label(RichText::new(tr_args("batch.count", &[&inputs])).weak());
+    });
+}
+
+fn render_batch_results(ui: &mut Ui, ui_state: &mut UiState, job: &BatchJob, on_action: &mut impl FnMut(BatchAction)) {
+    let counts = &job.counts;
+    let failed = counts.errored + counts.canceled + counts.expired;
+    match (&job.error, &job.id) {
+        (Some(error), _) => {
+            ui.colored_label(Color32::RED, tr_args("batch.failed", &[error]));
+        }
+        (None, None) => {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(tr("batch.creating"));
+            });
+        }
+        (None, Some(id)) if !job.done => {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(tr_args("batch.progress", &[id, &counts.processing, &counts.succeeded, &failed]));
+            });
+        }
+        (None, Some(id)) => {
+            ui.label(tr_args("batch.ended", &[id, &counts.succeeded, &failed]));
+        }
+    }
+
+    let answered = job.rows.iter().filter_map(|row| row.cost);
+    let unpriced = job
+        .rows
+        .iter()
+        .any(|row| row.cost.is_none() && matches!(row.status, RowStatus::Succeeded { .. }));
+    let total: f64 = answered.sum();
+    if total > 0.0 || unpriced {
+        let mut cost = tr_args("batch.cost", &[&format!("{:.4}", total)]);
+        if unpriced {
+            cost.push_str(tr("batch.cost_partial"));
+        }
+        ui.label(RichText::new(cost).weak());
+    }
+
+    ui.horizontal(|ui| {
+        let export = if ui_state.batch_selected.is_empty() {
+            tr("batch.export_all").to_string()
+        } else {
+            tr_args("batch.export_selected", &[&ui_state.batch_selected.len()])
+        };
+        if ui.add_enabled(job.done && job.error.is_none(), egui::Button::new(export)).clicked() {
+            on_action(BatchAction::ExportCsv);
+        }
+        if ui
+            .add_enabled(job.done, egui::Button::new(tr("batch.clear")))
+            .on_hover_text(tr("batch.clear_hint"))
+            .clicked()
+        {
+            on_action(BatchAction::Clear);
+        }
+    });
+    ui.separator();
+
+    ScrollArea::vertical().id_salt("batch_results").show(ui, |ui| {
+        egui::Grid::new("batch_results_grid").striped(true).num_columns(6).show(ui, |ui| {
+            for (idx, row) in job.rows.iter().enumerate() {
+                let mut selected = ui_state.batch_selected.contains(&idx);
+                if ui.add_enabled(job.done, egui::Checkbox::without_text(&mut selected)).changed() {
+                    if selected {
+                        ui_state.batch_selected.insert(idx);
+                    } else {
+                        ui_state.batch_selected.remove(&idx);
+                    }
+                }
+                ui.label(batch_cell(&row.input)).on_hover_text(&row.prompt);
+                match &row.status {
+                    RowStatus::Pending => {
+                        ui.label(RichText::new(tr("batch.pending")).weak());
+                    }
+                    RowStatus::Succeeded { text, .. } => {
+                        ui.label(batch_cell(text)).on_hover_text(text);
+                    }
+                    RowStatus::Failed(error) => {
+                        ui.colored_label(Color32::RED, batch_cell(error)).on_hover_text(error);
+                    }
+                }
+                match &row.status {
+                    RowStatus::Succeeded { usage, .. } => {
+                        ui.label(
+                            RichText::new(tr_args("batch.tokens", &[&usage.input_tokens, &usage.output_tokens]))
+                                .weak(),
+                        );
+                    }
+                    _ => {
+                        ui.label("");
+                    }
+                }
+                ui.label(row.cost.map(|cost| format!("${:.4}", cost)).unwrap_or_default());
+                let answered = matches!(row.status, RowStatus::Succeeded { .. });
+                if ui.add_enabled(answered, egui::Button::new(tr("batch.open_chat"))).clicked() {
+                    on_action(BatchAction::OpenAsChat(idx));
+                }
+                ui.end_row();
+            }
+        });
+    });
+}
+
+/// Side popup with a cited passage highlighted in its document
+pub fn render_citation_popup(ctx: &egui::Context, ui_state: &mut UiState) {
+    let Some(view) = &ui_state.citation_view else {
+        return;
+    };
+
+    let mut open = true;
+    let escape = ctx.input(|i| i.key_pressed(egui::Key::Escape));
+    egui::Window::new(format!("{} {}", citation::superscript(view.number), view.title))
+        .id(egui::Id::new("citation_popup"))
+        .open(&mut open)
+        .collapsible(false)
+        .default_width(320.0)
+        .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-12.0, 60.0))
+        .show(ctx, |ui| {
+            let body = egui::TextStyle::Body.resolve(ui.style());
+            let weak = ui.visuals().weak_text_color();
+            let highlight = if ui.visuals().dark_mode {
+                Color32::from_rgba_unmultiplied(255, 200, 0, 60)
+            } else {
+                Color32::from_rgba_unmultiplied(255, 210, 0, 110)
+            };
+            let mut job = egui::text::LayoutJob::default();
+            job.append(&view.before, 0.0, egui::TextFormat::simple(body.clone(), weak));
+            job.append(
+                &view.quoted,
+                0.0,
+                egui::TextFormat {
+                    background: highlight,
+                    ..egui::TextFormat::simple(body.clone(), ui.visuals().strong_text_color())
+                },
+            );
+            job.append(&view.after, 0.0, egui::TextFormat::simple(body, weak));
+            ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                ui.label(job);
+            });
+        });
+
+    if !open || escape {
+        ui_state.citation_view = None;
+    }
+}
+
+/// Draw pending toasts stacked in the bottom-right corner, dropping expired ones
+pub fn render_toasts(ctx: &egui::Context, ui_state: &mut UiState, reduce_motion: bool) {
+    ui_state.toasts.retain(|toast| toast.created.elapsed() < TOAST_DURATION);
+    if ui_state.toasts.is_empty() {
+        return;
+    }
+
+    egui::Area::new(egui::Id::new("toasts"))
+        .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-12.0, -12.0))
+        .order(egui::Order::Foreground)
+        .show(ctx, |ui| {
+            for toast in &ui_state.toasts {
+                let remaining = TOAST_DURATION.saturating_sub(toast.created.elapsed());
+                let opacity = if reduce_motion {
+                    1.0
+                } else {
+                    (remaining.as_secs_f32() / TOAST_FADE.as_secs_f32()).min(1.0)
+                };
+
+                ui.scope(|ui| {
+                    ui.set_opacity(opacity);
+                    egui::Frame::popup(ui.style()).show(ui, |ui| {
+                        ui.label(&toast.text);
+                    });
+                });
+            }
+        });
+
+    if reduce_motion {
+        ctx.request_repaint_after(TOAST_FADE);
+    } else {
+        ctx.request_repaint();
+    }
+}
+
+/// "≈ N tokens" readout for the draft, amber/red when it nears the room
+/// left in the context window after the conversation history
+fn render_draft_tokens(ui: &mut Ui, ui_state: &UiState, input_tokens: usize) {
+    let color = match ui_state.context_window {
+        Some(context_window) => {
+            let room = context_window.saturating_sub(ui_state.conversation_tokens);
+            if input_tokens as f32 > room as f32 * 0.9 {
+                Color32::RED
+            } else if input_tokens as f32 > room as f32 * 0.75 {
+                Color32::from_rgb(255, 191, 0)
+            } else {
+                ui.visuals().weak_text_color()
+            }
+        }
+        None => ui.visuals().weak_text_color(),
+    };
+
+    ui.label(
+        RichText::new(tr_args("input.tokens", &[&input_tokens]))
+            .color(color)
+            .small(),
+    );
+}
+
+/// Thin bar showing how much of the model's context window the conversation
+/// and the draft would use
+pub fn render_context_bar(ui: &mut Ui, ui_state: &UiState) {
+    let Some(context_window) = ui_state.context_window.filter(|&window| window > 0) else {
+        return;
+    };
+
+    let used = ui_state.conversation_tokens + ui_state.input_tokens.unwrap_or(0);
+    let fill = used as f32 / context_window as f32;
+    let color = if fill > 0.9 {
+        Color32::RED
+    } else if fill > 0.75 {
+        Color32::from_rgb(255, 191, 0)
+    } else {
+        ui.visuals().selection.bg_fill
+    };
+
+    ui.add(
+        egui::ProgressBar::new(fill.min(1.0))
+            .desired_height(4.0)
+            .fill(color),
+    )
+    .on_hover_text(tr_args(
+        "context.hover",
+        &[&used, &context_window, &format!("{:.0}", fill * 100.0)],
+    ));
+}
+
+/// Totals of the active conversation
+#[derive(Debug, Default)]
+pub struct ConversationSummary {
+    /// messages sent by the user
+    pub turns: usize,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// None when a response couldn't be priced
+    pub cost: Option<f64>,
+}
+
+/// Collapsible one-line accounting of the conversation under the transcript,
+/// `open` follows the header so it opens the same way on the next run
+pub fn render_conversation_summary(ui: &mut Ui, summary: &ConversationSummary, open: &mut bool) {
+    let cost = match summary.cost {
+        Some(cost) => format!("${:.4}", cost),
+        None => tr("summary.cost_unknown").to_string(),
+    };
+    let turns = if summary.turns == 1 { tr("summary.turn") } else { tr("summary.turns") };
+    let response = egui::CollapsingHeader::new(
+        RichText::new(format!("{} {} \u{00B7} {}", summary.turns, turns, cost)).weak().small(),
+    )
+    .id_salt("conversation_summary")
+    .default_open(*open)
+    .show(ui, |ui| {
+        egui::Grid::new("conversation_summary_grid").show(ui, |ui| {
+            ui.label(tr("summary.input_tokens"));
+            ui.label(summary.input_tokens.to_string());
+            ui.end_row();
+            ui.label(tr("summary.output_tokens"));
+            ui.label(summary.output_tokens.to_string());
+            ui.end_row();
+            ui.label(tr("summary.cost"));
+            ui.label(cost);
+            ui.end_row();
+        });
+    });
+    if response.header_response.clicked() {
+        *open = !*open;
+    }
+}
+
+/// Where the request of the shown conversation is at
+#[derive(Clone, Copy)]
+pub enum RequestPhase {
+    Idle,
+    /// waiting for other conversations' responses to free a slot
+    Queued,
+    /// sent, or about to be, no text yet
+    Waiting(Duration),
+    Streaming(Duration),
+    /// the last request failed
+    Error,
+}
+
+/// What the status bar shows besides the session totals
+pub struct StatusBar<'a> {
+    pub model: &'a str,
+    pub endpoint: &'a str,
+    pub phase: RequestPhase,
+}
+
+/// Slim line below the composer: model and server, request phase with
+/// its elapsed time, and the session totals
+pub fn render_status_bar(ui: &mut Ui, ui_state: &UiState, status: &StatusBar) {
+    let host = status
+        .endpoint
+        .split("://")
+        .last()
+        .unwrap_or(status.endpoint)
+        .trim_end_matches('/');
+    let (phase, color, elapsed) = match status.phase {
+        RequestPhase::Idle => (tr("status.idle"), ui.visuals().weak_text_color(), None),
+        RequestPhase::Queued => (tr("status.queued"), Color32::YELLOW, None),
+        RequestPhase::Waiting(elapsed) => (tr("status.waiting"), Color32::YELLOW, Some(elapsed)),
+        RequestPhase::Streaming(elapsed) => (tr("status.streaming"), Color32::LIGHT_GREEN, Some(elapsed)),
+        RequestPhase::Error => (tr("status.error"), Color32::RED, None),
+    };
+
+    ui.separator();
+    ui.horizontal(|ui| {
+        ui.label(RichText::new(format!("{} \u{00B7} {}", status.model, host)).small().weak())
+            .on_hover_text(status.endpoint);
+        ui.separator();
+        ui.label(RichText::new(phase).small().color(color));
+        if let Some(elapsed) = elapsed {
+            ui.label(RichText::new(format!("{:.1}s", elapsed.as_secs_f32())).small().monospace());
+            ui.ctx().request_repaint_after(Duration::from_millis(100));
+        }
+        if let Some(rate_limits) = ui_state.rate_limits.as_ref().filter(|limits| limits.is_low()) {
+            ui.separator();
+            ui.label(RichText::new(tr("status.rate_limit_low")).small().color(Color32::YELLOW))
+                .on_hover_text(rate_limits_tooltip(rate_limits));
+        }
+
+        ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+            let mut totals = tr_args(
+                "status.session_tokens",
+                &[&ui_state.total_input_tokens, &ui_state.total_output_tokens],
+            );
+            if ui_state.total_cost_known {
+                totals.push_str(&format!(" \u{00B7} ${:.4}", ui_state.total_cost));
+            }
+            ui.label(RichText::new(totals).small().weak());
+        });
+    });
 }
 
-impl Default for UiState {
-    fn default() -> Self {
-        Self{
-            settings_open: false,
-            api_key_buffer: String::new(),
-            input_cost_display: None,
-            total_cost: 0.0,
+/// Every reported limit with what's left of it and when it resets
+fn rate_limits_tooltip(rate_limits: &RateLimits) -> String {
+    let mut lines: Vec<String> = rate_limits
+        .limits()
+        .map(|(name, limit)| {
+            let mut line = tr_args("ratelimit.line", &[&tr(name), &limit.remaining, &limit.limit]);
+            if let Some(reset) = &limit.reset {
+                line.push_str(&tr_args("ratelimit.reset", &[reset]));
+            }
+            line
+        })
+        .collect();
+    if let Some(seconds) = rate_limits.retry_after {
+        lines.push(tr_args("ratelimit.retry_after", &[&seconds]));
+    }
+    lines.join("\n")
+}
+
+/// Persistent banner shown while TLS certificate verification is disabled
+pub fn render_insecure_tls_warning(ui: &mut Ui) {
+    ui.horizontal(|ui| {
+        ui.label(RichText::new(tr("banner.warning")).color(Color32::YELLOW).strong());
+        ui.label(tr("banner.insecure_tls"));
+    });
+    ui.separator();
+}
+
+pub fn render_error(ui: &mut Ui, error: &str) {
+    ui.horizontal(|ui| {
+        ui.label(RichText::new(tr("banner.error")).color(Color32::RED).strong());
+        ui.label(error);
+    });
+    ui.separator();
+}
+
+/// Placeholder shown in the assistant bubble until the first token arrives
+fn render_typing_indicator(ui: &mut Ui, reduce_motion: bool) {
+    if reduce_motion {
+        ui.label(RichText::new("...").weak());
+        return;
+    }
+
+    let dots = (ui.input(|i| i.time) * 3.0) as usize % 3 + 1;
+    ui.label(RichText::new(".".repeat(dots)).weak());
+    ui.ctx().request_repaint_after(Duration::from_millis(300));
+}
+
+/// Buttons of a message header, and its content's context menu
+pub enum MessageAction {
+    TogglePin,
+    /// read the message aloud
+    Speak,
+    StopSpeaking,
+    /// put the selected text into the input as a blockquote
+    Quote(String),
+    /// put the whole message into the input, after the draft when true
+    UseAsInput(bool),
+    /// the context divider above the message was clicked
+    OpenContextSettings,
+    /// request the failed response again
+    Retry,
+    /// request it again without the oldest messages
+    RetryTrimmed,
+    /// continue the failed response after the text it got
+    Resume,
+    /// ask the API again whether it takes the stored key
+    RecheckKey,
+    /// request the last response again, maybe from another model
+    Regenerate(RegenerateOptions),
+    /// show the passage of a citation, by its index
+    ShowCitation(usize),
+    /// highlight a code block, by its index, as another language. None
+    /// goes back to its tag
+    SetCodeLanguage(usize, Option<String>),
+    /// rate the response, None takes the rating back
+    Rate(Option<Rating>),
+    /// note why the response got its rating
+    RateNote(String),
+}
+
+/// Model and temperature for a single regeneration, None keeps the
+/// conversation's
+#[derive(Debug, Clone, Default)]
+pub struct RegenerateOptions {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+}
+
+/// Read-aloud button of a message
+#[derive(Clone, Copy)]
+pub enum SpeechButton {
+    /// text-to-speech unavailable, or not an assistant message
+    Hidden,
+    Speak,
+    Stop,
+}
+
+/// How a message is shown, besides its content
+#[derive(Clone, Copy, Default)]
+pub struct MessageFlags {
+    pub is_streaming: bool,
+    pub is_pinned: bool,
+    /// a failed response that can be requested again
+    pub can_retry: bool,
+    /// it failed for a prompt over the context window, so it can be
+    /// requested again without the oldest messages
+    pub can_trim: bool,
+    /// a key check is running, the chip's Re-check key is off meanwhile
+    pub checking_key: bool,
+    /// estimated tokens of the answer streaming into it so far
+    pub output_tokens: Option<usize>,
+}
+
+/// Short name of a model for the tag of its messages, e.g. "3.5 Haiku"
+/// for claude-3-5-haiku-latest. Other ids are shown as they are
+fn model_tag(model: &str) -> String {
+    let Some(name) = model.strip_prefix("claude-") else {
+        return model.to_string();
+    };
+    let mut groups: Vec<String> = Vec::new();
+    let mut version = false;
+    for part in name.split('-') {
+        // release dates and aliases say nothing about the model
+        if part == "latest" || (part.len() == 8 && part.bytes().all(|byte| byte.is_ascii_digit())) {
+            continue;
+        }
+        let number = part.bytes().all(|byte| byte.is_ascii_digit());
+        match groups.last_mut() {
+            Some(last) if number && version => {
+                last.push('.');
+                last.push_str(part);
+            }
+            _ if number => groups.push(part.to_string()),
+            _ => {
+                let mut chars = part.chars();
+                groups.push(chars.next().map_or_else(String::new, |first| {
+                    first.to_uppercase().chain(chars).collect()
+                }));
+            }
+        }
+        version = number;
+    }
+    groups.join(" ")
+}
+
+/// Thin bar of the tokens streamed against max_tokens, turning amber
+/// when the answer nears the cut
+fn render_output_progress(ui: &mut Ui, tokens: usize) {
+    let fraction = tokens as f32 / MAX_OUTPUT_TOKENS as f32;
+    let mut bar = egui::ProgressBar::new(fraction.min(1.0))
+        .desired_width(160.0)
+        .desired_height(4.0);
+    if fraction >= OUTPUT_WARNING_FRACTION {
+        bar = bar.fill(Color32::from_rgb(230, 160, 40));
+    }
+    ui.horizontal(|ui| {
+        ui.add(bar);
+        ui.label(
+            RichText::new(tr_args("message.output_tokens", &[&tokens, &MAX_OUTPUT_TOKENS]))
+                .weak()
+                .small(),
+        );
+    })
+    .response
+    .on_hover_text(tr("message.output_tokens_hint"));
+}
+
+/// Thumbs to rate a response and, once it's rated, a note saying why
+fn render_rating(ui: &mut Ui, message: &Message) -> Option<MessageAction> {
+    let mut action = None;
+    let ratings = [
+        (Rating::Good, "\u{1F44D}", tr("message.rate_good")),
+        (Rating::Bad, "\u{1F44E}", tr("message.rate_bad")),
+    ];
+    for (rating, icon, hint) in ratings {
+        let rated = message.rating == Some(rating);
+        let mut response = ui.selectable_label(rated, RichText::new(icon).small()).on_hover_text(hint);
+        if rated && !message.rating_note.is_empty() {
+            response = response.on_hover_text(&message.rating_note);
+        }
+        if response.clicked() {
+            action = Some(MessageAction::Rate((!rated).then_some(rating)));
         }
     }
+    if message.rating.is_none() {
+        return action;
+    }
 
+    // the note is edited in a buffer, saved with the button
+    let note_id = ui.make_persistent_id("rating_note");
+    let menu = ui.menu_button(RichText::new("\u{1F4DD}").small(), |ui| {
+        let mut note = ui
+            .data(|data| data.get_temp::<String>(note_id))
+            .unwrap_or_else(|| message.rating_note.clone());
+        ui.add(
+            TextEdit::multiline(&mut note)
+                .hint_text(tr("message.rating_note_hint"))
+                .desired_rows(3)
+                .desired_width(240.0),
+        );
+        if ui.button(tr("message.rating_note_save")).clicked() {
+            action = Some(MessageAction::RateNote(note.trim().to_string()));
+            ui.close_menu();
+        }
+        ui.data_mut(|data| data.insert_temp(note_id, note));
+    });
+    menu.response.on_hover_text(tr("message.rating_note"));
+    if menu.inner.is_none() {
+        ui.data_mut(|data| data.remove::<String>(note_id));
+    }
+    action
 }
 
-pub fn render_header(
+pub fn render_message(
     ui: &mut Ui,
-    ui_state: &mut UiState,
-    config: &mut Config,
-    on_api_key_change: impl FnOnce(String),
+    message: &Message,
+    flags: MessageFlags,
+    render: &mut RenderContext,
+    speech: SpeechButton,
+    on_action: impl FnOnce(MessageAction),
 ) {
-    ui.horizontal(|ui| {
-        // ui.heading("ClauChat");
-        ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
-            if ui.button("Settings").clicked() {
-                ui_state.settings_open = !ui_state.settings_open;
-                if ui_state.settings_open && ui_state.api_key_buffer.is_empty() {
-                    ui_state.api_key_buffer = config.api_key.clone();
+
+        // .color(Color32::from_rgba_premultiplied(255, 191, 0, 180))
+    let (color, prefix) = match message.role {
+        Role::User => (Color32::WHITE, tr("role.user")),
+        Role::Assistant => (Color32::from_rgba_premultiplied(255, 191, 145, 255), tr("role.assistant")),
+        Role::System => (Color32::LIGHT_GREEN, tr("role.system")),
+    };
+    // the per-message toggle overrides the global preference
+    let raw_id = ui.make_persistent_id("raw_markdown");
+    let mut raw = ui
+        .data(|data| data.get_temp::<bool>(raw_id))
+        .unwrap_or(render.config.show_raw_markdown);
+
+    let mut action = None;
+    // a failed response gets a red bar on its left
+    let left_margin = if message.error.is_some() { 8 } else { 0 };
+    let frame = egui::Frame::new().inner_margin(egui::Margin { left: left_margin, ..Default::default() });
+    let response = frame.show(ui, |ui| {
+        ui.horizontal(|ui| {
+            ui.horizontal(|ui| {
+                ui.spacing_mut().item_spacing.x = 0.0;
+                ui.label(RichText::new(prefix).color(color).strong());
+                // answers from the selected model need no tag, dates and
+                // aliases aside
+                let other_model = message
+                    .model
+                    .as_ref()
+                    .filter(|model| normalize_model_name(model) != normalize_model_name(render.model));
+                if let (Role::Assistant, Some(model)) = (&message.role, other_model) {
+                    ui.label(RichText::new(format!(" \u{00B7} {}", model_tag(model))).small().weak())
+                        .on_hover_text(model);
+                }
+                ui.label(RichText::new(": ").color(color).strong());
+            });
+            let answered = flags.can_retry && message.role == Role::Assistant && message.error.is_none();
+            if answered {
+                if let Some(options) = render_regenerate_menu(ui, render.models) {
+                    action = Some(MessageAction::Regenerate(options));
                 }
             }
-        });
-    });
 
-    ui.separator();
+            let (star, hint) = if flags.is_pinned {
+                ("\u{2605}", tr("message.unpin"))
+            } else {
+                ("\u{2606}", tr("message.pin"))
+            };
+            if ui.small_button(star).on_hover_text(hint).clicked() {
+                action = Some(MessageAction::TogglePin);
+            }
+            if ui
+                .selectable_label(raw, RichText::new("M\u{2193}").small())
+                .on_hover_text(tr("message.raw"))
+                .clicked()
+            {
+                raw = !raw;
+                ui.data_mut(|data| data.insert_temp(raw_id, raw));
+            }
 
-    if ui_state.settings_open {
-        egui::Frame::new()
-            // .fill(ui.style().visuals.extreme_bg_color)
-            .show(ui, |ui| {
-                ui.heading("Settings");
+            if message.role == Role::Assistant && !flags.is_streaming {
+                let use_as_input = ui.small_button("\u{270E}").on_hover_text(tr("message.use_as_input"));
+                if use_as_input.clicked() {
+                    let append = ui.input(|i| i.modifiers.shift);
+                    action = Some(MessageAction::UseAsInput(append));
+                }
+            }
 
-                ui.horizontal(|ui| {
-                    ui.label("API Key:");
-                    let api_key_response = ui.add(
-                        TextEdit::singleline(&mut ui_state.api_key_buffer)
-                            .password(true)
-                            .hint_text("API key"),
+            match speech {
+                SpeechButton::Hidden => {}
+                SpeechButton::Speak => {
+                    if ui.small_button("\u{1F50A}").on_hover_text(tr("message.read_aloud")).clicked() {
+                        action = Some(MessageAction::Speak);
+                    }
+                }
+                SpeechButton::Stop => {
+                    if ui.small_button("\u{23F9}").on_hover_text(tr("message.stop_reading")).clicked() {
+                        action = Some(MessageAction::StopSpeaking);
+                    }
+                }
+            }
+
+            if message.role == Role::Assistant && !message.local && !flags.is_streaming {
+                if let Some(rating) = render_rating(ui, message) {
+                    action = Some(rating);
+                }
+            }
+        });
+
+        if !message.attachments.is_empty() {
+            ui.horizontal_wrapped(|ui| {
+                for attachment in &message.attachments {
+                    ui.label(
+                        RichText::new(format!("\u{1F4CE} {} ({})", attachment.name, attachment.summary()))
+                            .weak(),
                     );
+                }
+            });
+        }
+
+        if flags.is_streaming && message.content.is_empty() {
+            render_typing_indicator(ui, render.config.reduce_motion);
+        } else if message.error.is_none() || !message.content.is_empty() {
+            // when nothing arrived before the error, the error alone is shown
+            let content = if raw {
+                Cow::Borrowed(message.content.as_str())
+            } else {
+                citation::with_markers(&message.content, &message.citations, citation::superscript)
+            };
+            let response = ChatRenderer::render_message_content(ui, &content, &message.code_languages, render, raw);
+            if let Some(quote) = response.quote {
+                action = Some(MessageAction::Quote(quote));
+            }
+            if let Some((block, language)) = response.code_language {
+                action = Some(MessageAction::SetCodeLanguage(block, language));
+            }
+        }
+
+        if let Some(tokens) = flags.output_tokens.filter(|&tokens| tokens > 0) {
+            render_output_progress(ui, tokens);
+        }
 
-                    if api_key_response.changed() {
-                        let new_key = ui_state.api_key_buffer.clone();
-                        on_api_key_change(new_key);
+        if !message.citations.is_empty() {
+            ui.horizontal_wrapped(|ui| {
+                for (idx, cited) in message.citations.iter().enumerate() {
+                    let title = cited.document_title.as_deref().unwrap_or(tr("citation.untitled"));
+                    let chip = ui
+                        .small_button(RichText::new(format!("{} {}", citation::superscript(idx + 1), title)).small())
+                        .on_hover_text(format!("\u{201C}{}\u{201D}", cited.cited_text.trim()));
+                    if chip.clicked() {
+                        action = Some(MessageAction::ShowCitation(idx));
                     }
-                });
+                }
+            });
+        }
 
-                ui.horizontal(|ui| {
-                    ui.label("Theme:");
-                    let current_theme = config.theme.clone();
-                    if ui
-                        .selectable_label(matches!(current_theme, Theme::Light), "Light")
-                        .clicked()
+        if let Some(error) = &message.error {
+            // a chip where the answer would be, it stays with the turn
+            let kind = message.error_kind.unwrap_or(ErrorKind::Other);
+            let chip = egui::Frame::new()
+                .fill(Color32::RED.gamma_multiply(0.15))
+                .stroke(egui::Stroke::new(1.0, Color32::RED.gamma_multiply(0.6)))
+                .corner_radius(4.0)
+                .inner_margin(egui::Margin::symmetric(6, 4));
+            chip.show(ui, |ui| {
+                ui.horizontal_wrapped(|ui| {
+                    ui.label(RichText::new(format!("\u{26A0} {}", tr(kind.label()))).color(Color32::RED).strong());
+                    ui.label(RichText::new(error).color(Color32::RED));
+                    if flags.can_retry && ui.button(tr("message.retry")).clicked() {
+                        action = Some(MessageAction::Retry);
+                    }
+                    if flags.can_trim && ui.button(tr("message.retry_trimmed")).clicked() {
+                        action = Some(MessageAction::RetryTrimmed);
+                    }
+                    // the refusal may have been a glitch, not a bad key
+                    if kind == ErrorKind::Authentication
+                        && ui
+                            .add_enabled(!flags.checking_key, egui::Button::new(tr("message.recheck_key")))
+                            .on_hover_text(tr("message.recheck_key_hint"))
+                            .clicked()
                     {
-                        config.theme = Theme::Light;
+                        action = Some(MessageAction::RecheckKey);
                     }
-
-                    if ui
-                        .selectable_label(matches!(current_theme, Theme::Dark), "Dark")
-                        .clicked()
+                    let partial = message.role == Role::Assistant && !message.content.trim().is_empty();
+                    if flags.can_retry
+                        && partial
+                        && ui.button(tr("message.resume")).on_hover_text(tr("message.resume_hint")).clicked()
                     {
-                        config.theme = Theme::Dark;
+                        action = Some(MessageAction::Resume);
                     }
+                });
+            });
+        }
+    });
+    if message.error.is_some() {
+        let rect = response.response.rect;
+        ui.painter()
+            .vline(rect.left() + 1.5, rect.y_range(), egui::Stroke::new(3.0, Color32::RED));
+    }
+
+    if let Some(action) = action {
+        on_action(action);
+    }
+    // ui.label(RichText::new(&message.content).color(color));
+    ui.add_space(8.0);
+}
+
+pub fn render_chat_area(
+    ui: &mut Ui,
+    messages: &[Message],
+    pinned: &BTreeSet<usize>,
+    ui_state: &UiState,
+    is_sending: bool,
+    render: &mut RenderContext,
+    mut on_action: impl FnMut(usize, MessageAction),
+) {
+    // focusable so the list can be scrolled from the keyboard
+    let list_rect = ui.available_rect_before_wrap();
+    let list_response = ui.interact(list_rect, messages_id(), egui::Sense::focusable_noninteractive());
+    if list_response.has_focus() {
+        ui.painter().rect_stroke(
+            list_rect,
+            2.0,
+            ui.visuals().selection.stroke,
+            egui::StrokeKind::Inside,
+        );
+    }
 
+    ScrollArea::vertical()
+        .auto_shrink([false, false])
+        .stick_to_bottom(true)
+        .show(ui, |ui| {
+            if list_response.has_focus() {
+                let page = ui.clip_rect().height() * 0.9;
+                let scroll = ui.input(|i| {
+                    let mut scroll = 0.0;
+                    if i.key_pressed(egui::Key::ArrowUp) {
+                        scroll += KEY_SCROLL_STEP;
+                    }
+                    if i.key_pressed(egui::Key::ArrowDown) {
+                        scroll -= KEY_SCROLL_STEP;
+                    }
+                    if i.key_pressed(egui::Key::PageUp) {
+                        scroll += page;
+                    }
+                    if i.key_pressed(egui::Key::PageDown) {
+                        scroll -= page;
+                    }
+                    scroll
                 });
+                if scroll != 0.0 {
+                    ui.scroll_with_delta(egui::vec2(0.0, scroll));
+                }
+            }
 
-                ui.horizontal(|ui| {
-                    let old_font_size = config.font_size;
-                    ui.label("Font Size:");
-                    let slider_response =
-                        ui.add(egui::Slider::new(&mut config.font_size, 12.0..=24.0).step_by(1.0));
-                    // save on slider change
-                    if slider_response.drag_stopped() {
-                        config
-                            .save()
-                            .unwrap_or_else(|e| {
-                                error!("Could not save config: {}", e);
-                                config.font_size = old_font_size;
-                            });
-                    }else if !slider_response.dragged() && slider_response.changed(){
-                        config
-                            .save()
-                            .unwrap_or_else(|e| {
-                                error!("Could not save config: {}", e);
-                                config.font_size = old_font_size;
-                            });
+            let last_idx = messages.len().saturating_sub(1);
+            for (idx, message) in messages.iter().enumerate() {
+                let is_pinned = pinned.contains(&idx);
+                if ui_state.show_pinned_only && !is_pinned {
+                    continue;
+                }
 
+                let flags = MessageFlags {
+                    is_streaming: is_sending && idx == last_idx,
+                    is_pinned,
+                    can_retry: !is_sending && !ui_state.conversation_archived && idx == last_idx,
+                    can_trim: !is_sending && idx == last_idx && ui_state.prompt_too_long,
+                    checking_key: ui_state.checking_key,
+                    output_tokens: ui_state.output_tokens.filter(|_| is_sending && idx == last_idx),
+                };
+                let cut = ui_state.context_cut.filter(|cut| cut.start > 0);
+                if cut.is_some_and(|cut| cut.start == idx) && render_context_divider(ui, &cut.unwrap()) {
+                    on_action(idx, MessageAction::OpenContextSettings);
+                }
+                // per-message id scope so code block toggles don't collide
+                ui.push_id(idx, |ui| {
+                    // left out of the next request
+                    if cut.is_some_and(|cut| idx < cut.start) && message.role != Role::System {
+                        ui.multiply_opacity(0.5);
                     }
+                    let speech = match ui_state.speaking_message {
+                        _ if !ui_state.tts_available || message.role != Role::Assistant => {
+                            SpeechButton::Hidden
+                        }
+                        Some(speaking) if speaking == idx => SpeechButton::Stop,
+                        _ => SpeechButton::Speak,
+                    };
+                    // the response streams on, only part of it may be revealed yet
+                    let revealed;
+                    let message = match &ui_state.revealed_response {
+                        Some(text) if flags.is_streaming => {
+                            revealed = Message {
+                                content: text.clone(),
+                                ..message.clone()
+                            };
+                            &revealed
+                        }
+                        _ => message,
+                    };
+                    render_message(ui, message, flags, render, speech, |action| on_action(idx, action));
                 });
+            }
 
-                ui.separator();
-            });
-    }
+            if ui_state.show_pinned_only && pinned.is_empty() {
+                ui.label(RichText::new(tr("chat.no_pinned")).weak());
+            }
+        });
 }
 
-pub fn render_error(ui: &mut Ui, error: &str) {
-    ui.horizontal(|ui| {
-        ui.label(RichText::new("Error: ").color(Color32::RED).strong());
-        ui.label(error);
-    });
-    ui.separator();
+/// Setting that leaves older messages out of the next request
+#[derive(Debug, Clone, Copy)]
+pub enum ContextLimit {
+    /// only this many messages are sent
+    Messages(usize),
+    /// the history and input are over this many tokens
+    Tokens(usize),
 }
 
-pub fn render_message(ui: &mut Ui, message: &Message) {
+/// First message sent with the next request, the ones above are left out
+#[derive(Debug, Clone, Copy)]
+pub struct ContextCut {
+    pub start: usize,
+    pub limit: ContextLimit,
+}
 
-        // .color(Color32::from_rgba_premultiplied(255, 191, 0, 180))
-    let (color, prefix) = match message.role {
-        Role::User => (Color32::WHITE, "You"),
-        Role::Assistant => (Color32::from_rgba_premultiplied(255, 191, 145, 255), "Claude"),
-        Role::System => (Color32::LIGHT_GREEN, "System"),
+/// Line above the first message of the context, true when clicked
+fn render_context_divider(ui: &mut Ui, cut: &ContextCut) -> bool {
+    let reason = match cut.limit {
+        ContextLimit::Messages(count) => tr_args("context.cut_messages", &[&count]),
+        ContextLimit::Tokens(budget) => tr_args("context.cut_tokens", &[&budget]),
     };
-    ui.horizontal(|ui| {
-        ui.label(RichText::new(format!("{}: ", prefix)).color(color).strong());
+    ui.separator();
+    ui.vertical_centered(|ui| {
+        let label = egui::Label::new(RichText::new(tr("context.divider")).small().weak());
+        ui.add(label.sense(egui::Sense::click()))
+            .on_hover_text(reason)
+            .on_hover_cursor(egui::CursorIcon::PointingHand)
+            .clicked()
+    })
+    .inner
+}
+
+/// What the user asked to do with a staged attachment
+pub enum AttachmentAction {
+    /// put the content back into the input text
+    KeepInline(usize),
+    Remove(usize),
+}
+
+/// Dictation state shown next to the input
+pub enum VoiceState {
+    Idle,
+    Recording(Duration),
+    Transcribing,
+}
+
+pub enum VoiceAction {
+    Start,
+    /// stop recording and transcribe
+    Stop,
+    /// drop the recording or the pending transcription
+    Cancel,
+}
+
+/// Mic button, or the recording indicator with its elapsed time
+pub fn render_voice_controls(ui: &mut Ui, state: VoiceState, on_action: impl FnOnce(VoiceAction)) {
+    let mut action = None;
+    ui.horizontal(|ui| match state {
+        VoiceState::Idle => {
+            if ui.small_button("\u{1F3A4}").on_hover_text(tr("voice.dictate")).clicked() {
+                action = Some(VoiceAction::Start);
+            }
+        }
+        VoiceState::Recording(elapsed) => {
+            ui.label(RichText::new("\u{25CF}").color(Color32::RED));
+            let secs = elapsed.as_secs();
+            ui.label(tr_args("voice.recording", &[&(secs / 60), &format!("{:02}", secs % 60)]));
+            if ui.small_button(tr("voice.done")).on_hover_text(tr("voice.done_hint")).clicked() {
+                action = Some(VoiceAction::Stop);
+            }
+            if ui.small_button(tr("voice.cancel")).clicked() {
+                action = Some(VoiceAction::Cancel);
+            }
+        }
+        VoiceState::Transcribing => {
+            ui.spinner();
+            ui.label(RichText::new(tr("voice.transcribing")).weak());
+            if ui.small_button(tr("voice.cancel")).clicked() {
+                action = Some(VoiceAction::Cancel);
+            }
+        }
     });
+    if let Some(action) = action {
+        on_action(action);
+    }
+}
 
-    ChatRenderer::render_message_content(ui, &message.content);
-    // ui.label(RichText::new(&message.content).color(color));
-    ui.add_space(8.0);
+/// Regenerate button, its dropdown picks another model or temperature
+/// for that response only
+fn render_regenerate_menu(ui: &mut Ui, models: &[String]) -> Option<RegenerateOptions> {
+    let options_id = ui.make_persistent_id("regenerate_options");
+    let mut options: RegenerateOptions = ui.data(|data| data.get_temp(options_id)).unwrap_or_default();
+    let mut chosen = None;
+
+    ui.menu_button("\u{27F3}", |ui| {
+        if ui.button(tr("message.regenerate")).clicked() {
+            chosen = Some(RegenerateOptions::default());
+            ui.close_menu();
+        }
+        ui.separator();
+
+        ui.label(RichText::new(tr("message.regenerate_model")).small().weak());
+        ScrollArea::vertical().max_height(160.0).show(ui, |ui| {
+            ui.selectable_value(&mut options.model, None, tr("message.regenerate_same_model"));
+            for model in models {
+                ui.selectable_value(&mut options.model, Some(model.clone()), model);
+            }
+        });
+        ui.horizontal(|ui| {
+            let mut custom = options.temperature.is_some();
+            if ui.checkbox(&mut custom, tr("settings.temperature")).changed() {
+                options.temperature = custom.then_some(1.0);
+            }
+            if let Some(temperature) = &mut options.temperature {
+                ui.add(egui::Slider::new(temperature, 0.0..=1.0).step_by(0.05));
+            }
+        });
+        if ui.button(tr("message.regenerate_with")).clicked() {
+            chosen = Some(options.clone());
+            ui.close_menu();
+        }
+    })
+    .response
+    .on_hover_text(tr("message.regenerate_hint"));
+
+    ui.data_mut(|data| data.insert_temp(options_id, options));
+    chosen
+}
+
+/// Id of the message input, to check its focus from outside
+pub fn input_id() -> egui::Id {
+    egui::Id::new("chat_input")
+}
+
+fn tag_input_id() -> egui::Id {
+    egui::Id::new("tag_input")
+}
+
+fn messages_id() -> egui::Id {
+    egui::Id::new("chat_messages")
+}
+
+/// Parts of the window the focus shortcut moves between, top to bottom
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FocusTarget {
+    Tags,
+    Messages,
+    Input,
+}
+
+impl FocusTarget {
+    const ORDER: [FocusTarget; 3] = [FocusTarget::Tags, FocusTarget::Messages, FocusTarget::Input];
+
+    pub fn id(self) -> egui::Id {
+        match self {
+            FocusTarget::Tags => tag_input_id(),
+            FocusTarget::Messages => messages_id(),
+            FocusTarget::Input => input_id(),
+        }
+    }
+
+    /// The one holding the keyboard focus, if any
+    pub fn focused(ctx: &egui::Context) -> Option<Self> {
+        Self::ORDER
+            .into_iter()
+            .find(|target| ctx.memory(|memory| memory.has_focus(target.id())))
+    }
+
+    /// Next one down, or up when `backwards`, wrapping around. From
+    /// elsewhere it starts at the input
+    pub fn cycle(from: Option<Self>, backwards: bool) -> Self {
+        let Some(idx) = from.and_then(|from| Self::ORDER.iter().position(|target| *target == from)) else {
+            return FocusTarget::Input;
+        };
+        let len = Self::ORDER.len();
+        let next = if backwards { idx + len - 1 } else { idx + 1 };
+        Self::ORDER[next % len]
+    }
+}
+
+/// Takes the place of the composer in an archived conversation
+pub fn render_archived_bar(ui: &mut Ui, on_unarchive: impl FnOnce()) {
+    ui.separator();
+    ui.horizontal(|ui| {
+        ui.label(RichText::new(tr("archived.notice")).weak());
+        if ui.button(tr("archived.unarchive")).clicked() {
+            on_unarchive();
+        }
+    });
 }
 
-pub fn render_chat_area(ui: &mut Ui, messages: &[Message]) {
+/// Strip of chips for the attachments staged for the next message, with
+/// their size and estimated tokens. It wraps and scrolls past a few rows
+pub fn render_attachment_chips(
+    ui: &mut Ui,
+    attachments: &[Attachment],
+    tokens: &[usize],
+    mut on_action: impl FnMut(AttachmentAction),
+) {
+    if attachments.is_empty() {
+        return;
+    }
+    // counted on the token thread, they lag behind a change for a moment
+    let tokens = (tokens.len() == attachments.len()).then_some(tokens);
+
     ScrollArea::vertical()
-        .auto_shrink([false, false])
-        .stick_to_bottom(true)
-        .max_height(ui.available_height() * 0.7)
+        .id_salt("attachment_strip")
+        .max_height(ATTACHMENT_STRIP_MAX_HEIGHT)
+        .auto_shrink([false, true])
         .show(ui, |ui| {
-            for message in messages {
-                render_message(ui, message);
-            }
+            ui.horizontal_wrapped(|ui| {
+                for (idx, attachment) in attachments.iter().enumerate() {
+                    egui::Frame::group(ui.style()).show(ui, |ui| {
+                        let mut label = format!(
+                            "\u{1F4CE} {} \u{00B7} {}",
+                            attachment.name,
+                            format_size(attachment.content.len())
+                        );
+                        if let Some(tokens) = tokens.and_then(|tokens| tokens.get(idx)) {
+                            label.push_str(" \u{00B7} ");
+                            label.push_str(&tr_args("input.tokens", &[tokens]));
+                        }
+                        ui.label(label).on_hover_text(attachment.summary());
+                        if ui
+                            .small_button(tr("attachment.keep_inline"))
+                            .on_hover_text(tr("attachment.keep_inline_hint"))
+                            .clicked()
+                        {
+                            on_action(AttachmentAction::KeepInline(idx));
+                        }
+                        if ui.small_button("\u{2715}").on_hover_text(tr("attachment.remove")).clicked() {
+                            on_action(AttachmentAction::Remove(idx));
+                        }
+                    });
+                }
+            });
         });
 }
+
+/// Countdown of a message about to be sent, with a button to take it back
+pub fn render_send_countdown(ui: &mut Ui, remaining: Duration, on_cancel: impl FnOnce()) {
+    ui.horizontal(|ui| {
+        ui.spinner();
+        let secs = remaining.as_secs_f32().ceil() as u64;
+        ui.label(RichText::new(tr_args("input.sending_in", &[&secs])).weak());
+        if ui.small_button(tr("dialog.cancel")).clicked() {
+            on_cancel();
+        }
+    });
+}
+
+/// Optional text the assistant's reply is forced to start with
+pub fn render_prefill_input(ui: &mut Ui, prefill: &mut String) {
+    ui.horizontal(|ui| {
+        ui.label(RichText::new(tr("prefill.label")).weak())
+            .on_hover_text(tr("prefill.hint"));
+        ui.add(
+            TextEdit::singleline(prefill)
+                .hint_text(tr("prefill.placeholder"))
+                .desired_width(f32::INFINITY),
+        );
+    });
+}
+
 //
+/// Whether the draft can be sent right now
+pub enum SendState<'a> {
+    Ready,
+    /// sending is disabled, with the reason shown on hover
+    Blocked(&'a str),
+    /// a response is streaming, the button turns into Stop
+    Sending,
+}
+
+/// Button actions of the input area
+pub enum InputAction {
+    Send,
+    /// show the request instead of sending it, Alt+click on send
+    Preview,
+    Stop,
+}
+
 pub fn render_input_area(
     ui: &mut Ui,
     input: &mut String,
     ui_state: &UiState,
-    is_sending: bool,
-    on_send: impl FnOnce(),
+    send_state: SendState,
+    show_input_stats: bool,
+    on_action: impl FnOnce(InputAction),
     on_input_change: impl FnOnce(),
 ) {
+    let mut action = None;
     ui.separator();
 
     let available_width = ui.available_width();
@@ -174,7 +2513,8 @@ pub fn render_input_area(
                 Layout::left_to_right(Align::LEFT),
                 |ui| {
                     let text_edit = TextEdit::multiline(input)
-                        .hint_text("Ask anything...")
+                        .id(input_id())
+                        .hint_text(tr("input.placeholder"))
                         .desired_width(available_width - 70.0)
                         .min_size(egui::vec2(available_width - 70.0, available_height))
                         .lock_focus(true)
@@ -190,37 +2530,104 @@ pub fn render_input_area(
                         on_input_change();
                     }
 
-                    if let Some(_input_cost) = ui_state.input_cost_display {
-                        let overlay_pos = ui.min_rect().max - egui::vec2(6.0, 8.0);
-                        let builder = egui::UiBuilder::new().max_rect(egui::Rect::from_min_size(
-                            overlay_pos - egui::vec2(70.0, 40.0),
-                            egui::vec2(72.0, 40.0),
-                        ));
-
-                        ui.allocate_new_ui(builder, |ui| {
-                            let overlay_text = RichText::new(format!("${:.6}", _input_cost))
-                                .color(Color32::from_rgba_premultiplied(250, 250, 210, 255))
-                                .size(14.0);
-                            ui.with_layout(Layout::right_to_left(Align::BOTTOM), |ui| {
-                                // debug!("make price overlay");
-                                ui.label(overlay_text);
-                            });
-                        });
-                    }
-
-                    let overlay_pos = ui.min_rect().max - egui::vec2(6.0, 2.0);
+                    // cost and count overlays, stacked upwards in the right margin
+                    let overlay_pos = ui.min_rect().max - egui::vec2(6.0, 8.0);
                     let builder = egui::UiBuilder::new().max_rect(egui::Rect::from_min_size(
-                        overlay_pos - egui::vec2(70.0, 70.0),
-                        egui::vec2(72.0, 40.0),
+                        overlay_pos - egui::vec2(70.0, 150.0),
+                        egui::vec2(72.0, 150.0),
                     ));
 
                     ui.allocate_new_ui(builder, |ui| {
-                        let overlay_text = RichText::new(format!("${:.6}", ui_state.total_cost))
-                            .color(Color32::from_rgba_premultiplied(255, 191, 145, 255))
-                            .size(14.0);
-                        ui.with_layout(Layout::right_to_left(Align::BOTTOM), |ui| {
-                            // debug!("make price overlay");
-                            ui.label(overlay_text);
+                        ui.with_layout(Layout::bottom_up(Align::RIGHT), |ui| {
+                            ui.spacing_mut().item_spacing.y = 2.0;
+
+                            match send_state {
+                                SendState::Ready => {
+                                    let (label, hint) = if ui_state.dry_run {
+                                        (tr("input.preview"), tr("input.preview_hint"))
+                                    } else {
+                                        (tr("input.send"), tr("input.send_hint"))
+                                    };
+                                    if ui.button(label).on_hover_text(hint).clicked() {
+                                        action = Some(if ui.input(|i| i.modifiers.alt) {
+                                            InputAction::Preview
+                                        } else {
+                                            InputAction::Send
+                                        });
+                                    }
+                                }
+                                SendState::Blocked(reason) => {
+                                    ui.add_enabled(false, egui::Button::new(tr("input.send")))
+                                        .on_disabled_hover_text(reason);
+                                }
+                                SendState::Sending => {
+                                    if ui.button(tr("input.stop")).on_hover_text(tr("input.stop_hint")).clicked() {
+                                        action = Some(InputAction::Stop);
+                                    }
+                                }
+                            }
+
+                            match (&ui_state.input_cost_display, &ui_state.input_cost_unknown) {
+                                (Some(input_cost), _) => {
+                                    ui.label(
+                                        RichText::new(format!("${:.6}", input_cost))
+                                            .color(Color32::from_rgba_premultiplied(250, 250, 210, 255))
+                                            .size(14.0),
+                                    );
+                                }
+                                (None, Some(reason)) => {
+                                    ui.label(
+                                        RichText::new("~?")
+                                            .color(Color32::from_rgba_premultiplied(250, 250, 210, 255))
+                                            .size(14.0),
+                                    )
+                                    .on_hover_text(reason);
+                                }
+                                (None, None) => {}
+                            }
+
+                            if let Some(input_tokens) = ui_state.input_tokens {
+                                render_draft_tokens(ui, ui_state, input_tokens);
+                            }
+                            if !ui_state.attachment_tokens.is_empty() {
+                                let attached: usize = ui_state.attachment_tokens.iter().sum();
+                                ui.label(
+                                    RichText::new(format!("\u{1F4CE} {}", tr_args("input.tokens", &[&attached])))
+                                        .weak()
+                                        .small(),
+                                )
+                                .on_hover_text(tr("input.attachment_tokens"));
+                            }
+
+                            // the status bar has the session totals when it's shown
+                            if !ui_state.status_bar_visible {
+                                let total_text = if ui_state.total_cost_known {
+                                    format!("${:.6}", ui_state.total_cost)
+                                } else {
+                                    format!(
+                                        "{} tok",
+                                        ui_state.total_input_tokens + ui_state.total_output_tokens
+                                    )
+                                };
+                                let response = ui.label(
+                                    RichText::new(total_text)
+                                        .color(Color32::from_rgba_premultiplied(255, 191, 145, 255))
+                                        .size(14.0),
+                                );
+                                if !ui_state.total_cost_known {
+                                    response.on_hover_text(tr_args(
+                                        "input.pricing_unknown",
+                                        &[&ui_state.total_input_tokens, &ui_state.total_output_tokens],
+                                    ));
+                                }
+                            }
+
+                            if show_input_stats {
+                                ui.spacing_mut().item_spacing.y = 0.0;
+                                ui.label(RichText::new(tr_args("input.lines", &[&input.lines().count()])).weak().small());
+                                ui.label(RichText::new(tr_args("input.words", &[&input.split_whitespace().count()])).weak().small());
+                                ui.label(RichText::new(tr_args("input.chars", &[&input.chars().count()])).weak().small());
+                            }
                         });
                     });
 
@@ -228,13 +2635,17 @@ pub fn render_input_area(
                     let pressed_enter = text_edit_response.has_focus()
                         && ui.input(|i| i.key_pressed(egui::Key::Enter) && i.modifiers.shift);
 
-                    // Call the callback if either the enter key was pressed or the button was clicked
-                    if pressed_enter && !input.trim().is_empty() && !is_sending {
-                        on_send();
+                    if pressed_enter && matches!(send_state, SendState::Ready) {
+                        action = Some(InputAction::Send);
                     }
 
                     ui.add_space(14.0);
                 },
             );
         });
+
+    // Call the callback if either the enter key was pressed or a button was clicked
+    if let Some(action) = action {
+        on_action(action);
+    }
 }
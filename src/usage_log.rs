@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use crate::api::ResponseUsage;
+use crate::config::Config;
+use crate::transcript::{append_line, now_millis};
+
+/// One line of the usage log, written for each completed response
+#[derive(Debug, Serialize)]
+pub struct UsageRecord {
+    /// unix milliseconds
+    pub timestamp: u128,
+    pub model: String,
+    pub conversation_id: String,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub cache_creation_input_tokens: u32,
+    pub cache_read_input_tokens: u32,
+    /// dollars, null when the model's pricing isn't known
+    pub cost: Option<f64>,
+}
+
+impl UsageRecord {
+    pub fn new(model: &str, conversation_id: &str, usage: &ResponseUsage, cost: Option<f64>) -> Self {
+        Self {
+            timestamp: now_millis(),
+            model: model.to_string(),
+            conversation_id: conversation_id.to_string(),
+            input_tokens: usage.input_tokens,
+            output_tokens: usage.output_tokens,
+            cache_creation_input_tokens: usage.cache_creation_input_tokens,
+            cache_read_input_tokens: usage.cache_read_input_tokens,
+            cost,
+        }
+    }
+}
+
+/// Appends records as JSON lines to `usage.jsonl` next to the config file,
+/// on a task of the runtime
+#[derive(Clone)]
+pub struct UsageLog {
+    sender: mpsc::UnboundedSender<UsageRecord>,
+}
+
+impl UsageLog {
+    pub fn path() -> Result<PathBuf> {
+        Ok(Config::data_dir()?.join("usage.jsonl"))
+    }
+
+    pub fn start(runtime: &Runtime) -> Result<Self> {
+        let path = Self::path()?;
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<UsageRecord>();
+        runtime.spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                if let Err(e) = write_record(&path, &record).await {
+                    warn!("Could not write the usage log: {:#}", e);
+                }
+            }
+        });
+        Ok(Self { sender })
+    }
+
+    pub fn record(&self, record: UsageRecord) {
+        // the writer only stops with the runtime
+        let _ = self.sender.send(record);
+    }
+}
+
+async fn write_record(path: &Path, record: &UsageRecord) -> Result<()> {
+    let mut line = serde_json::to_string(record).context("Failed to serialize the record")?;
+    line.push('\n');
+    append_line(path, &line).await
+}
@@ -0,0 +1,277 @@
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "voice")]
+use std::sync::{Arc, Mutex};
+
+/// Sample rate whisper models expect
+#[cfg(feature = "voice")]
+const WHISPER_SAMPLE_RATE: u32 = 16_000;
+
+/// Whether the app was built with voice input
+pub fn available() -> bool {
+    cfg!(feature = "voice")
+}
+
+/// Where dictation is turned into text
+#[cfg_attr(not(feature = "voice"), allow(dead_code))]
+#[derive(Debug, Clone, Default)]
+pub struct TranscriptionOptions {
+    /// local ggml whisper model, used when the `whisper` feature is built in
+    pub whisper_model_path: String,
+    /// OpenAI-compatible /v1/audio/transcriptions endpoint
+    pub url: String,
+    pub api_key: String,
+    pub model: String,
+}
+
+/// Mono audio captured from the microphone
+#[cfg_attr(not(feature = "voice"), allow(dead_code))]
+pub struct Recording {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+/// Microphone capture in progress for dictation, dropping it discards the audio.
+/// Without the `voice` feature recording always fails to start
+#[cfg_attr(not(feature = "voice"), allow(dead_code))]
+pub struct Recorder {
+    started: Instant,
+    #[cfg(feature = "voice")]
+    stream: cpal::Stream,
+    #[cfg(feature = "voice")]
+    samples: Arc<Mutex<Vec<f32>>>,
+    #[cfg(feature = "voice")]
+    sample_rate: u32,
+}
+
+impl Recorder {
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    #[cfg(not(feature = "voice"))]
+    pub fn start() -> Result<Self> {
+        Err(anyhow::anyhow!("This build has no voice input, rebuild with --features voice"))
+    }
+
+    #[cfg(not(feature = "voice"))]
+    pub fn stop(self) -> Recording {
+        Recording {
+            samples: Vec::new(),
+            sample_rate: 0,
+        }
+    }
+
+    /// Start recording from the default input device
+    #[cfg(feature = "voice")]
+    pub fn start() -> Result<Self> {
+        use anyhow::Context;
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+        let device = cpal::default_host()
+            .default_input_device()
+            .context("No microphone found")?;
+        let config = device.default_input_config().context(
+            "Could not open the microphone, check that the app is allowed to use it",
+        )?;
+        let sample_rate = config.sample_rate().0;
+        let samples = Arc::new(Mutex::new(Vec::new()));
+
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config.into(), samples.clone()),
+            cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config.into(), samples.clone()),
+            cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config.into(), samples.clone()),
+            format => return Err(anyhow::anyhow!("Unsupported microphone sample format {}", format)),
+        }
+        .context("Could not open the microphone, check that the app is allowed to use it")?;
+        stream.play().context("Could not start recording")?;
+
+        Ok(Self {
+            started: Instant::now(),
+            stream,
+            samples,
+            sample_rate,
+        })
+    }
+
+    /// Stop recording and hand over the audio
+    #[cfg(feature = "voice")]
+    pub fn stop(self) -> Recording {
+        drop(self.stream);
+        let samples = std::mem::take(&mut *self.samples.lock().unwrap());
+        Recording {
+            samples,
+            sample_rate: self.sample_rate,
+        }
+    }
+}
+
+/// Input stream appending the device's frames, downmixed to mono
+#[cfg(feature = "voice")]
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    samples: Arc<Mutex<Vec<f32>>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: cpal::SizedSample,
+    f32: cpal::FromSample<T>,
+{
+    use cpal::traits::DeviceTrait;
+    use cpal::FromSample;
+
+    let channels = config.channels.max(1) as usize;
+    device.build_input_stream(
+        config,
+        move |data: &[T], _| {
+            let mut samples = samples.lock().unwrap();
+            samples.extend(data.chunks(channels).map(|frame| {
+                frame.iter().map(|&sample| f32::from_sample(sample)).sum::<f32>() / channels as f32
+            }));
+        },
+        |e| log::error!("Microphone stream error: {}", e),
+        None,
+    )
+}
+
+#[cfg(not(feature = "voice"))]
+pub async fn transcribe(
+    _recording: Recording,
+    _options: TranscriptionOptions,
+    _http: crate::api::HttpOptions,
+) -> Result<String> {
+    Err(anyhow::anyhow!("This build has no voice input"))
+}
+
+/// Turn a recording into text, with the local whisper model when one is
+/// configured and the `whisper` feature is built in, else with the endpoint
+#[cfg(feature = "voice")]
+pub async fn transcribe(
+    recording: Recording,
+    options: TranscriptionOptions,
+    http: crate::api::HttpOptions,
+) -> Result<String> {
+    if recording.samples.is_empty() {
+        return Err(anyhow::anyhow!("Nothing was recorded"));
+    }
+
+    #[cfg(feature = "whisper")]
+    if !options.whisper_model_path.is_empty() {
+        return tokio::task::spawn_blocking(move || transcribe_locally(&recording, &options.whisper_model_path))
+            .await?;
+    }
+
+    if options.url.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Set a transcription endpoint or a whisper model in the config to use dictation"
+        ));
+    }
+    transcribe_remotely(&recording, &options, &http).await
+}
+
+#[cfg(feature = "voice")]
+async fn transcribe_remotely(
+    recording: &Recording,
+    options: &TranscriptionOptions,
+    http: &crate::api::HttpOptions,
+) -> Result<String> {
+    use anyhow::Context;
+
+    #[derive(serde::Deserialize)]
+    struct TranscriptionResponse {
+        text: String,
+    }
+
+    let client = http.client_builder(Duration::from_secs(60))?.build()?;
+    let file = reqwest::multipart::Part::bytes(wav_bytes(recording))
+        .file_name("dictation.wav")
+        .mime_str("audio/wav")?;
+    let form = reqwest::multipart::Form::new()
+        .text("model", options.model.clone())
+        .part("file", file);
+
+    let mut request = client.post(&options.url).multipart(form);
+    if !options.api_key.is_empty() {
+        request = request.bearer_auth(&options.api_key);
+    }
+    let response = request.send().await.context("Could not reach the transcription endpoint")?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await?;
+        return Err(anyhow::anyhow!("Transcription error ({}): {}", status, error_text));
+    }
+
+    let transcription: TranscriptionResponse =
+        response.json().await.context("Failed to parse the transcription")?;
+    Ok(transcription.text.trim().to_string())
+}
+
+#[cfg(feature = "whisper")]
+fn transcribe_locally(recording: &Recording, model_path: &str) -> Result<String> {
+    use anyhow::Context;
+    use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters};
+
+    let context = WhisperContext::new_with_params(model_path, WhisperContextParameters::default())
+        .with_context(|| format!("Could not load the whisper model {}", model_path))?;
+    let mut state = context.create_state().context("Could not start whisper")?;
+
+    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    params.set_print_timestamps(false);
+
+    let audio = resample(&recording.samples, recording.sample_rate, WHISPER_SAMPLE_RATE);
+    state.full(params, &audio).context("Transcription failed")?;
+
+    let mut text = String::new();
+    for segment in 0..state.full_n_segments()? {
+        text.push_str(&state.full_get_segment_text(segment)?);
+    }
+    Ok(text.trim().to_string())
+}
+
+/// Linear resampling, good enough for speech
+#[cfg(feature = "voice")]
+fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || from == 0 {
+        return samples.to_vec();
+    }
+    let ratio = from as f64 / to as f64;
+    let len = (samples.len() as f64 / ratio) as usize;
+    (0..len)
+        .map(|idx| {
+            let position = idx as f64 * ratio;
+            let left = position as usize;
+            let right = (left + 1).min(samples.len() - 1);
+            let fraction = (position - left as f64) as f32;
+            samples[left] * (1.0 - fraction) + samples[right] * fraction
+        })
+        .collect()
+}
+
+/// 16 kHz 16-bit mono WAV file of a recording
+#[cfg(feature = "voice")]
+fn wav_bytes(recording: &Recording) -> Vec<u8> {
+    let samples = resample(&recording.samples, recording.sample_rate, WHISPER_SAMPLE_RATE);
+    let data_len = (samples.len() * 2) as u32;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk size
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&WHISPER_SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(WHISPER_SAMPLE_RATE * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}